@@ -0,0 +1,76 @@
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use log::{debug, warn};
+
+use crate::error::ForgeError;
+use crate::rootless;
+use crate::tools;
+
+// On hosts with AppArmor, container processes are confined by a profile: forge-default, which
+// the runtime loads itself the first time, or one given with --security-opt apparmor=. PID 1
+// asks for it just before the exec, so the runtime's own setup isn't confined.
+
+const DEFAULT_PROFILE: &str = "forge-default";
+const DEFAULT_PROFILE_SOURCE: &str = include_str!("apparmor-profile");
+
+/// Whether the host kernel has AppArmor turned on
+pub fn enabled() -> bool {
+    fs::read_to_string("/sys/module/apparmor/parameters/enabled").is_ok_and(|s| s.trim() == "Y")
+}
+
+/// The profile a container runs under, from --security-opt apparmor= or the default when
+/// `choice` is None, loading the default if it isn't yet. None means unconfined.
+pub fn profile(choice: Option<&str>) -> Result<Option<String>, ForgeError> {
+    match choice {
+        Some("unconfined") => Ok(None),
+        Some(name) => {
+            if !enabled() {
+                return Err(format!("AppArmor profile {} was asked for, but AppArmor isn't enabled on this host", name).into());
+            }
+            if !loaded(name) {
+                return Err(format!("AppArmor profile {} isn't loaded; load it with apparmor_parser first", name).into());
+            }
+            Ok(Some(name.to_string()))
+        }
+        None if !enabled() => Ok(None),
+        None if loaded(DEFAULT_PROFILE) => Ok(Some(DEFAULT_PROFILE.to_string())),
+        // Loading profiles takes the host's root
+        None if rootless::active() => {
+            debug!("AppArmor profile {} isn't loaded, the rootless container runs unconfined", DEFAULT_PROFILE);
+            Ok(None)
+        }
+        None => match load_default() {
+            Ok(()) => Ok(Some(DEFAULT_PROFILE.to_string())),
+            Err(e) => {
+                warn!("{}, the container runs unconfined", e);
+                Ok(None)
+            }
+        },
+    }
+}
+
+/// Run under `profile` from the next exec on
+pub fn apply(profile: &str) -> Result<(), ForgeError> {
+    let command = format!("exec {}", profile);
+    // Kernels with more than one security module have AppArmor's own directory
+    fs::write("/proc/self/attr/apparmor/exec", &command)
+        .or_else(|_| fs::write("/proc/self/attr/exec", &command))
+        .map_err(|e| ForgeError::label(format!("switch to AppArmor profile {}", profile), e))
+}
+
+// The kernel lists every loaded profile as "NAME (MODE)"
+fn loaded(name: &str) -> bool {
+    fs::read_to_string("/sys/kernel/security/apparmor/profiles").unwrap_or_default().lines()
+        .any(|line| line.rsplit_once(' ').map_or(line, |(profile, _)| profile) == name)
+}
+
+fn load_default() -> Result<(), ForgeError> {
+    debug!("Loading AppArmor profile {}...", DEFAULT_PROFILE);
+    let mut cmd = Command::new("apparmor_parser");
+    // Replace any older version, without writing the host's profile cache
+    cmd.args(["-K", "-r"]).stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::piped());
+    let mut child = cmd.spawn().map_err(|e| tools::spawn_error(&cmd, e))?;
+    child.stdin.take().ok_or("Failed to open apparmor_parser's stdin")?.write_all(DEFAULT_PROFILE_SOURCE.as_bytes())?;
+    tools::check(&child.wait_with_output()?, &format!("Failed to load AppArmor profile {}", DEFAULT_PROFILE))
+}
@@ -0,0 +1,412 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+use std::fs;
+use std::io;
+use log::{debug, info};
+
+use crate::config;
+use crate::image::{HistoryEntry, ImageStore, ImageManifest, ImageConfig, PlatformImage};
+use crate::distribution::{compute_diff_id, OciImage, OciIndex, OciImageConfig, OciManifest, ANNOTATION_CONTAINERD_NAME, ANNOTATION_REF_NAME};
+use crate::layer::Compression;
+use crate::tarball;
+use crate::registry::{select_platform_manifest, verify_file, MEDIA_TYPE_OCI_INDEX, MEDIA_TYPE_DOCKER_MANIFEST_LIST};
+use crate::error::ForgeError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Oci,
+    Docker,
+}
+
+impl ArchiveFormat {
+    pub fn parse(s: &str) -> Result<Self, ForgeError> {
+        match s {
+            "oci" => Ok(ArchiveFormat::Oci),
+            "docker" | "docker-archive" => Ok(ArchiveFormat::Docker),
+            other => Err(format!("Unknown archive format: {} (expected oci or docker)", other).into()),
+        }
+    }
+}
+
+// One entry of a docker-archive manifest.json
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct DockerArchiveEntry {
+    config: String,
+    #[serde(default)]
+    repo_tags: Vec<String>,
+    layers: Vec<String>,
+}
+
+/// Import an image archive into the store: load [--format oci|docker] [-t NAME:TAG] PATH
+pub fn load_image(args: &[String]) -> Result<(), ForgeError> {
+    let mut format: Option<ArchiveFormat> = None;
+    let mut name_override: Option<String> = None;
+    let mut path: Option<PathBuf> = None;
+
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--format" => {
+                format = Some(ArchiveFormat::parse(args.get(i + 1).ok_or("--format requires a value")?)?);
+                i += 2;
+            }
+            "-t" | "--tag" => {
+                name_override = args.get(i + 1).map(|t| {
+                    if t.rsplit('/').next().unwrap_or(t).contains(':') { t.clone() } else { format!("{}:latest", t) }
+                });
+                i += 2;
+            }
+            other => {
+                path = Some(PathBuf::from(other));
+                i += 1;
+            }
+        }
+    }
+    let path = path.ok_or("Usage: container-runtime load [--format oci|docker] [-t NAME:TAG] PATH")?;
+
+    let store = ImageStore::new(config::images_dir()?)?;
+    let _lock = store.lock_shared()?;
+
+    // Tarballs are unpacked to a scratch directory and read like a layout directory
+    let (dir, scratch) = if path.is_dir() {
+        (path.clone(), None)
+    } else {
+        let scratch = config::tmp_dir()?.join(format!("load-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&scratch)?;
+        let unpacked = tarball::open(&path).and_then(|reader| tarball::unpack(reader, &scratch));
+        if let Err(e) = unpacked {
+            let _ = fs::remove_dir_all(&scratch);
+            return Err(format!("Failed to unpack {:?}: {}", path, e).into());
+        }
+        (scratch.clone(), Some(scratch))
+    };
+
+    let format = match format {
+        Some(format) => format,
+        None if dir.join("manifest.json").exists() => ArchiveFormat::Docker,
+        None if dir.join("index.json").exists() => ArchiveFormat::Oci,
+        None => return Err(format!("{:?} is neither an OCI layout nor a docker archive", path).into()),
+    };
+
+    let result = match format {
+        ArchiveFormat::Oci => load_oci_layout(&store, &dir, name_override.as_deref()),
+        ArchiveFormat::Docker => load_docker_archive(&store, &dir, name_override.as_deref()),
+    };
+
+    if let Some(scratch) = scratch {
+        let _ = fs::remove_dir_all(scratch);
+    }
+
+    for (name, tag) in result? {
+        info!("Loaded image {}:{}", name, tag);
+    }
+    Ok(())
+}
+
+fn load_oci_layout(store: &ImageStore, dir: &Path, name_override: Option<&str>) -> Result<Vec<(String, String)>, ForgeError> {
+    let index: OciIndex = serde_json::from_str(&fs::read_to_string(dir.join("index.json"))?)?;
+    if index.manifests.is_empty() {
+        return Err("index.json contains no manifests".into());
+    }
+
+    let mut loaded = Vec::new();
+    for entry in &index.manifests {
+        let ref_name = name_override
+            .or_else(|| entry.annotations.get(ANNOTATION_CONTAINERD_NAME).map(|s| s.as_str()))
+            .or_else(|| entry.annotations.get(ANNOTATION_REF_NAME).map(|s| s.as_str()))
+            .ok_or("Image in OCI layout has no reference name; pass -t NAME:TAG")?;
+        let (name, tag) = split_reference(ref_name)
+            .ok_or_else(|| format!("Reference {} has no repository name; pass -t NAME:TAG", ref_name))?;
+
+        // Multi-platform layouts point at a nested index
+        let mut digest = entry.digest.clone();
+        if entry.media_type == MEDIA_TYPE_OCI_INDEX || entry.media_type == MEDIA_TYPE_DOCKER_MANIFEST_LIST {
+            let nested: OciIndex = serde_json::from_slice(&read_blob(dir, &digest)?)?;
+            digest = select_platform_manifest(&nested.manifests)?;
+        }
+
+        let manifest: OciManifest = serde_json::from_slice(&read_blob(dir, &digest)?)?;
+        let oci_config: OciImageConfig = serde_json::from_slice(&read_blob(dir, &manifest.config.digest)?)?;
+
+        let mut layers = Vec::new();
+        for layer in &manifest.layers {
+            debug!("Importing layer {} ({})", layer.digest, layer.media_type);
+            let path = blob_path(dir, &layer.digest)?;
+            verify_file(&path, &layer.digest)?;
+            layers.push(import_layer(store, &path)?);
+        }
+
+        store_loaded_image(store, &name, &tag, layers, &oci_config)?;
+        loaded.push((name, tag));
+
+        // An override name only makes sense for a single image
+        if name_override.is_some() {
+            break;
+        }
+    }
+    Ok(loaded)
+}
+
+fn load_docker_archive(store: &ImageStore, dir: &Path, name_override: Option<&str>) -> Result<Vec<(String, String)>, ForgeError> {
+    let entries: Vec<DockerArchiveEntry> = serde_json::from_str(&fs::read_to_string(dir.join("manifest.json"))?)?;
+    if entries.is_empty() {
+        return Err("manifest.json contains no images".into());
+    }
+
+    let mut loaded = Vec::new();
+    for entry in &entries {
+        // The config is named after its digest, and its diff_ids pin the uncompressed layers
+        let config_path = archive_path(dir, &entry.config)?;
+        let config_digest = entry.config.strip_prefix("blobs/sha256/")
+            .or_else(|| entry.config.strip_suffix(".json"))
+            .map(|hex| format!("sha256:{}", hex))
+            .filter(|digest| is_digest(digest))
+            .ok_or_else(|| format!("Config {} in docker archive isn't named after its digest", entry.config))?;
+        verify_file(&config_path, &config_digest)?;
+        let oci_config: OciImageConfig = serde_json::from_slice(&fs::read(&config_path)?)?;
+        if oci_config.rootfs.diff_ids.len() != entry.layers.len() {
+            return Err(format!("Image {} lists {} layers but its config has {}", entry.config, entry.layers.len(), oci_config.rootfs.diff_ids.len()).into());
+        }
+
+        let mut layers = Vec::new();
+        for (layer, diff_id) in entry.layers.iter().zip(&oci_config.rootfs.diff_ids) {
+            debug!("Importing layer {}", layer);
+            let path = archive_path(dir, layer)?;
+            let actual = compute_diff_id(&path, Compression::detect(&path)?)?;
+            if &actual != diff_id {
+                return Err(format!("Layer {} is corrupt or was modified: content hashes to {}, expected {}", layer, actual, diff_id).into());
+            }
+            layers.push(import_layer(store, &path)?);
+        }
+
+        let refs: Vec<&str> = match name_override {
+            Some(name) => vec![name],
+            None => entry.repo_tags.iter().map(|s| s.as_str()).collect(),
+        };
+        if refs.is_empty() {
+            return Err("Image in docker archive has no RepoTags; pass -t NAME:TAG".into());
+        }
+
+        for reference in refs {
+            let (name, tag) = split_reference(reference)
+                .ok_or_else(|| format!("Invalid reference {}", reference))?;
+            store_loaded_image(store, &name, &tag, layers.clone(), &oci_config)?;
+            loaded.push((name, tag));
+        }
+
+        if name_override.is_some() {
+            break;
+        }
+    }
+    Ok(loaded)
+}
+
+/// Export a stored image as a tarball: save [--format oci|docker] -o FILE IMAGE:TAG
+pub fn save_image(args: &[String]) -> Result<(), ForgeError> {
+    let mut format = ArchiveFormat::Oci;
+    let mut output: Option<PathBuf> = None;
+    let mut image: Option<&str> = None;
+
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--format" => {
+                format = ArchiveFormat::parse(args.get(i + 1).ok_or("--format requires a value")?)?;
+                i += 2;
+            }
+            "-o" | "--output" => {
+                output = args.get(i + 1).map(PathBuf::from);
+                i += 2;
+            }
+            other => {
+                image = Some(other);
+                i += 1;
+            }
+        }
+    }
+    let usage = "Usage: container-runtime save [--format oci|docker] -o FILE IMAGE[:TAG|@DIGEST]";
+    let output = output.ok_or(usage)?;
+    let image = image.ok_or(usage)?;
+
+    let store = ImageStore::new(config::images_dir()?)?;
+    let _lock = store.lock_shared()?;
+
+    let scratch = config::tmp_dir()?.join(format!("save-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&scratch)?;
+
+    let result = match format {
+        ArchiveFormat::Oci => store.export_oci(image, &scratch).map(|_| ()),
+        ArchiveFormat::Docker => write_docker_archive(&store, image, &scratch),
+    }.and_then(|_| {
+        let file = tarball::pack(&scratch, io::BufWriter::new(fs::File::create(&output)?))?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    });
+    let _ = fs::remove_dir_all(&scratch);
+    result.map_err(|e| format!("Failed to write {:?}: {}", output, e))?;
+
+    info!("Saved {} to {:?}", image, output);
+    Ok(())
+}
+
+/// Lay out an image the way `docker save` does, so `docker load` accepts it
+fn write_docker_archive(store: &ImageStore, reference: &str, dest: &Path) -> Result<(), ForgeError> {
+    let PlatformImage { manifest, config } = store.resolve(reference)?;
+    let (name, tag) = (manifest.name.as_str(), manifest.tag.as_str());
+    let image = OciImage::from_store(store, &manifest, &config)?;
+
+    // Legacy layer directories hold uncompressed layer.tar files named by diff_id
+    let mut layer_paths = Vec::new();
+    let mut parent: Option<String> = None;
+    for (layer, diff_id) in image.manifest.layers.iter().zip(&image.diff_ids) {
+        let id = diff_id.trim_start_matches("sha256:").to_string();
+        let dir = dest.join(&id);
+        fs::create_dir_all(&dir)?;
+
+        let mut layer_tar = fs::File::create(dir.join("layer.tar"))?;
+        let compression = Compression::from_media_type(&layer.media_type);
+        let blob = io::BufReader::new(fs::File::open(store.get_layer_path(&layer.digest)?)?);
+        io::copy(&mut tarball::decoder(blob, compression)?, &mut layer_tar)
+            .map_err(|e| format!("Failed to decompress layer {}: {}", layer.digest, e))?;
+
+        fs::write(dir.join("VERSION"), "1.0")?;
+        let mut layer_json = serde_json::json!({ "id": id });
+        if let Some(parent) = &parent {
+            layer_json["parent"] = serde_json::json!(parent);
+        }
+        fs::write(dir.join("json"), serde_json::to_string(&layer_json)?)?;
+
+        layer_paths.push(format!("{}/layer.tar", id));
+        parent = Some(id);
+    }
+
+    let config_file = format!("{}.json", image.manifest.config.digest.trim_start_matches("sha256:"));
+    fs::write(dest.join(&config_file), &image.config_blob)?;
+
+    let entries = vec![DockerArchiveEntry {
+        config: config_file,
+        repo_tags: vec![format!("{}:{}", name, tag)],
+        layers: layer_paths,
+    }];
+    fs::write(dest.join("manifest.json"), serde_json::to_string(&entries)?)?;
+
+    if let Some(top) = parent {
+        let repositories = serde_json::json!({ name: { tag: top } });
+        fs::write(dest.join("repositories"), serde_json::to_string(&repositories)?)?;
+    }
+
+    debug!("Wrote docker archive for {}:{} to {:?}", name, tag, dest);
+    Ok(())
+}
+
+fn store_loaded_image(
+    store: &ImageStore,
+    name: &str,
+    tag: &str,
+    layers: Vec<(String, Compression)>,
+    oci_config: &OciImageConfig,
+) -> Result<(), ForgeError> {
+    let (manifest, config) = image_from_oci(name, tag, layers, oci_config);
+    store.save_image(manifest, config)?;
+    Ok(())
+}
+
+/// Translate an OCI config and its layers into our manifest and config
+pub fn image_from_oci(
+    name: &str,
+    tag: &str,
+    layers: Vec<(String, Compression)>,
+    oci_config: &OciImageConfig,
+) -> (ImageManifest, ImageConfig) {
+    let config = oci_config.image_config();
+
+    let layer_media_types = layers.iter()
+        .filter(|(_, compression)| *compression != Compression::Gzip)
+        .map(|(digest, compression)| (digest.clone(), compression.media_type().to_string()))
+        .collect();
+
+    let manifest = ImageManifest {
+        name: name.to_string(),
+        tag: tag.to_string(),
+        layers: layers.into_iter().map(|(digest, _)| digest).collect(),
+        layer_media_types,
+        architecture: oci_config.architecture.clone(),
+        os: oci_config.os.clone(),
+        variant: oci_config.variant.clone(),
+        digest: String::new(),
+        registry_digest: String::new(),
+        history: oci_config.history.iter()
+            .map(|h| HistoryEntry {
+                created_by: h.created_by.clone(),
+                created: h.created.clone().unwrap_or_default(),
+                empty_layer: h.empty_layer,
+                ..Default::default()
+            })
+            .collect(),
+        created: oci_config.created.clone().unwrap_or_default(),
+        author: oci_config.author.clone().unwrap_or_default(),
+        builder: String::new(),
+    };
+    (manifest, config)
+}
+
+/// Store a layer blob as-is, noting how it is compressed
+fn import_layer(store: &ImageStore, blob: &Path) -> Result<(String, Compression), ForgeError> {
+    let compression = Compression::detect(blob)?;
+    Ok((store.save_layer(blob)?, compression))
+}
+
+// Where a layout keeps the blob `digest`, which comes from the untrusted index or a manifest
+fn blob_path(dir: &Path, digest: &str) -> Result<PathBuf, ForgeError> {
+    if !is_digest(digest) {
+        return Err(format!("Invalid digest {:?} in OCI layout", digest).into());
+    }
+    archive_path(dir, &format!("blobs/sha256/{}", &digest["sha256:".len()..]))
+}
+
+// A JSON blob, checked against the digest it was referenced by
+fn read_blob(dir: &Path, digest: &str) -> Result<Vec<u8>, ForgeError> {
+    let path = blob_path(dir, digest)?;
+    verify_file(&path, digest)?;
+    Ok(fs::read(path)?)
+}
+
+/// Whether `digest` is a well-formed sha256 digest, and so safe to use as a file name
+pub fn is_digest(digest: &str) -> bool {
+    digest.strip_prefix("sha256:")
+        .is_some_and(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)))
+}
+
+// A file named in the archive's own metadata: relative, with no `..`, and not leading out through a symlink
+fn archive_path(dir: &Path, name: &str) -> Result<PathBuf, ForgeError> {
+    let relative = Path::new(name);
+    if name.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(format!("Path {:?} in image archive points outside it", name).into());
+    }
+    let path = dir.join(relative);
+    let resolved = path.canonicalize().map_err(|e| format!("{} in image archive: {}", name, e))?;
+    if !resolved.starts_with(dir.canonicalize()?) {
+        return Err(format!("Path {:?} in image archive points outside it", name).into());
+    }
+    Ok(path)
+}
+
+// "docker.io/library/alpine:3.19" -> ("alpine", "3.19"); a bare "v1" is only a tag, with no name
+fn split_reference(reference: &str) -> Option<(String, String)> {
+    if !reference.contains(':') && !reference.contains('/') {
+        return None;
+    }
+
+    let last_slash = reference.rfind('/').map(|p| p + 1).unwrap_or(0);
+    let (name, tag) = match reference[last_slash..].rfind(':') {
+        Some(pos) => (&reference[..last_slash + pos], &reference[last_slash + pos + 1..]),
+        None => (reference, "latest"),
+    };
+
+    let name = name.trim_start_matches("docker.io/").trim_start_matches("library/");
+    if name.is_empty() || tag.is_empty() {
+        return None;
+    }
+    Some((name.to_string(), tag.to_string()))
+}
@@ -0,0 +1,322 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use nix::pty::PtyMaster;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{dup2, Pid};
+use log::{debug, info, warn};
+
+use crate::error::ForgeError;
+use crate::lifecycle;
+use crate::rwlayer::{self, ContainerRecord};
+use crate::state;
+use crate::tty;
+
+// A container run with -t is served by its supervisor on containers/<id>/attach.sock, so the
+// terminal session can be left and picked up again. The client puts the host terminal in raw
+// mode and sends what is typed as frames, [kind][length, 4 bytes big-endian][payload]:
+//   0  input for the container's terminal
+//   1  the window size, rows and columns as 2 bytes big-endian each
+// and gets the container's output back as is. One client is attached at a time, the newest.
+// Once the detach keys are typed the client leaves and the container runs on; from then on
+// the supervisor logs to containers/<id>/supervisor.log instead of the terminal it came from.
+
+pub const DEFAULT_DETACH_KEYS: &str = "ctrl-p,ctrl-q";
+const SOCKET_FILE: &str = "attach.sock";
+const LOG_FILE: &str = "supervisor.log";
+const INPUT: u8 = 0;
+const RESIZE: u8 = 1;
+
+type Client = Arc<Mutex<Option<(u64, UnixStream)>>>;
+
+/// The bytes of a detach sequence like "ctrl-p,ctrl-q": ctrl-<key> or single characters
+pub fn parse_detach_keys(spec: &str) -> Result<Vec<u8>, ForgeError> {
+    let keys = spec.split(',').map(|key| match key.strip_prefix("ctrl-") {
+        Some(key) if key.len() == 1 && matches!(key.as_bytes()[0], b'a'..=b'z' | b'@' | b'['..=b'_') => {
+            Ok(key.as_bytes()[0].to_ascii_uppercase() & 0x1f)
+        }
+        None if key.len() == 1 => Ok(key.as_bytes()[0]),
+        _ => Err(format!("Invalid detach key {:?}, expected ctrl-<key> or a single character", key)),
+    }).collect::<Result<Vec<u8>, _>>()?;
+    Ok(keys)
+}
+
+/// attach [--detach-keys KEYS] CONTAINER: reconnect to the terminal of a container run with -t,
+/// returning the code to exit with
+pub fn attach_command(args: &[String]) -> Result<i32, ForgeError> {
+    let mut detach_keys = None;
+    let mut reference = None;
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--detach-keys" => {
+                detach_keys = Some(args.get(i + 1).ok_or("--detach-keys requires a sequence like ctrl-p,ctrl-q")?.as_str());
+                i += 2;
+            }
+            other => {
+                reference = Some(other);
+                i += 1;
+            }
+        }
+    }
+    let reference = reference.ok_or("Usage: container-runtime attach [--detach-keys KEYS] CONTAINER")?;
+    let record = rwlayer::find(reference)?;
+    if !record.tty {
+        return Err(format!("Container {} has no terminal to attach to, it wasn't run with -t", record.display_name()).into());
+    }
+    if !state::load(&record)?.is_active() {
+        return Err(format!("Container {} is not running", record.display_name()).into());
+    }
+    let keys = parse_detach_keys(detach_keys.unwrap_or(&record.detach_keys()))?;
+    client(&record, &keys, None)
+}
+
+/// Attach the host terminal to the container's until the container exits or the detach keys are
+/// typed. `supervisor` is the process starting the container, when this one forked it, and the
+/// code it exited with is returned.
+pub fn client(record: &ContainerRecord, detach_keys: &[u8], supervisor: Option<Pid>) -> Result<i32, ForgeError> {
+    let stream = connect(&record.dir()?, supervisor)?;
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    // Signals meant for the container are passed on, as the supervisor would
+    lifecycle::catch_signals();
+    tty::watch_resize();
+    let raw_mode = tty::enter_raw_mode();
+    send_window_size(&writer);
+
+    let detached = Arc::new(AtomicBool::new(false));
+    {
+        let (writer, detached, keys) = (writer.clone(), detached.clone(), detach_keys.to_vec());
+        thread::spawn(move || {
+            tty::block_signals();
+            if copy_input(&writer, &keys) {
+                detached.store(true, Ordering::SeqCst);
+                if let Ok(stream) = writer.lock() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+            }
+        });
+    }
+
+    let mut stream = stream;
+    let mut stdout = io::stdout();
+    let mut buffer = [0u8; 8192];
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                if stdout.write_all(&buffer[..n]).and_then(|_| stdout.flush()).is_err() {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                if lifecycle::relay_pending(record) {
+                    // Like a signal to the supervisor, this stops the container for good
+                    state::record(record, |s| s.stopped = true);
+                }
+                if tty::resized() {
+                    send_window_size(&writer);
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    drop(raw_mode);
+    lifecycle::stop_finished();
+
+    if detached.load(Ordering::SeqCst) {
+        info!("Detached from container {}, `attach {}` reconnects", record.display_name(), record.display_name());
+    } else if let Some(supervisor) = supervisor {
+        // The container ended; its supervisor still unmounts and records the exit, and has
+        // already said why if it failed, as on a timeout
+        if let Ok(WaitStatus::Exited(_, code)) = waitpid(supervisor, None) {
+            return Ok(code);
+        }
+    }
+    Ok(0)
+}
+
+/// In the supervisor: serve the container's terminal to attached clients. The returned thread
+/// ends once everything in the container has closed the terminal.
+pub fn serve(container_dir: &Path, master: &Arc<PtyMaster>) -> JoinHandle<()> {
+    let client: Client = Arc::new(Mutex::new(None));
+    match listen(container_dir) {
+        Ok(listener) => {
+            let (client, master, container_dir) = (client.clone(), master.clone(), container_dir.to_path_buf());
+            thread::spawn(move || {
+                tty::block_signals();
+                accept(listener, &client, &master, &container_dir);
+            });
+        }
+        Err(e) => warn!("Nothing can attach to the container's terminal: {}", e),
+    }
+
+    let (master, container_dir) = (master.clone(), container_dir.to_path_buf());
+    thread::spawn(move || {
+        tty::block_signals();
+        let mut buffer = [0u8; 8192];
+        // EIO once the container's side is closed everywhere
+        while let Ok(n @ 1..) = (&*master).read(&mut buffer) {
+            if let Ok(mut client) = client.lock() {
+                if client.as_mut().is_some_and(|(_, stream)| stream.write_all(&buffer[..n]).is_err()) {
+                    *client = None;
+                }
+            }
+        }
+        if let Some((_, stream)) = client.lock().ok().and_then(|mut c| c.take()) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        let _ = fs::remove_file(container_dir.join(SOCKET_FILE));
+    })
+}
+
+fn accept(listener: UnixListener, client: &Client, master: &Arc<PtyMaster>, container_dir: &Path) {
+    for (id, stream) in (0u64..).zip(listener.incoming()) {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let Ok(reader) = stream.try_clone() else {
+            continue;
+        };
+        debug!("Client {} attached", id);
+        if let Ok(mut client) = client.lock() {
+            if let Some((_, previous)) = client.replace((id, stream)) {
+                let _ = previous.shutdown(Shutdown::Both);
+            }
+        }
+        let (client, master, container_dir) = (client.clone(), master.clone(), container_dir.to_path_buf());
+        thread::spawn(move || {
+            tty::block_signals();
+            read_frames(reader, &master);
+            debug!("Client {} detached", id);
+            if let Ok(mut client) = client.lock() {
+                if client.as_ref().is_some_and(|(current, _)| *current == id) {
+                    *client = None;
+                }
+            }
+            log_to_file(&container_dir);
+        });
+    }
+}
+
+fn read_frames(mut stream: UnixStream, master: &PtyMaster) {
+    let mut header = [0u8; 5];
+    while stream.read_exact(&mut header).is_ok() {
+        let mut payload = vec![0u8; u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize];
+        if stream.read_exact(&mut payload).is_err() {
+            break;
+        }
+        match (header[0], payload.as_slice()) {
+            (INPUT, input) => {
+                if (&*master).write_all(input).is_err() {
+                    break;
+                }
+            }
+            (RESIZE, [r0, r1, c0, c1]) => {
+                tty::set_window_size(master, u16::from_be_bytes([*r0, *r1]), u16::from_be_bytes([*c0, *c1]));
+            }
+            (kind, _) => debug!("Ignoring attach frame of kind {}", kind),
+        }
+    }
+}
+
+// Once the terminal the supervisor was started from has been left, its logs go to a file
+fn log_to_file(container_dir: &Path) {
+    static REDIRECTED: Once = Once::new();
+    REDIRECTED.call_once(|| {
+        let path = container_dir.join(LOG_FILE);
+        match fs::OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => {
+                let _ = dup2(file.as_raw_fd(), 1);
+                let _ = dup2(file.as_raw_fd(), 2);
+            }
+            Err(e) => warn!("Failed to open {:?}: {}", path, e),
+        }
+    });
+}
+
+// Copy stdin to the container, holding back what may be the start of the detach keys.
+// Returns true once they were typed, false at the end of input.
+fn copy_input(writer: &Mutex<UnixStream>, detach_keys: &[u8]) -> bool {
+    let mut stdin = io::stdin().lock();
+    let mut buffer = [0u8; 4096];
+    let mut matched = 0;
+    while let Ok(n @ 1..) = stdin.read(&mut buffer) {
+        let mut input = Vec::with_capacity(n + matched);
+        for &byte in &buffer[..n] {
+            if detach_keys.get(matched) == Some(&byte) {
+                matched += 1;
+                if matched == detach_keys.len() {
+                    return true;
+                }
+                continue;
+            }
+            // Not the sequence after all: what was held back goes through
+            input.extend_from_slice(&detach_keys[..matched]);
+            matched = 0;
+            if detach_keys.first() == Some(&byte) {
+                matched = 1;
+            } else {
+                input.push(byte);
+            }
+        }
+        if !input.is_empty() && send(writer, INPUT, &input).is_err() {
+            break;
+        }
+    }
+    false
+}
+
+fn send_window_size(writer: &Mutex<UnixStream>) {
+    if let Some((rows, columns)) = tty::window_size() {
+        let mut size = rows.to_be_bytes().to_vec();
+        size.extend_from_slice(&columns.to_be_bytes());
+        let _ = send(writer, RESIZE, &size);
+    }
+}
+
+fn send(writer: &Mutex<UnixStream>, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = vec![kind];
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    let mut stream = writer.lock().map_err(|_| io::Error::other("attach stream poisoned"))?;
+    stream.write_all(&frame)
+}
+
+fn listen(container_dir: &Path) -> io::Result<UnixListener> {
+    let (_dir, path) = socket_path(container_dir)?;
+    let _ = fs::remove_file(&path);
+    UnixListener::bind(&path)
+}
+
+// The supervisor may still be starting the container; a client it forked waits for it
+fn connect(container_dir: &Path, supervisor: Option<Pid>) -> Result<UnixStream, ForgeError> {
+    let (_dir, path) = socket_path(container_dir)?;
+    loop {
+        let error = match UnixStream::connect(&path) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => e,
+        };
+        match supervisor {
+            Some(pid) if matches!(waitpid(pid, Some(WaitPidFlag::WNOHANG)), Ok(WaitStatus::StillAlive)) => {
+                thread::sleep(Duration::from_millis(50));
+            }
+            Some(_) => return Err("The container's supervisor exited before it could be attached to".into()),
+            None => return Err(format!("Can't attach to the container's terminal: {}", error).into()),
+        }
+    }
+}
+
+// A 64-digit id makes the real path too long for a socket address, so it is reached through
+// an open descriptor of the container's directory, which has to stay open while it is used
+fn socket_path(container_dir: &Path) -> io::Result<(fs::File, PathBuf)> {
+    let dir = fs::File::open(container_dir)?;
+    let path = PathBuf::from(format!("/proc/self/fd/{}/{}", dir.as_raw_fd(), SOCKET_FILE));
+    Ok((dir, path))
+}
@@ -0,0 +1,424 @@
+use std::path::PathBuf;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::{Command, Stdio};
+use log::{debug, info};
+
+use crate::image::temp_path;
+use crate::http;
+use crate::registry::RegistrySettings;
+use crate::error::ForgeError;
+
+const DOCKER_HUB_REGISTRY: &str = "registry-1.docker.io";
+const DOCKER_HUB_CONFIG_KEY: &str = "https://index.docker.io/v1/";
+
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    Basic { username: String, password: String },
+    IdentityToken(String),
+}
+
+// A parsed `WWW-Authenticate` header
+#[derive(Debug)]
+pub enum Challenge {
+    Basic,
+    Bearer { realm: String, service: Option<String>, scope: Option<String> },
+}
+
+impl Challenge {
+    pub fn parse(header: &str) -> Result<Self, ForgeError> {
+        let header = header.trim();
+        let (scheme, rest) = header.split_once(' ').unwrap_or((header, ""));
+        let params = parse_params(rest);
+        let param = |name: &str| params.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone());
+
+        match scheme.to_ascii_lowercase().as_str() {
+            "basic" => Ok(Challenge::Basic),
+            "bearer" => Ok(Challenge::Bearer {
+                realm: param("realm").ok_or("Bearer challenge has no realm")?,
+                service: param("service"),
+                scope: param("scope"),
+            }),
+            other => Err(format!("Unsupported authentication scheme: {}", other).into()),
+        }
+    }
+}
+
+/// Answer a registry's 401 challenge, returning the value for the Authorization header
+pub fn authorize(registry: &str, challenge: &str) -> Result<String, ForgeError> {
+    let credentials = load_credentials(registry)?;
+
+    match Challenge::parse(challenge)? {
+        Challenge::Basic => match credentials {
+            Some(Credentials::Basic { username, password }) => {
+                Ok(format!("Basic {}", base64_encode(format!("{}:{}", username, password).as_bytes())))
+            }
+            _ => Err(format!("{} requires credentials; run `container-runtime login {}`", registry, registry).into()),
+        },
+        Challenge::Bearer { realm, service, scope } => {
+            let token = fetch_token(&realm, service.as_deref(), scope.as_deref(), credentials.as_ref())?;
+            Ok(format!("Bearer {}", token))
+        }
+    }
+}
+
+fn fetch_token(
+    realm: &str,
+    service: Option<&str>,
+    scope: Option<&str>,
+    credentials: Option<&Credentials>,
+) -> Result<String, ForgeError> {
+    let agent = RegistrySettings::get()?.agent(realm)?;
+    debug!("Requesting registry token from {}", realm);
+
+    let mut fields = Vec::new();
+    if let Some(service) = service {
+        fields.push(("service", service));
+    }
+    if let Some(scope) = scope {
+        fields.push(("scope", scope));
+    }
+    let response = match credentials {
+        // Identity tokens are exchanged through the OAuth2 refresh_token grant
+        Some(Credentials::IdentityToken(refresh_token)) => {
+            let mut form = vec![("grant_type", "refresh_token"), ("client_id", "forge-container"), ("refresh_token", refresh_token.as_str())];
+            form.extend(fields);
+            let request = http::request("POST", realm, &["Content-Type: application/x-www-form-urlencoded".to_string()])?;
+            http::send(&agent, request, http::form_encode(&form), true)?
+        }
+        other => {
+            // The realm may come with a query string of its own
+            let url = match (fields.is_empty(), realm.contains('?')) {
+                (true, _) => realm.to_string(),
+                (false, true) => format!("{}&{}", realm, http::form_encode(&fields)),
+                (false, false) => format!("{}?{}", realm, http::form_encode(&fields)),
+            };
+
+            let headers = match other {
+                Some(Credentials::Basic { username, password }) => {
+                    vec![format!("Authorization: Basic {}", base64_encode(format!("{}:{}", username, password).as_bytes()))]
+                }
+                _ => Vec::new(),
+            };
+            http::send(&agent, http::request("GET", &url, &headers)?, (), true)?
+        }
+    };
+    if !response.status().is_success() {
+        return Err(format!("Token request to {} failed: HTTP {}", realm, response.status().as_u16()).into());
+    }
+
+    let value: serde_json::Value = serde_json::from_reader(response.into_body().into_reader())?;
+    value["token"].as_str()
+        .or_else(|| value["access_token"].as_str())
+        .map(|t| t.to_string())
+        .ok_or_else(|| "Token response did not contain a token".into())
+}
+
+/// Look up credentials for a registry in the docker config (inline auths, then credential helpers)
+pub fn load_credentials(registry: &str) -> Result<Option<Credentials>, ForgeError> {
+    let path = docker_config_path()?;
+    let config: serde_json::Value = match fs::read_to_string(&path) {
+        Ok(json) => serde_json::from_str(&json)?,
+        Err(_) => return Ok(None),
+    };
+    let key = config_key(registry);
+
+    // Per-registry helpers win over the global credsStore, matching docker's behaviour
+    let helper = config["credHelpers"][&key].as_str()
+        .or_else(|| config["credHelpers"][registry].as_str())
+        .or_else(|| config["credsStore"].as_str());
+    if let Some(helper) = helper {
+        if let Some(credentials) = credentials_from_helper(helper, &key)? {
+            return Ok(Some(credentials));
+        }
+    }
+
+    let auths = &config["auths"];
+    let entry = auths.get(&key)
+        .or_else(|| auths.get(registry))
+        .or_else(|| auths.get(format!("https://{}", registry)));
+    let entry = match entry {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+
+    if let Some(token) = entry["identitytoken"].as_str().filter(|t| !t.is_empty()) {
+        return Ok(Some(Credentials::IdentityToken(token.to_string())));
+    }
+
+    if let Some(auth) = entry["auth"].as_str().filter(|a| !a.is_empty()) {
+        let decoded = String::from_utf8(base64_decode(auth)?)
+            .map_err(|_| "Malformed auth entry in docker config")?;
+        let (username, password) = decoded.split_once(':')
+            .ok_or("Malformed auth entry in docker config")?;
+        return Ok(Some(Credentials::Basic {
+            username: username.to_string(),
+            password: password.to_string(),
+        }));
+    }
+
+    Ok(None)
+}
+
+fn credentials_from_helper(helper: &str, server: &str) -> Result<Option<Credentials>, ForgeError> {
+    let program = format!("docker-credential-{}", helper);
+    debug!("Querying credential helper {} for {}", program, server);
+
+    let mut child = match Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            debug!("Credential helper {} unavailable: {}", program, e);
+            return Ok(None);
+        }
+    };
+    child.stdin.take().ok_or("Failed to open helper stdin")?.write_all(server.as_bytes())?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        // Helpers exit non-zero with "credentials not found" for unknown servers
+        return Ok(None);
+    }
+
+    let value: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let username = value["Username"].as_str().unwrap_or_default();
+    let secret = value["Secret"].as_str().unwrap_or_default();
+
+    if username == "<token>" {
+        Ok(Some(Credentials::IdentityToken(secret.to_string())))
+    } else {
+        Ok(Some(Credentials::Basic { username: username.to_string(), password: secret.to_string() }))
+    }
+}
+
+/// Store credentials for a registry in the docker config file
+pub fn save_credentials(registry: &str, username: &str, password: &str) -> Result<(), ForgeError> {
+    let path = docker_config_path()?;
+    let mut config: serde_json::Value = match fs::read_to_string(&path) {
+        Ok(json) => serde_json::from_str(&json)?,
+        Err(_) => serde_json::json!({}),
+    };
+
+    if !config["auths"].is_object() {
+        config["auths"] = serde_json::json!({});
+    }
+    let auth = base64_encode(format!("{}:{}", username, password).as_bytes());
+    config["auths"][config_key(registry)] = serde_json::json!({ "auth": auth });
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // The file holds plaintext credentials: create it private, and replace the old one in one step
+    let staged = temp_path(&path);
+    let written = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&staged)
+        .and_then(|mut file| file.write_all(serde_json::to_string_pretty(&config)?.as_bytes()).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&staged, &path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&staged);
+        return Err(format!("Failed to write {:?}: {}", path, e).into());
+    }
+
+    debug!("Saved credentials for {} to {:?}", registry, path);
+    Ok(())
+}
+
+/// Log in to a registry: login [-u USER] [-p PASSWORD | --password-stdin] [REGISTRY]
+pub fn login(args: &[String]) -> Result<(), ForgeError> {
+    let mut registry = DOCKER_HUB_REGISTRY.to_string();
+    let mut username: Option<String> = None;
+    let mut password: Option<String> = None;
+    let mut password_stdin = false;
+
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-u" | "--username" => {
+                username = args.get(i + 1).cloned();
+                i += 2;
+            }
+            "-p" | "--password" => {
+                password = args.get(i + 1).cloned();
+                i += 2;
+            }
+            "--password-stdin" => {
+                password_stdin = true;
+                i += 1;
+            }
+            other => {
+                registry = normalize_registry(other);
+                i += 1;
+            }
+        }
+    }
+
+    let username = match username {
+        Some(u) => u,
+        None => prompt("Username: ", false)?,
+    };
+    let password = match password {
+        Some(p) => p,
+        None if password_stdin => {
+            let mut line = String::new();
+            std::io::stdin().lock().read_line(&mut line)?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+        None => prompt("Password: ", true)?,
+    };
+
+    verify_login(&registry, &username, &password)?;
+    save_credentials(&registry, &username, &password)?;
+
+    info!("Login succeeded for {}", registry);
+    Ok(())
+}
+
+// Hit /v2/ and answer whatever challenge comes back with the given credentials
+fn verify_login(registry: &str, username: &str, password: &str) -> Result<(), ForgeError> {
+    let settings = RegistrySettings::get()?;
+    let url = format!("{}/v2/", settings.base_url(registry));
+    let agent = settings.agent(&url)?;
+    let response = http::send(&agent, http::request("GET", &url, &[])?, (), false)?;
+
+    let challenge = match response.headers().get("www-authenticate").and_then(|v| v.to_str().ok()) {
+        Some(challenge) => challenge.to_string(),
+        None => {
+            debug!("{} did not ask for authentication", registry);
+            return Ok(());
+        }
+    };
+
+    let credentials = Credentials::Basic { username: username.to_string(), password: password.to_string() };
+    let authorization = match Challenge::parse(&challenge)? {
+        Challenge::Basic => format!("Basic {}", base64_encode(format!("{}:{}", username, password).as_bytes())),
+        Challenge::Bearer { realm, service, scope } => {
+            format!("Bearer {}", fetch_token(&realm, service.as_deref(), scope.as_deref(), Some(&credentials))?)
+        }
+    };
+
+    let header = format!("Authorization: {}", authorization);
+    let response = http::send(&agent, http::request("GET", &url, &[header])?, (), false)?;
+    if response.status() != 200 {
+        return Err(format!("Login to {} failed: HTTP {}", registry, response.status().as_u16()).into());
+    }
+    Ok(())
+}
+
+fn prompt(label: &str, hidden: bool) -> Result<String, ForgeError> {
+    print!("{}", label);
+    std::io::stdout().flush()?;
+
+    if hidden {
+        let _ = Command::new("stty").arg("-echo").stdin(Stdio::inherit()).status();
+    }
+    let mut line = String::new();
+    let result = std::io::stdin().lock().read_line(&mut line);
+    if hidden {
+        let _ = Command::new("stty").arg("echo").stdin(Stdio::inherit()).status();
+        println!();
+    }
+    result?;
+
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn docker_config_path() -> Result<PathBuf, ForgeError> {
+    if let Ok(dir) = std::env::var("DOCKER_CONFIG") {
+        return Ok(PathBuf::from(dir).join("config.json"));
+    }
+    Ok(PathBuf::from(std::env::var("HOME").map_err(|_| "HOME is not set")?).join(".docker/config.json"))
+}
+
+fn normalize_registry(registry: &str) -> String {
+    let host = registry.trim_start_matches("https://").trim_start_matches("http://").trim_end_matches('/');
+    match host {
+        "docker.io" | "index.docker.io" | "index.docker.io/v1" => DOCKER_HUB_REGISTRY.to_string(),
+        other => other.to_string(),
+    }
+}
+
+// Docker Hub credentials are keyed by the legacy index URL
+fn config_key(registry: &str) -> String {
+    if registry == DOCKER_HUB_REGISTRY {
+        DOCKER_HUB_CONFIG_KEY.to_string()
+    } else {
+        registry.to_string()
+    }
+}
+
+// Parse `realm="...",service="...",scope="..."` into key/value pairs
+fn parse_params(params: &str) -> Vec<(String, String)> {
+    let mut result = Vec::new();
+    let mut chars = params.chars().peekable();
+    loop {
+        let key: String = chars.by_ref()
+            .skip_while(|c| *c == ',' || c.is_whitespace())
+            .take_while(|c| *c != '=')
+            .collect();
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            for c in chars.by_ref() {
+                if c == '"' {
+                    break;
+                }
+                value.push(c);
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ',' {
+                    break;
+                }
+                value.push(c);
+                chars.next();
+            }
+        }
+        result.push((key.trim().to_ascii_lowercase(), value));
+    }
+    result
+}
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+
+        out.push(BASE64_ALPHABET[(n >> 18) as usize & 63] as char);
+        out.push(BASE64_ALPHABET[(n >> 12) as usize & 63] as char);
+        out.push(if chunk.len() > 1 { BASE64_ALPHABET[(n >> 6) as usize & 63] as char } else { '=' });
+        out.push(if chunk.len() > 2 { BASE64_ALPHABET[n as usize & 63] as char } else { '=' });
+    }
+    out
+}
+
+pub fn base64_decode(data: &str) -> Result<Vec<u8>, ForgeError> {
+    let mut out = Vec::with_capacity(data.len() / 4 * 3);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in data.trim().bytes().filter(|c| *c != b'=' && !c.is_ascii_whitespace()) {
+        let value = BASE64_ALPHABET.iter()
+            .position(|a| *a == c)
+            .ok_or("Invalid base64 data")? as u32;
+        buffer = (buffer << 6) | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Ok(out)
+}
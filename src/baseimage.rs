@@ -0,0 +1,246 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use nix::fcntl::{Flock, FlockArg};
+use sha2::{Digest, Sha256};
+use log::{debug, info, warn};
+
+use crate::config;
+use crate::http;
+use crate::image;
+use crate::registry::verify_file;
+use crate::tarball;
+use crate::error::ForgeError;
+
+// Distribution root filesystems that FROM can use without going through a registry.
+// Each source turns a tag and host architecture into a tarball URL plus its published
+// SHA-256; adding a distribution means adding an entry to SOURCES.
+
+const LXC_IMAGES: &str = "https://images.linuxcontainers.org";
+
+type Resolver = fn(tag: &str, arch: &str) -> Result<Download, ForgeError>;
+
+pub struct BaseImageSource {
+    pub name: &'static str,
+    default_tag: &'static str,
+    resolve: Resolver,
+}
+
+// A rootfs tarball and the checksum its publisher lists for it
+struct Download {
+    url: String,
+    sha256: String,
+}
+
+const SOURCES: &[BaseImageSource] = &[
+    BaseImageSource { name: "alpine", default_tag: "latest", resolve: alpine },
+    BaseImageSource { name: "ubuntu", default_tag: "24.04", resolve: ubuntu },
+    BaseImageSource { name: "debian", default_tag: "bookworm", resolve: debian },
+    BaseImageSource { name: "busybox", default_tag: "latest", resolve: busybox },
+];
+
+/// The source for a bare `name[:tag]` reference, with the tag to fetch
+pub fn find(image: &str) -> Option<(&'static BaseImageSource, String)> {
+    if image.contains('/') || image.contains('@') {
+        return None;
+    }
+    let (name, tag) = image.split_once(':').unwrap_or((image, "latest"));
+    let source = SOURCES.iter().find(|s| s.name == name)?;
+    let tag = if tag == "latest" { source.default_tag } else { tag };
+    Some((source, tag.to_string()))
+}
+
+/// Download (or reuse from the download cache) a verified rootfs tarball and unpack it into `dest`
+pub fn fetch(source: &BaseImageSource, tag: &str, dest: &Path) -> Result<(), ForgeError> {
+    let arch = std::env::consts::ARCH;
+    let download = (source.resolve)(tag, arch)?;
+    debug!("    {} (sha256 {})", download.url, download.sha256);
+
+    let archive = download_verified(&download)
+        .map_err(|e| format!("{}:{}: {}", source.name, tag, e))?;
+
+    // Publishers use a mix of gzip and xz
+    tarball::open(&archive)
+        .and_then(|reader| tarball::unpack(reader, dest))
+        .map_err(|e| format!("Failed to extract {}:{}: {}", source.name, tag, e).into())
+}
+
+// The cache holds `<sha256 of url>` once verified, and `<sha256 of url>.partial` while
+// downloading, so an interrupted download picks up where it stopped
+fn download_verified(download: &Download) -> Result<PathBuf, ForgeError> {
+    let dir = config::download_cache_dir()?;
+    let key = hex::encode(Sha256::digest(download.url.as_bytes()));
+    let complete = dir.join(&key);
+    let partial = dir.join(format!("{}.partial", key));
+    let digest = format!("sha256:{}", download.sha256);
+
+    // Concurrent builds of the same base image would otherwise append to one partial file
+    let lock_file = fs::File::options().create(true).truncate(false).write(true).open(dir.join(format!("{}.lock", key)))?;
+    let _lock = Flock::lock(lock_file, FlockArg::LockExclusive)
+        .map_err(|(_, e)| format!("Failed to lock download cache: {}", e))?;
+
+    if complete.exists() {
+        if verify_file(&complete, &digest).is_ok() {
+            debug!("    Using cached download {:?}", complete);
+            return Ok(complete);
+        }
+        // Upstream republished the file, or the cache was damaged
+        fs::remove_file(&complete)?;
+    }
+
+    for attempt in 0..2 {
+        // A partial may already hold everything if we were interrupted between download and rename
+        if !(partial.exists() && verify_file(&partial, &digest).is_ok()) {
+            let resumed = fs::metadata(&partial).map(|m| m.len()).unwrap_or(0);
+            if resumed > 0 {
+                info!("    Resuming download at {}", image::format_size(resumed));
+            } else {
+                info!("    Downloading {}...", download.url);
+            }
+
+            download_to(&download.url, &partial, resumed)
+                .map_err(|e| format!("Download interrupted, run the build again to resume it: {}", e))?;
+
+            if let Err(e) = verify_file(&partial, &digest) {
+                // The resumed bytes may belong to a different upload, so start over once
+                fs::remove_file(&partial)?;
+                if attempt == 0 && resumed > 0 {
+                    warn!("    Resumed download failed verification, downloading again");
+                    continue;
+                }
+                return Err(e);
+            }
+        }
+        fs::rename(&partial, &complete)?;
+        return Ok(complete);
+    }
+    unreachable!("the second attempt always returns")
+}
+
+// Add the bytes of `url` after the `resumed` that `partial` already holds, or the whole file
+// again if the server ignores the range
+fn download_to(url: &str, partial: &Path, resumed: u64) -> Result<(), ForgeError> {
+    let headers = if resumed > 0 { vec![format!("Range: bytes={}-", resumed)] } else { Vec::new() };
+    let response = http::send(&http::agent(None)?, http::request("GET", url, &headers)?, (), true)?;
+    let mut file = match response.status().as_u16() {
+        206 => fs::OpenOptions::new().append(true).open(partial)?,
+        200 => fs::File::create(partial)?,
+        // Nothing past the end: the partial is complete, or is a different file and fails verification
+        416 if resumed > 0 => return Ok(()),
+        status => return Err(format!("GET {} failed: HTTP {}", url, status).into()),
+    };
+    io::copy(&mut response.into_body().into_reader(), &mut file)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// Each Alpine branch lists its current minirootfs and checksum in latest-releases.yaml;
+// a full release such as 3.19.1 is checked against the `.sha256` file next to it
+fn alpine(tag: &str, arch: &str) -> Result<Download, ForgeError> {
+    let arch = match arch {
+        "x86_64" | "aarch64" => arch,
+        _ => return Err(format!("Alpine is not published for {}", arch).into()),
+    };
+    let (branch, release) = match tag.split('.').collect::<Vec<_>>()[..] {
+        ["latest"] => ("latest-stable".to_string(), None),
+        [major, minor] => (format!("v{}.{}", major, minor), None),
+        [major, minor, _] => (format!("v{}.{}", major, minor), Some(tag)),
+        _ => return Err(format!("Unknown Alpine release: {}", tag).into()),
+    };
+    let dir = format!("https://dl-cdn.alpinelinux.org/alpine/{}/releases/{}/", branch, arch);
+
+    if let Some(release) = release {
+        let url = format!("{}alpine-minirootfs-{}-{}.tar.gz", dir, release, arch);
+        let sums = fetch_text(&format!("{}.sha256", url))?;
+        let sha256 = find_checksum(&sums, |file| url.ends_with(file))
+            .ok_or_else(|| format!("No checksum published for {}", url))?
+            .0;
+        return Ok(Download { url, sha256 });
+    }
+
+    // A list of `-` separated entries of `key: value` lines
+    let releases = fetch_text(&format!("{}latest-releases.yaml", dir))?;
+    releases.split("\n-").find_map(|entry| {
+        let field = |key: &str| entry.lines()
+            .find_map(|line| line.trim().strip_prefix(key)?.strip_prefix(':'))
+            .map(|value| value.trim().trim_matches('"').to_string());
+        let file = field("file")?;
+        file.starts_with("alpine-minirootfs-").then_some(())?;
+        Some(Download { url: format!("{}{}", dir, file), sha256: field("sha256")? })
+    })
+    .ok_or_else(|| format!("No minirootfs listed for alpine:{} on {}", tag, arch).into())
+}
+
+// Ubuntu Base tarballs are named after the point release, so pick ours out of SHA256SUMS
+fn ubuntu(tag: &str, arch: &str) -> Result<Download, ForgeError> {
+    let version = match tag {
+        "noble" => "24.04",
+        "jammy" => "22.04",
+        "focal" => "20.04",
+        other => other,
+    };
+    let suffix = format!("-base-{}.tar.gz", debian_arch(arch)?);
+
+    let dir = format!("https://cdimage.ubuntu.com/ubuntu-base/releases/{}/release/", version);
+    let sums = fetch_text(&format!("{}SHA256SUMS", dir))?;
+    let (sha256, file) = find_checksum(&sums, |file| file.starts_with("ubuntu-base-") && file.ends_with(&suffix))
+        .ok_or_else(|| format!("ubuntu:{} is not published for {}", tag, arch))?;
+    Ok(Download { url: format!("{}{}", dir, file), sha256 })
+}
+
+fn debian(tag: &str, arch: &str) -> Result<Download, ForgeError> {
+    let release = match tag {
+        "11" => "bullseye",
+        "12" => "bookworm",
+        "13" => "trixie",
+        other => other,
+    };
+    lxc("debian", Some(release), arch)
+}
+
+fn busybox(tag: &str, arch: &str) -> Result<Download, ForgeError> {
+    lxc("busybox", (tag != "latest").then_some(tag), arch)
+}
+
+// images.linuxcontainers.org lists every build as `distro;release;arch;variant;serial;path`,
+// with a SHA256SUMS file in each build directory. No release means the newest one.
+fn lxc(distro: &str, release: Option<&str>, arch: &str) -> Result<Download, ForgeError> {
+    let arch = debian_arch(arch)?;
+    let index = fetch_text(&format!("{}/meta/1.0/index-system", LXC_IMAGES))?;
+
+    let path = index.lines()
+        .map(|line| line.split(';').collect::<Vec<_>>())
+        .filter(|f| f.len() == 6 && f[0] == distro && f[2] == arch && f[3] == "default")
+        .filter(|f| release.is_none_or(|r| f[1] == r))
+        .max_by(|a, b| a[4].cmp(b[4]))
+        .map(|f| f[5].to_string())
+        .ok_or_else(|| format!("{}:{} is not published for {}", distro, release.unwrap_or("latest"), arch))?;
+
+    let dir = format!("{}{}", LXC_IMAGES, path);
+    let sums = fetch_text(&format!("{}SHA256SUMS", dir))?;
+    let sha256 = find_checksum(&sums, |file| file == "rootfs.tar.xz")
+        .ok_or_else(|| format!("No checksum published for {}rootfs.tar.xz", dir))?
+        .0;
+    Ok(Download { url: format!("{}rootfs.tar.xz", dir), sha256 })
+}
+
+fn debian_arch(arch: &str) -> Result<&'static str, ForgeError> {
+    match arch {
+        "x86_64" => Ok("amd64"),
+        "aarch64" => Ok("arm64"),
+        other => Err(format!("Unsupported architecture: {}", other).into()),
+    }
+}
+
+fn fetch_text(url: &str) -> Result<String, ForgeError> {
+    http::get_text(&http::agent(None)?, url).map_err(|e| format!("Failed to fetch {}: {}", url, e).into())
+}
+
+// sha256sum output: `<hex>  <file>`, or `<hex> *<file>` for binary mode
+fn find_checksum(sums: &str, matches: impl Fn(&str) -> bool) -> Option<(String, String)> {
+    sums.lines().find_map(|line| {
+        let (hex, file) = line.split_once(char::is_whitespace)?;
+        let file = file.trim_start().trim_start_matches('*');
+        (hex.len() == 64 && matches(file)).then(|| (hex.to_lowercase(), file.to_string()))
+    })
+}
@@ -1,4 +1,5 @@
 use std::fs;
+use std::path::Path;
 use std::process::Command;
 use std::collections::HashSet;
 use log::debug;
@@ -7,11 +8,7 @@ fn copy_file(src: &str, dst: &str) -> bool {
     if let Some(parent) = std::path::Path::new(dst).parent() {
         let _ = fs::create_dir_all(parent);
     }
-    if let Err(_) = fs::copy(src, dst) {
-        false
-    } else {
-        true
-    }
+    fs::copy(src, dst).is_ok()
 }
 
 fn collect_shared_libraries(binary: &str, libs: &mut HashSet<String>) {
@@ -32,28 +29,40 @@ fn collect_shared_libraries(binary: &str, libs: &mut HashSet<String>) {
                 }
             }
         } else if line.trim().starts_with('/') {
-            if let Some(lib_path) = line.trim().split_whitespace().next() {
+            if let Some(lib_path) = line.split_whitespace().next() {
                 libs.insert(lib_path.to_string());
             }
         }
     }
 }
 
-fn copy_directory_recursive(src: &str, dst: &str) {
-    let _ = Command::new("cp")
-        .args(&["-r", src, dst])
-        .output();
+// Copy a directory tree, recreating symlinks rather than following them
+fn copy_directory_recursive(src: &Path, dst: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_directory_recursive(&entry.path(), &target)?;
+        } else if file_type.is_symlink() {
+            let _ = fs::remove_file(&target);
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+        } else if file_type.is_file() {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
 }
 
 fn copy_terminfo(root: &str) {
-    let share_dir = format!("{}/usr/share", root);
-    fs::create_dir_all(&share_dir).ok();
-    copy_directory_recursive("/usr/share/terminfo", &format!("{}/usr/share/terminfo", root));
-
-    if std::path::Path::new("/lib/terminfo").exists() {
-        let lib_dir = format!("{}/lib", root);
-        fs::create_dir_all(&lib_dir).ok();
-        copy_directory_recursive("/lib/terminfo", &format!("{}/lib/terminfo", root));
+    for dir in ["/usr/share/terminfo", "/lib/terminfo"] {
+        if !Path::new(dir).exists() {
+            continue;
+        }
+        if let Err(e) = copy_directory_recursive(Path::new(dir), &Path::new(root).join(&dir[1..])) {
+            debug!("Failed to copy {}: {}", dir, e);
+        }
     }
 }
 
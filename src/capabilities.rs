@@ -0,0 +1,137 @@
+use nix::errno::Errno;
+use nix::libc;
+use serde::{Deserialize, Serialize};
+use log::debug;
+
+use crate::error::ForgeError;
+
+// A container's processes are root, but not with all of root's capabilities: they keep the
+// same default set as other runtimes, adjusted with --cap-add and --cap-drop. The bounding set
+// goes first, so nothing the container execs, setuid binaries included, can get more back.
+
+// Every capability by number, as in <linux/capability.h>
+const NAMES: &[&str] = &[
+    "CHOWN", "DAC_OVERRIDE", "DAC_READ_SEARCH", "FOWNER", "FSETID", "KILL", "SETGID", "SETUID",
+    "SETPCAP", "LINUX_IMMUTABLE", "NET_BIND_SERVICE", "NET_BROADCAST", "NET_ADMIN", "NET_RAW",
+    "IPC_LOCK", "IPC_OWNER", "SYS_MODULE", "SYS_RAWIO", "SYS_CHROOT", "SYS_PTRACE", "SYS_PACCT",
+    "SYS_ADMIN", "SYS_BOOT", "SYS_NICE", "SYS_RESOURCE", "SYS_TIME", "SYS_TTY_CONFIG", "MKNOD",
+    "LEASE", "AUDIT_WRITE", "AUDIT_CONTROL", "SETFCAP", "MAC_OVERRIDE", "MAC_ADMIN", "SYSLOG",
+    "WAKE_ALARM", "BLOCK_SUSPEND", "AUDIT_READ", "PERFMON", "BPF", "CHECKPOINT_RESTORE",
+];
+
+const DEFAULT: &[&str] = &[
+    "CHOWN", "DAC_OVERRIDE", "FSETID", "FOWNER", "MKNOD", "NET_RAW", "SETGID", "SETUID",
+    "SETFCAP", "SETPCAP", "NET_BIND_SERVICE", "SYS_CHROOT", "KILL", "AUDIT_WRITE",
+];
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// `run --cap-add` and `--cap-drop`, as names without the CAP_ prefix or ALL
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Capabilities {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub add: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub drop: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn is_default(&self) -> bool {
+        self.add.is_empty() && self.drop.is_empty()
+    }
+
+    /// NET_ADMIN, CAP_NET_ADMIN or net_admin, or ALL
+    pub fn parse_name(name: &str) -> Result<String, ForgeError> {
+        let upper = name.to_ascii_uppercase();
+        let bare = upper.strip_prefix("CAP_").unwrap_or(&upper);
+        if bare == "ALL" || NAMES.contains(&bare) {
+            Ok(bare.to_string())
+        } else {
+            Err(format!("Unknown capability {:?}", name).into())
+        }
+    }
+
+    /// The capabilities a container keeps, one bit each: the default set less the dropped ones
+    /// plus the added ones, so adding one back after dropping ALL works
+    pub fn resolve(&self) -> u64 {
+        let bits = |names: &[String]| names.iter().fold(0u64, |set, name| set | bit(name));
+        let mut set = DEFAULT.iter().fold(0u64, |set, name| set | bit(name));
+        set &= !bits(&self.drop);
+        set | bits(&self.add)
+    }
+}
+
+/// Whether `set` has the capability `name`, as CAP_SYS_ADMIN
+pub fn has(set: u64, name: &str) -> bool {
+    let bare = name.strip_prefix("CAP_").unwrap_or(name);
+    NAMES.contains(&bare) && set & bit(bare) != 0
+}
+
+fn bit(name: &str) -> u64 {
+    match name {
+        "ALL" => u64::MAX,
+        _ => NAMES.iter().position(|n| *n == name).map_or(0, |i| 1 << i),
+    }
+}
+
+/// Drop every capability outside `set` from the bounding set and clear the ambient set. Needs
+/// CAP_SETPCAP, so it comes before the process changes to a user other than root.
+pub fn limit_bounding_set(set: u64) -> Result<(), ForgeError> {
+    let last = std::fs::read_to_string("/proc/sys/kernel/cap_last_cap").ok()
+        .and_then(|s| s.trim().parse::<u32>().ok())
+        .unwrap_or(NAMES.len() as u32 - 1);
+    for cap in 0..=last {
+        if cap < 64 && set & (1 << cap) != 0 {
+            continue;
+        }
+        Errno::result(unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) })
+            .map_err(|e| ForgeError::capability(format!("drop {} from the bounding set", name(cap)), e))?;
+    }
+    Errno::result(unsafe { libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong, 0, 0, 0) })
+        .map_err(|e| ForgeError::capability("clear the ambient capabilities", e))?;
+    debug!("Bounding set limited to {:#x}", set);
+    Ok(())
+}
+
+/// Keep only `set` of this process's own capabilities, with nothing inheritable
+pub fn restrict(set: u64) -> Result<(), ForgeError> {
+    let mut header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let mut data = [CapData::default(); 2];
+    Errno::result(unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) })
+        .map_err(|e| ForgeError::capability("read the capabilities", e))?;
+    for (i, half) in data.iter_mut().enumerate() {
+        let keep = (set >> (32 * i)) as u32;
+        half.permitted &= keep;
+        half.effective &= keep;
+        half.inheritable = 0;
+    }
+    Errno::result(unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) })
+        .map_err(|e| ForgeError::capability("set the capabilities", e))?;
+    Ok(())
+}
+
+/// Set no_new_privs, so that nothing this process execs, setuid and file-capability binaries
+/// included, runs with more privileges than it has
+pub fn forbid_new_privileges() -> Result<(), ForgeError> {
+    Errno::result(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1 as libc::c_ulong, 0, 0, 0) })
+        .map_err(|e| ForgeError::capability("set no_new_privs", e))?;
+    Ok(())
+}
+
+fn name(cap: u32) -> String {
+    NAMES.get(cap as usize).map_or_else(|| format!("capability {}", cap), |n| format!("CAP_{}", n))
+}
@@ -1,160 +1,1351 @@
+use std::collections::BTreeMap;
 use std::fs;
-use std::process;
+use std::os::unix::fs::symlink;
+use std::os::fd::AsFd;
+use std::process::Command;
+use std::time::{Duration, Instant};
 use nix::libc;
+use nix::mount::{mount, MsFlags};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
 use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::devices::{self, DeviceRule};
+use crate::error::ForgeError;
+use crate::rootless;
+use crate::tools;
 
 const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+// Every container's cgroup is made below this one, rather than at the root beside the host's
+const PARENT: &str = "forge.slice";
+// With the systemd driver a container's cgroup is a transient scope in PARENT, forge-<id>.scope,
+// so it shows in systemctl and systemd-cgtop and systemd knows the subtree is delegated. The
+// cgroupfs driver makes the directories itself, where systemd doesn't run or couldn't be reached.
+const SCOPE_PREFIX: &str = "forge-";
+const SCOPE_SUFFIX: &str = ".scope";
+// The v1 hierarchies a container gets a cgroup in; freezer is only there for pause, cpuacct for
+// stats and unified, the v2 hierarchy of a hybrid host, for pressure
+const V1_CONTROLLERS: &[&str] = &["cpu", "memory", "pids", "freezer", "cpuset", "blkio", "cpuacct", "devices", "hugetlb", "unified"];
+// The v2 controllers enabled for containers where the host has them
+const V2_CONTROLLERS: &[&str] = &["cpu", "memory", "pids", "cpuset", "io", "hugetlb"];
+// How long a cgroup's processes get to die once killed, and its directory to come free after
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(5);
+const RMDIR_ATTEMPTS: u32 = 50;
+// A directory per hugepage size the host has, hugepages-2048kB
+const HUGEPAGES_DIR: &str = "/sys/kernel/mm/hugepages";
+// As the kernel and other runtimes have it
+const DEFAULT_CPU_PERIOD: u64 = 100_000;
+// Below this a container can't so much as start
+const MIN_MEMORY: u64 = 6 << 20;
+
+/// A container's resource limits, from `run --memory`, `--cpus` and the rest. Every one is
+/// optional, and a container given none runs without limits, in a cgroup of its own all the same.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CgroupConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<u64>,        // bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_swap: Option<i64>,   // memory plus swap in bytes, -1 for unlimited swap; twice memory unless given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_swappiness: Option<u64>, // 0-100, cgroup v1 alone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_reservation: Option<u64>,    // bytes kept from reclaim under host memory pressure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_quota: Option<u64>,     // microseconds of CPU time per period
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_period: Option<u64>,    // microseconds, DEFAULT_CPU_PERIOD unless given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_shares: Option<u64>,    // 2-262144, the share of contended CPU time relative to the default 1024
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpuset_cpus: Option<String>,    // the CPUs it may run on, "0-3,6"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpuset_mems: Option<String>,    // the NUMA nodes it may allocate memory on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub io: Option<u64>,            // 10-1000, the share of contended block I/O
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hugetlb: BTreeMap<u64, u64>,    // bytes of hugepages by page size in bytes
+}
+
+impl CgroupConfig {
+    pub fn is_empty(&self) -> bool {
+        *self == CgroupConfig::default()
+    }
+
+    /// The controllers the limits need, by their v2 names
+    fn controllers(&self) -> Vec<&'static str> {
+        let mut controllers = Vec::new();
+        if self.memory.is_some() || self.memory_swap.is_some() || self.memory_swappiness.is_some() || self.memory_reservation.is_some() {
+            controllers.push("memory");
+        }
+        if self.cpu_quota.is_some() || self.cpu_period.is_some() || self.cpu_shares.is_some() {
+            controllers.push("cpu");
+        }
+        if self.pids.is_some() {
+            controllers.push("pids");
+        }
+        if self.cpuset_cpus.is_some() || self.cpuset_mems.is_some() {
+            controllers.push("cpuset");
+        }
+        if self.io.is_some() {
+            controllers.push("io");
+        }
+        if !self.hugetlb.is_empty() {
+            controllers.push("hugetlb");
+        }
+        controllers
+    }
+
+    /// `--cpus 1.5`: a quota of that many CPUs' worth of each period
+    pub fn set_cpus(&mut self, cpus: &str) -> Result<(), ForgeError> {
+        let count: f64 = cpus.parse().ok().filter(|c: &f64| *c > 0.0)
+            .ok_or_else(|| ForgeError::cgroup(format!("limit the container to {:?} CPUs", cpus), "use a number of CPUs such as 1.5"))?;
+        let available = std::thread::available_parallelism().map_or(1, |n| n.get());
+        if count > available as f64 {
+            return Err(ForgeError::cgroup(format!("limit the container to {} CPUs", cpus), format!("this host has {}", available)));
+        }
+        let period = self.cpu_period.unwrap_or(DEFAULT_CPU_PERIOD);
+        self.cpu_quota = Some((count * period as f64) as u64);
+        Ok(())
+    }
+
+    /// Refuse limits the kernel would, or that would keep the container from starting
+    pub fn validate(&self) -> Result<(), ForgeError> {
+        if let Some(memory) = self.memory.filter(|m| *m < MIN_MEMORY) {
+            return Err(ForgeError::cgroup(format!("set a memory limit of {} bytes", memory), "the minimum is 6MB"));
+        }
+        if self.memory_swap.is_some() && self.memory.is_none() {
+            return Err(ForgeError::cgroup("set a swap limit", "it needs a memory limit as well"));
+        }
+        if let Some(swap) = self.memory_swap.filter(|s| *s < -1) {
+            return Err(ForgeError::cgroup(format!("set the memory plus swap limit to {}", swap), "use -1 for unlimited swap"));
+        }
+        if let Some(swappiness) = self.memory_swappiness.filter(|s| *s > 100) {
+            return Err(ForgeError::cgroup(format!("set a swappiness of {}", swappiness), "use 0 to 100"));
+        }
+        if let (Some(memory), Some(swap)) = (self.memory, self.memory_swap.filter(|s| *s >= 0)) {
+            if (swap as u64) < memory {
+                return Err(ForgeError::cgroup(format!("set the memory plus swap limit to {}", swap), "it can't be lower than the memory limit"));
+            }
+        }
+        if let (Some(memory), Some(reservation)) = (self.memory, self.memory_reservation) {
+            if reservation > memory {
+                return Err(ForgeError::cgroup(format!("reserve {} bytes of memory", reservation), "it can't be higher than the memory limit"));
+            }
+        }
+        if let Some(period) = self.cpu_period.filter(|p| !(1_000..=1_000_000).contains(p)) {
+            return Err(ForgeError::cgroup(format!("set a CPU period of {}us", period), "use 1000 to 1000000"));
+        }
+        if let Some(quota) = self.cpu_quota.filter(|q| *q < 1_000) {
+            return Err(ForgeError::cgroup(format!("set a CPU quota of {}us", quota), "the minimum is 1000"));
+        }
+        if let Some(shares) = self.cpu_shares.filter(|s| !(2..=262_144).contains(s)) {
+            return Err(ForgeError::cgroup(format!("set CPU shares of {}", shares), "use 2 to 262144"));
+        }
+        if self.pids == Some(0) {
+            return Err(ForgeError::cgroup("set a PID limit of 0", "it must be at least 1"));
+        }
+        if let Some(cpus) = &self.cpuset_cpus {
+            check_cpu_list("--cpuset-cpus", "CPU", cpus, "/sys/devices/system/cpu/online")?;
+        }
+        if let Some(mems) = &self.cpuset_mems {
+            check_cpu_list("--cpuset-mems", "Memory node", mems, "/sys/devices/system/node/online")?;
+        }
+        if let Some(weight) = self.io.filter(|w| !(10..=1_000).contains(w)) {
+            return Err(ForgeError::cgroup(format!("set a block I/O weight of {}", weight), "use 10 to 1000"));
+        }
+        let sizes = hugepage_sizes();
+        if let Some(size) = self.hugetlb.keys().find(|s| !sizes.contains(s)) {
+            let host: Vec<String> = sizes.iter().map(|s| page_size_name(*s)).collect();
+            let action = format!("limit {} hugepages", page_size_name(*size));
+            if host.is_empty() {
+                return Err(ForgeError::cgroup(action, "this host has no hugepages"));
+            }
+            return Err(ForgeError::cgroup(action, format!("this host's hugepage sizes are {}", host.join(", "))));
+        }
+        Ok(())
+    }
+}
+
+/// The hugepage sizes the host has, in bytes
+fn hugepage_sizes() -> Vec<u64> {
+    let mut sizes: Vec<u64> = fs::read_dir(HUGEPAGES_DIR).into_iter().flatten().flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.strip_prefix("hugepages-")?.strip_suffix("kB")?.parse::<u64>().ok())
+        .map(|kb| kb << 10)
+        .collect();
+    sizes.sort();
+    sizes
+}
+
+/// A page size as the hugetlb controller names its files: 2MB, 1GB
+fn page_size_name(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 30 && b % (1 << 30) == 0 => format!("{}GB", b >> 30),
+        b if b >= 1 << 20 && b % (1 << 20) == 0 => format!("{}MB", b >> 20),
+        b => format!("{}KB", b >> 10),
+    }
+}
+
+/// The numbers in a list such as "0-3,6", as cpuset.cpus and the kernel's topology files have them
+fn parse_cpu_list(list: &str) -> Option<Vec<u32>> {
+    let mut numbers = Vec::new();
+    for range in list.trim().split(',') {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let (first, last): (u32, u32) = (first.parse().ok()?, last.parse().ok()?);
+        if first > last {
+            return None;
+        }
+        numbers.extend(first..=last);
+    }
+    Some(numbers)
+}
+
+// Every number in `list` has to be one of the host's, as listed in `online`
+fn check_cpu_list(flag: &str, what: &str, list: &str, online: &str) -> Result<(), ForgeError> {
+    let numbers = parse_cpu_list(list).ok_or_else(|| ForgeError::cgroup(format!("set {} {:?}", flag, list), "use a list such as 0-3,6"))?;
+    // Hosts without NUMA have no node directory, and one node
+    let online = fs::read_to_string(online).unwrap_or_else(|_| "0".to_string());
+    let host = parse_cpu_list(&online).unwrap_or_default();
+    match numbers.iter().find(|n| !host.contains(n)) {
+        Some(n) => Err(ForgeError::cgroup(format!("set {} {}", flag, list), format!("{} {} isn't one of this host's, which are {}", what, n, online.trim()))),
+        None => Ok(()),
+    }
+}
 
 fn is_cgroup_v2() -> bool {
     std::path::Path::new("/sys/fs/cgroup/cgroup.controllers").exists()
 }
 
-pub fn setup_cgroups(container_name: &str) {
+/// How the host has mounted its cgroups
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    Unified,    // cgroup v2 alone
+    Hybrid,     // v1 controllers, and a v2 hierarchy without any at /sys/fs/cgroup/unified
+    Legacy,     // v1 alone
+}
+
+pub fn layout() -> Layout {
+    if is_cgroup_v2() {
+        Layout::Unified
+    } else if std::path::Path::new(&format!("{}/unified/cgroup.controllers", CGROUP_ROOT)).exists() {
+        Layout::Hybrid
+    } else {
+        Layout::Legacy
+    }
+}
+
+// A feature and the files it needs, any one of them: in a v1 hierarchy, or on v2, where none
+// means v2 has no equivalent
+struct Interface {
+    feature: &'static str,
+    hierarchy: &'static str,
+    v1: Vec<String>,
+    v2: Vec<String>,
+    hint: &'static str,
+}
+
+fn interface(feature: &'static str, hierarchy: &'static str, v1: &[&str], v2: &[&str], hint: &'static str) -> Interface {
+    let strings = |files: &[&str]| files.iter().map(|f| f.to_string()).collect();
+    Interface { feature, hierarchy, v1: strings(v1), v2: strings(v2), hint }
+}
+
+// Everything this runtime does with cgroups that a host may not have
+fn interfaces() -> Vec<Interface> {
+    // Every hugepage size gets its files, so the smallest stands for them all
+    let hugetlb = hugepage_sizes().first().map(|size| page_size_name(*size));
+    let hugetlb_file = |suffix: &str| hugetlb.iter().map(|size| format!("hugetlb.{}.{}", size, suffix)).collect::<Vec<_>>();
+    let mut interfaces = vec![
+        interface("--memory", "memory", &["memory.limit_in_bytes"], &["memory.max"], ""),
+        interface("--memory-swap", "memory", &["memory.memsw.limit_in_bytes"], &["memory.swap.max"], "boot the kernel with swapaccount=1"),
+        interface("--memory-swappiness", "memory", &["memory.swappiness"], &[], ""),
+        interface("--memory-reservation", "memory", &["memory.soft_limit_in_bytes"], &["memory.low"], ""),
+        interface("--cpus, --cpu-quota", "cpu", &["cpu.cfs_quota_us"], &["cpu.max"], ""),
+        interface("--cpu-shares", "cpu", &["cpu.shares"], &["cpu.weight"], ""),
+        interface("--cpuset-cpus, --cpuset-mems", "cpuset", &["cpuset.cpus"], &["cpuset.cpus"], ""),
+        interface("--pids-limit", "pids", &["pids.max"], &["pids.max"], ""),
+        interface("--blkio-weight", "blkio", &["blkio.weight", "blkio.bfq.weight"], &["io.weight", "io.bfq.weight"], "use the bfq I/O scheduler"),
+        Interface { feature: "--hugetlb-limit", hierarchy: "hugetlb", v1: hugetlb_file("limit_in_bytes"), v2: hugetlb_file("max"), hint: "configure hugepages" },
+        // v2 takes an eBPF program attached to the cgroup itself
+        interface("device rules", "devices", &["devices.allow"], &["cgroup.procs"], ""),
+        interface("pause", "freezer", &["freezer.state"], &["cgroup.freeze"], ""),
+        interface("stats", "cpuacct", &["cpuacct.usage"], &["cpu.stat"], ""),
+        interface("stats --pressure", "unified", &["cpu.pressure"], &["cpu.pressure"], "boot the kernel with psi=1"),
+        interface("pids events", "pids", &["pids.events"], &["pids.events"], ""),
+        interface("OOM events", "memory", &["memory.oom_control"], &["memory.events"], ""),
+        interface("cgroup.kill", "unified", &["cgroup.kill"], &["cgroup.kill"], "Linux 5.14 or later"),
+    ];
+    interfaces.sort_by_key(|i| !i.feature.starts_with('-'));
+    interfaces
+}
+
+// Whether the cgroups made in PARENT have what `interface` needs, and why not
+fn probe(interface: &Interface) -> Result<(), String> {
+    let files = if is_cgroup_v2() { &interface.v2 } else { &interface.v1 };
+    if files.is_empty() {
+        return Err(match (is_cgroup_v2(), interface.hint) {
+            (true, _) => "cgroup v2 has no equivalent".to_string(),
+            (false, "") => "this host has none to use".to_string(),
+            (false, hint) => format!("this host has none to use; {}", hint),
+        });
+    }
+    if !is_cgroup_v2() && !v1_hierarchies().any(|h| h == interface.hierarchy) {
+        return Err(format!("this host has no {} hierarchy", interface.hierarchy));
+    }
+    let dir = parent_dir(interface.hierarchy);
+    if files.iter().any(|file| std::path::Path::new(&format!("{}/{}", dir, file)).exists()) {
+        return Ok(());
+    }
+    let hint = if interface.hint.is_empty() { String::new() } else { format!("; {}", interface.hint) };
+    Err(format!("{} has no {}{}", dir, files.join(" or "), hint))
+}
+
+// PARENT, in the v1 hierarchy of `controller`
+fn parent_dir(controller: &str) -> String {
+    if is_cgroup_v2() {
+        format!("{}/{}", CGROUP_ROOT, PARENT)
+    } else {
+        format!("{}/{}/{}", CGROUP_ROOT, controller, PARENT)
+    }
+}
+
+/// One of the features `system cgroups` reports on
+pub struct Feature {
+    pub name: &'static str,
+    pub missing: Option<String>,    // why the host can't do it
+}
+
+/// What a host's cgroups can do
+pub fn features() -> Result<Vec<Feature>, ForgeError> {
+    if rootless::active() {
+        return Err(ForgeError::cgroup("check the host's cgroups", "rootless containers have no cgroups"));
+    }
+    // Made by the first container otherwise, with the controllers it passes on
+    if is_cgroup_v2() {
+        let parent = parent_dir("");
+        fs::create_dir_all(&parent).map_err(|e| ForgeError::cgroup(format!("create cgroup {}", parent), e))?;
+        let _ = enable_controllers_v2(&parent, &[]);
+    } else {
+        for controller in v1_hierarchies() {
+            let _ = fs::create_dir_all(parent_dir(controller));
+        }
+    }
+    Ok(interfaces().iter().map(|i| Feature { name: i.feature, missing: probe(i).err() }).collect())
+}
+
+impl CgroupConfig {
+    // The features of `interfaces` these limits use
+    fn features(&self) -> Vec<&'static str> {
+        let mut features = Vec::new();
+        let mut uses = |used: bool, feature| if used { features.push(feature) };
+        uses(self.memory.is_some(), "--memory");
+        uses(self.memory_swap.is_some(), "--memory-swap");
+        // Warned about and left out on v2, which has no swappiness of its own
+        uses(self.memory_swappiness.is_some() && !is_cgroup_v2(), "--memory-swappiness");
+        uses(self.memory_reservation.is_some(), "--memory-reservation");
+        uses(self.cpu_quota.is_some() || self.cpu_period.is_some(), "--cpus, --cpu-quota");
+        uses(self.cpu_shares.is_some(), "--cpu-shares");
+        uses(self.cpuset_cpus.is_some() || self.cpuset_mems.is_some(), "--cpuset-cpus, --cpuset-mems");
+        uses(self.pids.is_some(), "--pids-limit");
+        uses(self.io.is_some(), "--blkio-weight");
+        uses(!self.hugetlb.is_empty(), "--hugetlb-limit");
+        features
+    }
+}
+
+// Refuse what the host's cgroups can't do, where writing a limit would fail on a missing file
+fn check_available(features: &[&str]) -> Result<(), ForgeError> {
+    for interface in interfaces().iter().filter(|i| features.contains(&i.feature)) {
+        probe(interface).map_err(|why| ForgeError::cgroup(format!("use {}", interface.feature), why))?;
+    }
+    Ok(())
+}
+
+// The v1 hierarchies this host has mounted
+fn v1_hierarchies() -> impl Iterator<Item = &'static str> {
+    V1_CONTROLLERS.iter().copied().filter(|controller| std::path::Path::new(&format!("{}/{}", CGROUP_ROOT, controller)).is_dir())
+}
+
+/// The directory of cgroup `name`, below the parent every container's cgroup is made in; on
+/// v1, in the hierarchy of `controller`
+fn cgroup_dir(controller: &str, name: &str) -> String {
+    let leaf = leaf(name);
+    if is_cgroup_v2() {
+        format!("{}/{}/{}", CGROUP_ROOT, PARENT, leaf)
+    } else {
+        format!("{}/{}/{}/{}", CGROUP_ROOT, controller, PARENT, leaf)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Driver {
+    Cgroupfs,
+    Systemd,
+}
+
+/// --cgroup-manager, otherwise systemd on hosts it booted
+fn driver() -> Driver {
+    match config::flags().cgroup_manager.as_deref() {
+        Some("systemd") => Driver::Systemd,
+        Some(_) => Driver::Cgroupfs,
+        None if std::path::Path::new("/run/systemd/system").is_dir() => Driver::Systemd,
+        None => Driver::Cgroupfs,
+    }
+}
+
+/// The scope unit cgroup `name` gets from systemd: forge-<id>.scope for "img-<id>"
+fn scope_unit(name: &str) -> String {
+    format!("{}{}{}", SCOPE_PREFIX, name.strip_prefix("img-").unwrap_or(name), SCOPE_SUFFIX)
+}
+
+// The other way round, for a directory in PARENT
+fn scope_name(unit: &str) -> Option<String> {
+    let id = unit.strip_prefix(SCOPE_PREFIX)?.strip_suffix(SCOPE_SUFFIX)?;
+    Some(if id.starts_with("shell-") { id.to_string() } else { format!("img-{}", id) })
+}
+
+// Its scope where systemd made one for it, whichever driver this process would use, as the
+// runtime that made it may have been given another --cgroup-manager
+fn leaf(name: &str) -> String {
+    let unit = scope_unit(name);
+    // On v1, systemd's own hierarchy has every unit
+    let hierarchy = if is_cgroup_v2() { CGROUP_ROOT.to_string() } else { format!("{}/systemd", CGROUP_ROOT) };
+    if std::path::Path::new(&format!("{}/{}/{}", hierarchy, PARENT, unit)).is_dir() {
+        unit
+    } else {
+        name.to_string()
+    }
+}
+
+/// Create the container's cgroup, set its limits, keep it to `devices`, or let it use any device
+/// with None, and move `pid` into it. A systemd scope can't be made empty, so this is done from
+/// the runtime once PID 1 is cloned, before PID 1 creates its cgroup namespace.
+pub fn setup_cgroups(container_name: &str, config: &CgroupConfig, devices: Option<&[DeviceRule]>, pid: Pid) -> Result<(), ForgeError> {
+    // Creating cgroups needs the host's root, or a delegated subtree this runtime doesn't look for
+    if rootless::active() {
+        debug!("Rootless: {} gets no cgroup", container_name);
+        return Ok(());
+    }
     debug!("Setting up cgroups for {}...", container_name);
 
-    create_cgroup_hierarchy(container_name);
-    set_resource_limits(container_name);
-    add_process_to_cgroup(container_name);
+    let mut required = config.controllers();
+    if devices.is_some() && !is_cgroup_v2() {
+        required.push("devices");
+    }
+    if driver() == Driver::Systemd {
+        match start_scope(container_name, config, pid) {
+            Ok(()) => debug!("Started {}", scope_unit(container_name)),
+            Err(e) if config::flags().cgroup_manager.is_some() => return Err(e),
+            Err(e) => warn!("{}, using cgroupfs instead", e),
+        }
+    }
+    // With a scope, for the v1 hierarchies systemd leaves alone
+    create_cgroup_hierarchy(container_name, &required)?;
+    let mut features = config.features();
+    if devices.is_some() {
+        features.push("device rules");
+    }
+    check_available(&features)?;
+    if is_cgroup_v2() {
+        set_limits_v2(container_name, config)?;
+    } else {
+        set_limits_v1(container_name, config)?;
+    }
+    if let Some(rules) = devices {
+        restrict_devices(container_name, rules)?;
+    }
+    // Only the container joins; a runtime inside would be killed by its own cleanup
+    add_process_to_cgroup(container_name, pid)?;
 
     debug!("Cgroups configured");
+    Ok(())
 }
 
-fn create_cgroup_hierarchy(name: &str) {
+// Ask systemd over D-Bus for a scope in PARENT holding `pid`, with the limits systemd has
+// properties for. The cgroup's files are written as well, which covers the rest.
+fn start_scope(name: &str, config: &CgroupConfig, pid: Pid) -> Result<(), ForgeError> {
+    let unit = scope_unit(name);
+    let mut properties: Vec<(&str, &str, Vec<String>)> = vec![
+        ("Description", "s", vec![format!("forge container {}", name)]),
+        ("Slice", "s", vec![PARENT.to_string()]),
+        ("Delegate", "b", vec!["true".to_string()]),
+        ("PIDs", "au", vec!["1".to_string(), pid.to_string()]),
+    ];
+    if let Some(memory) = config.memory {
+        properties.push(("MemoryMax", "t", vec![memory.to_string()]));
+    }
+    if let Some(reservation) = config.memory_reservation {
+        properties.push(("MemoryLow", "t", vec![reservation.to_string()]));
+    }
+    if let Some(quota) = config.cpu_quota {
+        let period = config.cpu_period.unwrap_or(DEFAULT_CPU_PERIOD);
+        properties.push(("CPUQuotaPerSecUSec", "t", vec![(quota * 1_000_000 / period).to_string()]));
+    }
+    if let Some(shares) = config.cpu_shares {
+        properties.push(("CPUWeight", "t", vec![cpu_weight(shares).to_string()]));
+    }
+    if let Some(pids) = config.pids {
+        properties.push(("TasksMax", "t", vec![pids.to_string()]));
+    }
+    if let Some(weight) = config.io {
+        properties.push(("IOWeight", "t", vec![io_weight(weight).to_string()]));
+    }
+
+    let mut busctl = systemd_call("StartTransientUnit", "ssa(sv)a(sa(sv))");
+    busctl.args([unit.as_str(), "fail"]).arg(properties.len().to_string());
+    for (property, signature, values) in properties {
+        busctl.args([property, signature]).args(values);
+    }
+    busctl.arg("0");
+    tools::run(&mut busctl, "busctl").map_err(|e| ForgeError::cgroup(format!("start {}", unit), e))?;
+
+    // The job that moves `pid` runs once the call returns
+    let cgroup = format!("/proc/{}/cgroup", pid);
+    for _ in 0..100 {
+        if fs::read_to_string(&cgroup).is_ok_and(|c| c.contains(&unit)) {
+            return Ok(());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    Err(ForgeError::cgroup(format!("start {}", unit), "systemd didn't move PID 1 into it"))
+}
+
+// busctl calling a method of systemd's manager on the system bus
+fn systemd_call(method: &str, signature: &str) -> Command {
+    let mut busctl = Command::new("busctl");
+    busctl.args(["call", "org.freedesktop.systemd1", "/org/freedesktop/systemd1", "org.freedesktop.systemd1.Manager", method, signature]);
+    busctl
+}
+
+fn create_cgroup_hierarchy(name: &str, required: &[&str]) -> Result<(), ForgeError> {
     debug!("Creating cgroup hierarchy...");
     if is_cgroup_v2() {
-        let path = format!("{}/{}", CGROUP_ROOT, name);
+        let parent = format!("{}/{}", CGROUP_ROOT, PARENT);
+        fs::create_dir_all(&parent).map_err(|e| ForgeError::cgroup(format!("create cgroup {}", parent), e))?;
+        enable_controllers_v2(&parent, required)?;
+        let path = cgroup_dir("", name);
+        fs::create_dir_all(&path).map_err(|e| ForgeError::cgroup(format!("create cgroup {}", path), e))?;
+        return Ok(());
+    }
+    for controller in required.iter().map(|c| if *c == "io" { "blkio" } else { c }) {
+        if !v1_hierarchies().any(|h| h == controller) {
+            return Err(ForgeError::cgroup(format!("use the {} controller", controller), "this host has no cgroup hierarchy for it"));
+        }
+    }
+    // Every hierarchy that can be is created, then the first failure reported
+    let mut failure = None;
+    for controller in v1_hierarchies() {
+        let path = cgroup_dir(controller, name);
         if let Err(e) = fs::create_dir_all(&path) {
-            warn!("Failed to create cgroup {}: {}", path, e);
+            failure.get_or_insert(ForgeError::cgroup(format!("create cgroup {}", path), e));
         }
-        enable_controllers_v2();
-    } else {
-        let controllers = vec!["cpu", "memory", "pids"];
-        for controller in controllers {
-            let path = format!("{}/{}/{}", CGROUP_ROOT, controller, name);
-            if let Err(e) = fs::create_dir_all(&path) {
-                warn!("Failed to create cgroup {}: {}", path, e);
+    }
+    // A v1 cpuset takes no processes until it has CPUs and memory nodes, which start out empty,
+    // in the parent as much as in the container's cgroup
+    let cpuset = format!("{}/cpuset", CGROUP_ROOT);
+    let parent = format!("{}/{}", cpuset, PARENT);
+    for file in ["cpuset.cpus", "cpuset.mems"] {
+        for (from, to) in [(&cpuset, &parent), (&parent, &cgroup_dir("cpuset", name))] {
+            if let Ok(value) = fs::read_to_string(format!("{}/{}", from, file)) {
+                let _ = fs::write(format!("{}/{}", to, file), value.trim());
             }
         }
     }
+    failure.map_or(Ok(()), Err)
 }
 
-fn enable_controllers_v2() {
-    let controllers_file = format!("{}/cgroups.controllers", CGROUP_ROOT);
-    if let Ok(_controllers) = fs::read_to_string(&controllers_file) {
-        let subtree_file = format!("{}/cgroup.subtree_control", CGROUP_ROOT);
-        let enable = "+cpu +memory +pids".to_string();
+// v2 has no device controller to enable, the program is attached to the cgroup itself
+fn restrict_devices(name: &str, rules: &[DeviceRule]) -> Result<(), ForgeError> {
+    if is_cgroup_v2() {
+        devices::attach_program(&cgroup_dir("", name), rules)?;
+    } else {
+        let dir = cgroup_dir("devices", name);
+        write_limit(&format!("{}/devices.deny", dir), "a")?;
+        for rule in rules {
+            write_limit(&format!("{}/devices.allow", dir), rule)?;
+        }
+    }
+    debug!("Devices restricted to {} rules", rules.len());
+    Ok(())
+}
 
-        if let Err(e) = fs::write(&subtree_file, &enable) {
-            debug!("Failed to enable controllers: {}", e);
+/// Make the controllers the host has available to the cgroups below `parent`. The root cgroup
+/// may hold processes and still pass controllers on; `parent` holds none so that it can too.
+fn enable_controllers_v2(parent: &str, required: &[&str]) -> Result<(), ForgeError> {
+    for dir in [CGROUP_ROOT, parent] {
+        let available = fs::read_to_string(format!("{}/cgroup.controllers", dir)).unwrap_or_default();
+        let subtree_file = format!("{}/cgroup.subtree_control", dir);
+        // One at a time, so a controller that can't be enabled doesn't keep the others out
+        for controller in V2_CONTROLLERS.iter().filter(|c| available.split_whitespace().any(|a| a == **c)) {
+            if let Err(e) = fs::write(&subtree_file, format!("+{}", controller)) {
+                debug!("Failed to enable the {} controller in {}: {}", controller, dir, e);
+            }
         }
     }
+    let enabled = fs::read_to_string(format!("{}/cgroup.subtree_control", parent)).unwrap_or_default();
+    match required.iter().find(|c| !enabled.split_whitespace().any(|e| e == **c)) {
+        Some(missing) => Err(ForgeError::cgroup(format!("enable the {} controller in {}", missing, parent), "the host's cgroup hierarchy doesn't make it available")),
+        None => Ok(()),
+    }
 }
 
-fn set_resource_limits(name: &str) {
-    debug!("Setting resource limits...");
+fn set_limits_v1(name: &str, config: &CgroupConfig) -> Result<(), ForgeError> {
+    if let Some(memory) = config.memory {
+        write_limit(&format!("{}/memory.limit_in_bytes", cgroup_dir("memory", name)), memory)?;
+    }
+    // After the memory limit, which it can't be below
+    write_swap_limit(&format!("{}/memory.memsw.limit_in_bytes", cgroup_dir("memory", name)), config, false)?;
+    if let Some(swappiness) = config.memory_swappiness {
+        write_limit(&format!("{}/memory.swappiness", cgroup_dir("memory", name)), swappiness)?;
+    }
+    // What the kernel reclaims the cgroup back down to first, once the host runs short
+    if let Some(reservation) = config.memory_reservation {
+        write_limit(&format!("{}/memory.soft_limit_in_bytes", cgroup_dir("memory", name)), reservation)?;
+    }
+    if let Some(period) = config.cpu_period {
+        write_limit(&format!("{}/cpu.cfs_period_us", cgroup_dir("cpu", name)), period)?;
+    }
+    if let Some(quota) = config.cpu_quota {
+        write_limit(&format!("{}/cpu.cfs_quota_us", cgroup_dir("cpu", name)), quota)?;
+    }
+    if let Some(shares) = config.cpu_shares {
+        write_limit(&format!("{}/cpu.shares", cgroup_dir("cpu", name)), shares)?;
+    }
+    if let Some(pids) = config.pids {
+        write_limit(&format!("{}/pids.max", cgroup_dir("pids", name)), pids)?;
+    }
+    if let Some(cpus) = &config.cpuset_cpus {
+        write_limit(&format!("{}/cpuset.cpus", cgroup_dir("cpuset", name)), cpus)?;
+    }
+    if let Some(mems) = &config.cpuset_mems {
+        write_limit(&format!("{}/cpuset.mems", cgroup_dir("cpuset", name)), mems)?;
+    }
+    if let Some(weight) = config.io {
+        write_limit(&weight_file(&cgroup_dir("blkio", name), "blkio.weight", "blkio.bfq.weight"), weight)?;
+    }
+    for (size, limit) in &config.hugetlb {
+        write_limit(&format!("{}/hugetlb.{}.limit_in_bytes", cgroup_dir("hugetlb", name), page_size_name(*size)), limit)?;
+    }
+    debug!("Resource limits set (v1): {:?}", config);
+    Ok(())
+}
 
-    if is_cgroup_v2() {
-        set_limits_v2(name);
-    } else {
-        set_limits_v1(name);
+fn set_limits_v2(name: &str, config: &CgroupConfig) -> Result<(), ForgeError> {
+    if let Some(memory) = config.memory {
+        write_limit(&format!("{}/memory.max", cgroup_dir("memory", name)), memory)?;
+    }
+    write_swap_limit(&format!("{}/memory.swap.max", cgroup_dir("memory", name)), config, true)?;
+    if config.memory_swappiness.is_some() {
+        warn!("cgroup v2 has no swappiness of its own, --memory-swappiness is ignored");
+    }
+    if let Some(reservation) = config.memory_reservation {
+        // Protection only reaches as far down as every ancestor has it; the parent claims none of
+        // its own, it passes on what its cgroups ask for
+        write_limit(&format!("{}/{}/memory.low", CGROUP_ROOT, PARENT), "max")?;
+        write_limit(&format!("{}/memory.low", cgroup_dir("memory", name)), reservation)?;
+    }
+    if config.cpu_quota.is_some() || config.cpu_period.is_some() {
+        let quota = config.cpu_quota.map_or("max".to_string(), |q| q.to_string());
+        write_limit(&format!("{}/cpu.max", cgroup_dir("cpu", name)), format!("{} {}", quota, config.cpu_period.unwrap_or(DEFAULT_CPU_PERIOD)))?;
+    }
+    if let Some(shares) = config.cpu_shares {
+        write_limit(&format!("{}/cpu.weight", cgroup_dir("cpu", name)), cpu_weight(shares))?;
+    }
+    if let Some(pids) = config.pids {
+        write_limit(&format!("{}/pids.max", cgroup_dir("pids", name)), pids)?;
     }
+    if let Some(cpus) = &config.cpuset_cpus {
+        write_limit(&format!("{}/cpuset.cpus", cgroup_dir("cpuset", name)), cpus)?;
+    }
+    if let Some(mems) = &config.cpuset_mems {
+        write_limit(&format!("{}/cpuset.mems", cgroup_dir("cpuset", name)), mems)?;
+    }
+    if let Some(weight) = config.io {
+        // io.bfq.weight keeps blkio's range
+        let file = weight_file(&cgroup_dir("io", name), "io.weight", "io.bfq.weight");
+        let value = if file.ends_with("/io.weight") { io_weight(weight) } else { weight };
+        write_limit(&file, format!("default {}", value))?;
+    }
+    for (size, limit) in &config.hugetlb {
+        write_limit(&format!("{}/hugetlb.{}.max", cgroup_dir("hugetlb", name), page_size_name(*size)), limit)?;
+    }
+    debug!("Resource limits set (v2): {:?}", config);
+    Ok(())
 }
 
-fn set_limits_v1(name: &str) {
-    write_cgroup_file(&format!("cpu/{}/cpu.cfs_quota_us", name), "50000");
-    write_cgroup_file(&format!("cpu/{}/cpu.cfs_period_us", name), "100000");
-    write_cgroup_file(&format!("memory/{}/memory.limit_in_bytes", name), "536870912");
-    write_cgroup_file(&format!("pids/{}/pids.max", name), "100");
-    debug!("Resource limits set (v1): CPU 50%, Memory 512MB, PIDs 100");
+// The I/O scheduler decides which weight file there is: CFQ or blk-cgroup's own, or BFQ's
+fn weight_file(dir: &str, file: &str, bfq: &str) -> String {
+    let path = format!("{}/{}", dir, file);
+    if std::path::Path::new(&path).exists() {
+        path
+    } else {
+        format!("{}/{}", dir, bfq)
+    }
 }
 
-fn set_limits_v2(name: &str) {
-    write_cgroup_file(&format!("{}/cpu.max", name), "50000 100000");
-    write_cgroup_file(&format!("{}/memory.max", name), "536870912");
-    write_cgroup_file(&format!("{}/pids.max", name), "100");
-    debug!("Resource limits set (v2): CPU 50%, Memory 512MB, PIDs 100");
+// cpu.weight runs from 1 to 10000 where cpu.shares ran from 2 to 262144
+fn cpu_weight(shares: u64) -> u64 {
+    1 + (shares - 2) * 9_999 / 262_142
 }
 
-pub fn add_process_to_cgroup(name: &str) {
-    let pid = process::id().to_string();
+// io.weight runs from 1 to 10000 where blkio.weight ran from 10 to 1000
+fn io_weight(weight: u64) -> u64 {
+    1 + (weight - 10) * 9_999 / 990
+}
 
+/// Mount the cgroup filesystems on `target`, the container's /sys/fs/cgroup. In the container's
+/// cgroup namespace each shows its own cgroup as the root, so limits read from there are its own.
+pub fn mount_cgroupfs(target: &str, flags: MsFlags) -> Result<(), ForgeError> {
     if is_cgroup_v2() {
-        write_cgroup_file(&format!("{}/cgroup.procs", name), &pid);
+        return mount(Some("cgroup2"), target, Some("cgroup2"), flags, None::<&str>)
+            .map_err(|e| ForgeError::mount("mount /sys/fs/cgroup", e));
+    }
+    // v1: a directory per hierarchy on a tmpfs, as on the host
+    mount(Some("tmpfs"), target, Some("tmpfs"), flags & !MsFlags::MS_RDONLY, Some("mode=755"))
+        .map_err(|e| ForgeError::mount("mount /sys/fs/cgroup", e))?;
+    let hierarchies = fs::read_to_string("/proc/self/cgroup").map_err(|e| ForgeError::mount("read /proc/self/cgroup", e))?;
+    for line in hierarchies.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (Some(id), Some(controllers)) = (fields.next(), fields.next()) else { continue };
+        // The v2 hierarchy of a hybrid host is on "unified", where it's mounted at all
+        let (dir, fstype, data) = match (id, controllers) {
+            ("0", "") if std::path::Path::new(&format!("{}/unified", CGROUP_ROOT)).exists() => ("unified", "cgroup2", ""),
+            (_, "") => continue,
+            _ => (controllers.trim_start_matches("name="), "cgroup", controllers),
+        };
+        let path = format!("{}/{}", target, dir);
+        fs::create_dir_all(&path).map_err(|e| ForgeError::mount(format!("create {}", path), e))?;
+        mount(Some("cgroup"), path.as_str(), Some(fstype), flags, Some(data))
+            .map_err(|e| ForgeError::mount(format!("mount /sys/fs/cgroup/{}", dir), e))?;
+        // cpu,cpuacct is also reached as cpu and cpuacct
+        if dir.contains(',') {
+            for controller in dir.split(',') {
+                let _ = symlink(dir, format!("{}/{}", target, controller));
+            }
+        }
+    }
+    if flags.contains(MsFlags::MS_RDONLY) {
+        mount(None::<&str>, target, None::<&str>, flags | MsFlags::MS_REMOUNT, None::<&str>)
+            .map_err(|e| ForgeError::mount("make /sys/fs/cgroup read-only", e))?;
+    }
+    Ok(())
+}
+
+/// Move `pid` into the cgroup `setup_cgroups` made
+pub fn add_process_to_cgroup(name: &str, pid: Pid) -> Result<(), ForgeError> {
+    if rootless::active() {
+        return Ok(());
+    }
+    let pid = pid.to_string();
+    let procs: Vec<String> = if is_cgroup_v2() {
+        vec![format!("{}/cgroup.procs", cgroup_dir("", name))]
     } else {
-        let controllers = vec!["cpu", "memory", "pids"];
-        for controller in controllers {
-            write_cgroup_file(&format!("{}/{}/cgroup.procs", controller, name), &pid);
+        v1_hierarchies().map(|controller| format!("{}/cgroup.procs", cgroup_dir(controller, name))).collect()
+    };
+    let mut failure = None;
+    for path in procs {
+        if let Err(e) = fs::write(&path, &pid) {
+            failure.get_or_insert(ForgeError::cgroup(format!("join cgroup {}", name), format!("{}: {}", path, e)));
         }
     }
+    failure.map_or(Ok(()), Err)
 }
 
-fn write_cgroup_file(path: &str, content: &str) {
-    let full_path = format!("{}/{}", CGROUP_ROOT, path);
-    if let Err(e) = fs::write(&full_path, content) {
-        debug!("Failed to write to {}: {}", full_path, e);
+// Without --memory-swap a memory limit allows as much swap again. That default is left out
+// where the kernel doesn't account swap, a limit that was asked for is not.
+fn write_swap_limit(path: &str, config: &CgroupConfig, v2: bool) -> Result<(), ForgeError> {
+    let Some(memory) = config.memory else { return Ok(()) };
+    if !std::path::Path::new(path).exists() {
+        if config.memory_swap.is_some() {
+            return Err(ForgeError::cgroup("limit swap", "this kernel doesn't account swap; boot it with swapaccount=1"));
+        }
+        debug!("No swap accounting, swap is left unlimited");
+        return Ok(());
     }
+    let value = match (config.memory_swap.unwrap_or(2 * memory as i64), v2) {
+        (-1, true) => "max".to_string(),
+        // v2 limits swap alone rather than memory plus swap
+        (swap, true) => (swap as u64).saturating_sub(memory).to_string(),
+        (swap, false) => swap.to_string(),
+    };
+    write_limit(path, value)
 }
 
-pub fn cleanup_cgroup(name: &str) {
-    debug!("Cleaning up cgroups...");
+// A missing file is a controller this kernel doesn't have, or hasn't enabled for the cgroup
+fn write_limit(path: &str, value: impl std::fmt::Display) -> Result<(), ForgeError> {
+    fs::write(path, value.to_string()).map_err(|e| ForgeError::cgroup(format!("set {} to {}", path, value), e))
+}
+
+/// What a container's cgroup has used, from `stats` and `inspect`
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CgroupStats {
+    pub cpu: CpuStats,
+    pub memory: MemoryStats,
+    pub io: IoStats,
+    pub pids: PidsStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pressure: Option<PressureStats>,    // None without PSI, or on a v1 host without a v2 hierarchy
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CpuStats {
+    pub usage_usec: u64,        // since the cgroup was made
+    pub user_usec: u64,
+    pub system_usec: u64,
+    pub nr_periods: u64,        // quota periods that have passed with the cgroup runnable
+    pub nr_throttled: u64,      // of those, the ones it ran out of quota in
+    pub throttled_usec: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct MemoryStats {
+    pub usage: u64,             // bytes, page cache included
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,     // None for unlimited
+    pub anon: u64,
+    pub file: u64,              // page cache
+    pub swap: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct IoStats {
+    pub read_bytes: u64,        // summed over every device
+    pub write_bytes: u64,
+    pub read_ios: u64,
+    pub write_ios: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PidsStats {
+    pub current: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+    pub limit_hits: u64,        // forks refused for the limit, from pids.events
+}
+
+/// How long the cgroup's tasks were stalled waiting on each resource, from its *.pressure files
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PressureStats {
+    pub cpu: Pressure,
+    pub memory: Pressure,
+    pub io: Pressure,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Pressure {
+    pub some: PressureLine,     // some tasks stalled
+    pub full: PressureLine,     // every task stalled at once
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PressureLine {
+    pub avg10: f64,             // percentages of wall time over 10, 60 and 300 seconds
+    pub avg60: f64,
+    pub avg300: f64,
+    pub total_usec: u64,
+}
+
+impl CgroupStats {
+    /// The share of one CPU the cgroup used between `earlier` and now, `elapsed` apart; 200.0
+    /// is two CPUs' worth
+    pub fn cpu_percent(&self, earlier: &CgroupStats, elapsed: std::time::Duration) -> f64 {
+        let elapsed = elapsed.as_micros() as f64;
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+        self.cpu.usage_usec.saturating_sub(earlier.cpu.usage_usec) as f64 / elapsed * 100.0
+    }
+}
+
+/// Read the usage of cgroup `name`. Controllers it isn't in count as nothing used.
+pub fn stats(name: &str) -> Result<CgroupStats, ForgeError> {
+    if rootless::active() {
+        return Err(ForgeError::cgroup(format!("read cgroup {}", name), "rootless containers have no cgroup"));
+    }
+    // On v2, the cgroup itself
+    let dir = cgroup_dir("memory", name);
+    if !std::path::Path::new(&dir).is_dir() {
+        return Err(ForgeError::cgroup(format!("read cgroup {}", name), "it doesn't exist"));
+    }
+    Ok(if is_cgroup_v2() { stats_v2(&dir) } else { stats_v1(name) })
+}
+
+fn stats_v2(dir: &str) -> CgroupStats {
+    let read = |file: &str| fs::read_to_string(format!("{}/{}", dir, file)).unwrap_or_default();
+    CgroupStats {
+        cpu: cpu_stats_v2(&read("cpu.stat")),
+        memory: memory_stats_v2(&read("memory.stat"), &read("memory.current"), &read("memory.max"), &read("memory.swap.current")),
+        io: io_stats_v2(&read("io.stat")),
+        pids: pids_stats(&read("pids.current"), &read("pids.max"), &read("pids.events")),
+        pressure: pressure(dir),
+    }
+}
+
+fn stats_v1(name: &str) -> CgroupStats {
+    let read = |controller: &str, file: &str| fs::read_to_string(format!("{}/{}", cgroup_dir(controller, name), file)).unwrap_or_default();
+    CgroupStats {
+        cpu: cpu_stats_v1(&read("cpu", "cpu.stat"), &read("cpuacct", "cpuacct.usage"), &read("cpuacct", "cpuacct.usage_user"), &read("cpuacct", "cpuacct.usage_sys")),
+        memory: memory_stats_v1(&read("memory", "memory.stat"), &read("memory", "memory.usage_in_bytes"), &read("memory", "memory.limit_in_bytes"), &read("memory", "memory.memsw.usage_in_bytes")),
+        io: io_stats_v1(&read("blkio", "blkio.throttle.io_service_bytes_recursive"), &read("blkio", "blkio.throttle.io_serviced_recursive")),
+        pids: pids_stats(&read("pids", "pids.current"), &read("pids", "pids.max"), &read("pids", "pids.events")),
+        pressure: pressure(&cgroup_dir("unified", name)),
+    }
+}
+
+// The parsers below take the contents of the files they're named for, empty where a file is missing
+
+fn cpu_stats_v2(cpu_stat: &str) -> CpuStats {
+    CpuStats {
+        usage_usec: keyed(cpu_stat, "usage_usec"),
+        user_usec: keyed(cpu_stat, "user_usec"),
+        system_usec: keyed(cpu_stat, "system_usec"),
+        nr_periods: keyed(cpu_stat, "nr_periods"),
+        nr_throttled: keyed(cpu_stat, "nr_throttled"),
+        throttled_usec: keyed(cpu_stat, "throttled_usec"),
+    }
+}
+
+// v1 counts in nanoseconds
+fn cpu_stats_v1(cpu_stat: &str, usage: &str, usage_user: &str, usage_sys: &str) -> CpuStats {
+    let usec = |text: &str| parse_value(text).unwrap_or(0) / 1_000;
+    CpuStats {
+        usage_usec: usec(usage),
+        user_usec: usec(usage_user),
+        system_usec: usec(usage_sys),
+        nr_periods: keyed(cpu_stat, "nr_periods"),
+        nr_throttled: keyed(cpu_stat, "nr_throttled"),
+        throttled_usec: keyed(cpu_stat, "throttled_time") / 1_000,
+    }
+}
+
+fn memory_stats_v2(memory_stat: &str, current: &str, max: &str, swap_current: &str) -> MemoryStats {
+    MemoryStats {
+        usage: parse_value(current).unwrap_or(0),
+        limit: parse_value(max),
+        anon: keyed(memory_stat, "anon"),
+        file: keyed(memory_stat, "file"),
+        swap: parse_value(swap_current).unwrap_or(0),
+    }
+}
+
+// Above a page-aligned 2^63 means no limit, and memsw is memory and swap together
+fn memory_stats_v1(memory_stat: &str, usage_in_bytes: &str, limit_in_bytes: &str, memsw_usage_in_bytes: &str) -> MemoryStats {
+    let usage = parse_value(usage_in_bytes).unwrap_or(0);
+    MemoryStats {
+        usage,
+        limit: parse_value(limit_in_bytes).filter(|l| *l < 1 << 62),
+        anon: keyed(memory_stat, "total_rss"),
+        file: keyed(memory_stat, "total_cache"),
+        swap: parse_value(memsw_usage_in_bytes).map_or(0, |memsw| memsw.saturating_sub(usage)),
+    }
+}
+
+// "8:0 rbytes=1 wbytes=2 rios=3 wios=4 dbytes=0 dios=0", a line per device
+fn io_stats_v2(io_stat: &str) -> IoStats {
+    let mut io = IoStats::default();
+    for (key, value) in io_stat.split_whitespace().filter_map(|field| field.split_once('=')) {
+        let value: u64 = value.parse().unwrap_or(0);
+        match key {
+            "rbytes" => io.read_bytes += value,
+            "wbytes" => io.write_bytes += value,
+            "rios" => io.read_ios += value,
+            "wios" => io.write_ios += value,
+            _ => {}
+        }
+    }
+    io
+}
 
-    std::thread::sleep(std::time::Duration::from_millis(100));
+// "8:0 Read 4096", a line per device and operation, then "Total 4096"
+fn io_stats_v1(service_bytes: &str, serviced: &str) -> IoStats {
+    let totals = |text: &str| {
+        let (mut read, mut write) = (0, 0);
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [_, operation, value] = fields[..] else { continue };
+            let value: u64 = value.parse().unwrap_or(0);
+            match operation {
+                "Read" => read += value,
+                "Write" => write += value,
+                _ => {}
+            }
+        }
+        (read, write)
+    };
+    let (read_bytes, write_bytes) = totals(service_bytes);
+    let (read_ios, write_ios) = totals(serviced);
+    IoStats { read_bytes, write_bytes, read_ios, write_ios }
+}
+
+fn pids_stats(current: &str, max: &str, events: &str) -> PidsStats {
+    PidsStats {
+        current: parse_value(current).unwrap_or(0),
+        limit: parse_value(max),
+        limit_hits: keyed(events, "max"),
+    }
+}
+
+/// How often the cgroup ran into its memory limits, counted since it was made
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MemoryEvents {
+    pub high: u64,              // throttled above memory.high; v2 alone
+    pub max: u64,               // allocations that hit the limit and had to reclaim
+    pub oom: u64,               // reclaim failed and the OOM killer was called
+    pub oom_kill: u64,          // processes it killed
+}
 
+/// The memory events of cgroup `name`, None where it has none to read
+pub fn memory_events(name: &str) -> Option<MemoryEvents> {
+    let dir = cgroup_dir("memory", name);
     if is_cgroup_v2() {
-        cleanup_cgroup_v2(name);
-    } else {
-        cleanup_cgroup_v1(name);
+        let events = fs::read_to_string(format!("{}/memory.events", dir)).ok()?;
+        return Some(MemoryEvents { high: keyed(&events, "high"), max: keyed(&events, "max"), oom: keyed(&events, "oom"), oom_kill: keyed(&events, "oom_kill") });
     }
+    // v1 counts hits of the limit in failcnt, and OOM kills in oom_control on 4.13 and later.
+    // It has no count of OOM killer calls, which are the kills as near as matters.
+    let oom_control = fs::read_to_string(format!("{}/memory.oom_control", dir)).ok()?;
+    let failcnt = parse_value(&fs::read_to_string(format!("{}/memory.failcnt", dir)).unwrap_or_default()).unwrap_or(0);
+    let oom_kill = keyed(&oom_control, "oom_kill");
+    Some(MemoryEvents { high: 0, max: failcnt, oom: oom_kill, oom_kill })
 }
 
-fn cleanup_cgroup_v2(name: &str) {
-    let path = format!("{}/{}", CGROUP_ROOT, name);
-    let procs_file = format!("{}/cgroup.procs", path);
+/// The file that changes when cgroup `name` has a memory event, for inotify to wait on; v1
+/// raises none that inotify sees
+pub fn memory_events_file(name: &str) -> Option<String> {
+    is_cgroup_v2().then(|| format!("{}/memory.events", cgroup_dir("memory", name)))
+}
 
-    if let Ok(pids) = fs::read_to_string(&procs_file) {
-        for pid_str in pids.lines() {
-            if let Ok(pid) = pid_str.trim().parse::<i32>() {
-                if pid > 0 {
-                    unsafe {
-                        libc::kill(pid, libc::SIGKILL);
-                    }
-                }
+// The pressure files of the v2 cgroup at `dir`, which it has whatever its controllers
+fn pressure(dir: &str) -> Option<PressureStats> {
+    let read = |resource: &str| fs::read_to_string(format!("{}/{}.pressure", dir, resource)).ok().map(|text| parse_pressure(&text));
+    Some(PressureStats { cpu: read("cpu")?, memory: read("memory")?, io: read("io")? })
+}
+
+// "some avg10=0.00 avg60=0.00 avg300=0.00 total=0", then the same for "full"
+fn parse_pressure(text: &str) -> Pressure {
+    let mut pressure = Pressure::default();
+    for line in text.lines() {
+        let mut fields = line.split_whitespace();
+        let target = match fields.next() {
+            Some("some") => &mut pressure.some,
+            Some("full") => &mut pressure.full,
+            _ => continue,
+        };
+        for (key, value) in fields.filter_map(|field| field.split_once('=')) {
+            match key {
+                "avg10" => target.avg10 = value.parse().unwrap_or(0.0),
+                "avg60" => target.avg60 = value.parse().unwrap_or(0.0),
+                "avg300" => target.avg300 = value.parse().unwrap_or(0.0),
+                "total" => target.total_usec = value.parse().unwrap_or(0),
+                _ => {}
             }
         }
-        std::thread::sleep(std::time::Duration::from_millis(50));
     }
+    pressure
+}
+
+// The value of `key` in "key value" lines, as in cpu.stat and memory.stat
+fn keyed(text: &str, key: &str) -> u64 {
+    text.lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(' ')?.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+// A single number; "max" and a missing file are None
+fn parse_value(text: &str) -> Option<u64> {
+    text.trim().parse().ok()
+}
+
+/// Stop or resume every process in the cgroup at once
+pub fn freeze(name: &str, frozen: bool) -> Result<(), ForgeError> {
+    let action = if frozen { format!("freeze cgroup {}", name) } else { format!("thaw cgroup {}", name) };
+    if rootless::active() {
+        return Err(ForgeError::cgroup(action, "rootless containers have no cgroup"));
+    }
+    check_available(&["pause"])?;
+    let (path, value) = if is_cgroup_v2() {
+        (format!("{}/cgroup.freeze", cgroup_dir("", name)), if frozen { "1" } else { "0" })
+    } else {
+        (format!("{}/freezer.state", cgroup_dir("freezer", name)), if frozen { "FROZEN" } else { "THAWED" })
+    };
+    fs::write(&path, value).map_err(|e| ForgeError::cgroup(action, format!("writing {}: {}", path, e)))?;
+    Ok(())
+}
+
+/// Names of the cgroups this runtime may have created, including ones left behind by a runtime that was killed
+pub fn list_cgroups() -> Vec<String> {
+    let dirs: Vec<String> = if is_cgroup_v2() {
+        vec![format!("{}/{}", CGROUP_ROOT, PARENT)]
+    } else {
+        v1_hierarchies().map(|c| format!("{}/{}/{}", CGROUP_ROOT, c, PARENT)).collect()
+    };
+    let mut names: Vec<String> = dirs.iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.flatten())
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .map(|dir| scope_name(&dir).unwrap_or(dir))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Kill whatever is left in cgroup `name`, wait for it to empty and remove it. An error means
+/// the cgroup is still there.
+pub fn cleanup_cgroup(name: &str) -> Result<(), ForgeError> {
+    if rootless::active() {
+        return Ok(());
+    }
+    debug!("Cleaning up cgroups...");
+
+    // Before the scope goes, which takes its directory on v1's systemd hierarchy with it
+    let unit = leaf(name);
+    let dirs: Vec<String> = if is_cgroup_v2() {
+        vec![cgroup_dir("", name)]
+    } else {
+        v1_hierarchies().map(|controller| cgroup_dir(controller, name)).collect()
+    };
+    let dirs: Vec<String> = dirs.into_iter().filter(|dir| std::path::Path::new(dir).is_dir()).collect();
+    // cgroup.kill and cgroup.events are v2's, which a hybrid host has on its unified hierarchy
+    let unified = dirs.iter().find(|dir| std::path::Path::new(&format!("{}/cgroup.events", dir)).exists());
+
+    let killed = unified.is_some_and(|dir| fs::write(format!("{}/cgroup.kill", dir), "1").is_ok());
+    // Before cgroup.kill, which came with Linux 5.14, one process at a time
+    if !killed {
+        kill_processes(&dirs);
+    }
+    let emptied = wait_until_empty(name, &dirs, unified.map(|dir| format!("{}/cgroup.events", dir)));
+    let removed = emptied.and_then(|_| dirs.iter().try_for_each(|dir| remove_cgroup_dir(dir)));
+    if unit != name {
+        // Emptied already; stopping it has systemd forget it now rather than later
+        let mut busctl = systemd_call("StopUnit", "ss");
+        busctl.args([unit.as_str(), "fail"]);
+        if let Err(e) = tools::run(&mut busctl, "busctl") {
+            debug!("Failed to stop {}: {}", unit, e);
+        }
+    }
+    if removed.is_ok() {
+        debug!("Cgroup removed: {}", name);
+    }
+    removed
+}
 
-    match fs::remove_dir(&path) {
-        Ok(_) => debug!("Cgroup removed: {}", name),
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
-        Err(_) => {}
+// The processes in `dir` and the cgroups below it
+fn processes(dir: &str) -> Vec<Pid> {
+    let mut pids: Vec<Pid> = fs::read_to_string(format!("{}/cgroup.procs", dir)).unwrap_or_default()
+        .lines()
+        .filter_map(|pid| pid.trim().parse().ok())
+        .map(Pid::from_raw)
+        .collect();
+    for child in subdirs(dir) {
+        pids.extend(processes(&child));
     }
+    pids
 }
 
-fn cleanup_cgroup_v1(name: &str) {
-    let controllers = vec!["cpu", "memory", "pids"];
+fn subdirs(dir: &str) -> Vec<String> {
+    fs::read_dir(dir).into_iter().flatten().flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|entry| entry.path().to_string_lossy().into_owned())
+        .collect()
+}
 
-    for controller in &controllers {
-        let path = format!("{}/{}/{}", CGROUP_ROOT, controller, name);
-        let procs_file = format!("{}/cgroup.procs", path);
+fn kill_processes(dirs: &[String]) {
+    for pid in dirs.iter().flat_map(|dir| processes(dir)) {
+        let _ = signal::kill(pid, Signal::SIGKILL);
+    }
+}
 
-        if let Ok(pids) = fs::read_to_string(&procs_file) {
-            for pid_str in pids.lines() {
-                if let Ok(pid) = pid_str.trim().parse::<i32>() {
-                    if pid > 0 {
-                        unsafe {
-                            libc::kill(pid, libc::SIGKILL);
-                        }
-                    }
-                }
+// Until cgroup.events says nothing is left, woken by inotify as it changes; without it, until
+// every cgroup.procs is empty, killing whatever was forked in the meantime
+fn wait_until_empty(name: &str, dirs: &[String], events: Option<String>) -> Result<(), ForgeError> {
+    let inotify = events.as_deref().and_then(|file| {
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK).ok()?;
+        inotify.add_watch(file, AddWatchFlags::IN_MODIFY).ok()?;
+        Some(inotify)
+    });
+    let deadline = Instant::now() + CLEANUP_TIMEOUT;
+    loop {
+        let empty = match &events {
+            // Gone is empty too
+            Some(file) => fs::read_to_string(file).map_or(true, |e| keyed(&e, "populated") == 0),
+            None => dirs.iter().all(|dir| processes(dir).is_empty()),
+        };
+        if empty {
+            return Ok(());
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(ForgeError::cgroup(format!("remove cgroup {}", name), format!("its processes were still there after {}s", CLEANUP_TIMEOUT.as_secs())));
+        }
+        match &inotify {
+            Some(inotify) => {
+                let mut fds = [PollFd::new(inotify.as_fd(), PollFlags::POLLIN)];
+                let _ = poll(&mut fds, PollTimeout::try_from(left).unwrap_or(PollTimeout::MAX));
+                let _ = inotify.read_events();
+            }
+            None => {
+                kill_processes(dirs);
+                std::thread::sleep(left.min(Duration::from_millis(10)));
+            }
+        }
+    }
+}
+
+// Cgroups the container made below its own go first. An emptied cgroup can be busy for a moment
+// while the kernel finishes with its last process.
+fn remove_cgroup_dir(dir: &str) -> Result<(), ForgeError> {
+    for child in subdirs(dir) {
+        remove_cgroup_dir(&child)?;
+    }
+    let mut attempts = 0;
+    loop {
+        match fs::remove_dir(dir) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) && attempts < RMDIR_ATTEMPTS => {
+                attempts += 1;
+                std::thread::sleep(Duration::from_millis(10));
             }
+            Err(e) => return Err(ForgeError::cgroup(format!("remove cgroup {}", dir), e)),
         }
     }
+}
 
-    std::thread::sleep(std::time::Duration::from_millis(50));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPU_STAT_V2: &str = "usage_usec 2500000\nuser_usec 1500000\nsystem_usec 1000000\nnr_periods 40\nnr_throttled 3\nthrottled_usec 12000\nnr_bursts 0\nburst_usec 0\n";
+    const MEMORY_STAT_V2: &str = "anon 7340032\nfile 3145728\nkernel 524288\nanon_thp 0\nfile_mapped 1048576\nfile_dirty 0\n";
+    const IO_STAT_V2: &str = "8:0 rbytes=4096 wbytes=8192 rios=1 wios=2 dbytes=0 dios=0\n259:0 rbytes=1024 wbytes=0 rios=3 wios=0 dbytes=0 dios=0\n";
+
+    const CPU_STAT_V1: &str = "nr_periods 40\nnr_throttled 3\nthrottled_time 12000000\n";
+    const MEMORY_STAT_V1: &str = "cache 1024\nrss 2048\ntotal_cache 3145728\ntotal_rss 7340032\n";
+    const SERVICE_BYTES_V1: &str = "8:0 Read 4096\n8:0 Write 8192\n8:0 Sync 12288\n8:0 Async 0\n8:0 Total 12288\n8:16 Read 1024\n8:16 Write 0\nTotal 13312\n";
+    const SERVICED_V1: &str = "8:0 Read 1\n8:0 Write 2\n8:0 Total 3\n8:16 Read 3\n8:16 Write 0\nTotal 6\n";
+
+    #[test]
+    fn cpu_stats_v2_reads_cpu_stat() {
+        let cpu = cpu_stats_v2(CPU_STAT_V2);
+        assert_eq!(cpu.usage_usec, 2_500_000);
+        assert_eq!(cpu.user_usec, 1_500_000);
+        assert_eq!(cpu.system_usec, 1_000_000);
+        assert_eq!(cpu.nr_periods, 40);
+        assert_eq!(cpu.nr_throttled, 3);
+        assert_eq!(cpu.throttled_usec, 12_000);
+    }
+
+    #[test]
+    fn cpu_stats_v1_converts_nanoseconds() {
+        let cpu = cpu_stats_v1(CPU_STAT_V1, "2500000000\n", "1500000000\n", "1000000000\n");
+        assert_eq!(cpu.usage_usec, 2_500_000);
+        assert_eq!(cpu.user_usec, 1_500_000);
+        assert_eq!(cpu.system_usec, 1_000_000);
+        assert_eq!(cpu.nr_periods, 40);
+        assert_eq!(cpu.nr_throttled, 3);
+        assert_eq!(cpu.throttled_usec, 12_000);
+    }
+
+    #[test]
+    fn memory_stats_v2_reads_limit_and_swap() {
+        let memory = memory_stats_v2(MEMORY_STAT_V2, "10485760\n", "67108864\n", "4096\n");
+        assert_eq!(memory.usage, 10_485_760);
+        assert_eq!(memory.limit, Some(67_108_864));
+        assert_eq!(memory.anon, 7_340_032);
+        assert_eq!(memory.file, 3_145_728);
+        assert_eq!(memory.swap, 4096);
+
+        let unlimited = memory_stats_v2(MEMORY_STAT_V2, "10485760\n", "max\n", "");
+        assert_eq!(unlimited.limit, None);
+        assert_eq!(unlimited.swap, 0);
+    }
+
+    #[test]
+    fn memory_stats_v1_uses_totals() {
+        let memory = memory_stats_v1(MEMORY_STAT_V1, "10485760\n", "67108864\n", "10489856\n");
+        assert_eq!(memory.usage, 10_485_760);
+        assert_eq!(memory.limit, Some(67_108_864));
+        // The hierarchy's totals, not the cgroup's own pages
+        assert_eq!(memory.anon, 7_340_032);
+        assert_eq!(memory.file, 3_145_728);
+        assert_eq!(memory.swap, 4096);
+
+        let unlimited = memory_stats_v1(MEMORY_STAT_V1, "10485760\n", "9223372036854771712\n", "");
+        assert_eq!(unlimited.limit, None);
+        assert_eq!(unlimited.swap, 0);
+    }
+
+    #[test]
+    fn io_stats_v2_sums_devices() {
+        let io = io_stats_v2(IO_STAT_V2);
+        assert_eq!(io.read_bytes, 5120);
+        assert_eq!(io.write_bytes, 8192);
+        assert_eq!(io.read_ios, 4);
+        assert_eq!(io.write_ios, 2);
+    }
+
+    #[test]
+    fn io_stats_v1_sums_devices_without_totals() {
+        let io = io_stats_v1(SERVICE_BYTES_V1, SERVICED_V1);
+        assert_eq!(io.read_bytes, 5120);
+        assert_eq!(io.write_bytes, 8192);
+        assert_eq!(io.read_ios, 4);
+        assert_eq!(io.write_ios, 2);
+    }
+
+    #[test]
+    fn pids_stats_reads_limit_hits() {
+        let pids = pids_stats("12\n", "100\n", "max 5\n");
+        assert_eq!(pids.current, 12);
+        assert_eq!(pids.limit, Some(100));
+        assert_eq!(pids.limit_hits, 5);
+
+        let unlimited = pids_stats("12\n", "max\n", "");
+        assert_eq!(unlimited.limit, None);
+        assert_eq!(unlimited.limit_hits, 0);
+    }
+
+    #[test]
+    fn missing_files_read_as_nothing_used() {
+        let cpu = cpu_stats_v2("");
+        assert_eq!(cpu.usage_usec, 0);
+        let memory = memory_stats_v1("", "", "", "");
+        assert_eq!((memory.usage, memory.limit), (0, None));
+        let io = io_stats_v1("", "");
+        assert_eq!(io.read_bytes + io.write_bytes + io.read_ios + io.write_ios, 0);
+    }
+
+    fn with_usage(usage_usec: u64) -> CgroupStats {
+        CgroupStats { cpu: CpuStats { usage_usec, ..Default::default() }, ..Default::default() }
+    }
+
+    #[test]
+    fn cpu_percent_is_usage_over_elapsed() {
+        let before = with_usage(1_000_000);
+        let now = with_usage(1_500_000);
+        assert_eq!(now.cpu_percent(&before, Duration::from_secs(1)), 50.0);
+        // Two CPUs' worth
+        let busy = with_usage(5_000_000);
+        assert_eq!(busy.cpu_percent(&before, Duration::from_secs(2)), 200.0);
+    }
+
+    #[test]
+    fn cpu_percent_of_zero_elapsed_is_zero() {
+        let before = with_usage(1_000_000);
+        let now = with_usage(1_500_000);
+        assert_eq!(now.cpu_percent(&before, Duration::ZERO), 0.0);
+    }
 
-    for controller in &controllers {
-        let path = format!("{}/{}/{}", CGROUP_ROOT, controller, name);
-        let _ = fs::remove_dir(&path);
+    #[test]
+    fn cpu_percent_of_a_reset_counter_is_zero() {
+        let before = with_usage(1_500_000);
+        let now = with_usage(1_000_000);
+        assert_eq!(now.cpu_percent(&before, Duration::from_secs(1)), 0.0);
     }
 }
@@ -0,0 +1,217 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::prctl;
+use nix::unistd::Pid;
+use log::{debug, info, warn};
+
+use crate::cgroups;
+use crate::config;
+use crate::filesystem::BindMount;
+use crate::image::{self, write_atomic, ImageStore};
+use crate::ipam;
+use crate::lifecycle;
+use crate::netconfig;
+use crate::network;
+use crate::recover;
+use crate::rwlayer;
+use crate::snapshot;
+use crate::state::{self, Status};
+use crate::stdio::{self, ContainerStdio};
+use crate::tools;
+use crate::volumes;
+use crate::error::ForgeError;
+
+// A running container's process tree dumped with CRIU, next to the writable layer it was using:
+//   containers/<id>/checkpoint/                  CRIU's images, dump.log and restore.log
+//   containers/<id>/checkpoint/checkpoint.json   what the restore needs besides CRIU's images
+// The rootfs and bind mounts are external to the dump and mounted again for the restore, and
+// the restored tree gets fresh stdio pipes in place of the ones the supervisor held.
+
+const CHECKPOINT_DIR: &str = "checkpoint";
+const CHECKPOINT_FILE: &str = "checkpoint.json";
+const PID_FILE: &str = "restore.pid";
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    created: String,
+    pid: i32,                   // PID 1 as the host saw it; its veth pair is named after it
+    address: Ipv4Addr,          // which the restored network namespace still has
+    descriptors: Vec<String>,   // what PID 1 had as fds 0, 1 and 2, "pipe:[1234]"
+}
+
+/// checkpoint [--leave-running] CONTAINER: dump the container's processes, stopping them unless told not to
+pub fn checkpoint_command(args: &[String]) -> Result<(), ForgeError> {
+    let leave_running = args[2..].iter().any(|a| a == "--leave-running");
+    let reference = args[2..].iter().find(|a| !a.starts_with("--"))
+        .ok_or("Usage: container-runtime checkpoint [--leave-running] CONTAINER")?;
+    let record = rwlayer::find(reference)?;
+    let state = state::load(&record)?;
+    let (pid, address) = match (state.pid, &state.network) {
+        (Some(pid), Some(network)) if state.status == Status::Running => (pid, network.ip_address.parse().map_err(|_| format!("Invalid address {} in the state of {}", network.ip_address, record.display_name()))?),
+        _ => return Err(format!("Container {} is not running", record.display_name()).into()),
+    };
+    if record.tty {
+        return Err("Containers with a terminal can't be checkpointed".into());
+    }
+    if !record.net.is_default() || !record.pid.is_default() {
+        return Err("Containers sharing a network or PID namespace can't be checkpointed".into());
+    }
+    // criu can't restore a Landlock domain
+    if record.landlock {
+        return Err("Containers sandboxed with Landlock can't be checkpointed".into());
+    }
+
+    let dir = record.dir()?.join(CHECKPOINT_DIR);
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+    let descriptors = (0..3)
+        .map(|fd| Ok(fs::read_link(format!("/proc/{}/fd/{}", pid, fd))?.to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>, std::io::Error>>()?;
+    if !leave_running {
+        // The dump ends the processes, which the restart policy mustn't undo
+        state::update(&record, |s| s.stopped = true)?;
+    }
+    let mut criu = criu("dump", &dir);
+    criu.args(["--tree", &pid.to_string()]);
+    // Bind mounts from the host, as "SOURCE:TARGET", are left to the restore to provide
+    for target in state.mounts.iter().filter_map(|m| m.rsplit_once(':')).map(|(_, target)| target) {
+        criu.arg("--external").arg(format!("mnt[{}]:{}", target, target));
+    }
+    if leave_running {
+        criu.arg("--leave-running");
+    }
+    if let Err(e) = tools::run(&mut criu, &format!("criu dump failed, see {:?}", dir.join("dump.log"))) {
+        if !leave_running {
+            state::record(&record, |s| s.stopped = false);
+        }
+        return Err(e);
+    }
+    // Written last, so a failed dump leaves nothing to restore from
+    let checkpoint = Checkpoint { created: image::now_rfc3339(), pid, address, descriptors };
+    write_atomic(&dir.join(CHECKPOINT_FILE), serde_json::to_string_pretty(&checkpoint)?.as_bytes())?;
+    info!("Checkpointed container {}{}", record.display_name(), if leave_running { ", it keeps running" } else { "" });
+    Ok(())
+}
+
+/// restore CONTAINER: bring a checkpointed container back and stay in the foreground until it exits
+pub fn restore_command(args: &[String]) -> Result<(), ForgeError> {
+    let reference = args.get(2).ok_or("Usage: container-runtime restore CONTAINER")?;
+    recover::recover_quietly();
+    let record = rwlayer::find(reference)?;
+    let running = record.lock()?;
+    let container_dir = record.dir()?;
+    let dir = container_dir.join(CHECKPOINT_DIR);
+    let checkpoint: Checkpoint = match fs::read_to_string(dir.join(CHECKPOINT_FILE)) {
+        Ok(json) => serde_json::from_str(&json)?,
+        Err(_) => return Err(format!("Container {} has no checkpoint", record.display_name()).into()),
+    };
+    info!("Restoring container {} from its checkpoint of {}", record.display_name(), checkpoint.created);
+
+    if record.overlay {
+        let store = ImageStore::new(config::images_dir()?)?
+            .with_additional_stores(&config::additional_stores())?;
+        let _lock = store.lock_shared()?;
+        snapshot::remount(&store, &record.manifest, &container_dir, record.selinux.as_ref())?;
+    }
+    let mut options = record.rootfs.clone();
+    let (container_stdio, mut host_stdio) = stdio::open(false, record.interactive, &container_dir)?;
+    prctl::set_child_subreaper(true)?;
+    let lease = ipam::acquire_address(&container_dir, checkpoint.address)?;
+    let restored = volumes::mount_all(&mut options.mounts).and_then(|_| netconfig::prepare(&record.network, &container_dir, checkpoint.address, &mut options))
+        .and_then(|_| restore(&container_dir, &dir, &checkpoint, &options.mounts, &container_stdio));
+    let pid = match restored {
+        Ok(pid) => pid,
+        Err(e) => {
+            lease.release();
+            volumes::unmount_all(&options.mounts);
+            snapshot::unmount(&container_dir);
+            return Err(e);
+        }
+    };
+    // The restored processes have their own copies now
+    drop(container_stdio);
+
+    let veth_host = format!("veth-{}", checkpoint.pid);
+    // The restored processes are already running, so they carry on without a network instead
+    let attached = network::get_default_interface_public()
+        .and_then(|iface| network::attach_restored_veth(&veth_host, pid as u32, &iface, lease.subnet));
+    if let Err(e) = attached {
+        warn!("{}, the restored container is offline", e);
+    }
+    state::record(&record, |s| {
+        s.starting();
+        s.start(pid, &options.mounts, checkpoint.address);
+    });
+    info!("Restored container {} as PID {}", record.display_name(), pid);
+
+    // Supervised like one `start` ran: the restored PID 1 was reparented to this subreaper
+    lifecycle::catch_signals();
+    let output = host_stdio.forward();
+    let (exit_code, _, _) = image::wait_for_runtime(&record, Pid::from_raw(pid), None);
+    if let Err(e) = cgroups::cleanup_cgroup(&format!("img-{}", record.id)) {
+        warn!("{}", e);
+    }
+    for thread in output {
+        let _ = thread.join();
+    }
+    volumes::unmount_all(&options.mounts);
+    lease.release();
+    snapshot::unmount(&container_dir);
+    state::record(&record, |s| s.exit(exit_code));
+    info!("Container {} stopped", record.display_name());
+    drop(running);
+    Ok(())
+}
+
+// Run `criu restore` detached, returning the restored PID 1
+fn restore(container_dir: &Path, dir: &Path, checkpoint: &Checkpoint, mounts: &[BindMount], stdio: &ContainerStdio) -> Result<i32, ForgeError> {
+    let _ = fs::remove_file(dir.join(PID_FILE));
+    let mut criu = criu("restore", dir);
+    criu.arg("--root").arg(snapshot::rootfs(container_dir))
+        .args(["--restore-detached", "--pidfile", PID_FILE])
+        .arg("--external").arg(format!("veth[veth-c-{}]:veth-{}", checkpoint.pid, checkpoint.pid));
+    for mount in mounts {
+        criu.arg("--external").arg(format!("mnt[{}]:{}", mount.target, mount.source.display()));
+    }
+
+    // The new pipes stand in for the ones the dump found on 0, 1 and 2
+    let ContainerStdio::Pipes { stdin, stdout, stderr } = stdio else {
+        return Err("Containers with a terminal can't be restored".into());
+    };
+    let fds: Vec<RawFd> = [stdin, stdout, stderr].iter().map(|fd| fd.as_raw_fd()).collect();
+    for (fd, descriptor) in fds.iter().zip(&checkpoint.descriptors) {
+        if descriptor.starts_with("pipe:") {
+            criu.arg("--inherit-fd").arg(format!("fd[{}]:{}", fd, descriptor));
+        }
+    }
+    unsafe {
+        criu.pre_exec(move || {
+            for fd in &fds {
+                fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+            }
+            Ok(())
+        });
+    }
+
+    tools::run(&mut criu, &format!("criu restore failed, see {:?}", dir.join("restore.log")))?;
+    let pid = fs::read_to_string(dir.join(PID_FILE))?.trim().parse()
+        .map_err(|_| format!("criu left an invalid PID in {:?}", dir.join(PID_FILE)))?;
+    debug!("criu restored PID 1 as {}", pid);
+    Ok(pid)
+}
+
+fn criu(action: &str, dir: &Path) -> Command {
+    let mut criu = Command::new("criu");
+    criu.arg(action).arg("--images-dir").arg(dir)
+        .args(["--log-file", &format!("{}.log", action)])
+        .args(["--manage-cgroups", "--tcp-established", "--file-locks", "--ext-unix-sk"]);
+    criu
+}
@@ -0,0 +1,166 @@
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::error::ForgeError;
+
+// Everything the runtime writes lives under one root directory:
+//   images/      the image store
+//   containers/  one directory per container: rootfs/, upper/, container.json, generated /etc files
+//   volumes/     named volumes, kept until removed
+//   tmp/         build directories and scratch space
+// Keeping them together means a store on a dedicated disk can hardlink and rename
+// between them, and separate roots give fully isolated installations.
+
+const ROOT_ENV: &str = "FORGE_HOME";
+const ADDITIONAL_STORES_ENV: &str = "FORGE_ADDITIONAL_STORES";
+const SNAPSHOT_FORMAT_ENV: &str = "FORGE_SNAPSHOT_FORMAT";
+const SUBNET_ENV: &str = "FORGE_SUBNET";
+const DEFAULT_SUBNET: &str = "10.0.0.0/16";
+// System-wide store shared by every user on the host, e.g. provisioned on CI machines
+const SYSTEM_STORE: &str = "/var/lib/forge/images";
+const DIR_NAME: &str = ".container-runtime";
+const XDG_DIR_NAME: &str = "container-runtime";
+
+const GLOBAL_FLAGS: &[&str] = &["--root", "--registry-mirror", "--insecure-registry", "--registry-ca", "--cgroup-manager"];
+
+/// Options accepted before or after any command
+#[derive(Default)]
+pub struct GlobalFlags {
+    pub root: Option<PathBuf>,
+    pub registry_mirrors: Vec<String>,
+    pub insecure_registries: Vec<String>,
+    pub registry_ca: Option<PathBuf>,
+    pub cgroup_manager: Option<String>,     // "systemd" or "cgroupfs"; systemd where it booted the host unless given
+}
+
+static FLAGS: OnceLock<GlobalFlags> = OnceLock::new();
+
+/// Remove the global flags (`--root DIR`, `--registry-mirror HOST`, ... or their `--flag=value`
+/// forms) from the arguments before commands parse them
+pub fn take_global_flags(args: &mut Vec<String>) -> Result<(), ForgeError> {
+    let mut flags = GlobalFlags::default();
+    let mut i = 1;
+    while i < args.len() {
+        let (name, inline) = match args[i].split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (args[i].clone(), None),
+        };
+        if !GLOBAL_FLAGS.contains(&name.as_str()) {
+            i += 1;
+            continue;
+        }
+        let value = match inline {
+            Some(value) => value,
+            None if i + 1 < args.len() => args.remove(i + 1),
+            None => return Err(format!("{} requires a value", name).into()),
+        };
+        args.remove(i);
+
+        match name.as_str() {
+            "--root" => {
+                if flags.root.is_some() {
+                    return Err("--root given twice".into());
+                }
+                // Containers chdir into their rootfs, so a relative root must be pinned now
+                flags.root = Some(std::path::absolute(&value)?);
+            }
+            "--registry-mirror" => flags.registry_mirrors.push(value),
+            "--insecure-registry" => flags.insecure_registries.push(value),
+            "--cgroup-manager" => {
+                if value != "systemd" && value != "cgroupfs" {
+                    return Err(format!("Invalid --cgroup-manager {:?}: use systemd or cgroupfs", value).into());
+                }
+                flags.cgroup_manager = Some(value);
+            }
+            _ => flags.registry_ca = Some(std::path::absolute(&value)?),
+        }
+    }
+
+    FLAGS.set(flags).map_err(|_| "Global flags parsed twice")?;
+    Ok(())
+}
+
+pub fn flags() -> &'static GlobalFlags {
+    FLAGS.get_or_init(GlobalFlags::default)
+}
+
+/// The state root: --root, then $FORGE_HOME, then ~/.container-runtime, or
+/// $XDG_DATA_HOME/container-runtime for installs that don't have the former yet
+pub fn root() -> Result<PathBuf, ForgeError> {
+    if let Some(root) = &flags().root {
+        return Ok(root.clone());
+    }
+    if let Some(root) = std::env::var_os(ROOT_ENV).filter(|v| !v.is_empty()) {
+        return Ok(std::path::absolute(Path::new(&root))?);
+    }
+
+    let legacy = PathBuf::from(std::env::var("HOME").map_err(|_| "HOME is not set")?).join(DIR_NAME);
+    match std::env::var_os("XDG_DATA_HOME").filter(|v| !v.is_empty()) {
+        Some(data_home) if !legacy.exists() => Ok(PathBuf::from(data_home).join(XDG_DIR_NAME)),
+        _ => Ok(legacy),
+    }
+}
+
+/// Mirror, plain-HTTP and CA settings for registries
+pub fn registries_file() -> Result<PathBuf, ForgeError> {
+    Ok(root()?.join("registries.json"))
+}
+
+/// Lifecycle hooks run for every container
+pub fn hooks_file() -> Result<PathBuf, ForgeError> {
+    Ok(root()?.join("hooks.json"))
+}
+
+pub fn images_dir() -> Result<PathBuf, ForgeError> {
+    Ok(root()?.join("images"))
+}
+
+/// Read-only stores to search after the user's own: $FORGE_ADDITIONAL_STORES as a
+/// colon-separated list, otherwise the system store if there is one
+pub fn additional_stores() -> Vec<PathBuf> {
+    match std::env::var_os(ADDITIONAL_STORES_ENV) {
+        Some(paths) => std::env::split_paths(&paths).filter(|p| !p.as_os_str().is_empty()).collect(),
+        None => Some(PathBuf::from(SYSTEM_STORE)).filter(|p| p.is_dir()).into_iter().collect(),
+    }
+}
+
+/// Container state, readable only by the owner since it holds whole root filesystems
+/// How unpacked layers are kept: "dir" (the default), "squashfs" or "erofs", from $FORGE_SNAPSHOT_FORMAT
+pub fn snapshot_format() -> String {
+    std::env::var(SNAPSHOT_FORMAT_ENV).ok().filter(|v| !v.is_empty()).unwrap_or_else(|| "dir".to_string())
+}
+
+/// The subnet of the forge0 bridge containers get their addresses from, from $FORGE_SUBNET
+pub fn subnet() -> String {
+    std::env::var(SUBNET_ENV).ok().filter(|v| !v.is_empty()).unwrap_or_else(|| DEFAULT_SUBNET.to_string())
+}
+
+pub fn containers_dir() -> Result<PathBuf, ForgeError> {
+    let dir = root()?.join("containers");
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+pub fn volumes_dir() -> Result<PathBuf, ForgeError> {
+    Ok(root()?.join("volumes"))
+}
+
+/// Downloaded base image tarballs, shared by every root since each file is checked against its
+/// published checksum before use: $XDG_CACHE_HOME/container-runtime/downloads or ~/.cache/...
+pub fn download_cache_dir() -> Result<PathBuf, ForgeError> {
+    let cache_home = match std::env::var_os("XDG_CACHE_HOME").filter(|v| !v.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var("HOME").map_err(|_| "HOME is not set")?).join(".cache"),
+    };
+    let dir = cache_home.join(XDG_DIR_NAME).join("downloads");
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Scratch space on the same filesystem as the store; callers create what they need inside it
+pub fn tmp_dir() -> Result<PathBuf, ForgeError> {
+    let dir = root()?.join("tmp");
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
@@ -1,136 +1,436 @@
-use nix::unistd::{execvp, fork, ForkResult};
-use nix::sys::wait::waitpid;
+use nix::unistd::{execvp, Pid};
+use nix::sys::signal::{self, SigHandler, Signal};
+use nix::errno::Errno;
+use nix::sched::CloneFlags;
+use nix::sys::wait::{waitpid, WaitStatus};
 use std::ffi::CString;
+use std::io::IsTerminal;
 use std::process;
 use log::{debug, info, warn, error};
 
-use crate::namespace;
-use crate::filesystem::setup_root_filesystem;
-use crate::cgroups;
+use crate::init;
+use crate::ipam::Subnet;
+use crate::netconfig::NetworkConfig;
+use crate::lifecycle;
+use crate::namespace::{self, ClockOffsets, NamespaceMode};
+use crate::spawn::{self, Runtime, Spawn};
+use crate::stdio::ContainerStdio;
+use crate::filesystem::{setup_root_filesystem, RootfsOptions};
+use crate::cgroups::{self, CgroupConfig};
+use crate::devices::{self, DeviceRule};
+use crate::error::ForgeError;
 use crate::network;
+use crate::rootless;
 use crate::image::ImageConfig;
+use crate::user::{self, User};
+use crate::userns::UserNamespace;
+use crate::capabilities::{self, Capabilities};
+use crate::seccomp::{self, Seccomp};
+use crate::apparmor;
+use crate::landlock;
+use crate::selinux;
+use crate::rlimits::{self, Ulimit};
+use crate::rwlayer::ContainerRecord;
 
-const CONTAINER_ROOT: &str = "/tmp/container-root";
-const CONTAINER_NAME: &str = "my_container";  
+// The shell container's PID 1, up to the exec; returns only if that failed
+fn become_shell(container_root: &str, container_name: &str, confinement: &Confinement, runtime: &mut Runtime) -> ForgeError {
+    // shell- and the start of its directory's UUID, enough to tell shells apart in a prompt
+    let hostname = container_name.get(..14).unwrap_or(container_name);
+    let setup = (if rootless::active() { network::bring_up_loopback() } else { Ok(()) })
+        .and_then(|_| namespace::set_hostname(hostname))
+        .and_then(|_| namespace::set_domainname(""))
+        .and_then(|_| namespace::join_session_keyring(container_name))
+        .and_then(|_| {
+            // The runtime has moved this process into its cgroup by then
+            runtime.wait_for_go();
+            namespace::create_cgroup_namespace()
+        })
+        .and_then(|_| {
+            // An empty directory has nothing to run without the host's shell and tools
+            setup_root_filesystem(container_root, &RootfsOptions { host_utils: true, ..RootfsOptions::default() })
+        })
+        .and_then(|_| {
+            set_environment(hostname, std::io::stdin().is_terminal(), &[]);
+            drop_privileges(None, None, confinement)
+        });
+    match setup {
+        Ok(()) => start_shell(),
+        Err(e) => e,
+    }
+}
 
-pub fn run_container() -> ! {
+/// Run a shell in a throwaway container whose root is built at `container_root`, in cgroup
+/// `container_name`, and return the code the shell exited with
+pub fn run_container(container_root: &str, container_name: &str, subnet: Subnet) -> Result<i32, ForgeError> {
     debug!("Setting up container (PID: {})...", process::id());
 
-    cgroups::setup_cgroups(CONTAINER_NAME);
-    let _ = std::fs::write("/proc/sys/net/ipv4/ip_forward", "1");
-    let default_iface = network::get_default_interface_public();
+    let devices = devices::allowed(&RootfsOptions::default())?;
+    // Rootless containers get loopback alone: veths and NAT need the host's root
+    let default_iface = if rootless::active() {
+        None
+    } else {
+        let _ = std::fs::write("/proc/sys/net/ipv4/ip_forward", "1");
+        Some(network::get_default_interface_public()?)
+    };
+
+    let confinement = Confinement::shell()?;
+    debug!("Cloning PID 1...");
+
+    match spawn::clone(namespace::clone_flags(&NamespaceMode::Private, &NamespaceMode::Private, &NamespaceMode::Private)) {
+        Ok(Spawn::Parent(child)) => {
+            let pid = child.pid;
+            debug!("Spawned PID 1 process: {}", pid);
 
-    namespace::create_namespaces_without_network();
-    let _netns_pid = process::id();
+            lifecycle::catch_signals();
+            if let Err(e) = cgroups::setup_cgroups(container_name, &CgroupConfig::default(), devices.as_deref(), pid) {
+                warn!("{}, the shell runs without a cgroup", e);
+            }
+            let started = default_iface.map_or(Ok(()), |iface| network::setup_veth_pair_with_iface(pid.as_raw() as u32, &iface, subnet))
+                .and_then(|_| child.go());
+            let failed = started.err().map(|e| {
+                error!("{}", e);
+                let _ = signal::kill(pid, Signal::SIGKILL);
+                crate::exit_code(&e)
+            });
 
-    debug!("Forking to become PID 1...");
+            // The caller removes the directory once the shell has exited
+            let status = loop {
+                match waitpid(pid, None) {
+                    Err(Errno::EINTR) => match lifecycle::take_pending() {
+                        Some(Signal::SIGTERM | Signal::SIGHUP) => lifecycle::stop_gracefully(pid, Signal::SIGTERM, lifecycle::DEFAULT_STOP_TIMEOUT),
+                        Some(signal) => {
+                            let _ = signal::kill(pid, signal);
+                        }
+                        None => {}
+                    },
+                    status => break status,
+                }
+            };
+            lifecycle::stop_finished();
+            Ok(match (failed, status) {
+                (Some(code), _) => code,
+                (None, Ok(WaitStatus::Exited(_, code))) => code,
+                (None, Ok(WaitStatus::Signaled(_, signal, _))) => 128 + signal as i32,
+                _ => 1,
+            })
+        }
+        Ok(Spawn::Child(mut runtime)) => {
+            let e = become_shell(container_root, container_name, &confinement, &mut runtime);
+            runtime.fail(&e);
+        }
+        Err(e) => Err(e),
+    }
+}
 
-    match unsafe { fork() } {
-        Ok(ForkResult::Parent {child}) => {
-            debug!("Spawned PID 1 process: {}", child);
+/// How the container's PID 1 is started and reached
+pub struct ProcessOptions {
+    pub init: bool,             // PID 1 is the built-in init and the entrypoint its child
+    pub tty: bool,              // TERM is set for the terminal PID 1 gets
+    pub stdio: ContainerStdio,
+    pub subnet: Subnet,         // leased for this container alone
+    pub userns: Option<UserNamespace>,  // entered last, once the root is set up
+    pub ipc: NamespaceMode,
+    pub net: NamespaceMode,
+    pub pid: NamespaceMode,
+    pub clock_offsets: ClockOffsets,
+    pub confinement: Confinement,
+    pub resources: CgroupConfig,
+}
 
-            network::setup_veth_pair_with_iface(child.as_raw() as u32, &default_iface);
+/// What confines a container's processes, PID 1 and every exec alike
+#[derive(Clone)]
+pub struct Confinement {
+    pub capabilities: u64,      // from `Capabilities::resolve`
+    pub seccomp: Seccomp,
+    pub apparmor: Option<String>,   // the profile to exec into, None for unconfined
+    pub selinux: Option<String>,    // the process label to exec into
+    pub landlock: bool,
+    pub no_new_privileges: bool,
+    pub ulimits: Vec<Ulimit>,
+}
 
-            let _ = waitpid(child, None);
-            let _ = std::fs::remove_dir_all(CONTAINER_ROOT);
+impl Confinement {
+    /// The container's, loading the default AppArmor profile if it needs that
+    pub fn of(record: &ContainerRecord) -> Result<Self, ForgeError> {
+        Ok(Confinement {
+            capabilities: record.capabilities.resolve(),
+            seccomp: record.seccomp.clone(),
+            apparmor: apparmor::profile(record.apparmor.as_deref())?,
+            selinux: record.selinux.as_ref().map(|label| label.process.clone()),
+            landlock: record.landlock,
+            no_new_privileges: !record.new_privileges,
+            ulimits: record.ulimits.clone(),
+        })
+    }
 
-            process::exit(0);
+    // The shell container gets the defaults
+    fn shell() -> Result<Self, ForgeError> {
+        Ok(Confinement {
+            capabilities: Capabilities::default().resolve(),
+            seccomp: Seccomp::Default,
+            apparmor: apparmor::profile(None)?,
+            selinux: None,
+            landlock: false,
+            no_new_privileges: true,
+            ulimits: Vec::new(),
+        })
+    }
+
+    /// Before the user namespace, which has no say over the host's hard limits
+    pub fn set_ulimits(&self) -> Result<(), ForgeError> {
+        rlimits::apply(&self.ulimits)
+    }
+
+    /// Ask for the AppArmor profile and SELinux label the next exec switches to
+    pub fn attach_labels(&self) -> Result<(), ForgeError> {
+        if let Some(profile) = &self.apparmor {
+            apparmor::apply(profile)?;
+        }
+        if let Some(label) = &self.selinux {
+            selinux::apply(label)?;
         }
-        Ok(ForkResult::Child) => {
-            namespace::create_network_namespace();
-            cgroups::add_process_to_cgroup(CONTAINER_NAME);
-            setup_root_filesystem(CONTAINER_ROOT);
+        Ok(())
+    }
 
-            start_shell();
+    /// While still root: limit the bounding set and install the seccomp filter, which both take
+    /// capabilities no other user keeps. The filter must allow what follows it.
+    pub fn limit(&self) -> Result<(), ForgeError> {
+        if self.landlock {
+            landlock::restrict_to_root()?;
         }
-        Err(e) => {
-            error!("Fork failed: {}", e);
-            process::exit(1);
+        capabilities::limit_bounding_set(self.capabilities)?;
+        seccomp::install(&self.seccomp, self.capabilities)
+    }
+
+    /// As the container's user: down to its own capabilities, and no regaining others
+    pub fn finish(&self) -> Result<(), ForgeError> {
+        capabilities::restrict(self.capabilities)?;
+        if self.no_new_privileges {
+            capabilities::forbid_new_privileges()?;
         }
+        Ok(())
     }
 }
 
-pub fn run_container_from_image(rootfs_path: &str, config: &ImageConfig, container_name: &str) -> ! {
-    debug!("Setting up container from image (PID: {})...", process::id());
+// Last before the entrypoint: into the user namespace, where root has a full bounding set again,
+// then down to the container's confinement and user
+fn drop_privileges(userns: Option<&UserNamespace>, user: Option<&User>, confinement: &Confinement) -> Result<(), ForgeError> {
+    confinement.set_ulimits()?;
+    confinement.attach_labels()?;
+    if let Some(userns) = userns {
+        userns.enter()?;
+    }
+    confinement.limit()?;
+    // Until the ids change a process in a user namespace is whatever root outside maps to, often
+    // nobody, and an exec would leave it without capabilities
+    if let Some(user) = user {
+        user.switch().map_err(|e| ForgeError::capability(format!("become uid {} and gid {}", user.uid, user.gid), e))?;
+    }
+    confinement.finish()
+}
+
+// Where a container without ENV PATH finds its programs, as in other runtimes
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Replace this process's whole environment with a container process's, for the exec and init's
+/// child to inherit: PATH, TERM with a terminal and HOSTNAME, then `env` ("KEY=VALUE", the
+/// image's ENV and `-e`), which may override them. Nothing of the runtime's own is passed on,
+/// HOME, SSH_AUTH_SOCK and secrets included.
+pub fn set_environment(hostname: &str, tty: bool, env: &[String]) {
+    for (key, _) in std::env::vars_os() {
+        std::env::remove_var(key);
+    }
+    std::env::set_var("PATH", DEFAULT_PATH);
+    if tty {
+        std::env::set_var("TERM", "xterm");
+    }
+    std::env::set_var("HOSTNAME", hostname);
+    for (key, value) in env.iter().filter_map(|variable| variable.split_once('=')) {
+        if !key.is_empty() {
+            std::env::set_var(key, value);
+        }
+    }
+}
+
+/// What the runtime tells its caller as the container comes up, with PID 1's host PID
+pub trait Startup {
+    /// PID 1 has its namespaces and network and waits to go on; an error kills it
+    fn created(&mut self, pid: i32) -> Result<(), ForgeError>;
+    /// PID 1 has set up its root and exec'd the entrypoint, or init that runs it
+    fn started(&mut self, pid: i32);
+    /// PID 1 has exited, and its cgroup is still there to read
+    fn exited(&mut self);
+}
 
-    cgroups::setup_cgroups(container_name);
-    let _ = std::fs::write("/proc/sys/net/ipv4/ip_forward", "1");
-    let default_iface = network::get_default_interface_public();
+/// Start the container and return PID 1's exit code once it has exited
+pub fn run_container_from_image(rootfs_path: &str, config: &ImageConfig, container_name: &str, network: &NetworkConfig, options: &RootfsOptions, start: ProcessOptions, mut startup: impl Startup) -> Result<i32, ForgeError> {
+    debug!("Setting up container from image (PID: {})...", process::id());
 
-    namespace::create_namespaces_without_network();
+    let devices = devices::allowed(options)?;
+    // Rootless containers get loopback alone: veths and NAT need the host's root. A shared
+    // network namespace is set up already.
+    let default_iface = if rootless::active() || !start.net.is_default() {
+        None
+    } else {
+        let _ = std::fs::write("/proc/sys/net/ipv4/ip_forward", "1");
+        Some(network::get_default_interface_public()?)
+    };
 
-    debug!("Forking to become PID 1...");
+    namespace::join_namespace(&start.ipc, "ipc", CloneFlags::CLONE_NEWIPC)?;
+    namespace::join_namespace(&start.pid, "pid", CloneFlags::CLONE_NEWPID)?;
+    namespace::create_time_namespace(&start.clock_offsets)?;
 
-    match unsafe { fork() } {
-        Ok(ForkResult::Parent {child}) => {
-            debug!("Spawned PID 1 process: {}", child);
+    debug!("Cloning PID 1...");
 
-            network::setup_veth_pair_with_iface(child.as_raw() as u32, &default_iface);
+    match spawn::clone(namespace::clone_flags(&start.ipc, &start.net, &start.pid)) {
+        Ok(Spawn::Parent(child)) => {
+            let pid = child.pid;
+            debug!("Spawned PID 1 process: {}", pid);
+            if matches!(start.pid, NamespaceMode::Container(_)) {
+                if let Err(e) = namespace::leave_pid_namespace() {
+                    let _ = signal::kill(pid, Signal::SIGKILL);
+                    return Err(e);
+                }
+            }
+            drop(start.stdio);
+            // The supervisor above relays these to PID 1; dying from them would skip the cleanup
+            for &signal in lifecycle::RELAYED {
+                let _ = unsafe { signal::signal(signal, SigHandler::SigIgn) };
+            }
 
-            let _ = waitpid(child, None);
+            let started = setup_cgroup(container_name, &start.resources, devices.as_deref(), pid)
+                .and_then(|_| default_iface.map_or(Ok(()), |iface| network::setup_veth_pair_with_iface(pid.as_raw() as u32, &iface, start.subnet)))
+                .and_then(|_| startup.created(pid.as_raw()))
+                .and_then(|_| child.go());
+            // PID 1 never got going, so the runtime's own failure, or the one PID 1 reported, is
+            // what the container exits with
+            let failed = match started {
+                Ok(()) => {
+                    startup.started(pid.as_raw());
+                    None
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    let _ = signal::kill(pid, Signal::SIGKILL);
+                    Some(crate::exit_code(&e))
+                }
+            };
 
-            cgroups::cleanup_cgroup(container_name);
-            let _ = std::fs::remove_dir_all(rootfs_path);
-            info!("Container exited");
+            let code = match (failed, waitpid(pid, None)) {
+                (Some(code), _) => code,
+                (None, Ok(WaitStatus::Exited(_, code))) => code,
+                (None, Ok(WaitStatus::Signaled(_, signal, _))) => 128 + signal as i32,
+                _ => 1,
+            };
 
-            process::exit(0);
+            startup.exited();
+            // The runtime that started us unmounts or removes the rootfs
+            if let Err(e) = cgroups::cleanup_cgroup(container_name) {
+                warn!("{}", e);
+            }
+            info!("Container exited with {}", code);
+            Ok(code)
         }
-        Ok(ForkResult::Child) => {
-            namespace::create_network_namespace();
-            cgroups::add_process_to_cgroup(container_name);
-            setup_root_filesystem(rootfs_path);
-
-            for env_var in &config.env {
-                if let Some(pos) = env_var.find('=') {
-                    let key = &env_var[..pos];
-                    let value = &env_var[pos + 1..];
-                    std::env::set_var(key, value);
-                }
+        Ok(Spawn::Child(mut runtime)) => {
+            if let Err(e) = start.stdio.attach() {
+                runtime.fail(&e);
+            }
+            // Set up by whoever created it, or the host's
+            if let Err(e) = namespace::join_namespace(&start.net, "net", CloneFlags::CLONE_NEWNET) {
+                runtime.fail(&e);
             }
+            let setup = (if rootless::active() && start.net.is_default() { network::bring_up_loopback() } else { Ok(()) })
+                .and_then(|_| namespace::join_session_keyring(container_name))
+                .and_then(|_| {
+                    namespace::set_hostname(&network.hostname)
+                        .and_then(|_| namespace::set_domainname(&network.domainname))
+                })
+                .and_then(|_| {
+                    // The runtime has moved this process into its cgroup by then
+                    runtime.wait_for_go();
+                    namespace::create_cgroup_namespace()
+                })
+                .and_then(|_| setup_root_filesystem(rootfs_path, options));
+            if let Err(e) = setup {
+                runtime.fail(&e);
+            }
+
+            set_environment(&network.hostname, start.tty, &config.env);
 
             if let Err(e) = std::env::set_current_dir(&config.working_dir) {
                 warn!("Failed to change directory to {}: {}", config.working_dir, e);
             }
 
-            if !config.entrypoint.is_empty() {
-                start_entrypoint(&config.entrypoint);
+            // Looked up in the container's own /etc/passwd and /etc/group
+            let user = match user::default_user(&config.user, start.userns.as_ref()) {
+                Ok(user) => user,
+                Err(e) => runtime.fail(&e),
+            };
+            drop(start.stdio);
+            if let Err(e) = drop_privileges(start.userns.as_ref(), Some(&user), &start.confinement) {
+                runtime.fail(&e);
+            }
+            // With --init it is init that has started, and its child's exec is its own to fail
+            let runtime = if start.init {
+                drop(runtime);
+                init::run();
+                None
+            } else {
+                Some(runtime)
+            };
+            let e = if !config.entrypoint.is_empty() {
+                start_entrypoint(&config.entrypoint)
             } else {
-                start_shell();
+                start_shell()
+            };
+            match runtime {
+                Some(runtime) => runtime.fail(&e),
+                None => fail(&e),
             }
         }
-        Err(e) => {
-            error!("Fork failed: {}", e);
-            process::exit(1);
+        Err(e) => Err(e),
+    }
+}
+
+// Limits that were asked for have to hold, and the host's devices have to stay out of reach. A
+// privileged container without limits can go without a cgroup.
+fn setup_cgroup(name: &str, resources: &CgroupConfig, devices: Option<&[DeviceRule]>, pid: Pid) -> Result<(), ForgeError> {
+    match cgroups::setup_cgroups(name, resources, devices, pid) {
+        Err(e) if resources.is_empty() && devices.is_none() => {
+            warn!("{}, the container runs without a cgroup", e);
+            Ok(())
         }
+        result => result,
     }
 }
 
-fn start_entrypoint(entrypoint: &[String]) -> ! {
+// Both return only if the exec failed
+fn start_entrypoint(entrypoint: &[String]) -> ForgeError {
     debug!("Starting entrypoint: {:?}", entrypoint);
-
-    let program = CString::new(entrypoint[0].as_str()).unwrap();
-    let args: Vec<CString> = entrypoint.iter()
-        .map(|s| CString::new(s.as_str()).unwrap())
-        .collect();
-
-    match execvp(&program, &args) {
-        Ok(_) => unreachable!(),
-        Err(e) => panic!("Failed to exec entrypoint: {}", e),
-    }
+    exec(entrypoint)
 }
 
-fn start_shell() -> ! {
+fn start_shell() -> ForgeError {
     debug!("Starting shell...");
     let shell = if std::path::Path::new("/bin/bash").exists() {
         "/bin/bash"
     } else {
         "/bin/sh"
     };
-    let shell = CString::new(shell).unwrap();
-    let args = vec![shell.clone()];
+    exec(&[shell.to_string()])
+}
 
-    match execvp(&shell, &args) {
-        Ok(_) => unreachable!(),
-        Err(e) => panic!("Failed to exec shell: {}", e),
-    }
+fn exec(argv: &[String]) -> ForgeError {
+    // A NUL can't be passed on, and the kernel would refuse the whole command line for it
+    let cause = match argv.iter().map(|arg| CString::new(arg.as_str())).collect::<Result<Vec<_>, _>>() {
+        Ok(args) => execvp(&args[0], &args).unwrap_err(),
+        Err(_) => Errno::EINVAL,
+    };
+    ForgeError::Exec { program: argv[0].clone(), cause }
+}
+
+// A forked container process can't return its error to main, so it exits with main's code for it
+fn fail(error: &ForgeError) -> ! {
+    error!("{}", error);
+    process::exit(crate::exit_code(error))
 }
\ No newline at end of file
@@ -0,0 +1,196 @@
+use nix::errno::Errno;
+use nix::libc;
+use std::fmt;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use crate::error::ForgeError;
+use crate::filesystem::{self, RootfsOptions};
+
+// The device controller keeps a container's processes to the nodes its /dev was given, however
+// they come by others: a mknod with CAP_MKNOD, a node in a volume or an image, or devtmpfs
+// mounted by a privileged container. v1 takes the rules in devices.deny and devices.allow; v2
+// has no files for them and runs an eBPF program on every open and mknod instead.
+
+/// One kind of access to one device or a range of them, as devices.allow takes it
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceRule {
+    pub kind: char,             // 'c', 'b', or 'a' for every device
+    pub major: Option<u64>,     // None for any
+    pub minor: Option<u64>,
+    pub access: String,         // "rwm": read, write, mknod
+}
+
+impl DeviceRule {
+    fn char(major: u64, minor: Option<u64>, access: &str) -> Self {
+        DeviceRule { kind: 'c', major: Some(major), minor, access: access.to_string() }
+    }
+}
+
+impl fmt::Display for DeviceRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.kind == 'a' {
+            return write!(f, "a");
+        }
+        let number = |n: Option<u64>| n.map_or("*".to_string(), |n| n.to_string());
+        write!(f, "{} {}:{} {}", self.kind, number(self.major), number(self.minor), self.access)
+    }
+}
+
+/// What the container may use: the nodes every /dev gets, its terminal and `--device` grants.
+/// Making nodes is allowed for any device, as opening them is what counts. None with
+/// --privileged, which may use every device.
+pub fn allowed(options: &RootfsOptions) -> Result<Option<Vec<DeviceRule>>, ForgeError> {
+    if options.all_devices {
+        return Ok(None);
+    }
+    let mut rules = vec![
+        DeviceRule { kind: 'c', major: None, minor: None, access: "m".to_string() },
+        DeviceRule { kind: 'b', major: None, minor: None, access: "m".to_string() },
+    ];
+    rules.extend(filesystem::DEVICES.iter().map(|(_, major, minor)| DeviceRule::char(*major, Some(*minor), "rwm")));
+    // The console, /dev/ptmx and the pseudo-terminals behind it
+    rules.push(DeviceRule::char(5, Some(1), "rwm"));
+    rules.push(DeviceRule::char(5, Some(2), "rwm"));
+    rules.push(DeviceRule::char(136, None, "rwm"));
+    for device in &options.devices {
+        rules.push(device.rule().map_err(|e| ForgeError::cgroup("allow the container's devices", e))?);
+    }
+    Ok(Some(rules))
+}
+
+// struct bpf_insn: the destination register in the low four bits, the source in the high
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Insn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+const fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+    Insn { code, regs: dst | src << 4, off, imm }
+}
+
+const LDX_W: u8 = 0x61;         // dst = *(u32 *)(src + off)
+const AND32_K: u8 = 0x54;
+const RSH32_K: u8 = 0x74;
+const MOV32_X: u8 = 0xbc;
+const MOV64_K: u8 = 0xb7;
+const JNE_K: u8 = 0x55;         // skip off instructions unless dst == imm
+const JNE_X: u8 = 0x5d;
+const EXIT: u8 = 0x95;
+
+// bpf_cgroup_dev_ctx's device types and access bits
+const DEVCG_DEV_BLOCK: i32 = 1;
+const DEVCG_DEV_CHAR: i32 = 2;
+const DEVCG_ACC_MKNOD: i32 = 1;
+const DEVCG_ACC_READ: i32 = 2;
+const DEVCG_ACC_WRITE: i32 = 4;
+
+// A program that returns 1, allowing the access, when a rule matches and 0 when none does.
+// r2 is the device type, r3 the access asked for, r4 and r5 the major and minor number.
+fn program(rules: &[DeviceRule]) -> Vec<Insn> {
+    let mut program = vec![
+        insn(LDX_W, 2, 1, 0, 0),
+        insn(AND32_K, 2, 0, 0, 0xffff),
+        insn(LDX_W, 3, 1, 0, 0),
+        insn(RSH32_K, 3, 0, 0, 16),
+        insn(LDX_W, 4, 1, 4, 0),
+        insn(LDX_W, 5, 1, 8, 0),
+    ];
+    for rule in rules {
+        // Each check jumps past the rest of the rule when it fails, so the offsets are set last
+        let mut block = Vec::new();
+        match rule.kind {
+            'c' => block.push(insn(JNE_K, 2, 0, 0, DEVCG_DEV_CHAR)),
+            'b' => block.push(insn(JNE_K, 2, 0, 0, DEVCG_DEV_BLOCK)),
+            _ => {}
+        }
+        let access = rule.access.chars().fold(0, |bits, c| bits | match c {
+            'r' => DEVCG_ACC_READ,
+            'w' => DEVCG_ACC_WRITE,
+            'm' => DEVCG_ACC_MKNOD,
+            _ => 0,
+        });
+        if rule.kind != 'a' && access != DEVCG_ACC_READ | DEVCG_ACC_WRITE | DEVCG_ACC_MKNOD {
+            // Everything asked for has to be among what the rule allows
+            block.push(insn(MOV32_X, 1, 3, 0, 0));
+            block.push(insn(AND32_K, 1, 0, 0, access));
+            block.push(insn(JNE_X, 1, 3, 0, 0));
+        }
+        if let Some(major) = rule.major {
+            block.push(insn(JNE_K, 4, 0, 0, major as i32));
+        }
+        if let Some(minor) = rule.minor {
+            block.push(insn(JNE_K, 5, 0, 0, minor as i32));
+        }
+        block.push(insn(MOV64_K, 0, 0, 0, 1));
+        block.push(insn(EXIT, 0, 0, 0, 0));
+        let len = block.len();
+        for (i, check) in block.iter_mut().enumerate().filter(|(_, c)| c.code == JNE_K || c.code == JNE_X) {
+            check.off = (len - i - 1) as i16;
+        }
+        program.extend(block);
+    }
+    program.push(insn(MOV64_K, 0, 0, 0, 0));
+    program.push(insn(EXIT, 0, 0, 0, 0));
+    program
+}
+
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_ATTACH: libc::c_long = 8;
+const BPF_PROG_TYPE_CGROUP_DEVICE: u32 = 15;
+const BPF_CGROUP_DEVICE: u32 = 6;
+// Alongside whatever else is attached, every program having to allow the access
+const BPF_F_ALLOW_MULTI: u32 = 2;
+
+// The start of union bpf_attr for BPF_PROG_LOAD; the kernel takes the rest as zero
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+}
+
+#[repr(C)]
+struct ProgAttachAttr {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+}
+
+/// cgroup v2: load a program that allows `rules` alone and attach it to the cgroup at `dir`.
+/// The cgroup holds on to it, and it goes with the cgroup.
+pub fn attach_program(dir: &str, rules: &[DeviceRule]) -> Result<(), ForgeError> {
+    let program = program(rules);
+    let license = b"GPL\0";
+    let attr = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_CGROUP_DEVICE,
+        insn_cnt: program.len() as u32,
+        insns: program.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 0,
+        log_size: 0,
+        log_buf: 0,
+    };
+    let fd = Errno::result(unsafe { libc::syscall(libc::SYS_bpf, BPF_PROG_LOAD, &attr as *const ProgLoadAttr, std::mem::size_of::<ProgLoadAttr>()) })
+        .map_err(|e| ForgeError::cgroup("load the device filter", e))?;
+    let program_fd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+    let cgroup = std::fs::File::open(dir).map_err(|e| ForgeError::cgroup(format!("open cgroup {}", dir), e))?;
+    let attr = ProgAttachAttr {
+        target_fd: cgroup.as_raw_fd() as u32,
+        attach_bpf_fd: program_fd.as_raw_fd() as u32,
+        attach_type: BPF_CGROUP_DEVICE,
+        attach_flags: BPF_F_ALLOW_MULTI,
+    };
+    Errno::result(unsafe { libc::syscall(libc::SYS_bpf, BPF_PROG_ATTACH, &attr as *const ProgAttachAttr, std::mem::size_of::<ProgAttachAttr>()) })
+        .map_err(|e| ForgeError::cgroup(format!("attach the device filter to {}", dir), e))?;
+    Ok(())
+}
@@ -0,0 +1,452 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use sha2::{Sha256, Digest};
+use log::{debug, info};
+
+use crate::archive;
+use crate::health::HealthConfig;
+use crate::signing;
+use crate::image::{ImageStore, ImageIndex, ImageManifest, ImageConfig, PlatformImage};
+use crate::layer::Compression;
+use crate::registry::{hash_reader, Descriptor, ImageReference, RegistryClient, RequestBody, DEFAULT_REGISTRY, MEDIA_TYPE_OCI_INDEX, MEDIA_TYPE_OCI_MANIFEST};
+use crate::tarball;
+use crate::error::ForgeError;
+
+pub const MEDIA_TYPE_OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+pub const ANNOTATION_CONTAINERD_NAME: &str = "io.containerd.image.name";
+
+// Blobs larger than this are uploaded in PATCH chunks instead of a single PUT
+const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OciManifest {
+    pub schema_version: u32,
+    pub media_type: String,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+}
+
+// index.json at the top of an OCI image layout
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OciIndex {
+    pub schema_version: u32,
+    #[serde(default)]
+    pub media_type: String,
+    pub manifests: Vec<Descriptor>,
+}
+
+impl OciIndex {
+    pub fn new() -> Self {
+        Self {
+            schema_version: 2,
+            media_type: MEDIA_TYPE_OCI_INDEX.to_string(),
+            manifests: Vec::new(),
+        }
+    }
+}
+
+// The subset of the OCI image config that maps onto our ImageConfig
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OciImageConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub architecture: String,
+    pub os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    #[serde(default)]
+    pub config: OciRuntimeConfig,
+    pub rootfs: OciRootfs,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<OciHistory>,
+}
+
+impl OciImageConfig {
+    /// How to run the image, in our form
+    pub fn image_config(&self) -> ImageConfig {
+        let config = &self.config;
+        // Our config has no separate CMD, so it is folded into the entrypoint
+        let mut entrypoint = config.entrypoint.clone();
+        entrypoint.extend(config.cmd.iter().cloned());
+
+        let mut exposed_ports: Vec<String> = config.exposed_ports.keys().cloned().collect();
+        exposed_ports.sort();
+
+        ImageConfig {
+            entrypoint,
+            env: config.env.clone(),
+            working_dir: if config.working_dir.is_empty() { "/".to_string() } else { config.working_dir.clone() },
+            user: config.user.clone(),
+            labels: config.labels.clone(),
+            exposed_ports,
+            stop_signal: config.stop_signal.clone(),
+            healthcheck: config.healthcheck.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct OciRuntimeConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entrypoint: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cmd: Vec<String>,
+    #[serde(default)]
+    pub working_dir: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub user: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+    // Keys are "port/proto"; the values are always empty objects
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub exposed_ports: HashMap<String, serde_json::Value>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub stop_signal: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub healthcheck: Option<HealthConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct OciHistory {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub created_by: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub empty_layer: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OciRootfs {
+    #[serde(rename = "type")]
+    pub fs_type: String,
+    pub diff_ids: Vec<String>,
+}
+
+// A local image translated to OCI form, ready to be written or uploaded
+pub struct OciImage {
+    pub manifest: OciManifest,
+    pub config_blob: Vec<u8>,
+    pub diff_ids: Vec<String>,
+}
+
+impl OciImage {
+    pub fn from_store(store: &ImageStore, manifest: &ImageManifest, config: &ImageConfig) -> Result<Self, ForgeError> {
+        let mut layers = Vec::new();
+        let mut diff_ids = Vec::new();
+
+        for digest in &manifest.layers {
+            let path = store.get_layer_path(digest)?;
+            let size = fs::metadata(&path)
+                .map_err(|e| format!("Layer {} missing from store: {}", digest, e))?
+                .len();
+
+            let compression = store.layer_compression(manifest, digest)?;
+            layers.push(Descriptor {
+                media_type: compression.media_type().to_string(),
+                digest: digest.clone(),
+                size,
+                annotations: HashMap::new(),
+                platform: None,
+            });
+            diff_ids.push(compute_diff_id(&path, compression)?);
+        }
+
+        let non_empty = |s: &str| Some(s.to_string()).filter(|s| !s.is_empty());
+        let oci_config = OciImageConfig {
+            created: non_empty(&manifest.created),
+            author: non_empty(&manifest.author),
+            architecture: manifest.architecture.clone(),
+            os: manifest.os.clone(),
+            variant: manifest.variant.clone(),
+            config: OciRuntimeConfig {
+                env: config.env.clone(),
+                entrypoint: config.entrypoint.clone(),
+                cmd: Vec::new(),
+                working_dir: config.working_dir.clone(),
+                user: config.user.clone(),
+                labels: config.labels.clone(),
+                exposed_ports: config.exposed_ports.iter()
+                    .map(|port| (port.clone(), serde_json::json!({})))
+                    .collect(),
+                stop_signal: config.stop_signal.clone(),
+                healthcheck: config.healthcheck.clone(),
+            },
+            rootfs: OciRootfs {
+                fs_type: "layers".to_string(),
+                diff_ids: diff_ids.clone(),
+            },
+            history: manifest.history.iter()
+                .map(|h| OciHistory {
+                    created: non_empty(&h.created),
+                    created_by: h.created_by.clone(),
+                    empty_layer: h.empty_layer,
+                })
+                .collect(),
+        };
+        let config_blob = serde_json::to_vec(&oci_config)?;
+
+        let manifest = OciManifest {
+            schema_version: 2,
+            media_type: MEDIA_TYPE_OCI_MANIFEST.to_string(),
+            config: Descriptor {
+                media_type: MEDIA_TYPE_OCI_CONFIG.to_string(),
+                digest: sha256_digest(&config_blob),
+                size: config_blob.len() as u64,
+                annotations: HashMap::new(),
+                platform: None,
+            },
+            layers,
+        };
+
+        Ok(Self { manifest, config_blob, diff_ids })
+    }
+
+    pub fn manifest_blob(&self) -> Result<Vec<u8>, ForgeError> {
+        Ok(serde_json::to_vec(&self.manifest)?)
+    }
+}
+
+/// Upload a stored image to a registry, returning the pushed manifest digest.
+/// Tags holding several platforms are pushed as an OCI image index.
+pub fn push(store: &ImageStore, index: &ImageIndex, target: &str) -> Result<String, ForgeError> {
+    let reference = ImageReference::parse(target)?;
+    info!("Pushing to {}/{}:{}", reference.registry, reference.repository, reference.reference);
+
+    let tag = reference.reference.clone();
+    let mut client = RegistryClient::new(reference)?;
+
+    if let [entry] = index.manifests.as_slice() {
+        let (digest, _) = push_platform_image(store, &mut client, entry, &tag)?;
+        info!("  ✅ Pushed {}", digest);
+        return Ok(digest);
+    }
+
+    // Each platform's manifest is pushed by digest, then the index takes the tag
+    let mut oci_index = OciIndex::new();
+    for entry in &index.manifests {
+        let platform = entry.manifest.platform();
+        info!("  Platform {}", platform);
+        let (digest, size) = push_platform_image(store, &mut client, entry, "")?;
+        oci_index.manifests.push(Descriptor {
+            media_type: MEDIA_TYPE_OCI_MANIFEST.to_string(),
+            digest,
+            size,
+            annotations: HashMap::new(),
+            platform: Some(platform),
+        });
+    }
+
+    let index_blob = serde_json::to_vec(&oci_index)?;
+    put_manifest(&mut client, &tag, MEDIA_TYPE_OCI_INDEX, &index_blob)?;
+
+    let digest = sha256_digest(&index_blob);
+    info!("  ✅ Pushed index {}", digest);
+    Ok(digest)
+}
+
+// Upload one platform's blobs and manifest, tagging it unless `tag` is empty
+fn push_platform_image(store: &ImageStore, client: &mut RegistryClient, entry: &PlatformImage, tag: &str) -> Result<(String, u64), ForgeError> {
+    let image = OciImage::from_store(store, &entry.manifest, &entry.config)?;
+
+    for (i, layer) in image.manifest.layers.iter().enumerate() {
+        debug!("  [{}/{}] Layer {}", i + 1, image.manifest.layers.len(), layer.digest);
+        let path = store.get_layer_path(&layer.digest)?;
+        upload_blob(client, layer, RequestBody::File(&path))?;
+    }
+    upload_blob(client, &image.manifest.config, RequestBody::Bytes(&image.config_blob))?;
+
+    let manifest_blob = image.manifest_blob()?;
+    let digest = sha256_digest(&manifest_blob);
+    let reference = if tag.is_empty() { digest.as_str() } else { tag };
+    put_manifest(client, reference, MEDIA_TYPE_OCI_MANIFEST, &manifest_blob)?;
+
+    Ok((digest, manifest_blob.len() as u64))
+}
+
+fn put_manifest(client: &mut RegistryClient, reference: &str, media_type: &str, blob: &[u8]) -> Result<(), ForgeError> {
+    let path = format!("/v2/{}/manifests/{}", client.reference().repository, reference);
+    let response = client.request(
+        "PUT",
+        &path,
+        &[format!("Content-Type: {}", media_type)],
+        RequestBody::Bytes(blob),
+    )?;
+    if response.status != 201 && response.status != 200 {
+        return Err(format!("Manifest upload failed: HTTP {}", response.status).into());
+    }
+    Ok(())
+}
+
+/// Download an image into the store, returning the local name and tag it was stored under.
+/// By default only this host's platform is fetched; `all_platforms` keeps every linux image of a manifest list.
+/// With trusted `keys`, images that fail signature verification are not stored.
+pub fn pull(store: &ImageStore, source: &str, all_platforms: bool, keys: &[PathBuf]) -> Result<(String, String), ForgeError> {
+    let reference = ImageReference::parse(source)?;
+    // The client checks a manifest fetched by digest hashes to it. The image is stored under a tag
+    // made from the digest, and records it so `name@sha256:...` finds it again.
+    let registry_digest = if reference.is_digest() { reference.reference.clone() } else { String::new() };
+    info!("Pulling {}", reference);
+
+    let name = local_name(&reference);
+    let tag = reference.reference.replace(':', "-");
+    let mut client = RegistryClient::new(reference)?;
+
+    let manifests = if all_platforms {
+        client.fetch_all_manifests()?
+    } else {
+        vec![client.fetch_manifest()?]
+    };
+
+    for manifest in &manifests {
+        let mut layers = Vec::new();
+        for (i, descriptor) in manifest.layers.iter().enumerate() {
+            let path = store.get_layer_path(&descriptor.digest)?;
+            if path.exists() {
+                debug!("  [{}/{}] Layer {} already present", i + 1, manifest.layers.len(), descriptor.digest);
+            } else {
+                info!("  [{}/{}] Downloading {} ({} bytes)...",
+                    i + 1, manifest.layers.len(), short_digest(&descriptor.digest), descriptor.size);
+                client.fetch_blob(descriptor, &path)?;
+            }
+            layers.push((descriptor.digest.clone(), Compression::from_media_type(&descriptor.media_type)));
+        }
+
+        let oci_config: OciImageConfig = serde_json::from_slice(&client.fetch_blob_bytes(&manifest.config)?)?;
+
+        let (mut image_manifest, image_config) = archive::image_from_oci(&name, &tag, layers, &oci_config);
+        image_manifest.registry_digest = registry_digest.clone();
+        if !keys.is_empty() {
+            signing::verify(store, &image_manifest, &image_config, keys)?;
+        }
+
+        debug!("Storing {} image for {}:{}", oci_config.architecture, name, tag);
+        store.save_image(image_manifest, image_config)?;
+    }
+
+    info!("  ✅ Pulled {}:{} ({} platform{})", name, tag, manifests.len(), if manifests.len() == 1 { "" } else { "s" });
+    Ok((name, tag))
+}
+
+// "registry-1.docker.io/library/alpine" -> "alpine", other registries keep their host
+fn local_name(reference: &ImageReference) -> String {
+    if reference.registry == DEFAULT_REGISTRY {
+        reference.repository.trim_start_matches("library/").to_string()
+    } else {
+        format!("{}/{}", reference.registry, reference.repository)
+    }
+}
+
+fn upload_blob(client: &mut RegistryClient, descriptor: &Descriptor, body: RequestBody) -> Result<(), ForgeError> {
+    let repository = client.reference().repository.clone();
+
+    let exists = client.request("HEAD", &format!("/v2/{}/blobs/{}", repository, descriptor.digest), &[], RequestBody::Empty)?;
+    if exists.status == 200 {
+        info!("    {} already exists", short_digest(&descriptor.digest));
+        return Ok(());
+    }
+
+    let response = client.request("POST", &format!("/v2/{}/blobs/uploads/", repository), &[], RequestBody::Empty)?;
+    if response.status != 202 {
+        return Err(format!("Failed to start blob upload: HTTP {}", response.status).into());
+    }
+    let mut location = response.header("location")
+        .ok_or("Blob upload response has no Location header")?
+        .to_string();
+
+    info!("    Uploading {} ({} bytes)...", short_digest(&descriptor.digest), descriptor.size);
+
+    let body = match body {
+        RequestBody::File(path) if descriptor.size > CHUNK_SIZE => {
+            location = upload_chunks(client, path, location)?;
+            RequestBody::Empty
+        }
+        other => other,
+    };
+
+    let separator = if location.contains('?') { '&' } else { '?' };
+    let url = format!("{}{}digest={}", location, separator, descriptor.digest.replace(':', "%3A"));
+    let response = client.request(
+        "PUT",
+        &url,
+        &["Content-Type: application/octet-stream".to_string()],
+        body,
+    )?;
+    if response.status != 201 {
+        return Err(format!("Failed to complete upload of {}: HTTP {}", descriptor.digest, response.status).into());
+    }
+    Ok(())
+}
+
+// PATCH the file in CHUNK_SIZE pieces, returning the location to finalize the upload at
+fn upload_chunks(client: &mut RegistryClient, path: &Path, mut location: String) -> Result<String, ForgeError> {
+    let mut file = fs::File::open(path)?;
+    let mut buf = vec![0u8; CHUNK_SIZE as usize];
+    let mut offset: u64 = 0;
+
+    loop {
+        let n = read_full(&mut file, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+
+        let range = format!("Content-Range: {}-{}", offset, offset + n as u64 - 1);
+        let response = client.request(
+            "PATCH",
+            &location,
+            &[range, "Content-Type: application/octet-stream".to_string()],
+            RequestBody::Bytes(&buf[..n]),
+        )?;
+        if response.status != 202 {
+            return Err(format!("Chunk upload at offset {} failed: HTTP {}", offset, response.status).into());
+        }
+
+        location = response.header("location")
+            .ok_or("Chunk upload response has no Location header")?
+            .to_string();
+        offset += n as u64;
+        debug!("      uploaded {} bytes", offset);
+    }
+
+    Ok(location)
+}
+
+fn read_full(file: &mut fs::File, buf: &mut [u8]) -> Result<usize, ForgeError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = file.read(&mut buf[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+/// The diff_id is the digest of the uncompressed layer tarball
+pub fn compute_diff_id(layer_path: &Path, compression: Compression) -> Result<String, ForgeError> {
+    let blob = io::BufReader::new(fs::File::open(layer_path)?);
+    let mut hasher = Sha256::new();
+    hash_reader(&mut tarball::decoder(blob, compression)?, &mut hasher)
+        .map_err(|e| format!("Failed to decompress layer {:?}: {}", layer_path, e))?;
+    Ok(format!("sha256:{}", hex::encode(hasher.finalize())))
+}
+
+pub fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(data)))
+}
+
+fn short_digest(digest: &str) -> &str {
+    &digest[..digest.len().min(19)]
+}
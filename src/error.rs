@@ -0,0 +1,89 @@
+use nix::errno::Errno;
+
+// Failures of the runtime's own machinery each have a variant, and the plain messages and I/O
+// errors most commands fail with come along as Other, Io and the like. They travel up as
+// Results; main.rs alone decides what exit code each one becomes, including for the forked
+// container processes that never return to main.
+
+/// What went wrong setting up, running or building a container
+#[derive(Debug, thiserror::Error)]
+pub enum ForgeError {
+    #[error("Failed to {action}: {cause}")]
+    Mount { action: String, cause: String },        // "mount /proc"
+    #[error("Failed to {action}: {cause}")]
+    Namespace { action: String, cause: Errno },     // "create the network namespace"
+    #[error("Failed to {action}: {cause}")]
+    Cgroup { action: String, cause: String },       // "join cgroup img-<id>"
+    #[error("Failed to {action}: {cause}")]
+    Network { action: String, cause: String },      // "run ip link add ..."
+    #[error("Failed to {action}: {cause}")]
+    Capability { action: String, cause: Errno },    // "drop CAP_SYS_ADMIN from the bounding set"
+    #[error("Failed to {action}: {cause}")]
+    Limit { action: String, cause: Errno },         // "set the nofile limit to 65536:65536"
+    #[error("Failed to {action}: {cause}")]
+    Seccomp { action: String, cause: String },      // "install the seccomp filter"
+    #[error("Failed to {action}: {cause}")]
+    Label { action: String, cause: String },        // "switch to AppArmor profile forge-default"
+    #[error("{0}")]
+    Image(String),                                  // the store or a registry couldn't give or take an image
+    #[error("{0}")]
+    Build(String),
+    #[error("Failed to exec {program}: {cause}")]
+    Exec { program: String, cause: Errno },         // the container's entrypoint couldn't start
+    #[error("{message}")]
+    Child { code: i32, message: String },           // PID 1's own failure, as it reported it to the runtime
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Sys(#[from] Errno),
+    #[error("{0}")]
+    Other(String),                                  // a plain message, such as a usage error
+}
+
+impl ForgeError {
+    pub fn mount(action: impl Into<String>, cause: impl std::fmt::Display) -> Self {
+        ForgeError::Mount { action: action.into(), cause: cause.to_string() }
+    }
+
+    pub fn namespace(action: impl Into<String>, cause: Errno) -> Self {
+        ForgeError::Namespace { action: action.into(), cause }
+    }
+
+    pub fn cgroup(action: impl Into<String>, cause: impl std::fmt::Display) -> Self {
+        ForgeError::Cgroup { action: action.into(), cause: cause.to_string() }
+    }
+
+    pub fn network(action: impl Into<String>, cause: impl std::fmt::Display) -> Self {
+        ForgeError::Network { action: action.into(), cause: cause.to_string() }
+    }
+
+    pub fn seccomp(action: impl Into<String>, cause: impl std::fmt::Display) -> Self {
+        ForgeError::Seccomp { action: action.into(), cause: cause.to_string() }
+    }
+
+    pub fn label(action: impl Into<String>, cause: impl std::fmt::Display) -> Self {
+        ForgeError::Label { action: action.into(), cause: cause.to_string() }
+    }
+
+    pub fn capability(action: impl Into<String>, cause: Errno) -> Self {
+        ForgeError::Capability { action: action.into(), cause }
+    }
+
+    pub fn limit(action: impl Into<String>, cause: Errno) -> Self {
+        ForgeError::Limit { action: action.into(), cause }
+    }
+}
+
+impl From<String> for ForgeError {
+    fn from(message: String) -> Self {
+        ForgeError::Other(message)
+    }
+}
+
+impl From<&str> for ForgeError {
+    fn from(message: &str) -> Self {
+        ForgeError::Other(message.to_string())
+    }
+}
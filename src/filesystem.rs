@@ -1,14 +1,29 @@
+use nix::errno::Errno;
+use nix::libc;
 use nix::mount::{mount, umount2, MsFlags, MntFlags};
+use nix::sys::stat::{major, makedev, minor, mknod, Mode, SFlag};
+use nix::sys::statvfs::{statvfs, FsFlags};
 use nix::unistd::{chdir, pivot_root};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::ffi::{CString, OsString};
 use std::fs;
-use std::process;
-use log::{debug, warn, error};
+use std::io::{self, IsTerminal};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use log::{debug, warn};
 
 use crate::binaries::copy_bash_and_dependencies;
+use crate::cgroups;
+use crate::devices::DeviceRule;
+use crate::error::ForgeError;
+use crate::rootless;
 
-fn create_container_dirs(new_root: &str) {
+fn create_container_dirs(new_root: &str) -> Result<(), ForgeError> {
     debug!("Creating container directory structure...");
-    fs::create_dir_all(new_root).expect("Failed to create container root");
+    fs::create_dir_all(new_root).map_err(|e| ForgeError::mount(format!("create the container root {}", new_root), e))?;
 
     let dirs = vec![
         "bin", "sbin", "lib", "lib64",
@@ -26,141 +41,800 @@ fn create_container_dirs(new_root: &str) {
     }
 
     debug!("Container directories created");
+    Ok(())
 }
 
-fn pivot_to_new_root(new_root: &str) {
-    if let Err(e) = chdir(new_root) {
-        error!("Failed to chdir to new root: {}", e);
-        process::exit(1);
-    }
+/// Why the container couldn't move into its new root
+#[derive(Debug)]
+enum PivotError {
+    Chdir(&'static str, Errno),
+    OldRoot(std::io::Error),
+    PivotRoot(Errno),
+    Detach(Errno),
+}
 
-    let old_root_path = "./old_root";
-    if let Err(e) = fs::create_dir_all(old_root_path) {
-        warn!("Failed to create old_root: {}", e);
+impl std::fmt::Display for PivotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PivotError::Chdir(dir, e) => write!(f, "chdir to {} failed: {}", dir, e),
+            PivotError::OldRoot(e) => write!(f, "creating old_root failed: {}", e),
+            // EINVAL is what pivot_root gives for a shared mount or a root that isn't a mount point
+            PivotError::PivotRoot(Errno::EINVAL) => write!(
+                f, "pivot_root failed: {} (the new root must be a private mount point, and the host root can't be a ramfs)",
+                Errno::EINVAL
+            ),
+            PivotError::PivotRoot(e) => write!(f, "pivot_root failed: {}", e),
+            PivotError::Detach(e) => write!(f, "detaching the host's root failed: {}", e),
+        }
     }
+}
 
-    if let Err(e) = pivot_root(".", "./old_root") {
-        debug!("pivot_root failed: {}, trying chroot fallback...", e);
-        use_mount_instead_of_pivot(new_root);
-        return;
-    }
+impl std::error::Error for PivotError {}
 
-    if let Err(e) = chdir("/") {
-        error!("Failed to chdir to /: {}", e);
-        process::exit(1);
-    }
+// Stop mounts from propagating between the container and the host. Slave rather than private when
+// a bind asked to receive the host's mounts, since a private mount can't be anyone's slave.
+fn make_mounts_private(options: &RootfsOptions) -> Result<(), ForgeError> {
+    let receives = options.mounts.iter().any(|m| m.has("rshared") || m.has("shared") || m.has("rslave") || m.has("slave"));
+    let propagation = if receives { MsFlags::MS_SLAVE } else { MsFlags::MS_PRIVATE };
+    mount(None::<&str>, "/", None::<&str>, MsFlags::MS_REC | propagation, None::<&str>)
+        .map_err(|e| ForgeError::mount("make the container's mounts private", e))?;
+    debug!("Mount propagation to the host is off");
+    Ok(())
+}
 
-    if let Err(e) = umount2("/old_root", MntFlags::MNT_DETACH) {
-        debug!("Failed to unmount old root: {}", e);
-    }
+// There is no chroot fallback: from a chroot the host's filesystem is still reachable
+fn pivot_to_new_root(new_root: &str) -> Result<(), PivotError> {
+    chdir(new_root).map_err(|e| PivotError::Chdir("the new root", e))?;
+    fs::create_dir_all("./old_root").map_err(PivotError::OldRoot)?;
+    pivot_root(".", "./old_root").map_err(PivotError::PivotRoot)?;
+    chdir("/").map_err(|e| PivotError::Chdir("/", e))?;
+    umount2("/old_root", MntFlags::MNT_DETACH).map_err(PivotError::Detach)?;
 
     if let Err(e) = fs::remove_dir("/old_root") {
         debug!("Failed to remove /old_root: {}", e);
     }
-
     debug!("Pivoted to new root");
+    Ok(())
 }
 
-fn use_mount_instead_of_pivot(new_root: &str) {
-    debug!("Using chroot as fallback...");
+fn mount_proc(new_root: &str) -> Result<(), ForgeError> {
+    let target = format!("{}/proc", new_root);
+    match mount(Some("proc"), target.as_str(), Some("proc"), MsFlags::empty(), None::<&str>) {
+        // A rootless container sharing the host's PID namespace may not mount its procfs, but it
+        // may see the host's
+        Err(Errno::EPERM) if rootless::active() => bind_host(&target, "/proc"),
+        result => result.map_err(|e| ForgeError::mount("mount /proc", e)),
+    }
+}
 
-    use nix::unistd::chroot;
+fn bind_host(target: &str, source: &str) -> Result<(), ForgeError> {
+    mount(Some(source), target, None::<&str>, MsFlags::MS_BIND | MsFlags::MS_REC, None::<&str>)
+        .map_err(|e| ForgeError::mount(format!("bind the host's {}", source), e))
+}
 
-    if let Err(e) = chroot(new_root) {
-        error!("Failed to chroot: {}", e);
-        process::exit(1);
+fn mount_sys(new_root: &str, read_only: bool) -> Result<(), ForgeError> {
+    let flags = if read_only { MsFlags::MS_RDONLY } else { MsFlags::empty() };
+    let target = format!("{}/sys", new_root);
+    match mount(Some("sysfs"), target.as_str(), Some("sysfs"), MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC | flags, None::<&str>) {
+        // Likewise for a rootless container sharing the host's network namespace
+        Err(Errno::EPERM) if rootless::active() => return bind_host(&target, "/sys"),
+        result => result.map_err(|e| ForgeError::mount("mount /sys", e))?,
+    }
+    // Older kernels don't let a user namespace mount cgroup filesystems, and rootless containers go without
+    let cgroupfs = cgroups::mount_cgroupfs(&format!("{}/sys/fs/cgroup", new_root), MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC | flags);
+    match cgroupfs {
+        Err(e) if rootless::active() => debug!("{}", e),
+        result => result?,
     }
+    Ok(())
+}
+
+/// The device nodes every container gets; host disks and the rest of devtmpfs stay out of reach
+pub const DEVICES: &[(&str, u64, u64)] = &[
+    ("null", 1, 3),
+    ("zero", 1, 5),
+    ("full", 1, 7),
+    ("random", 1, 8),
+    ("urandom", 1, 9),
+    ("tty", 5, 0),
+];
+
+const DEV_LINKS: &[(&str, &str)] = &[
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+    ("ptmx", "pts/ptmx"),
+];
+
+// `host_nodes` are DEVICES bound from the host, in its order, when they can't be created here
+fn mount_dev(options: &RootfsOptions, host_nodes: Option<&[OwnedFd]>) -> Result<(), ForgeError> {
+    mount(
+        Some("tmpfs"),
+        "/dev",
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_STRICTATIME,
+        Some(options.labeled("mode=755,size=65536k").as_str()),
+    ).map_err(|e| ForgeError::mount("mount /dev", e))?;
 
-    if let Err(e) = chdir("/") {
-        error!("Failed to chdir after chroot: {}", e);
-        process::exit(1);
+    for (i, (name, major, minor)) in DEVICES.iter().enumerate() {
+        let path = Path::new("/dev").join(name);
+        let created = match host_nodes {
+            Some(nodes) => attach_clone(&nodes[i], &path),
+            // mknod is subject to the umask, so set the mode afterwards
+            None => mknod(&path, SFlag::S_IFCHR, Mode::empty(), makedev(*major, *minor))
+                .map_err(|e| e.to_string())
+                .and_then(|_| fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).map_err(|e| e.to_string())),
+        };
+        created.map_err(|e| ForgeError::mount(format!("create /dev/{}", name), e))?;
     }
+    for (name, target) in DEV_LINKS {
+        if let Err(e) = std::os::unix::fs::symlink(target, Path::new("/dev").join(name)) {
+            warn!("Failed to link /dev/{}: {}", name, e);
+        }
+    }
+    debug!("Populated /dev with {} devices", DEVICES.len());
+    Ok(())
+}
 
-    debug!("Chrooted to new root");
+// A detached copy of the mount at `path`, which a plain bind mount couldn't reach once the pivot
+// has taken the host's /dev away
+fn clone_mount(path: &Path) -> Result<OwnedFd, String> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+    let fd = unsafe {
+        libc::syscall(libc::SYS_open_tree, libc::AT_FDCWD, path.as_ptr(), libc::OPEN_TREE_CLONE | libc::OPEN_TREE_CLOEXEC)
+    };
+    if fd < 0 { Err(io::Error::last_os_error().to_string()) } else { Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) }) }
 }
 
-fn mount_proc() {
-    mount(
-        Some("proc"),
-        "/proc",
-        Some("proc"),
-        MsFlags::empty(),
-        None::<&str>,
-    ).expect("Failed to mount /proc");
+// Mount a clone on a new empty file at `target`
+fn attach_clone(clone: &OwnedFd, target: &Path) -> Result<(), String> {
+    fs::File::create(target).map_err(|e| e.to_string())?;
+    let target = CString::new(target.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+    let moved = unsafe {
+        libc::syscall(libc::SYS_move_mount, clone.as_raw_fd(), c"".as_ptr(), libc::AT_FDCWD, target.as_ptr(), libc::MOVE_MOUNT_F_EMPTY_PATH)
+    };
+    if moved < 0 { Err(io::Error::last_os_error().to_string()) } else { Ok(()) }
 }
 
-fn mount_sys() {
-    mount(
-        Some("sysfs"),
-        "/sys",
-        Some("sysfs"),
-        MsFlags::empty(),
-        None::<&str>,
-    ).expect("Failed to mount /sys");
+// The terminal PID 1 was given, cloned before the pivot like the host's device nodes
+fn clone_console() -> Option<OwnedFd> {
+    if !io::stdin().is_terminal() {
+        return None;
+    }
+    // By its path: the open file itself belongs to the mounts of the namespace it was opened in
+    let cloned = fs::read_link("/proc/self/fd/0").map_err(|e| e.to_string()).and_then(|path| clone_mount(&path));
+    cloned.map_err(|e| warn!("No /dev/console, the terminal can't be cloned: {}", e)).ok()
 }
 
-fn mount_dev() {
-    if let Err(_) = mount(
-        Some("devtmpfs"),
-        "/dev",
-        Some("devtmpfs"),
-        MsFlags::MS_NOSUID | MsFlags::MS_STRICTATIME,
-        None::<&str>,
-    ) {
-        debug!("devtmpfs failed, trying tmpfs fallback...");
-        if let Err(e) = mount(
-            Some("tmpfs"),
-            "/dev",
-            Some("tmpfs"),
-            MsFlags::MS_NOSUID,
-            Some("mode=755"),
-        ) {
-            warn!("Failed to mount /dev: {}", e);
-        }
+// Init systems and some daemons write to /dev/console rather than their stdio
+fn mount_console(console: &OwnedFd) {
+    if let Err(e) = attach_clone(console, Path::new("/dev/console")) {
+        warn!("Failed to mount /dev/console: {}", e);
+    }
+}
+
+// A private instance, so the container sees only its own terminals and not the host's
+fn mount_devpts(options: &RootfsOptions) -> Result<(), ForgeError> {
+    // The tty group is only there when the user namespace has more ids than the user's own
+    let data = options.labeled(if rootless::single_id() { "newinstance,ptmxmode=0666,mode=0620" } else { "newinstance,ptmxmode=0666,mode=0620,gid=5" });
+    fs::create_dir_all("/dev/pts").map_err(|e| e.to_string()).and_then(|_| mount(
+        Some("devpts"),
+        "/dev/pts",
+        Some("devpts"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        Some(data.as_str()),
+    ).map_err(|e| e.to_string())).map_err(|e| ForgeError::mount("mount /dev/pts", e))
+}
+
+// The POSIX message queues of the container's IPC namespace. The host's, with --ipc host, are
+// out of reach of a rootless runtime, whose containers go without.
+fn mount_mqueue(options: &RootfsOptions) {
+    let mounted = fs::create_dir_all("/dev/mqueue").map_err(|e| e.to_string()).and_then(|_| mount(
+        Some("mqueue"),
+        "/dev/mqueue",
+        Some("mqueue"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        Some(options.labeled("").as_str()),
+    ).map_err(|e| e.to_string()));
+    if let Err(e) = mounted {
+        warn!("Failed to mount /dev/mqueue: {}", e);
     }
 }
 
-fn mount_tmp() {
+// POSIX shared memory, which Python multiprocessing, browsers and databases rely on
+fn mount_shm(options: &RootfsOptions) -> Result<(), ForgeError> {
+    let size = options.shm_size.as_deref().unwrap_or(DEFAULT_SHM_SIZE);
+    fs::create_dir_all("/dev/shm").map_err(|e| e.to_string()).and_then(|_| mount(
+        Some("shm"),
+        "/dev/shm",
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        Some(options.labeled(&format!("mode=1777,size={}", size)).as_str()),
+    ).map_err(|e| e.to_string())).map_err(|e| ForgeError::mount("mount /dev/shm", e))
+}
+
+fn mount_tmp(options: &RootfsOptions) -> Result<(), ForgeError> {
     mount(
         Some("tmpfs"),
         "/tmp",
         Some("tmpfs"),
         MsFlags::empty(),
-        None::<&str>,
-    ).expect("Failed to mount /tmp");
+        Some(options.labeled("").as_str()),
+    ).map_err(|e| ForgeError::mount("mount /tmp", e))
 }
 
-fn make_mount_point(new_root: &str) {
+fn make_mount_point(new_root: &str) -> Result<(), ForgeError> {
     debug!("Making {} a mount point...", new_root);
 
-    if let Err(e) = mount(
+    mount(
         Some(new_root),
         new_root,
         None::<&str>,
         MsFlags::MS_BIND | MsFlags::MS_REC,
         None::<&str>,
-    ) {
-        error!("Failed to bind mount new root: {}", e);
-        process::exit(1);
-    }
+    ).map_err(|e| ForgeError::mount("bind mount the new root", e))?;
 
     debug!("New root is now a mount point");
+    Ok(())
 }
 
-fn mount_essential_filesystems() {
-    mount_proc();
-    mount_sys();
-    mount_dev();
-    mount_tmp();
+// Before the pivot: inside a user namespace the kernel only allows a new proc or sysfs where
+// the host's is still visible
+fn mount_kernel_filesystems(new_root: &str, options: &RootfsOptions) -> Result<(), ForgeError> {
+    mount_proc(new_root)?;
+    mount_sys(new_root, !options.unconfined_system_paths)
+}
+
+fn mount_essential_filesystems(options: &RootfsOptions, host_nodes: Option<&[OwnedFd]>) -> Result<(), ForgeError> {
+    mount_dev(options, host_nodes)?;
+    mount_devpts(options)?;
+    mount_mqueue(options);
+    // A --tmpfs or volume of the user's own takes the place of these
+    if !options.covers("/tmp") {
+        mount_tmp(options)?;
+    }
+    if !options.covers("/dev/shm") {
+        mount_shm(options)?;
+    }
+    // tmpfs needs nothing from the host, so these go on top of /dev and /tmp once they exist
+    mount_tmpfs("/", options)?;
     debug!("Essential filesystems mounted");
+    Ok(())
+}
+
+// Hide or freeze the host kernel's sensitive /proc and /sys entries; ones this kernel lacks are skipped
+fn protect_system_paths() -> Result<(), ForgeError> {
+    for path in MASKED_PATHS {
+        let Ok(meta) = fs::metadata(path) else {
+            continue;
+        };
+        let masked = if meta.is_dir() {
+            mount(Some("tmpfs"), *path, Some("tmpfs"), MsFlags::MS_RDONLY, Some("size=0"))
+        } else {
+            mount(Some("/dev/null"), *path, None::<&str>, MsFlags::MS_BIND, None::<&str>)
+        };
+        masked.map_err(|e| ForgeError::mount(format!("mask {}", path), e))?;
+    }
+
+    for path in READ_ONLY_PATHS {
+        if fs::symlink_metadata(path).is_err() {
+            continue;
+        }
+        let frozen = mount(Some(*path), *path, None::<&str>, MsFlags::MS_BIND | MsFlags::MS_REC, None::<&str>)
+            .and_then(|_| mount(
+                None::<&str>,
+                *path,
+                None::<&str>,
+                MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+                None::<&str>,
+            ));
+        frozen.map_err(|e| ForgeError::mount(format!("make {} read-only", path), e))?;
+    }
+    debug!("Masked {} and froze {} system paths", MASKED_PATHS.len(), READ_ONLY_PATHS.len());
+    Ok(())
+}
+
+// Give a read-only container its scratch tmpfs, then make the root itself read-only
+fn make_root_read_only(options: &RootfsOptions) -> Result<(), ForgeError> {
+    for dir in READ_ONLY_SCRATCH {
+        if options.covers(dir) {
+            continue;
+        }
+        let mounted = fs::create_dir_all(dir).map_err(|e| e.to_string()).and_then(|_| mount(
+            Some("tmpfs"),
+            *dir,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            Some(options.labeled("mode=1777").as_str()),
+        ).map_err(|e| e.to_string()));
+        mounted.map_err(|e| ForgeError::mount(format!("mount tmpfs on {}", dir), e))?;
+    }
+
+    // Only the root mount changes; everything mounted on top keeps its own flags
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY,
+        None::<&str>,
+    ).map_err(|e| ForgeError::mount("make the root filesystem read-only", e))?;
+    debug!("Root filesystem is read-only");
+    Ok(())
+}
+
+/// Whether something is mounted exactly at `path` in this mount namespace
+pub fn is_mounted(path: &Path) -> bool {
+    let mounts = fs::read_to_string("/proc/self/mounts").unwrap_or_default();
+    mounts.lines().any(|line| line.split_whitespace().nth(1) == Some(&*path.to_string_lossy()))
+}
+
+/// Whether a rootfs ships its own shell; symlinks such as /bin/sh -> busybox count without being followed
+pub fn has_shell(root: &Path) -> bool {
+    ["bin/sh", "bin/bash"].iter().any(|shell| fs::symlink_metadata(root.join(shell)).is_ok())
+}
+
+// Symlinks followed while resolving a mount target before giving up
+const MAX_SYMLINKS: usize = 40;
+
+/// Everything about a container's filesystem besides the image: what is mounted into it and how
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RootfsOptions {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mounts: Vec<BindMount>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tmpfs: Vec<TmpfsMount>, // empty again on every start
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub read_only: bool,        // the root itself can't be written, only mounts and scratch tmpfs
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unconfined_system_paths: bool, // the host kernel's /proc and /sys entries are left as they are
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shm_size: Option<String>, // "64m" unless --shm-size says otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_size: Option<u64>, // bytes the writable layer may hold, from --storage-opt size=
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<DeviceMapping>, // host devices passed through with --device
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub all_devices: bool,      // --privileged: the device controller lets it use any device
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub host_utils: bool,       // the host's shell and tools are copied in, for debugging
+    #[serde(skip)]
+    pub mount_context: Option<String>, // the SELinux context= option for the container's own mounts
+}
+
+// Kernel interfaces in /proc and /sys that leak host details or reach the host kernel;
+// files are covered with /dev/null and directories with an empty read-only tmpfs
+const MASKED_PATHS: &[&str] = &[
+    "/proc/acpi", "/proc/asound", "/proc/kcore", "/proc/keys", "/proc/latency_stats",
+    "/proc/sched_debug", "/proc/scsi", "/proc/timer_list", "/proc/timer_stats",
+    "/sys/firmware", "/sys/devices/virtual/powercap",
+];
+// Readable but never writable from a container; /sys is mounted read-only as a whole
+const READ_ONLY_PATHS: &[&str] = &["/proc/bus", "/proc/fs", "/proc/irq", "/proc/sys", "/proc/sysrq-trigger"];
+
+// Where a read-only container still gets somewhere to write
+// besides /tmp and /dev/shm, which every container has
+const READ_ONLY_SCRATCH: &[&str] = &["/run"];
+
+const DEFAULT_SHM_SIZE: &str = "64m";
+
+impl RootfsOptions {
+    /// Whether the user mounted something of their own exactly at `target`
+    pub fn covers(&self, target: &str) -> bool {
+        let target = Path::new(target);
+        self.mounts.iter().any(|m| Path::new(&m.target) == target)
+            || self.tmpfs.iter().any(|t| Path::new(&t.target) == target)
+    }
+
+    // Mount data for a filesystem of the container's own, with its SELinux label when it has one
+    fn labeled(&self, data: &str) -> String {
+        match &self.mount_context {
+            Some(context) if data.is_empty() => context.clone(),
+            Some(context) => format!("{},{}", data, context),
+            None => data.to_string(),
+        }
+    }
 }
 
-pub fn setup_root_filesystem(new_root: &str) {
+/// A host directory or file bound into the container, from `-v SOURCE:TARGET`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BindMount {
+    pub source: PathBuf,        // host path; a named volume's data directory
+    pub target: String,         // absolute path inside the container
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<String>, // the named volume `source` belongs to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,   // "ro", "rslave", "nosuid", ... as given after the target
+}
+
+// What `-v SOURCE:TARGET:OPTIONS` accepts, comma separated
+const ACCESS_OPTIONS: &[&str] = &["ro", "rw"];
+const PROPAGATION_OPTIONS: &[&str] = &["rshared", "shared", "rslave", "slave", "rprivate", "private"];
+const RECURSION_OPTIONS: &[&str] = &["rbind", "bind"];
+const SECURITY_OPTIONS: &[&str] = &["nosuid", "nodev", "noexec"];
+// SELinux relabeling of the source: z for every container, Z for this one alone
+const LABEL_OPTIONS: &[&str] = &["z", "Z"];
+
+impl BindMount {
+    /// Parse `SOURCE:TARGET[:OPTIONS]`, where SOURCE is an absolute host path or a volume name
+    pub fn parse(spec: &str) -> Result<(String, String, Vec<String>), ForgeError> {
+        let mut parts = spec.splitn(3, ':');
+        let (Some(source), Some(target)) = (parts.next(), parts.next()) else {
+            return Err(format!("Invalid mount {:?}: expected SOURCE:TARGET[:OPTIONS]", spec).into());
+        };
+        if source.is_empty() || !target.starts_with('/') {
+            return Err(format!("Invalid mount {:?}: the target must be an absolute path", spec).into());
+        }
+
+        let options: Vec<String> = parts.next().unwrap_or("")
+            .split(',')
+            .filter(|o| !o.is_empty())
+            .map(str::to_string)
+            .collect();
+        // At most one choice from each group; the security options combine freely
+        for group in [ACCESS_OPTIONS, PROPAGATION_OPTIONS, RECURSION_OPTIONS, LABEL_OPTIONS] {
+            if options.iter().filter(|o| group.contains(&o.as_str())).count() > 1 {
+                return Err(format!("Invalid mount {:?}: use only one of {}", spec, group.join(", ")).into());
+            }
+        }
+        let known = |o: &String| [ACCESS_OPTIONS, PROPAGATION_OPTIONS, RECURSION_OPTIONS, SECURITY_OPTIONS, LABEL_OPTIONS]
+            .iter().any(|group| group.contains(&o.as_str()));
+        if let Some(unknown) = options.iter().find(|o| !known(o)) {
+            return Err(format!("Invalid mount {:?}: unknown option {}", spec, unknown).into());
+        }
+        Ok((source.to_string(), target.to_string(), options))
+    }
+
+    fn has(&self, option: &str) -> bool {
+        self.options.iter().any(|o| o == option)
+    }
+
+    /// Whether the source is relabeled for the container: Some(true) shared with every
+    /// container (:z), Some(false) for this one alone (:Z)
+    pub fn relabel(&self) -> Option<bool> {
+        if self.has("z") { Some(true) } else if self.has("Z") { Some(false) } else { None }
+    }
+
+    // Flags for the read-only and security remount, none if the bind stays as it is
+    fn remount_flags(&self) -> MsFlags {
+        let mut flags = MsFlags::empty();
+        for (option, flag) in [
+            ("ro", MsFlags::MS_RDONLY),
+            ("nosuid", MsFlags::MS_NOSUID),
+            ("nodev", MsFlags::MS_NODEV),
+            ("noexec", MsFlags::MS_NOEXEC),
+        ] {
+            if self.has(option) {
+                flags |= flag;
+            }
+        }
+        flags
+    }
+
+    fn propagation(&self) -> Option<MsFlags> {
+        let option = self.options.iter().find(|o| PROPAGATION_OPTIONS.contains(&o.as_str()))?;
+        let recursive = if option.starts_with('r') { MsFlags::MS_REC } else { MsFlags::empty() };
+        let kind = match option.trim_start_matches('r') {
+            "shared" => MsFlags::MS_SHARED,
+            "slave" => MsFlags::MS_SLAVE,
+            _ => MsFlags::MS_PRIVATE,
+        };
+        Some(kind | recursive)
+    }
+}
+
+// Bind each mount onto its target inside the rootfs, before pivoting into it
+fn mount_binds(new_root: &str, mounts: &[BindMount]) -> Result<(), ForgeError> {
+    for bind in mounts {
+        let action = || format!("mount {:?} on {}", bind.source, bind.target);
+        let target = resolve_in_root(Path::new(new_root), &bind.target).map_err(|e| ForgeError::mount(action(), e))?;
+
+        // The mount point has to exist and be the same kind as the source
+        let created = if bind.source.is_dir() {
+            fs::create_dir_all(&target)
+        } else {
+            target.parent().map(fs::create_dir_all).unwrap_or(Ok(()))
+                .and_then(|_| fs::OpenOptions::new().create(true).append(true).open(&target).map(|_| ()))
+        };
+        created.map_err(|e| ForgeError::mount(format!("create mount point {}", bind.target), e))?;
+
+        apply_bind(bind, &target).map_err(|e| ForgeError::mount(action(), e))?;
+        debug!("Mounted {:?} on {} ({})", bind.source, bind.target, bind.options.join(","));
+    }
+    Ok(())
+}
+
+fn apply_bind(bind: &BindMount, target: &Path) -> Result<(), ForgeError> {
+    // Mounts below the source come along unless "bind" asks for the source alone
+    let recursive = if bind.has("bind") { MsFlags::empty() } else { MsFlags::MS_REC };
+    mount(Some(&bind.source), target, None::<&str>, MsFlags::MS_BIND | recursive, None::<&str>)?;
+
+    // A bind ignores every flag but MS_REC, so read-only and the rest need a remount.
+    // The kernel refuses to clear flags the source mount already has, so keep those.
+    let flags = bind.remount_flags();
+    if !flags.is_empty() {
+        let existing = statvfs(target)?.flags();
+        let mut keep = MsFlags::empty();
+        for (flag, ms) in [
+            (FsFlags::ST_RDONLY, MsFlags::MS_RDONLY),
+            (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+            (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+            (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        ] {
+            if existing.contains(flag) {
+                keep |= ms;
+            }
+        }
+        mount(None::<&str>, target, None::<&str>, MsFlags::MS_REMOUNT | MsFlags::MS_BIND | flags | keep, None::<&str>)
+            .map_err(|e| format!("remount with {}: {}", bind.options.join(","), e))?;
+    }
+
+    if let Some(propagation) = bind.propagation() {
+        mount(None::<&str>, target, None::<&str>, propagation, None::<&str>)
+            .map_err(|e| format!("set propagation: {}", e))?;
+    }
+    Ok(())
+}
+
+/// A fresh tmpfs inside the container, from `--tmpfs TARGET[:OPTIONS]`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TmpfsMount {
+    pub target: String,         // absolute path inside the container
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,   // "size=64m", "mode=1777", "noexec", ...
+}
+
+impl TmpfsMount {
+    /// Parse `TARGET[:OPTIONS]`, options being size=, mode=, nr_inodes=, uid=, gid=, ro and noexec
+    pub fn parse(spec: &str) -> Result<Self, ForgeError> {
+        let (target, options) = spec.split_once(':').unwrap_or((spec, ""));
+        if !target.starts_with('/') {
+            return Err(format!("Invalid tmpfs {:?}: the target must be an absolute path", spec).into());
+        }
+        let options: Vec<String> = options.split(',').filter(|o| !o.is_empty()).map(str::to_string).collect();
+        for option in &options {
+            let valid = match option.split_once('=') {
+                Some(("size", value)) => valid_size(value),
+                Some(("mode", value)) => u32::from_str_radix(value, 8).is_ok_and(|mode| mode <= 0o7777),
+                Some(("nr_inodes" | "uid" | "gid", value)) => value.parse::<u64>().is_ok(),
+                Some(_) => false,
+                None => ["ro", "rw", "noexec", "exec"].contains(&option.as_str()),
+            };
+            if !valid {
+                return Err(format!("Invalid tmpfs {:?}: bad option {}", spec, option).into());
+            }
+        }
+        Ok(TmpfsMount { target: target.to_string(), options })
+    }
+
+    // Mount flags and the tmpfs-specific data string; setuid files and devices are never allowed
+    fn flags_and_data(&self) -> (MsFlags, String) {
+        let mut flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+        let mut data = Vec::new();
+        for option in &self.options {
+            match option.as_str() {
+                "ro" => flags |= MsFlags::MS_RDONLY,
+                "noexec" => flags |= MsFlags::MS_NOEXEC,
+                "rw" | "exec" => {}
+                other => data.push(other),
+            }
+        }
+        (flags, data.join(","))
+    }
+}
+
+/// A tmpfs size: bytes, a k/m/g suffixed amount or a percentage of memory
+pub fn valid_size(value: &str) -> bool {
+    value.trim_end_matches(['k', 'm', 'g', '%']).parse::<u64>().is_ok()
+}
+
+/// A host device made available inside the container, from `--device HOST[:CONTAINER][:PERMISSIONS]`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceMapping {
+    pub host: PathBuf,          // "/dev/fuse"
+    pub container: String,      // where it appears in the container, the host path unless given
+    pub permissions: String,    // "rwm": read, write, mknod
+}
+
+// A device node looked up on the host before the pivot, to be recreated inside the container
+struct DeviceNode<'a> {
+    mapping: &'a DeviceMapping,
+    kind: SFlag,
+    rdev: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    host_clone: Option<OwnedFd>,    // rootless: the host's node itself, to bind instead of recreate
+}
+
+impl DeviceMapping {
+    pub fn parse(spec: &str) -> Result<Self, ForgeError> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        let is_permissions = |p: &str| !p.is_empty() && p.chars().all(|c| "rwm".contains(c));
+        let (host, container, permissions) = match parts[..] {
+            [host] => (host, host, "rwm"),
+            [host, permissions] if is_permissions(permissions) => (host, host, permissions),
+            [host, container] => (host, container, "rwm"),
+            [host, container, permissions] => (host, container, permissions),
+            _ => return Err(format!("Invalid --device {:?}: expected HOST[:CONTAINER][:PERMISSIONS]", spec).into()),
+        };
+        if !host.starts_with('/') || !container.starts_with('/') {
+            return Err(format!("Invalid --device {:?}: paths must be absolute", spec).into());
+        }
+        if !is_permissions(permissions) {
+            return Err(format!("Invalid --device {:?}: permissions are a combination of r, w and m", spec).into());
+        }
+        let mapping = DeviceMapping { host: PathBuf::from(host), container: container.to_string(), permissions: permissions.to_string() };
+        mapping.node()?;
+        Ok(mapping)
+    }
+
+    fn node(&self) -> Result<DeviceNode<'_>, ForgeError> {
+        // Follow links like /dev/dri/by-path/... to the node itself
+        let meta = fs::metadata(&self.host).map_err(|e| format!("Device {:?}: {}", self.host, e))?;
+        let kind = if meta.file_type().is_char_device() {
+            SFlag::S_IFCHR
+        } else if meta.file_type().is_block_device() {
+            SFlag::S_IFBLK
+        } else {
+            return Err(format!("{:?} is not a device", self.host).into());
+        };
+
+        // The host's owner and mode, less whatever r and w weren't granted
+        let mut mode = meta.mode() & 0o777;
+        if !self.permissions.contains('r') {
+            mode &= !0o444;
+        }
+        if !self.permissions.contains('w') {
+            mode &= !0o222;
+        }
+        Ok(DeviceNode { mapping: self, kind, rdev: meta.rdev(), mode, uid: meta.uid(), gid: meta.gid(), host_clone: None })
+    }
+}
+
+impl DeviceMapping {
+    /// The device controller's rule for the device, with the access granted
+    pub fn rule(&self) -> Result<DeviceRule, ForgeError> {
+        let node = self.node()?;
+        Ok(DeviceRule {
+            kind: if node.kind == SFlag::S_IFBLK { 'b' } else { 'c' },
+            major: Some(major(node.rdev)),
+            minor: Some(minor(node.rdev)),
+            access: self.permissions.clone(),
+        })
+    }
+}
+
+// Recreate the passed-through devices in the container's fresh /dev, or wherever they were asked for
+fn create_devices(nodes: &[DeviceNode]) -> Result<(), ForgeError> {
+    for node in nodes {
+        let created = resolve_in_root(Path::new("/"), &node.mapping.container).and_then(|path| {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // Replacing one of the standard nodes is allowed
+            let _ = fs::remove_file(&path);
+            // A bound node keeps the host's mode and owner, which chmod would change on the host too
+            if let Some(clone) = &node.host_clone {
+                attach_clone(clone, &path)?;
+                return Ok(());
+            }
+            mknod(&path, node.kind, Mode::empty(), node.rdev)?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(node.mode))?;
+            std::os::unix::fs::chown(&path, Some(node.uid), Some(node.gid))?;
+            Ok(())
+        });
+        created.map_err(|e| ForgeError::mount(format!("create device {}", node.mapping.container), e))?;
+        debug!("Created device {} from {:?}", node.mapping.container, node.mapping.host);
+    }
+    Ok(())
+}
+
+// Mount each tmpfs inside the root, after the binds so one can sit on top of a volume
+fn mount_tmpfs(new_root: &str, options: &RootfsOptions) -> Result<(), ForgeError> {
+    for tmpfs in &options.tmpfs {
+        let (flags, data) = tmpfs.flags_and_data();
+        let data = options.labeled(&data);
+        let mounted = resolve_in_root(Path::new(new_root), &tmpfs.target).and_then(|target| {
+            fs::create_dir_all(&target)?;
+            mount(Some("tmpfs"), &target, Some("tmpfs"), flags, Some(data.as_str()))?;
+            Ok(())
+        });
+        mounted.map_err(|e| ForgeError::mount(format!("mount tmpfs on {}", tmpfs.target), e))?;
+        debug!("Mounted tmpfs on {} ({})", tmpfs.target, tmpfs.options.join(","));
+    }
+    Ok(())
+}
+
+// Resolve `path` as the container would see it, following symlinks without ever leaving `root`,
+// so an image can't point a mount target at the host's own directories
+fn resolve_in_root(root: &Path, path: &str) -> Result<PathBuf, ForgeError> {
+    let mut resolved = PathBuf::new();
+    let mut queue = path_components(Path::new(path));
+    let mut links = 0;
+
+    while let Some(name) = queue.pop_front() {
+        // ".." can't climb above the container's root
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+
+        let candidate = resolved.join(&name);
+        let is_link = fs::symlink_metadata(root.join(&candidate)).map(|m| m.file_type().is_symlink()).unwrap_or(false);
+        if !is_link {
+            resolved = candidate;
+            continue;
+        }
+
+        links += 1;
+        if links > MAX_SYMLINKS {
+            return Err(format!("Too many levels of symbolic links in {}", path).into());
+        }
+        // Absolute links restart from the container's root, relative ones from the link's directory
+        let link = fs::read_link(root.join(&candidate))?;
+        if link.is_absolute() {
+            resolved.clear();
+        }
+        for name in path_components(&link).into_iter().rev() {
+            queue.push_front(name);
+        }
+    }
+    Ok(root.join(resolved))
+}
+
+// The names and ".." steps of a path; "/" and "." add nothing
+fn path_components(path: &Path) -> VecDeque<OsString> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_owned()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+/// In the container's PID 1: move into `new_root` with everything `options` asks for mounted
+pub fn setup_root_filesystem(new_root: &str, options: &RootfsOptions) -> Result<(), ForgeError> {
     debug!("Setting up isolated root filesystem at {}...", new_root);
 
-    create_container_dirs(new_root);
-    copy_bash_and_dependencies(new_root);
-    make_mount_point(new_root);
-    pivot_to_new_root(new_root);
-    mount_essential_filesystems();
+    // The host's /dev is gone after the pivot
+    let mut devices = options.devices.iter().map(DeviceMapping::node).collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ForgeError::mount("pass devices through", e))?;
+
+    make_mounts_private(options)?;
+    let console = clone_console();
+    // Without the host's CAP_MKNOD, device nodes can only be bound in from the host
+    let host_nodes = if rootless::active() {
+        for node in &mut devices {
+            node.host_clone = Some(clone_mount(&node.mapping.host).map_err(|e| ForgeError::mount(format!("bind device {:?}", node.mapping.host), e))?);
+        }
+        let nodes = DEVICES.iter().map(|(name, ..)| clone_mount(&Path::new("/dev").join(name)))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| ForgeError::mount("bind the host's device nodes", e))?;
+        Some(nodes)
+    } else {
+        None
+    };
+    create_container_dirs(new_root)?;
+    // An image brings its own userland; host binaries linked against the host's libc don't belong there
+    if options.host_utils {
+        copy_bash_and_dependencies(new_root);
+    }
+    make_mount_point(new_root)?;
+    mount_binds(new_root, &options.mounts)?;
+    mount_kernel_filesystems(new_root, options)?;
+    pivot_to_new_root(new_root).map_err(|e| ForgeError::mount(format!("switch to the container's root {}", new_root), e))?;
+    mount_essential_filesystems(options, host_nodes.as_deref())?;
+    if let Some(console) = console.filter(|_| !options.covers("/dev/console")) {
+        mount_console(&console);
+    }
+    create_devices(&devices)?;
+    if !options.unconfined_system_paths {
+        protect_system_paths()?;
+    }
+    if options.read_only {
+        make_root_read_only(options)?;
+    }
+    Ok(())
 }
@@ -1,14 +1,56 @@
 use std::fs;
 use std::path::{Path, PathBuf};
 
+use crate::health::HealthConfig;
+use crate::lifecycle;
+use crate::error::ForgeError;
+
+/// `FROM scratch` starts from an empty rootfs
+pub const SCRATCH: &str = "scratch";
+
 #[derive(Debug, Clone)]
 pub enum Instruction {
     From{ image: String },
     Copy{ src: String, dest: String },
     Run{ command: String },
     Workdir{ path: String },
+    User { user: String },
     Env{ key: String, value: String },
     Entrypoint { args: Vec<String> },
+    Label { key: String, value: String },
+    Expose { ports: Vec<String> },
+    StopSignal { signal: String },
+    Healthcheck { config: HealthConfig },
+}
+
+impl Instruction {
+    /// FROM, COPY and RUN change the filesystem; the rest only change the config
+    pub fn creates_layer(&self) -> bool {
+        match self {
+            Instruction::From { image } => image != SCRATCH,
+            Instruction::Copy { .. } | Instruction::Run { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Instruction::From { image } => write!(f, "FROM {}", image),
+            Instruction::Copy { src, dest } => write!(f, "COPY {} {}", src, dest),
+            Instruction::Run { command } => write!(f, "RUN {}", command),
+            Instruction::Workdir { path } => write!(f, "WORKDIR {}", path),
+            Instruction::User { user } => write!(f, "USER {}", user),
+            Instruction::Env { key, value } => write!(f, "ENV {}={}", key, value),
+            Instruction::Entrypoint { args } => write!(f, "ENTRYPOINT {:?}", args),
+            Instruction::Label { key, value } => write!(f, "LABEL {}={:?}", key, value),
+            Instruction::Expose { ports } => write!(f, "EXPOSE {}", ports.join(" ")),
+            Instruction::StopSignal { signal } => write!(f, "STOPSIGNAL {}", signal),
+            Instruction::Healthcheck { config } if config.is_disabled() => write!(f, "HEALTHCHECK NONE"),
+            Instruction::Healthcheck { config } => write!(f, "HEALTHCHECK {:?}", config.test),
+        }
+    }
 }
 
 pub struct Forgefile {
@@ -17,7 +59,7 @@ pub struct Forgefile {
 }
 
 impl Forgefile {
-    pub fn parse(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn parse(path: &Path) -> Result<Self, ForgeError> {
         let content = fs::read_to_string(path)?;
         let context_dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
 
@@ -45,7 +87,7 @@ impl Forgefile {
         })
     }
 
-    fn parse_command_line(parts: Vec<&str>) -> Result<Option<Instruction>, Box<dyn std::error::Error>> {
+    fn parse_command_line(parts: Vec<&str>) -> Result<Option<Instruction>, ForgeError> {
         let command = parts[0].to_uppercase();
         let args = parts[1];
 
@@ -70,6 +112,13 @@ impl Forgefile {
             "WORKDIR" => {
                 Ok(Some(Instruction::Workdir { path: args.to_string() }))
             }
+            "USER" => {
+                let user = args.trim();
+                if user.is_empty() || user.contains(char::is_whitespace) {
+                    return Err("USER requires USER[:GROUP]".into());
+                }
+                Ok(Some(Instruction::User { user: user.to_string() }))
+            }
             "ENV" => {
                 let env_parts: Vec<&str> = args.splitn(2, '=').collect();
                 if env_parts.len() < 2 {
@@ -84,12 +133,34 @@ impl Forgefile {
                 let args = parse_json_array(args)?;
                 Ok(Some(Instruction::Entrypoint { args }))
             }
+            "LABEL" => {
+                let label_parts: Vec<&str> = args.splitn(2, '=').collect();
+                if label_parts.len() < 2 {
+                    return Err("LABEL requires KEY=VALUE format".into());
+                }
+                Ok(Some(Instruction::Label {
+                    key: label_parts[0].trim().to_string(),
+                    value: label_parts[1].trim().trim_matches('"').to_string(),
+                }))
+            }
+            "EXPOSE" => {
+                // "8080" means "8080/tcp", like the OCI ExposedPorts keys
+                let ports = args.split_whitespace()
+                    .map(|p| if p.contains('/') { p.to_string() } else { format!("{}/tcp", p) })
+                    .collect();
+                Ok(Some(Instruction::Expose { ports }))
+            }
+            "STOPSIGNAL" => {
+                lifecycle::parse_signal(args)?;
+                Ok(Some(Instruction::StopSignal { signal: args.to_string() }))
+            }
+            "HEALTHCHECK" => Ok(Some(Instruction::Healthcheck { config: HealthConfig::parse_instruction(args)? })),
             _ => Ok(None),
         }
     }
 }
 
-fn parse_json_array(s: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+fn parse_json_array(s: &str) -> Result<Vec<String>, ForgeError> {
     let s = s.trim();
     if !s.starts_with('[') || !s.ends_with(']') {
         return Err("ENTRYPOINT requires JSON array format: [\"cmd\", \"arg\"]".into());
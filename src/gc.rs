@@ -0,0 +1,311 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::time::SystemTime;
+use log::{debug, info};
+
+use crate::config;
+use crate::image::{ImageStore, parse_size, unix_now};
+use crate::rwlayer;
+use crate::snapshot;
+use crate::error::ForgeError;
+
+// Automatic `builder prune` after every build, e.g. {"keep_storage": "10GB", "until": "168h"}
+const BUILDER_POLICY_FILE: &str = "builder.json";
+
+// How much build cache to keep beyond what tagged images reference
+pub struct CachePolicy {
+    pub max_size: Option<u64>,  // Keep the most recently used cache-only layers up to this many bytes
+    pub keep_cache: bool,       // false drops every cache-only layer
+}
+
+#[derive(Default)]
+pub struct GcReport {
+    pub layers_removed: usize,
+    pub bytes_freed: u64,
+    pub cache_entries_removed: usize,
+}
+
+/// Delete layers no manifest references, trimming cache-only layers according to the policy
+pub fn collect_garbage(store: &ImageStore, policy: &CachePolicy, dry_run: bool) -> Result<GcReport, ForgeError> {
+    let _lock = store.lock_exclusive()?;
+    let _metadata_lock = store.lock_metadata()?;
+
+    let mut reachable: HashSet<String> = HashSet::new();
+    for (name, tag) in store.list_images()? {
+        for entry in store.load_index(&name, &tag)?.manifests {
+            reachable.extend(entry.manifest.layers);
+        }
+    }
+    // Stopped containers are remounted on their image's layers
+    reachable.extend(rwlayer::referenced_layers()?);
+    debug!("{} layers reachable from manifests", reachable.len());
+
+    let mut cache_index = store.load_cache_index();
+    let mut report = GcReport::default();
+
+    // Cache keys pointing at layers that no longer exist are useless
+    let before = cache_index.len();
+    cache_index.retain(|_, digest| store.layer_exists(digest));
+    report.cache_entries_removed += before - cache_index.len();
+
+    // Layers only the build cache refers to, most recently used first
+    let mut cache_only: Vec<(String, u64, SystemTime)> = cache_index.values()
+        .filter(|digest| !reachable.contains(*digest))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .filter_map(|digest| {
+            let meta = fs::metadata(store.get_layer_path(digest).ok()?).ok()?;
+            Some((digest.clone(), meta.len(), meta.modified().unwrap_or(SystemTime::UNIX_EPOCH)))
+        })
+        .collect();
+    cache_only.sort_by_key(|entry| std::cmp::Reverse(entry.2));
+
+    let mut keep: HashSet<String> = reachable.clone();
+    let mut cache_bytes: u64 = 0;
+    for (digest, size, _) in &cache_only {
+        if !policy.keep_cache {
+            break;
+        }
+        if let Some(max) = policy.max_size {
+            if cache_bytes + size > max {
+                continue;
+            }
+        }
+        cache_bytes += size;
+        keep.insert(digest.clone());
+    }
+
+    let before = cache_index.len();
+    cache_index.retain(|_, digest| keep.contains(digest));
+    report.cache_entries_removed += before - cache_index.len();
+
+    for entry in fs::read_dir(store.root.join("layers"))? {
+        let entry = entry?;
+        let digest = entry.file_name().to_string_lossy().to_string();
+        if keep.contains(&digest) {
+            continue;
+        }
+
+        let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+        debug!("Removing unreferenced layer {} ({} bytes)", digest, size);
+        if !dry_run {
+            fs::remove_file(entry.path())?;
+        }
+        report.layers_removed += 1;
+        report.bytes_freed += size;
+    }
+
+    if !dry_run {
+        store.save_cache_index(&cache_index)?;
+        let snapshots = snapshot::remove_unused(store)?;
+        debug!("Removed {} unpacked layer snapshots", snapshots);
+    }
+    Ok(report)
+}
+
+/// image gc [--max-cache-size SIZE] [--no-cache] [--dry-run]
+pub fn gc_command(store: &ImageStore, args: &[String]) -> Result<(), ForgeError> {
+    let mut policy = CachePolicy { max_size: None, keep_cache: true };
+    let mut dry_run = false;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--max-cache-size" => {
+                let value = args.get(i + 1).ok_or("--max-cache-size requires a value")?;
+                policy.max_size = Some(parse_size(value)?);
+                i += 2;
+            }
+            "--no-cache" => {
+                policy.keep_cache = false;
+                i += 1;
+            }
+            "--dry-run" => {
+                dry_run = true;
+                i += 1;
+            }
+            other => return Err(format!("Unknown gc option: {}", other).into()),
+        }
+    }
+
+    let report = collect_garbage(store, &policy, dry_run)?;
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    info!("{} {} layers ({:.1} MB) and {} cache entries",
+        verb, report.layers_removed, report.bytes_freed as f64 / 1_048_576.0, report.cache_entries_removed);
+    Ok(())
+}
+
+// Which build cache entries `builder prune` may evict
+#[derive(Default)]
+pub struct PruneOptions {
+    pub keep_storage: Option<u64>,  // Stop once the cache is down to this many bytes
+    pub until: Option<u64>,         // Only entries unused for at least this many seconds
+}
+
+#[derive(Deserialize)]
+struct BuilderPolicy {
+    keep_storage: Option<String>,
+    until: Option<String>,
+}
+
+impl PruneOptions {
+    fn from_policy(policy: BuilderPolicy) -> Result<Self, ForgeError> {
+        Ok(PruneOptions {
+            keep_storage: policy.keep_storage.as_deref().map(parse_size).transpose()?,
+            until: policy.until.as_deref().map(parse_duration).transpose()?,
+        })
+    }
+}
+
+/// Evict least-recently-used build cache entries and the layers only they referenced.
+/// Callers hold the store's exclusive lock.
+fn prune_build_cache(store: &ImageStore, options: &PruneOptions, dry_run: bool) -> Result<GcReport, ForgeError> {
+    let _metadata_lock = store.lock_metadata()?;
+
+    let mut reachable: HashSet<String> = HashSet::new();
+    for (name, tag) in store.list_images()? {
+        for entry in store.load_index(&name, &tag)?.manifests {
+            reachable.extend(entry.manifest.layers);
+        }
+    }
+    // Stopped containers are remounted on their image's layers
+    reachable.extend(rwlayer::referenced_layers()?);
+
+    let mut cache_index = store.load_cache_index();
+    let mut usage = store.load_cache_usage();
+    let mut report = GcReport::default();
+
+    let before = cache_index.len();
+    cache_index.retain(|_, digest| store.layer_exists(digest));
+    report.cache_entries_removed += before - cache_index.len();
+
+    // A layer was last used when any of its cache keys was
+    let mut last_used: HashMap<String, u64> = HashMap::new();
+    for (key, digest) in &cache_index {
+        if reachable.contains(digest) {
+            continue;
+        }
+        let used = usage.get(key).copied().unwrap_or(0);
+        let entry = last_used.entry(digest.clone()).or_insert(0);
+        *entry = (*entry).max(used);
+    }
+
+    let mut layers: Vec<(String, u64, u64)> = last_used.into_iter()
+        .map(|(digest, used)| {
+            let size = store.layer_size(&digest);
+            (digest, size, used)
+        })
+        .collect();
+    layers.sort_by_key(|(_, _, used)| *used);
+
+    let now = unix_now();
+    let mut cache_bytes: u64 = layers.iter().map(|(_, size, _)| size).sum();
+    let mut evicted: HashSet<String> = HashSet::new();
+    for (digest, size, used) in layers {
+        if options.keep_storage.is_some_and(|keep| cache_bytes <= keep) {
+            break;
+        }
+        if options.until.is_some_and(|until| now.saturating_sub(used) < until) {
+            continue;
+        }
+        debug!("Evicting cache layer {} ({} bytes)", digest, size);
+        if !dry_run {
+            fs::remove_file(store.get_layer_path(&digest)?)?;
+        }
+        cache_bytes -= size;
+        report.layers_removed += 1;
+        report.bytes_freed += size;
+        evicted.insert(digest);
+    }
+
+    let before = cache_index.len();
+    cache_index.retain(|_, digest| !evicted.contains(digest));
+    report.cache_entries_removed += before - cache_index.len();
+
+    if !dry_run {
+        usage.retain(|key, _| cache_index.contains_key(key));
+        store.save_cache_index(&cache_index)?;
+        store.save_cache_usage(&usage)?;
+        snapshot::remove_unused(store)?;
+    }
+    Ok(report)
+}
+
+/// Apply the store's builder.json policy, if any. Skipped when other builds or runs hold the store.
+pub fn auto_prune(store: &ImageStore) -> Result<(), ForgeError> {
+    let Ok(json) = fs::read_to_string(store.root.join(BUILDER_POLICY_FILE)) else {
+        return Ok(());
+    };
+    let options = PruneOptions::from_policy(serde_json::from_str(&json)?)?;
+
+    let Some(_lock) = store.try_lock_exclusive()? else {
+        debug!("Image store busy, skipping automatic cache pruning");
+        return Ok(());
+    };
+    let report = prune_build_cache(store, &options, false)?;
+    if report.layers_removed > 0 {
+        info!("Pruned {} build cache layers ({:.1} MB)", report.layers_removed, report.bytes_freed as f64 / 1_048_576.0);
+    }
+    Ok(())
+}
+
+/// builder prune [--keep-storage SIZE] [--filter until=DURATION] [--dry-run]
+pub fn builder_command(args: &[String]) -> Result<(), ForgeError> {
+    let usage = "Usage: container-runtime builder prune [--keep-storage SIZE] [--filter until=DURATION] [--dry-run]";
+    match args.get(2).map(|s| s.as_str()) {
+        Some("prune") => {}
+        Some(other) => return Err(format!("Unknown builder command: {}", other).into()),
+        None => return Err(usage.into()),
+    }
+
+    let mut options = PruneOptions::default();
+    let mut dry_run = false;
+
+    let mut i = 3;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--keep-storage" => {
+                let value = args.get(i + 1).ok_or("--keep-storage requires a size")?;
+                options.keep_storage = Some(parse_size(value)?);
+                i += 2;
+            }
+            "--filter" | "-f" => {
+                let filter = args.get(i + 1).ok_or("--filter requires until=DURATION")?;
+                let until = filter.strip_prefix("until=")
+                    .ok_or_else(|| format!("Unknown builder prune filter: {} (expected until=DURATION)", filter))?;
+                options.until = Some(parse_duration(until)?);
+                i += 2;
+            }
+            "--dry-run" => {
+                dry_run = true;
+                i += 1;
+            }
+            other => return Err(format!("Unknown builder prune option: {}\n{}", other, usage).into()),
+        }
+    }
+
+    let store = ImageStore::new(config::images_dir()?)?;
+    let _lock = store.lock_exclusive()?;
+    let report = prune_build_cache(&store, &options, dry_run)?;
+
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    info!("{} {} cache layers ({:.1} MB) and {} cache entries",
+        verb, report.layers_removed, report.bytes_freed as f64 / 1_048_576.0, report.cache_entries_removed);
+    Ok(())
+}
+
+// Durations like 90s, 30m, 168h or 7d
+fn parse_duration(value: &str) -> Result<u64, ForgeError> {
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("Invalid duration: {}", value))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("Invalid duration: {} (use s, m, h or d)", value).into()),
+    };
+    Ok(number * seconds)
+}
@@ -0,0 +1,317 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::prctl;
+use nix::unistd::{fork, pipe, ForkResult, Pid};
+use log::{debug, info, warn};
+
+use crate::config;
+use crate::image;
+use crate::lifecycle;
+use crate::restart::RestartPolicy;
+use crate::rwlayer::{self, ContainerRecord};
+use crate::state::{self, Status};
+use crate::error::ForgeError;
+
+// HEALTHCHECK probes, run with `exec` inside the container by a child of its runtime, so every
+// start begins again at "starting". Each result goes to state.json, which keeps the last few:
+//   starting    until the first probe passes; failures within the start period don't count
+//   healthy     the last probe passed
+//   unhealthy   `retries` probes in a row failed; with --restart on-unhealthy the container
+//               is stopped, and started again by its supervisor
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_RETRIES: u32 = 3;
+const KEPT_PROBES: usize = 5;
+const MAX_OUTPUT: usize = 4096;
+
+/// How the container is probed, as in the OCI image config; zero means the default
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct HealthConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub test: Vec<String>,      // ["CMD-SHELL", "curl -f localhost"], ["CMD", "curl", ...] or ["NONE"]
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub interval: u64,          // nanoseconds, like the other durations
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub timeout: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub start_period: u64,
+    #[serde(default, skip_serializing_if = "is_zero_u32")]
+    pub retries: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+/// What the probes found since the container was last started
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HealthState {
+    pub status: HealthStatus,
+    #[serde(default)]
+    pub failing_streak: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub log: Vec<Probe>,        // the last few, oldest first
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Probe {
+    pub start: String,          // RFC 3339
+    pub end: String,
+    pub exit_code: i32,         // -1 when the probe timed out
+    pub output: String,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+fn is_zero_u32(n: &u32) -> bool {
+    *n == 0
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthStatus::Starting => write!(f, "health: starting"),
+            HealthStatus::Healthy => write!(f, "healthy"),
+            HealthStatus::Unhealthy => write!(f, "unhealthy"),
+        }
+    }
+}
+
+impl HealthConfig {
+    /// HEALTHCHECK NONE, or `run --no-healthcheck`
+    pub fn none() -> Self {
+        HealthConfig { test: vec!["NONE".to_string()], ..HealthConfig::default() }
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.test.first().is_none_or(|test| test == "NONE")
+    }
+
+    /// The arguments of a Forgefile `HEALTHCHECK [--interval=D] [--timeout=D] [--start-period=D]
+    /// [--retries=N] CMD command`, or `HEALTHCHECK NONE`
+    pub fn parse_instruction(args: &str) -> Result<Self, ForgeError> {
+        let mut config = HealthConfig::default();
+        let mut rest = args.trim();
+        while let Some(option) = rest.strip_prefix("--") {
+            let (option, after) = option.split_once(char::is_whitespace).unwrap_or((option, ""));
+            let (name, value) = option.split_once('=').ok_or(format!("HEALTHCHECK option --{} needs a value", option))?;
+            config.set(name, value)?;
+            rest = after.trim_start();
+        }
+        match rest.split_once(char::is_whitespace) {
+            _ if rest == "NONE" => Ok(HealthConfig::none()),
+            Some(("CMD", command)) => {
+                config.test = if command.trim_start().starts_with('[') {
+                    std::iter::once("CMD".to_string()).chain(serde_json::from_str::<Vec<String>>(command)?).collect()
+                } else {
+                    vec!["CMD-SHELL".to_string(), command.trim().to_string()]
+                };
+                Ok(config)
+            }
+            _ => Err(format!("Invalid HEALTHCHECK {:?}, expected [OPTIONS] CMD command or NONE", args).into()),
+        }
+    }
+
+    /// One of `run --health-cmd`, `--health-interval`, `--health-timeout`, `--health-start-period`
+    /// or `--health-retries`, by its name without the `health-`
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), ForgeError> {
+        match name {
+            "cmd" => self.test = vec!["CMD-SHELL".to_string(), value.to_string()],
+            "interval" => self.interval = parse_duration(value)?,
+            "timeout" => self.timeout = parse_duration(value)?,
+            "start-period" => self.start_period = parse_duration(value)?,
+            "retries" => self.retries = value.parse().map_err(|_| format!("Invalid retry count {:?}", value))?,
+            other => return Err(format!("Unknown health check option {:?}", other).into()),
+        }
+        Ok(())
+    }
+
+    /// The image's health check with what was given to `run` on top
+    pub fn merge(image: Option<&HealthConfig>, overrides: &HealthConfig) -> Option<HealthConfig> {
+        let mut config = image.cloned().unwrap_or_default();
+        if !overrides.test.is_empty() {
+            config.test = overrides.test.clone();
+        }
+        for (value, given) in [
+            (&mut config.interval, overrides.interval),
+            (&mut config.timeout, overrides.timeout),
+            (&mut config.start_period, overrides.start_period),
+        ] {
+            if given != 0 {
+                *value = given;
+            }
+        }
+        if overrides.retries != 0 {
+            config.retries = overrides.retries;
+        }
+        (!config.test.is_empty()).then_some(config)
+    }
+
+    fn interval(&self) -> Duration {
+        nanos_or(self.interval, DEFAULT_INTERVAL)
+    }
+
+    fn timeout(&self) -> Duration {
+        nanos_or(self.timeout, DEFAULT_TIMEOUT)
+    }
+
+    fn retries(&self) -> u32 {
+        if self.retries == 0 { DEFAULT_RETRIES } else { self.retries }
+    }
+
+    // What `exec` runs in the container
+    fn command(&self) -> Vec<String> {
+        match self.test.split_first() {
+            Some((kind, command)) if kind == "CMD-SHELL" => vec!["/bin/sh".to_string(), "-c".to_string(), command.join(" ")],
+            Some((_, command)) => command.to_vec(),
+            None => Vec::new(),
+        }
+    }
+}
+
+fn nanos_or(nanos: u64, default: Duration) -> Duration {
+    if nanos == 0 { default } else { Duration::from_nanos(nanos) }
+}
+
+// "30s", "1m30s", "500ms" or "2h" as nanoseconds
+fn parse_duration(value: &str) -> Result<u64, ForgeError> {
+    let invalid = || format!("Invalid duration {:?}, expected e.g. 30s, 1m30s or 500ms", value);
+    let mut nanos: u64 = 0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+        let (number, after) = rest.split_at(split);
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        let unit_len = after.find(|c: char| c.is_ascii_digit()).unwrap_or(after.len());
+        let (unit, after) = after.split_at(unit_len);
+        let scale: u64 = match unit {
+            "ms" => 1_000_000,
+            "s" => 1_000_000_000,
+            "m" => 60_000_000_000,
+            "h" => 3_600_000_000_000,
+            _ => return Err(invalid().into()),
+        };
+        nanos += number * scale;
+        rest = after;
+    }
+    if nanos == 0 {
+        return Err(invalid().into());
+    }
+    Ok(nanos)
+}
+
+/// In the runtime, before it creates the container's namespaces: fork a process that probes the
+/// container for as long as the runtime lives
+pub fn monitor(record: &ContainerRecord) {
+    let Some(config) = record.config.healthcheck.clone().filter(|c| !c.is_disabled()) else {
+        return;
+    };
+    match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            // The runtime exits right after PID 1, and the probes end with it
+            let _ = prctl::set_pdeathsig(Signal::SIGKILL);
+            probe_until_stopped(record, &config);
+        }
+        Ok(ForkResult::Parent { child }) => debug!("Health checks run in process {}", child),
+        Err(e) => warn!("Failed to start the health checks: {}", e),
+    }
+}
+
+fn probe_until_stopped(record: &ContainerRecord, config: &HealthConfig) -> ! {
+    // PID 1 is recorded once it has its namespaces and network
+    let pid = loop {
+        match state::read(record) {
+            Ok(state) if state.status == Status::Running && state.pid.is_some() => break state.pid.unwrap_or_default(),
+            _ => thread::sleep(Duration::from_millis(100)),
+        }
+    };
+    state::record(record, |s| s.health = Some(HealthState { status: HealthStatus::Starting, failing_streak: 0, log: Vec::new() }));
+    let started = Instant::now();
+    loop {
+        thread::sleep(config.interval());
+        let probe = run_probe(record, config);
+        let in_start_period = started.elapsed() < Duration::from_nanos(config.start_period);
+        let mut status = HealthStatus::Starting;
+        state::record(record, |s| {
+            let health = s.health.get_or_insert(HealthState { status: HealthStatus::Starting, failing_streak: 0, log: Vec::new() });
+            if probe.exit_code == 0 {
+                health.status = HealthStatus::Healthy;
+                health.failing_streak = 0;
+            } else if !in_start_period || health.status != HealthStatus::Starting {
+                health.failing_streak += 1;
+                if health.failing_streak >= config.retries() {
+                    health.status = HealthStatus::Unhealthy;
+                }
+            }
+            health.log.push(probe);
+            let excess = health.log.len().saturating_sub(KEPT_PROBES);
+            health.log.drain(..excess);
+            status = health.status;
+        });
+        // Read again, as `update --restart` may have changed the policy
+        let policy = rwlayer::find(&record.id).map_or(record.restart, |r| r.restart);
+        if status == HealthStatus::Unhealthy && policy == RestartPolicy::OnUnhealthy {
+            info!("Container {} is unhealthy, stopping it to restart", record.display_name());
+            lifecycle::stop_gracefully(Pid::from_raw(pid), lifecycle::stop_signal(record), lifecycle::stop_timeout(record));
+            // Kept alive for the SIGKILL at the end of the stop timeout
+            loop {
+                thread::sleep(Duration::from_secs(3600));
+            }
+        }
+    }
+}
+
+fn run_probe(record: &ContainerRecord, config: &HealthConfig) -> Probe {
+    let start = image::now_rfc3339();
+    let (exit_code, output) = match exec_probe(record, config) {
+        Ok(result) => result,
+        Err(e) => (1, format!("Failed to run the health check: {}", e)),
+    };
+    debug!("Health check of {} exited with {}", record.display_name(), exit_code);
+    Probe { start, end: image::now_rfc3339(), exit_code, output }
+}
+
+// `exec` the probe in the container, killing it once it runs out of time
+fn exec_probe(record: &ContainerRecord, config: &HealthConfig) -> Result<(i32, String), ForgeError> {
+    let (read, write) = pipe()?;
+    let mut child = Command::new("/proc/self/exe")
+        .arg("--root").arg(config::root()?)
+        .arg("exec").arg(&record.id)
+        .args(config.command())
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(write.try_clone()?))
+        .stderr(Stdio::from(write))
+        .spawn()?;
+    let probe = Pid::from_raw(child.id() as i32);
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // Read to the end, so a chatty probe doesn't block on a full pipe
+        let mut output = Vec::new();
+        let _ = std::fs::File::from(read).read_to_end(&mut output);
+        output.truncate(MAX_OUTPUT);
+        let _ = sender.send((child.wait().ok().and_then(|s| s.code()), output));
+    });
+    match receiver.recv_timeout(config.timeout()) {
+        Ok((code, output)) => Ok((code.unwrap_or(1), String::from_utf8_lossy(&output).into_owned())),
+        Err(_) => {
+            warn!("Health check of {} timed out after {:?}", record.display_name(), config.timeout());
+            let _ = kill(probe, Signal::SIGKILL);
+            Ok((-1, format!("Health check exceeded timeout ({:?})", config.timeout())))
+        }
+    }
+}
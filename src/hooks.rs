@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, warn};
+
+use crate::config;
+use crate::rwlayer::ContainerRecord;
+use crate::error::ForgeError;
+
+// Executables run at points in a container's life, as in the OCI runtime spec. Each gets the
+// container's state as JSON on stdin and runs in the host's namespaces:
+//   prestart    PID 1 has its namespaces and network but waits for the hooks; one failing stops the container
+//   poststart   the entrypoint has been started; failures are only logged
+//   poststop    everything in the container has exited and been cleaned up; failures are only logged
+// They come from hooks.json under the state root, for every container, and then `run --hook`.
+
+const OCI_VERSION: &str = "1.0.2";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hook {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,      // including argv[0], as in the OCI spec
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<String>,       // the hook's whole environment, "KEY=VALUE"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,   // seconds before the hook is killed and counts as failed
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Hooks {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prestart: Vec<Hook>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub poststart: Vec<Hook>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub poststop: Vec<Hook>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Stage {
+    Prestart,
+    Poststart,
+    Poststop,
+}
+
+/// The state a hook reads from stdin
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OciState<'a> {
+    oci_version: &'a str,
+    id: &'a str,
+    status: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<i32>,
+    bundle: &'a Path,
+    annotations: HashMap<String, String>,
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.prestart.is_empty() && self.poststart.is_empty() && self.poststop.is_empty()
+    }
+
+    /// `run --hook STAGE=PATH [ARG...]`, e.g. `--hook "poststop=/usr/bin/logger -t forge"`
+    pub fn add(&mut self, spec: &str) -> Result<(), ForgeError> {
+        let (stage, command) = spec.split_once('=').ok_or(format!("Invalid hook {:?}, expected STAGE=PATH [ARG...]", spec))?;
+        let args: Vec<String> = command.split_whitespace().map(String::from).collect();
+        let path = args.first().ok_or(format!("Hook {:?} has no executable", spec))?.clone();
+        let hook = Hook { path, args, env: Vec::new(), timeout: None };
+        match stage {
+            "prestart" => self.prestart.push(hook),
+            "poststart" => self.poststart.push(hook),
+            "poststop" => self.poststop.push(hook),
+            other => return Err(format!("Unknown hook stage {:?}, expected prestart, poststart or poststop", other).into()),
+        }
+        Ok(())
+    }
+
+    fn stage(&self, stage: Stage) -> &[Hook] {
+        match stage {
+            Stage::Prestart => &self.prestart,
+            Stage::Poststart => &self.poststart,
+            Stage::Poststop => &self.poststop,
+        }
+    }
+}
+
+/// The hooks of every container, then the container's own
+pub fn for_container(record: &ContainerRecord) -> Result<Hooks, ForgeError> {
+    let file = config::hooks_file()?;
+    let mut hooks: Hooks = match fs::read_to_string(&file) {
+        Ok(json) => serde_json::from_str(&json).map_err(|e| format!("Invalid {:?}: {}", file, e))?,
+        Err(_) => Hooks::default(),
+    };
+    hooks.prestart.extend(record.hooks.prestart.iter().cloned());
+    hooks.poststart.extend(record.hooks.poststart.iter().cloned());
+    hooks.poststop.extend(record.hooks.poststop.iter().cloned());
+    Ok(hooks)
+}
+
+/// Run the hooks of a stage in order, stopping at the first that fails
+pub fn run(hooks: &Hooks, stage: Stage, record: &ContainerRecord, pid: Option<i32>) -> Result<(), ForgeError> {
+    let hooks = hooks.stage(stage);
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    let bundle = record.dir()?;
+    let status = match stage {
+        Stage::Prestart => "created",
+        Stage::Poststart => "running",
+        Stage::Poststop => "stopped",
+    };
+    let state = serde_json::to_vec(&OciState {
+        oci_version: OCI_VERSION,
+        id: &record.id,
+        status,
+        pid,
+        bundle: &bundle,
+        annotations: HashMap::new(),
+    })?;
+    for hook in hooks {
+        run_hook(hook, &state).map_err(|e| format!("{:?} hook {} failed: {}", stage, hook.path, e))?;
+    }
+    Ok(())
+}
+
+/// Run the hooks of a stage where a failure doesn't stop the container
+pub fn run_or_warn(hooks: &Hooks, stage: Stage, record: &ContainerRecord, pid: Option<i32>) {
+    if let Err(e) = run(hooks, stage, record, pid) {
+        warn!("{}", e);
+    }
+}
+
+fn run_hook(hook: &Hook, state: &[u8]) -> Result<(), ForgeError> {
+    debug!("Running hook {} {:?}", hook.path, hook.args);
+    let mut command = Command::new(&hook.path);
+    if let Some((argv0, args)) = hook.args.split_first() {
+        command.arg0(argv0).args(args);
+    }
+    command.env_clear()
+        .envs(hook.env.iter().filter_map(|v| v.split_once('=')))
+        .stdin(Stdio::piped());
+    let mut child = command.spawn().map_err(|e| format!("can't run it: {}", e))?;
+    // A hook that doesn't read its stdin is fine
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(state);
+    }
+
+    let deadline = hook.timeout.map(|secs| Instant::now() + Duration::from_secs(secs));
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("timed out after {}s", hook.timeout.unwrap_or_default()).into());
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    if !status.success() {
+        return Err(format!("it exited with {}", status).into());
+    }
+    Ok(())
+}
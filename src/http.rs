@@ -0,0 +1,118 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use ureq::config::RedirectAuthHeaders;
+use ureq::http::{request, Request, Response};
+use ureq::tls::{PemItem, RootCerts, TlsConfig};
+use ureq::{Agent, AsSendBody, Body};
+
+use crate::error::ForgeError;
+
+// HTTP for registries, token servers and base image downloads. There is one agent per trust
+// store, so the requests of a pull share connections.
+
+static AGENTS: Mutex<Vec<(Option<PathBuf>, Agent)>> = Mutex::new(Vec::new());
+
+/// An agent trusting the certificates in `ca_bundle`, or the system's when there is none.
+/// Error statuses come back as responses, for callers to tell apart.
+pub fn agent(ca_bundle: Option<&Path>) -> Result<Agent, ForgeError> {
+    let mut agents = AGENTS.lock().unwrap();
+    if let Some((_, agent)) = agents.iter().find(|(bundle, _)| bundle.as_deref() == ca_bundle) {
+        return Ok(agent.clone());
+    }
+
+    let roots = match ca_bundle {
+        Some(path) => {
+            let pem = fs::read(path).map_err(|e| format!("Failed to read CA bundle {}: {}", path.display(), e))?;
+            let certs: Vec<_> = ureq::tls::parse_pem(&pem)
+                .filter_map(|item| match item {
+                    Ok(PemItem::Certificate(cert)) => Some(cert),
+                    _ => None,
+                })
+                .collect();
+            if certs.is_empty() {
+                return Err(format!("No certificates in CA bundle {}", path.display()).into());
+            }
+            RootCerts::new_with_certs(&certs)
+        }
+        None => RootCerts::PlatformVerifier,
+    };
+    let agent: Agent = Agent::config_builder()
+        .http_status_as_error(false)
+        // Blob downloads are redirected to CDNs, which must not see registry credentials
+        .redirect_auth_headers(RedirectAuthHeaders::SameHost)
+        .tls_config(TlsConfig::builder().root_certs(roots).build())
+        .build()
+        .into();
+    agents.push((ca_bundle.map(Path::to_path_buf), agent.clone()));
+    Ok(agent)
+}
+
+/// A request for `url` carrying `headers`, each written `Name: value`
+pub fn request(method: &str, url: &str, headers: &[String]) -> Result<request::Builder, ForgeError> {
+    let mut builder = Request::builder().method(method).uri(url);
+    for header in headers {
+        let (name, value) = header.split_once(':').ok_or("Malformed request header")?;
+        builder = builder.header(name.trim(), value.trim());
+    }
+    Ok(builder)
+}
+
+/// `name=value` pairs joined with `&`, percent-encoded for a query string or a form body, so a
+/// token's `+` or `=` and a scope's `:` and `/` arrive as they were
+pub fn form_encode(fields: &[(&str, &str)]) -> String {
+    let encode = |text: &str| {
+        let mut encoded = String::with_capacity(text.len());
+        for byte in text.bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+                _ => encoded.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+        encoded
+    };
+    fields.iter().map(|(name, value)| format!("{}={}", encode(name), encode(value))).collect::<Vec<_>>().join("&")
+}
+
+/// Send a request, following redirects only if `follow_redirects`
+pub fn send(agent: &Agent, builder: request::Builder, body: impl AsSendBody, follow_redirects: bool) -> Result<Response<Body>, ForgeError> {
+    let request = builder.body(body).map_err(|e| format!("Invalid request: {}", e))?;
+    let target = format!("{} {}", request.method(), request.uri());
+    let request = if follow_redirects {
+        request
+    } else {
+        agent.configure_request(request).max_redirects(0).build()
+    };
+    agent.run(request).map_err(|e| format!("{} failed: {}", target, e).into())
+}
+
+/// GET `url`, failing on anything but a 2xx
+pub fn get(agent: &Agent, url: &str, headers: &[String]) -> Result<Response<Body>, ForgeError> {
+    let response = send(agent, request("GET", url, headers)?, (), true)?;
+    if !response.status().is_success() {
+        return Err(format!("GET {} failed: HTTP {}", url, response.status().as_u16()).into());
+    }
+    Ok(response)
+}
+
+/// GET a small text document such as a checksum list
+pub fn get_text(agent: &Agent, url: &str) -> Result<String, ForgeError> {
+    get(agent, url, &[])?.body_mut().read_to_string().map_err(|e| format!("Failed to read {}: {}", url, e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn form_encode_escapes_reserved_characters() {
+        let encoded = form_encode(&[("refresh_token", "a+b/c=="), ("scope", "repository:a/b:pull,push"), ("service", "registry.example.com")]);
+        assert_eq!(encoded, "refresh_token=a%2Bb%2Fc%3D%3D&scope=repository%3Aa%2Fb%3Apull%2Cpush&service=registry.example.com");
+    }
+
+    #[test]
+    fn form_encode_escapes_spaces_and_utf8() {
+        assert_eq!(form_encode(&[("q", "a b&é")]), "q=a%20b%26%C3%A9");
+        assert_eq!(form_encode(&[]), "");
+    }
+}
@@ -1,16 +1,64 @@
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use std::fs;
-use std::io::Write;
+use std::io::{self, Write};
 use std::collections::HashMap;
-use log::{debug, info};
+use std::net::Ipv4Addr;
+use std::os::fd::AsRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::sys::prctl;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{dup2, fork, setsid, ForkResult, Pid};
+use log::{debug, error, info, warn};
 
+use crate::archive;
+use crate::attach;
+use crate::config;
 use crate::imagebuilder::ImageBuilder;
-use crate::container::run_container_from_image;
+use crate::container::{run_container_from_image, Confinement, ProcessOptions, Startup};
+use crate::error::ForgeError;
+use crate::health::{self, HealthConfig, HealthStatus};
+use crate::oom;
+use crate::hooks::{self, Hooks, Stage};
+use crate::ipam;
+use crate::distribution::{self, OciImage, OciIndex, ANNOTATION_CONTAINERD_NAME, ANNOTATION_REF_NAME};
+use crate::registry::{self, host_platform, Descriptor, Platform, MEDIA_TYPE_OCI_MANIFEST};
+use crate::gc;
+use crate::rootless;
+use crate::rwlayer::{self, ContainerRecord};
+use crate::snapshot;
+use crate::filesystem::{self, BindMount, DeviceMapping, RootfsOptions, TmpfsMount};
+use crate::netconfig::{self, NetworkConfig};
+use crate::lifecycle;
+use crate::recover;
+use crate::restart::{Backoff, RestartPolicy};
+use crate::stdio;
+use crate::volumes;
+use crate::signing;
+use crate::state::{self, ContainerState};
+use crate::store;
+use crate::tarball;
+use crate::layer::Compression;
+use crate::userns::{IdMapping, UserNamespace};
+use crate::capabilities::Capabilities;
+use crate::cgroups::CgroupConfig;
+use crate::seccomp::Seccomp;
+use crate::selinux::{self, Label, LabelOptions};
+use crate::landlock;
+use crate::rlimits::Ulimit;
+use crate::namespace::{ClockOffsets, NamespaceMode};
 
 const LAYERS: &str = "layers";
 const MANIFESTS: &str = "manifests";
 const CACHE_INDEX: &str = "cache_index.json";
+const CACHE_USAGE: &str = "cache_usage.json";  // cache key -> unix time it was last created or hit
+const CACHE_CONFIGS: &str = "cache_configs.json";  // FROM cache key -> the base image's config, null for a bare rootfs
+const LOCK_FILE: &str = ".lock";              // shared while building/loading/running, exclusive for gc
+const METADATA_LOCK_FILE: &str = ".index.lock"; // serializes read-modify-write of the cache index and manifests
+const FICLONE: nix::libc::c_ulong = 0x40049409;
 
 // This represents ONE image (like "myapp:v1.0")
 #[derive(Serialize, Deserialize, Debug)]
@@ -18,63 +66,387 @@ pub struct ImageManifest {
     pub name: String,           // "myapp"
     pub tag: String,            // "v1.0"
     pub layers: Vec<String>,    // ["sha256:abc...", "sha256:def..."]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub layer_media_types: HashMap<String, String>,  // digest -> OCI layer media type; absent means gzip
+    #[serde(default)]
+    pub architecture: String,   // "amd64"; empty in manifests written before platforms were recorded
+    #[serde(default)]
+    pub os: String,             // "linux"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub digest: String,         // content digest recorded when stored; empty for older images
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub registry_digest: String,  // manifest digest it was pulled by, for images pulled as name@sha256:...
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<HistoryEntry>,  // one entry per instruction, in build order
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub created: String,        // RFC 3339, "2024-05-01T12:00:00Z"
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub author: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub builder: String,        // "container-runtime 0.1.0" for images built here
+}
+
+// How a step of the image was produced; entries that created no layer are marked empty_layer
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HistoryEntry {
+    pub created_by: String,     // "RUN apk add python3"
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub created: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub empty_layer: bool,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub duration_ms: u64,       // how long the step took to build
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub size: u64,              // bytes of the layer it produced
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+impl ImageManifest {
+    pub fn platform(&self) -> Platform {
+        Platform {
+            architecture: self.architecture.clone(),
+            os: self.os.clone(),
+            variant: self.variant.clone(),
+        }
+    }
+
+    pub fn runs_on_host(&self) -> bool {
+        let host = host_platform();
+        self.os == host.os && self.architecture == host.architecture
+    }
+
+    /// The history entry that created each layer, in layer order
+    pub fn layer_history(&self) -> Vec<Option<&HistoryEntry>> {
+        let mut entries = self.history.iter().filter(|h| !h.empty_layer);
+        self.layers.iter().map(|_| entries.next()).collect()
+    }
+
+    fn same_platform(&self, other: &ImageManifest) -> bool {
+        self.os == other.os && self.architecture == other.architecture && self.variant == other.variant
+    }
 }
 
 // This is the configuration for HOW to run the container
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ImageConfig {
     pub entrypoint: Vec<String>,  // ["python3", "app.py"]
     pub env: Vec<String>,         // ["PATH=/usr/bin", "PYTHONUNBUFFERED=1"]
     pub working_dir: String,      // "/app"
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub user: String,             // "app" or "1000:1000"; root when empty
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exposed_ports: Vec<String>,  // ["8080/tcp"]
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub stop_signal: String,         // "SIGQUIT"; SIGTERM when empty
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub healthcheck: Option<HealthConfig>,
+}
+
+// A tag built for several platforms; stored at manifests/<name>/<tag> in place of a single manifest
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageIndex {
+    pub name: String,
+    pub tag: String,
+    pub manifests: Vec<PlatformImage>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlatformImage {
+    pub manifest: ImageManifest,
+    pub config: ImageConfig,
+}
+
+/// A condition on listed images, from `images --filter KEY=VALUE`
+pub enum ImageFilter {
+    Reference(String),                               // glob over NAME or NAME:TAG
+    Label { key: String, value: Option<String> },    // label=KEY or label=KEY=VALUE
+    Before(u64),                                     // created before IMAGE
+    Since(u64),                                      // created after IMAGE
+    Dangling(bool),
+}
+
+impl ImageFilter {
+    /// Parse KEY=VALUE; before/since are resolved to the referenced image's creation time
+    pub fn parse(store: &ImageStore, spec: &str) -> Result<Self, ForgeError> {
+        let (key, value) = spec.split_once('=')
+            .ok_or_else(|| format!("Invalid filter {:?}, expected KEY=VALUE", spec))?;
+        match key {
+            "reference" => Ok(ImageFilter::Reference(value.to_string())),
+            "label" => Ok(match value.split_once('=') {
+                Some((k, v)) => ImageFilter::Label { key: k.to_string(), value: Some(v.to_string()) },
+                None => ImageFilter::Label { key: value.to_string(), value: None },
+            }),
+            "before" | "since" => {
+                let manifest = store.resolve(value)?.manifest;
+                let created = parse_rfc3339(&manifest.created)
+                    .ok_or_else(|| format!("{} has no creation time to compare against", value))?;
+                Ok(if key == "before" { ImageFilter::Before(created) } else { ImageFilter::Since(created) })
+            }
+            "dangling" => match value {
+                "true" => Ok(ImageFilter::Dangling(true)),
+                "false" => Ok(ImageFilter::Dangling(false)),
+                _ => Err(format!("dangling filter must be true or false, not {:?}", value).into()),
+            },
+            _ => Err(format!("Unknown filter {:?} (expected reference, label, before, since or dangling)", key).into()),
+        }
+    }
+
+    fn matches(&self, manifest: &ImageManifest, config: &ImageConfig) -> bool {
+        match self {
+            ImageFilter::Reference(pattern) => {
+                glob_match(pattern, &manifest.name) || glob_match(pattern, &format!("{}:{}", manifest.name, manifest.tag))
+            }
+            ImageFilter::Label { key, value } => match config.labels.get(key) {
+                Some(actual) => value.as_ref().is_none_or(|v| v == actual),
+                None => false,
+            },
+            ImageFilter::Before(time) => parse_rfc3339(&manifest.created).is_some_and(|t| t < *time),
+            ImageFilter::Since(time) => parse_rfc3339(&manifest.created).is_some_and(|t| t > *time),
+            // Every stored image is reached through a tag, so none are dangling
+            ImageFilter::Dangling(dangling) => !dangling,
+        }
+    }
 }
 
 pub struct ImageStore {
-    pub root: PathBuf,  // Like ~/.container-runtime/images
+    pub root: PathBuf,  // Like ~/.container-runtime/images, see config::images_dir
+    // Read-only stores searched after this one when resolving images and layers
+    additional: Vec<ImageStore>,
 }
 
 impl ImageStore {
-    pub fn new(root: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
-        let _ = fs::create_dir_all(&root)?;
+    pub fn new(root: PathBuf) -> Result<Self, ForgeError> {
+        fs::create_dir_all(&root)?;
         let _ = fs::create_dir_all(root.join(LAYERS));
         let _ = fs::create_dir_all(root.join(MANIFESTS));
 
-        Ok(Self { root })
+        let store = Self { root, additional: Vec::new() };
+        store::migrate(&store)?;
+        Ok(store)
+    }
+
+    /// Open a store that is only ever read: nothing is created, migrated or locked
+    pub fn open_read_only(root: PathBuf) -> Result<Self, ForgeError> {
+        if !root.join(MANIFESTS).is_dir() {
+            return Err(ForgeError::Image(format!("{:?} is not an image store", root)));
+        }
+        let store = Self { root, additional: Vec::new() };
+        store::check_version(&store)?;
+        Ok(store)
+    }
+
+    /// Resolve images through these read-only stores, in order, when they aren't in this one.
+    /// Writes still only go to this store.
+    pub fn with_additional_stores(mut self, roots: &[PathBuf]) -> Result<Self, ForgeError> {
+        for root in roots {
+            if *root == self.root {
+                continue;
+            }
+            debug!("Using read-only image store {:?}", root);
+            self.additional.push(Self::open_read_only(root.clone())?);
+        }
+        Ok(self)
+    }
+
+    /// This store followed by its read-only stores
+    pub fn chain(&self) -> impl Iterator<Item = &ImageStore> {
+        std::iter::once(self).chain(self.additional.iter())
     }
 
-    pub fn save_manifest(&self, manifest: &ImageManifest) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn save_manifest(&self, manifest: &ImageManifest) -> Result<(), ForgeError> {
         let dir = self.root.join(MANIFESTS).join(&manifest.name);
         fs::create_dir_all(&dir)?;
 
-        let file_path = dir.join(&manifest.tag);
         let json = serde_json::to_string_pretty(manifest)?;
-        let file = fs::File::create(file_path);
-        let _ = file?.write_all(json.as_bytes());
+        write_atomic(&dir.join(&manifest.tag), json.as_bytes())?;
 
         debug!("Saved manifest: {}:{}", manifest.name, manifest.tag);
         Ok(())
     }
 
-    pub fn load_manifest(&self, name: &str, tag: &str) -> Result<ImageManifest, Box<dyn std::error::Error>> {
-        let file_path = self.root.join(MANIFESTS).join(name).join(tag);
-        let json = fs::read_to_string(file_path)?;
-        let manifest: ImageManifest = serde_json::from_str(&json)?;
-        Ok(manifest)
+    /// Load every platform stored under a tag; single-platform tags come back as a one-entry index
+    pub fn load_index(&self, name: &str, tag: &str) -> Result<ImageIndex, ForgeError> {
+        let dir = self.root.join(MANIFESTS).join(name);
+        let json = fs::read_to_string(dir.join(tag))
+            .map_err(|e| ForgeError::Image(format!("Image {}:{} not found: {}", name, tag, e)))?;
+        if let Ok(index) = serde_json::from_str::<ImageIndex>(&json) {
+            return Ok(index);
+        }
+
+        let mut manifest: ImageManifest = serde_json::from_str(&json)?;
+        // Older manifests carry no platform; they were built on this host
+        if manifest.architecture.is_empty() {
+            let host = host_platform();
+            manifest.architecture = host.architecture;
+            manifest.os = host.os;
+            manifest.variant = host.variant;
+        }
+
+        let config_json = fs::read_to_string(dir.join(format!("{}.config", tag)))?;
+        let config: ImageConfig = serde_json::from_str(&config_json)?;
+        Ok(ImageIndex {
+            name: name.to_string(),
+            tag: tag.to_string(),
+            manifests: vec![PlatformImage { manifest, config }],
+        })
+    }
+
+    /// Pick the entry of a tag that runs on this host
+    pub fn load_platform_image(&self, name: &str, tag: &str) -> Result<PlatformImage, ForgeError> {
+        let index = self.load_index(name, tag)?;
+        let host = host_platform();
+
+        let available: Vec<String> = index.manifests.iter().map(|e| e.manifest.platform().to_string()).collect();
+        let mut candidates: Vec<PlatformImage> = index.manifests.into_iter()
+            .filter(|e| e.manifest.runs_on_host())
+            .collect();
+        if candidates.is_empty() {
+            return Err(ForgeError::Image(format!("{}:{} has no image for {} (available: {})", name, tag, host, available.join(", "))));
+        }
+
+        // Prefer an exact variant match, otherwise take the first entry for the architecture
+        let pos = candidates.iter().position(|e| e.manifest.variant == host.variant).unwrap_or(0);
+        Ok(candidates.swap_remove(pos))
+    }
+
+    /// Look up "name:tag" (this host's platform) or "name@sha256:..." (that exact content)
+    pub fn resolve(&self, reference: &str) -> Result<PlatformImage, ForgeError> {
+        let mut first_error = None;
+        for store in self.chain() {
+            match store.resolve_local(reference) {
+                Ok(image) => return Ok(image),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        Err(first_error.unwrap_or_else(|| ForgeError::Image(format!("No image {} in the store", reference))))
+    }
+
+    fn resolve_local(&self, reference: &str) -> Result<PlatformImage, ForgeError> {
+        let Some((name, digest)) = reference.split_once('@') else {
+            let parts: Vec<&str> = reference.split(':').collect();
+            return self.load_platform_image(parts[0], parts.get(1).unwrap_or(&"latest"));
+        };
+
+        // A registry digest of a manifest list matches each of its platforms, so prefer this host's
+        let mut found = None;
+        for (image_name, tag) in self.list_images()? {
+            if image_name != name {
+                continue;
+            }
+            for entry in self.load_index(&image_name, &tag)?.manifests {
+                if entry.manifest.registry_digest != digest && image_digest(&entry.manifest, &entry.config)? != digest {
+                    continue;
+                }
+                if entry.manifest.runs_on_host() {
+                    return Ok(entry);
+                }
+                found.get_or_insert(entry);
+            }
+        }
+        found.ok_or_else(|| ForgeError::Image(format!("No image {} in the store", reference)))
     }
 
-    pub fn save_layer(&self, tarball_path: &Path) -> Result<String, Box<dyn std::error::Error>> {
-        use sha2::{Sha256, Digest};
-    
-        let data = fs::read(tarball_path)?;
-        let digest = format!("sha256:{}", hex::encode(Sha256::digest(&data)));
-    
+    /// Store an image under its tag, alongside any images already stored there for other platforms.
+    /// Returns the content digest recorded in the manifest.
+    pub fn save_image(&self, mut manifest: ImageManifest, config: ImageConfig) -> Result<String, ForgeError> {
+        manifest.digest = image_digest(&manifest, &config)?;
+        let digest = manifest.digest.clone();
+
+        let _lock = self.lock_metadata()?;
+
+        let mut entries = match self.load_index(&manifest.name, &manifest.tag) {
+            Ok(index) => index.manifests,
+            Err(_) => Vec::new(),
+        };
+        entries.retain(|e| !e.manifest.same_platform(&manifest));
+
+        if entries.is_empty() {
+            self.save_manifest(&manifest)?;
+            self.save_config(&manifest.name, &manifest.tag, &config)?;
+            return Ok(digest);
+        }
+
+        let name = manifest.name.clone();
+        let tag = manifest.tag.clone();
+        entries.push(PlatformImage { manifest, config });
+        let index = ImageIndex { name, tag, manifests: entries };
+        let dir = self.root.join(MANIFESTS).join(&index.name);
+        fs::create_dir_all(&dir)?;
+        write_atomic(&dir.join(&index.tag), serde_json::to_string_pretty(&index)?.as_bytes())?;
+        // The index embeds each platform's config
+        let _ = fs::remove_file(dir.join(format!("{}.config", index.tag)));
+
+        debug!("Saved {}:{} with {} platforms", index.name, index.tag, index.manifests.len());
+        Ok(digest)
+    }
+
+    pub fn save_layer(&self, tarball_path: &Path) -> Result<String, ForgeError> {
+        // Layers can be several gigabytes, so hash from disk rather than reading them into memory
+        let digest = registry::file_digest(tarball_path)?;
+
         let dest = self.root.join("layers").join(&digest);
-        fs::copy(tarball_path, dest)?;
-        
+        if dest.exists() {
+            debug!("Layer {} already stored, skipping copy", digest);
+            return Ok(digest);
+        }
+
+        // The source may be a user's file, so never hardlink it into the store.
+        // Writing beside the target and renaming means readers never see a partial layer.
+        let tmp = temp_path(&dest);
+        clone_file(tarball_path, &tmp, false)?;
+        if let Err(e) = fs::rename(&tmp, &dest) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+
         Ok(digest)
     }
 
-    pub fn get_layer_path(&self, digest: &str) -> PathBuf {
-        self.root.join("layers").join(digest)
+    /// Pack `dir` into a layer, writing it straight into the store and hashing it on the way
+    pub fn pack_layer(&self, dir: &Path, compression: Compression) -> Result<String, ForgeError> {
+        let layers = self.root.join("layers");
+        fs::create_dir_all(&layers)?;
+        let tmp = temp_path(&layers.join("layer"));
+        let result = pack_into(dir, compression, &tmp).and_then(|digest| {
+            let dest = layers.join(&digest);
+            if dest.exists() {
+                debug!("Layer {} already stored", digest);
+                fs::remove_file(&tmp)?;
+            } else {
+                fs::rename(&tmp, &dest)?;
+            }
+            Ok(digest)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    /// Where a layer lives: this store, else the first read-only store that has it.
+    /// Layers are content-addressed, so any copy will do. Digests often come from a registry or an
+    /// archive, so anything but `sha256:<hex>` is refused rather than joined onto the store's path.
+    pub fn get_layer_path(&self, digest: &str) -> Result<PathBuf, ForgeError> {
+        if !archive::is_digest(digest) {
+            return Err(ForgeError::Image(format!("Invalid layer digest {:?}", digest)));
+        }
+        let own = self.root.join("layers").join(digest);
+        if own.exists() {
+            return Ok(own);
+        }
+        Ok(self.additional.iter()
+            .map(|store| store.root.join("layers").join(digest))
+            .find(|path| path.exists())
+            .unwrap_or(own))
     }
 
     /// Load the cache index (cache_key -> layer_digest mapping)
@@ -88,49 +460,363 @@ impl ImageStore {
     }
 
     /// Save the cache index
-    pub fn save_cache_index(&self, index: &HashMap<String, String>) -> Result<(), Box<dyn std::error::Error>> {
-        let path = self.root.join(CACHE_INDEX);
+    pub fn save_cache_index(&self, index: &HashMap<String, String>) -> Result<(), ForgeError> {
         let json = serde_json::to_string_pretty(index)?;
-        fs::write(path, json)?;
+        write_atomic(&self.root.join(CACHE_INDEX), json.as_bytes())?;
+
+        // Base configs go with their FROM steps' cache entries
+        let mut configs = self.load_cache_configs();
+        let before = configs.len();
+        configs.retain(|key, _| index.contains_key(key));
+        if configs.len() != before {
+            self.save_cache_configs(&configs)?;
+        }
         Ok(())
     }
 
+    /// The config of the base image a cached FROM step brought in: `Some(None)` when the base
+    /// was a bare rootfs, `None` when the step was cached before base configs were kept
+    pub fn get_cached_base_config(&self, cache_key: &str) -> Option<Option<ImageConfig>> {
+        self.load_cache_configs().remove(cache_key)
+    }
+
+    pub fn cache_base_config(&self, cache_key: &str, config: Option<&ImageConfig>) -> Result<(), ForgeError> {
+        let _lock = self.lock_metadata()?;
+        let mut configs = self.load_cache_configs();
+        configs.insert(cache_key.to_string(), config.cloned());
+        self.save_cache_configs(&configs)
+    }
+
+    fn load_cache_configs(&self) -> HashMap<String, Option<ImageConfig>> {
+        fs::read_to_string(self.root.join(CACHE_CONFIGS))
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    fn save_cache_configs(&self, configs: &HashMap<String, Option<ImageConfig>>) -> Result<(), ForgeError> {
+        let json = serde_json::to_string_pretty(configs)?;
+        write_atomic(&self.root.join(CACHE_CONFIGS), json.as_bytes())
+    }
+
+    /// When each cache key was last created or hit, for least-recently-used pruning
+    pub fn load_cache_usage(&self) -> HashMap<String, u64> {
+        fs::read_to_string(self.root.join(CACHE_USAGE))
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save_cache_usage(&self, usage: &HashMap<String, u64>) -> Result<(), ForgeError> {
+        let json = serde_json::to_string_pretty(usage)?;
+        write_atomic(&self.root.join(CACHE_USAGE), json.as_bytes())
+    }
+
     /// Check if a layer exists for the given cache key
     pub fn get_cached_layer(&self, cache_key: &str) -> Option<String> {
         let index = self.load_cache_index();
-        index.get(cache_key).cloned()
+        let digest = index.get(cache_key).cloned()?;
+
+        // Bump the layer's mtime so GC can treat it as recently used
+        if let Ok(file) = self.get_layer_path(&digest).and_then(|path| Ok(fs::File::options().append(true).open(path)?)) {
+            let _ = file.set_modified(std::time::SystemTime::now());
+        }
+        // A hit that can't be recorded just makes the entry look older to builder prune
+        if let Err(e) = self.lock_metadata().and_then(|_lock| self.touch_cache_key(cache_key)) {
+            debug!("Failed to record cache hit for {}: {}", cache_key, e);
+        }
+        Some(digest)
     }
 
     /// Store a cache key -> layer digest mapping
-    pub fn cache_layer(&self, cache_key: &str, layer_digest: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn cache_layer(&self, cache_key: &str, layer_digest: &str) -> Result<(), ForgeError> {
+        let _lock = self.lock_metadata()?;
         let mut index = self.load_cache_index();
         index.insert(cache_key.to_string(), layer_digest.to_string());
         self.save_cache_index(&index)?;
-        Ok(())
+        self.touch_cache_key(cache_key)
+    }
+
+    // Callers hold the metadata lock
+    fn touch_cache_key(&self, cache_key: &str) -> Result<(), ForgeError> {
+        let mut usage = self.load_cache_usage();
+        usage.insert(cache_key.to_string(), unix_now());
+        self.save_cache_usage(&usage)
+    }
+
+    /// Compression of a stored layer, from the manifest record or sniffed from the blob itself
+    pub fn layer_compression(&self, manifest: &ImageManifest, digest: &str) -> Result<Compression, ForgeError> {
+        match manifest.layer_media_types.get(digest) {
+            Some(media_type) => Ok(Compression::from_media_type(media_type)),
+            None => Compression::detect(&self.get_layer_path(digest)?),
+        }
+    }
+
+    /// Size of a stored layer's blob, or 0 if it is missing
+    pub fn layer_size(&self, digest: &str) -> u64 {
+        self.get_layer_path(digest).ok()
+            .and_then(|path| fs::metadata(path).ok())
+            .map(|m| m.len())
+            .unwrap_or(0)
     }
 
     /// Check if a layer file exists
     pub fn layer_exists(&self, digest: &str) -> bool {
-        self.get_layer_path(digest).exists()
+        self.get_layer_path(digest).is_ok_and(|path| path.exists())
     }
 
-    /// Load image configuration
-    pub fn load_config(&self, name: &str, tag: &str) -> Result<ImageConfig, Box<dyn std::error::Error>> {
-        let config_path = self.root.join(MANIFESTS)
-            .join(name)
-            .join(format!("{}.config", tag));
-        let config_json = fs::read_to_string(config_path)?;
-        let config: ImageConfig = serde_json::from_str(&config_json)?;
-        Ok(config)
+    /// List every (name, tag) with a manifest in the store
+    pub fn list_images(&self) -> Result<Vec<(String, String)>, ForgeError> {
+        let mut images = Vec::new();
+        let manifests = self.root.join(MANIFESTS);
+        collect_manifests(&manifests, &manifests, &mut images)?;
+        images.sort();
+        Ok(images)
+    }
+
+    /// Every platform image in the store that passes `filters`. Reference filters match if any
+    /// of them does, like repeated --filter reference=...; all other filters must match.
+    pub fn list_filtered(&self, filters: &[ImageFilter]) -> Result<Vec<PlatformImage>, ForgeError> {
+        let (references, others): (Vec<&ImageFilter>, Vec<&ImageFilter>) = filters.iter()
+            .partition(|f| matches!(f, ImageFilter::Reference(_)));
+
+        let mut images = Vec::new();
+        for (name, tag) in self.list_images()? {
+            for entry in self.load_index(&name, &tag)?.manifests {
+                let matches = |f: &&ImageFilter| f.matches(&entry.manifest, &entry.config);
+                if (references.is_empty() || references.iter().any(matches)) && others.iter().all(matches) {
+                    images.push(entry);
+                }
+            }
+        }
+        Ok(images)
+    }
+
+    /// Take an exclusive lock on the whole store, released when the guard is dropped
+    pub fn lock_exclusive(&self) -> Result<Flock<fs::File>, ForgeError> {
+        self.lock(LOCK_FILE, FlockArg::LockExclusive)
+    }
+
+    /// Keep gc out while layers are being written or read; any number of holders can share it
+    pub fn lock_shared(&self) -> Result<Flock<fs::File>, ForgeError> {
+        self.lock(LOCK_FILE, FlockArg::LockShared)
+    }
+
+    /// Like lock_exclusive, but None instead of waiting when someone else holds the store
+    pub fn try_lock_exclusive(&self) -> Result<Option<Flock<fs::File>>, ForgeError> {
+        match Flock::lock(self.lock_file(LOCK_FILE)?, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => Ok(Some(lock)),
+            Err((_, nix::errno::Errno::EWOULDBLOCK)) => Ok(None),
+            Err((_, e)) => Err(format!("Failed to lock image store: {}", e).into()),
+        }
+    }
+
+    /// Serialize updates to the cache index and manifests
+    pub fn lock_metadata(&self) -> Result<Flock<fs::File>, ForgeError> {
+        self.lock(METADATA_LOCK_FILE, FlockArg::LockExclusive)
+    }
+
+    fn lock(&self, name: &str, arg: FlockArg) -> Result<Flock<fs::File>, ForgeError> {
+        Flock::lock(self.lock_file(name)?, arg)
+            .map_err(|(_, e)| format!("Failed to lock image store: {}", e).into())
+    }
+
+    fn lock_file(&self, name: &str) -> Result<fs::File, ForgeError> {
+        Ok(fs::File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.root.join(name))?)
+    }
+
+    /// Save image configuration
+    pub fn save_config(&self, name: &str, tag: &str, config: &ImageConfig) -> Result<(), ForgeError> {
+        let dir = self.root.join(MANIFESTS).join(name);
+        fs::create_dir_all(&dir)?;
+
+        let config_json = serde_json::to_string_pretty(config)?;
+        write_atomic(&dir.join(format!("{}.config", tag)), config_json.as_bytes())?;
+        Ok(())
+    }
+
+    /// Write a stored image into an OCI image layout directory, adding it to any existing index.json
+    pub fn export_oci(&self, reference: &str, dest: &Path) -> Result<String, ForgeError> {
+        let PlatformImage { manifest, config } = self.resolve(reference)?;
+        let (name, tag) = (manifest.name.as_str(), manifest.tag.as_str());
+        let image = OciImage::from_store(self, &manifest, &config)?;
+
+        let blobs = dest.join("blobs/sha256");
+        fs::create_dir_all(&blobs)?;
+        let blob_path = |digest: &str| blobs.join(digest.trim_start_matches("sha256:"));
+
+        for layer in &image.manifest.layers {
+            let target = blob_path(&layer.digest);
+            if !target.exists() {
+                clone_file(&self.get_layer_path(&layer.digest)?, &target, true)?;
+            }
+        }
+        fs::write(blob_path(&image.manifest.config.digest), &image.config_blob)?;
+
+        let manifest_blob = image.manifest_blob()?;
+        let manifest_digest = distribution::sha256_digest(&manifest_blob);
+        fs::write(blob_path(&manifest_digest), &manifest_blob)?;
+
+        fs::write(dest.join("oci-layout"), r#"{"imageLayoutVersion":"1.0.0"}"#)?;
+
+        let index_path = dest.join("index.json");
+        let mut index: OciIndex = match fs::read_to_string(&index_path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(_) => OciIndex::new(),
+        };
+
+        // Re-exporting the same image replaces its previous entry
+        let full_name = format!("{}:{}", name, tag);
+        index.manifests.retain(|m| m.annotations.get(ANNOTATION_CONTAINERD_NAME) != Some(&full_name));
+        index.manifests.push(Descriptor {
+            media_type: MEDIA_TYPE_OCI_MANIFEST.to_string(),
+            digest: manifest_digest.clone(),
+            size: manifest_blob.len() as u64,
+            annotations: HashMap::from([
+                (ANNOTATION_REF_NAME.to_string(), tag.to_string()),
+                (ANNOTATION_CONTAINERD_NAME.to_string(), full_name),
+            ]),
+            platform: None,
+        });
+        fs::write(&index_path, serde_json::to_string_pretty(&index)?)?;
+
+        debug!("Exported {}:{} to {:?}", name, tag, dest);
+        Ok(manifest_digest)
+    }
+}
+
+/// Replace `path` with `data` in one step, so readers see either the old or the new file
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), ForgeError> {
+    let tmp = temp_path(path);
+    let result = fs::File::create(&tmp)
+        .and_then(|mut file| file.write_all(data).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&tmp, path));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+// A hidden sibling of `path`, so a rename into place stays on one filesystem
+// Write a compressed layer of `dir` to `path`, returning its digest
+fn pack_into(dir: &Path, compression: Compression, path: &Path) -> Result<String, ForgeError> {
+    let file = registry::Hashing::new(io::BufWriter::new(fs::File::create(path)?));
+    let mut file = tarball::pack_compressed(dir, compression, file)?;
+    file.flush()?;
+    file.inner.get_ref().sync_all()?;
+    Ok(file.digest())
+}
+
+pub fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!(".{}.tmp-{}", name, uuid::Uuid::new_v4()))
+}
+
+/// Digest of an image's content (layers, platform and config), independent of its name and tag
+pub fn image_digest(manifest: &ImageManifest, config: &ImageConfig) -> Result<String, ForgeError> {
+    // serde_json objects are key-sorted, so this serialization is stable
+    let content = serde_json::json!({
+        "layers": manifest.layers,
+        "layerMediaTypes": manifest.layer_media_types,
+        "architecture": manifest.architecture,
+        "os": manifest.os,
+        "variant": manifest.variant,
+        "config": config,
+    });
+    Ok(distribution::sha256_digest(&serde_json::to_vec(&content)?))
+}
+
+/// Check an image still has the content digest recorded when it was stored
+pub fn verify_digest(manifest: &ImageManifest, config: &ImageConfig) -> Result<(), ForgeError> {
+    if manifest.digest.is_empty() {
+        return Ok(());
+    }
+    let actual = image_digest(manifest, config)?;
+    if actual != manifest.digest {
+        return Err(ForgeError::Image(format!("{}:{} was modified after it was stored: expected {}, got {}",
+            manifest.name, manifest.tag, manifest.digest, actual)));
+    }
+    Ok(())
+}
+
+/// Place `src` at `dest` sharing storage where possible: hardlink (if allowed), then reflink, then copy
+pub fn clone_file(src: &Path, dest: &Path, allow_hardlink: bool) -> Result<(), ForgeError> {
+    use std::os::unix::io::AsRawFd;
+
+    if allow_hardlink && fs::hard_link(src, dest).is_ok() {
+        return Ok(());
     }
+
+    let source = fs::File::open(src)?;
+    let target = fs::File::create(dest)?;
+    // FICLONE shares extents on btrfs/xfs; other filesystems reject it and we copy instead
+    let ret = unsafe { nix::libc::ioctl(target.as_raw_fd(), FICLONE, source.as_raw_fd()) };
+    if ret == 0 {
+        return Ok(());
+    }
+    drop(target);
+
+    fs::copy(src, dest)?;
+    Ok(())
+}
+
+// Manifests live at manifests/<name>/<tag>, where name may itself contain slashes
+fn collect_manifests(base: &Path, dir: &Path, images: &mut Vec<(String, String)>) -> Result<(), ForgeError> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_manifests(base, &path, images)?;
+            continue;
+        }
+
+        let tag = path.file_name().unwrap().to_string_lossy().to_string();
+        if tag.ends_with(".config") || tag.starts_with('.') {
+            continue;
+        }
+        if let Some(name) = path.parent().and_then(|p| p.strip_prefix(base).ok()) {
+            images.push((name.to_string_lossy().to_string(), tag));
+        }
+    }
+    Ok(())
+}
+
+/// Parse a human size like "512M", "10GB" or "1048576" into bytes
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn parse_size(value: &str) -> Result<u64, ForgeError> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("Invalid size: {}", value))?;
+
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        other => return Err(format!("Unknown size unit: {}", other).into()),
+    };
+    Ok((number * multiplier as f64) as u64)
 }
 
 /// Build an image from a Forgefile
-pub fn build_image(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+pub fn build_image(args: &[String]) -> Result<(), ForgeError> {
     // Parse args: build -f Containerfile -t myapp:v1.0
     let mut containerfile_path = PathBuf::from("ForgeFile");
     let mut image_name = "app";
     let mut image_tag = "latest";
+    let mut compression = Compression::Gzip;
 
     let mut i = 2;
     while i < args.len() {
@@ -145,66 +831,1031 @@ pub fn build_image(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
                 image_tag = parts.get(1).unwrap_or(&"latest");
                 i += 2;
             }
+            "--compression" => {
+                compression = Compression::parse(&args[i + 1])?;
+                i += 2;
+            }
             _ => i += 1,
         }
     }
 
-    // Create image store
-    let store_path = PathBuf::from(std::env::var("HOME")?)
-        .join(".container-runtime/images");
-    let store = ImageStore::new(store_path)?;
+    // Create image store; FROM can also use images from the read-only stores
+    let store = ImageStore::new(config::images_dir()?)?
+        .with_additional_stores(&config::additional_stores())?;
 
     // Build the image
     info!("Building image {}:{}", image_name, image_tag);
-    let builder = ImageBuilder::new(store);
-    builder.build(&containerfile_path, image_name, image_tag)?;
+    let builder = ImageBuilder::new(store).with_compression(compression);
+    // A build fails as a build, whatever image it was working with
+    builder.build(&containerfile_path, image_name, image_tag).map_err(|e| match e {
+        ForgeError::Image(message) => ForgeError::Build(message),
+        e => e,
+    })?;
+
+    // The build cache only grows; trim it if the store has a policy for that
+    if let Err(e) = gc::auto_prune(&ImageStore::new(config::images_dir()?)?) {
+        warn!("Automatic build cache pruning failed: {}", e);
+    }
 
     Ok(())
 }
 
-/// Run a container from an image
-pub fn run_image(image_ref: &str) -> Result<(), Box<dyn std::error::Error>> {
+/// Run a container from an image: run [--verify KEY.pub] IMAGE[:TAG|@DIGEST]
+pub fn run_image(args: &[String]) -> Result<i32, ForgeError> {
+    let mut verify_key = None;
+    let mut name = None;
+    let mut remove = false;
+    let mut init = false;
+    let mut tty = false;
+    let mut interactive = false;
+    let mut restart = RestartPolicy::No;
+    let mut detach_keys = String::new();
+    let mut stop_timeout = None;
+    let mut timeout = None;
+    let mut health = HealthConfig::default();
+    let mut no_healthcheck = false;
+    let mut hooks = Hooks::default();
+    let mut mount_specs = Vec::new();
+    let mut rootfs = RootfsOptions::default();
+    let mut network = NetworkConfig::default();
+    let (mut uid_map, mut gid_map, mut keep_id) = (Vec::new(), Vec::new(), false);
+    let mut capabilities = Capabilities::default();
+    let mut seccomp = Seccomp::default();
+    let mut new_privileges = false;
+    let mut apparmor = None;
+    let mut label_options = LabelOptions::default();
+    let mut landlock = false;
+    let mut ipc = NamespaceMode::default();
+    let mut net = NamespaceMode::default();
+    let mut pid = NamespaceMode::default();
+    let mut clock_offsets = ClockOffsets::default();
+    let mut ulimits: Vec<Ulimit> = Vec::new();
+    let mut resources = CgroupConfig::default();
+    let mut cpus = None;
+    let mut env = Vec::new();
+    let mut user = None;
+    let usage = "Usage: container-runtime run [--name NAME] [--rm] [-i] [-t] [-e KEY[=VALUE]]... [-u USER[:GROUP]] [--detach-keys KEYS] [--init] [--restart POLICY] [--stop-timeout SECONDS] [--timeout SECONDS] [--health-cmd CMD] [--health-interval D] [--health-timeout D] [--health-start-period D] [--health-retries N] [--no-healthcheck] [--hook STAGE=COMMAND]... [-v SOURCE:TARGET]... [--tmpfs TARGET[:OPTIONS]]... [--device HOST[:CONTAINER][:PERMISSIONS]]... [--ipc host|private|container:NAME] [--net host|private|container:NAME] [--pid host|private|container:NAME] [--clock-offset CLOCK=OFFSET]... [--ulimit NAME=SOFT[:HARD]]... [-m SIZE] [--memory-reservation SIZE] [--memory-swap SIZE|-1] [--memory-swappiness N] [--cpus N] [--cpu-quota US] [--cpu-period US] [-c SHARES] [--cpuset-cpus LIST] [--cpuset-mems LIST] [--pids-limit N] [--blkio-weight N] [--hugetlb-limit PAGESIZE:LIMIT]... [--hostname NAME] [--domainname NAME] [--add-host HOST:IP]... [--dns IP]... [--dns-search DOMAIN]... [--dns-option OPT]... [--shm-size SIZE] [--storage-opt size=SIZE] [--read-only] [--privileged] [--cap-add CAP]... [--cap-drop CAP]... [--security-opt OPT] [--uidmap C:H:N]... [--gidmap C:H:N]... [--userns keep-id] [--with-host-utils] [--verify KEY.pub] IMAGE[:TAG|@DIGEST] [COMMAND [ARG...]]";
+
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--verify" => {
+                verify_key = Some(PathBuf::from(args.get(i + 1).ok_or("--verify requires a public key")?));
+                i += 2;
+            }
+            "--name" => {
+                name = Some(args.get(i + 1).ok_or("--name requires a name")?.as_str());
+                i += 2;
+            }
+            "--rm" => {
+                remove = true;
+                i += 1;
+            }
+            "-v" | "--volume" => {
+                mount_specs.push(args.get(i + 1).ok_or("--volume requires SOURCE:TARGET")?.as_str());
+                i += 2;
+            }
+            "--privileged" => {
+                rootfs.unconfined_system_paths = true;
+                rootfs.all_devices = true;
+                capabilities.add.push("ALL".to_string());
+                seccomp = Seccomp::Unconfined;
+                apparmor = Some("unconfined".to_string());
+                label_options.disable = true;
+                i += 1;
+            }
+            "--cap-add" => {
+                capabilities.add.push(Capabilities::parse_name(args.get(i + 1).ok_or("--cap-add requires a capability")?)?);
+                i += 2;
+            }
+            "--cap-drop" => {
+                capabilities.drop.push(Capabilities::parse_name(args.get(i + 1).ok_or("--cap-drop requires a capability")?)?);
+                i += 2;
+            }
+            "--security-opt" => {
+                match args.get(i + 1).map(|s| s.as_str()) {
+                    Some("systempaths=unconfined") => rootfs.unconfined_system_paths = true,
+                    Some(opt) if opt.starts_with("seccomp=") => seccomp = Seccomp::parse(&opt["seccomp=".len()..])?,
+                    Some("no-new-privileges" | "no-new-privileges=true") => new_privileges = false,
+                    Some("no-new-privileges=false") => new_privileges = true,
+                    Some(opt) if opt.starts_with("apparmor=") && opt.len() > "apparmor=".len() => apparmor = Some(opt["apparmor=".len()..].to_string()),
+                    Some(opt) if opt.starts_with("label=") => label_options.set(&opt["label=".len()..])?,
+                    Some("landlock=on") => landlock = true,
+                    Some("landlock=off") => landlock = false,
+                    Some(other) => return Err(format!("Unknown security option: {}", other).into()),
+                    None => return Err("--security-opt requires an option".into()),
+                }
+                i += 2;
+            }
+            "--ipc" => {
+                ipc = NamespaceMode::parse("--ipc", args.get(i + 1).ok_or("--ipc requires host, private or container:NAME")?)?;
+                i += 2;
+            }
+            "--net" | "--network" => {
+                net = NamespaceMode::parse("--net", args.get(i + 1).ok_or("--net requires host, private or container:NAME")?)?;
+                i += 2;
+            }
+            "--pid" => {
+                pid = NamespaceMode::parse("--pid", args.get(i + 1).ok_or("--pid requires host, private or container:NAME")?)?;
+                i += 2;
+            }
+            "--clock-offset" => {
+                clock_offsets.set(args.get(i + 1).ok_or("--clock-offset requires CLOCK=OFFSET")?)?;
+                i += 2;
+            }
+            "--ulimit" => {
+                let ulimit = Ulimit::parse(args.get(i + 1).ok_or("--ulimit requires NAME=SOFT[:HARD]")?)?;
+                // The last one given for a resource wins
+                ulimits.retain(|u| u.name != ulimit.name);
+                ulimits.push(ulimit);
+                i += 2;
+            }
+            "-m" | "--memory" => {
+                resources.memory = Some(parse_size(args.get(i + 1).ok_or("--memory requires a size")?)?);
+                i += 2;
+            }
+            "--memory-reservation" => {
+                resources.memory_reservation = Some(parse_size(args.get(i + 1).ok_or("--memory-reservation requires a size")?)?);
+                i += 2;
+            }
+            "--memory-swap" => {
+                // Memory plus swap, or -1 for unlimited swap
+                resources.memory_swap = match args.get(i + 1).ok_or("--memory-swap requires a size")?.as_str() {
+                    "-1" => Some(-1),
+                    size => Some(parse_size(size)? as i64),
+                };
+                i += 2;
+            }
+            "--memory-swappiness" => {
+                let swappiness = args.get(i + 1).ok_or("--memory-swappiness requires 0 to 100")?;
+                resources.memory_swappiness = Some(swappiness.parse().map_err(|_| format!("Invalid --memory-swappiness: {}", swappiness))?);
+                i += 2;
+            }
+            "--cpus" => {
+                cpus = Some(args.get(i + 1).ok_or("--cpus requires a number of CPUs")?.as_str());
+                i += 2;
+            }
+            "--cpu-quota" => {
+                let quota = args.get(i + 1).ok_or("--cpu-quota requires microseconds")?;
+                resources.cpu_quota = Some(quota.parse().map_err(|_| format!("Invalid --cpu-quota: {}", quota))?);
+                i += 2;
+            }
+            "--cpu-period" => {
+                let period = args.get(i + 1).ok_or("--cpu-period requires microseconds")?;
+                resources.cpu_period = Some(period.parse().map_err(|_| format!("Invalid --cpu-period: {}", period))?);
+                i += 2;
+            }
+            "-c" | "--cpu-shares" => {
+                let shares = args.get(i + 1).ok_or("--cpu-shares requires a number of shares")?;
+                resources.cpu_shares = Some(shares.parse().map_err(|_| format!("Invalid --cpu-shares: {}", shares))?);
+                i += 2;
+            }
+            "--cpuset-cpus" => {
+                resources.cpuset_cpus = Some(args.get(i + 1).ok_or("--cpuset-cpus requires a list of CPUs")?.clone());
+                i += 2;
+            }
+            "--cpuset-mems" => {
+                resources.cpuset_mems = Some(args.get(i + 1).ok_or("--cpuset-mems requires a list of memory nodes")?.clone());
+                i += 2;
+            }
+            "--pids-limit" => {
+                // -1 for none, as other runtimes take it
+                resources.pids = match args.get(i + 1).ok_or("--pids-limit requires a number")?.as_str() {
+                    "-1" => None,
+                    limit => Some(limit.parse().map_err(|_| format!("Invalid --pids-limit: {}", limit))?),
+                };
+                i += 2;
+            }
+            "--blkio-weight" => {
+                let weight = args.get(i + 1).ok_or("--blkio-weight requires a weight")?;
+                resources.io = Some(weight.parse().map_err(|_| format!("Invalid --blkio-weight: {}", weight))?);
+                i += 2;
+            }
+            "--hugetlb-limit" => {
+                // PAGESIZE:LIMIT, once for each page size
+                let spec = args.get(i + 1).ok_or("--hugetlb-limit requires PAGESIZE:LIMIT")?;
+                let (size, limit) = spec.split_once(':').ok_or_else(|| format!("Invalid --hugetlb-limit {:?}: use PAGESIZE:LIMIT, such as 2MB:512M", spec))?;
+                resources.hugetlb.insert(parse_size(size)?, parse_size(limit)?);
+                i += 2;
+            }
+            "--hostname" | "-h" => {
+                network.hostname = NetworkConfig::parse_uts_name("hostname", args.get(i + 1).ok_or("--hostname requires a name")?)?;
+                i += 2;
+            }
+            "--domainname" => {
+                network.domainname = NetworkConfig::parse_uts_name("domain", args.get(i + 1).ok_or("--domainname requires a name")?)?;
+                i += 2;
+            }
+            "--add-host" => {
+                network.extra_hosts.push(NetworkConfig::parse_host(args.get(i + 1).ok_or("--add-host requires HOST:IP")?)?);
+                i += 2;
+            }
+            "--dns" => {
+                network.dns.push(NetworkConfig::parse_nameserver(args.get(i + 1).ok_or("--dns requires an IP address")?)?);
+                i += 2;
+            }
+            "--dns-search" => {
+                network.dns_search.push(args.get(i + 1).ok_or("--dns-search requires a domain")?.clone());
+                i += 2;
+            }
+            "--dns-option" => {
+                network.dns_options.push(args.get(i + 1).ok_or("--dns-option requires an option")?.clone());
+                i += 2;
+            }
+            "--shm-size" => {
+                let size = args.get(i + 1).ok_or("--shm-size requires a size")?;
+                if !filesystem::valid_size(size) {
+                    return Err(format!("Invalid --shm-size {:?}: use bytes or a k, m or g suffix", size).into());
+                }
+                rootfs.shm_size = Some(size.clone());
+                i += 2;
+            }
+            "--storage-opt" => {
+                let opt = args.get(i + 1).ok_or("--storage-opt requires size=SIZE")?;
+                match opt.split_once('=') {
+                    Some(("size", size)) => rootfs.storage_size = Some(parse_size(size)?),
+                    _ => return Err(format!("Unknown storage option: {}", opt).into()),
+                }
+                i += 2;
+            }
+            "--read-only" => {
+                rootfs.read_only = true;
+                i += 1;
+            }
+            "-t" | "--tty" => {
+                tty = true;
+                i += 1;
+            }
+            "-i" | "--interactive" => {
+                interactive = true;
+                i += 1;
+            }
+            "-u" | "--user" => {
+                user = Some(args.get(i + 1).ok_or("--user requires USER[:GROUP]")?.clone());
+                i += 2;
+            }
+            "-e" | "--env" => {
+                let variable = args.get(i + 1).ok_or("--env requires KEY=VALUE")?;
+                // A bare KEY passes on this process's value, as `exec -e` does
+                match variable.split_once('=') {
+                    Some(_) => env.push(variable.clone()),
+                    None => env.extend(std::env::var(variable).ok().map(|value| format!("{}={}", variable, value))),
+                }
+                i += 2;
+            }
+            "-it" | "-ti" => {
+                (interactive, tty) = (true, true);
+                i += 1;
+            }
+            "--detach-keys" => {
+                detach_keys = args.get(i + 1).ok_or("--detach-keys requires a sequence like ctrl-p,ctrl-q")?.clone();
+                attach::parse_detach_keys(&detach_keys)?;
+                i += 2;
+            }
+            "--stop-timeout" => {
+                let value = args.get(i + 1).ok_or("--stop-timeout requires a number of seconds")?;
+                stop_timeout = Some(value.parse().map_err(|_| format!("Invalid stop timeout {:?}", value))?);
+                i += 2;
+            }
+            "--timeout" => {
+                let value = args.get(i + 1).ok_or("--timeout requires a number of seconds")?;
+                timeout = Some(value.parse().ok().filter(|&t: &u64| t > 0).ok_or(format!("Invalid timeout {:?}", value))?);
+                i += 2;
+            }
+            "--health-cmd" | "--health-interval" | "--health-timeout" | "--health-start-period" | "--health-retries" => {
+                let value = args.get(i + 1).ok_or(format!("{} requires a value", args[i]))?;
+                health.set(&args[i]["--health-".len()..], value)?;
+                i += 2;
+            }
+            "--no-healthcheck" => {
+                no_healthcheck = true;
+                i += 1;
+            }
+            "--restart" => {
+                restart = args.get(i + 1).ok_or("--restart requires a policy")?.parse()?;
+                i += 2;
+            }
+            "--hook" => {
+                hooks.add(args.get(i + 1).ok_or("--hook requires STAGE=PATH [ARG...]")?)?;
+                i += 2;
+            }
+            "--init" => {
+                init = true;
+                i += 1;
+            }
+            "--with-host-utils" => {
+                rootfs.host_utils = true;
+                i += 1;
+            }
+            "--device" => {
+                rootfs.devices.push(DeviceMapping::parse(args.get(i + 1).ok_or("--device requires HOST[:CONTAINER][:PERMISSIONS]")?)?);
+                i += 2;
+            }
+            "--tmpfs" => {
+                rootfs.tmpfs.push(TmpfsMount::parse(args.get(i + 1).ok_or("--tmpfs requires TARGET[:OPTIONS]")?)?);
+                i += 2;
+            }
+            "--uidmap" => {
+                uid_map.push(IdMapping::parse(args.get(i + 1).ok_or("--uidmap requires CONTAINER_ID:HOST_ID:COUNT")?)?);
+                i += 2;
+            }
+            "--gidmap" => {
+                gid_map.push(IdMapping::parse(args.get(i + 1).ok_or("--gidmap requires CONTAINER_ID:HOST_ID:COUNT")?)?);
+                i += 2;
+            }
+            "--userns" => {
+                match args.get(i + 1).map(|s| s.as_str()) {
+                    Some("keep-id") => keep_id = true,
+                    Some(other) => return Err(format!("Unknown --userns mode {:?}: only keep-id is supported", other).into()),
+                    None => return Err("--userns requires a mode".into()),
+                }
+                i += 2;
+            }
+            other if other.starts_with('-') => return Err(format!("Unknown run option {}\n{}", other, usage).into()),
+            _ => break,
+        }
+    }
+    // The first argument that isn't an option is the image, and any after it the command
+    let image_ref = args.get(i).ok_or(usage)?.as_str();
+    let command = args.get(i + 1..).unwrap_or_default();
+    if remove && !restart.is_no() {
+        return Err("--rm and --restart can't be combined: a removed container has nothing to restart".into());
+    }
+    // After every flag, as --cpus is a quota of whichever period was given
+    if let Some(cpus) = cpus {
+        if resources.cpu_quota.is_some() {
+            return Err("--cpus and --cpu-quota can't be given together".into());
+        }
+        resources.set_cpus(cpus)?;
+    }
+    resources.validate()?;
+    if rootless::active() && !resources.is_empty() {
+        warn!("Rootless containers get no cgroup, the resource limits are ignored");
+        resources = CgroupConfig::default();
+    }
+    let userns = match (keep_id, uid_map.is_empty() && gid_map.is_empty()) {
+        (true, false) => return Err("--userns keep-id makes its own mappings and can't be combined with --uidmap or --gidmap".into()),
+        (true, true) => Some(UserNamespace::keep_id()?),
+        (false, false) => Some(UserNamespace::from_maps(uid_map, gid_map)?),
+        (false, true) => None,
+    };
     info!("Running container from image: {}", image_ref);
 
-    // Parse image reference (e.g., "myapp:v1.0")
-    let parts: Vec<&str> = image_ref.split(':').collect();
-    let name = parts[0];
-    let tag = parts.get(1).unwrap_or(&"latest");
+    // Load image from the user store or one of the read-only stores
+    let store = ImageStore::new(config::images_dir()?)?
+        .with_additional_stores(&config::additional_stores())?;
+
+    debug!("Loading image {}...", image_ref);
+    let PlatformImage { manifest, config } = store.resolve(image_ref)?;
+    if !manifest.runs_on_host() {
+        return Err(ForgeError::Image(format!("{} is a {} image, this host is {}", image_ref, manifest.platform(), host_platform())));
+    }
+    verify_digest(&manifest, &config)?;
+    debug!("Selected {} image {}", manifest.platform(), manifest.digest);
 
-    // Load image from store
-    let store_path = PathBuf::from(std::env::var("HOME")?)
-        .join(".container-runtime/images");
-    let store = ImageStore::new(store_path)?;
+    let keys = signing::Policy::load(&store)?.trusted_keys(verify_key.as_deref());
+    if !keys.is_empty() {
+        signing::verify(&store, &manifest, &config, &keys)?;
+    }
 
-    debug!("Loading image {}:{}...", name, tag);
-    let manifest = store.load_manifest(name, tag)?;
+    recover::recover_quietly();
+    rootfs.mounts = mount_specs.iter()
+        .map(|spec| volumes::resolve_mount(spec))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut config = config;
+    // After the image's ENV, so they win
+    config.env.extend(env);
+    if let Some(user) = user {
+        config.user = user;
+    }
+    if !command.is_empty() {
+        config.entrypoint = command.to_vec();
+    }
+    config.healthcheck = if no_healthcheck { Some(HealthConfig::none()) } else { HealthConfig::merge(config.healthcheck.as_ref(), &health) };
+    let mut container = ContainerRecord::new(name, image_ref, manifest, config, rootfs, network)?;
+    container.init = init;
+    container.tty = tty;
+    container.interactive = interactive;
+    container.detach_keys = detach_keys;
+    container.stop_timeout = stop_timeout;
+    container.timeout = timeout;
+    container.restart = restart;
+    container.hooks = hooks;
+    container.userns = userns;
+    container.capabilities = capabilities;
+    container.seccomp = seccomp;
+    container.new_privileges = new_privileges;
+    container.apparmor = apparmor;
+    let taken: Vec<Label> = rwlayer::list()?.into_iter().filter_map(|c| c.selinux).collect();
+    container.selinux = Label::new(&label_options, &taken)?;
+    if landlock && landlock::abi().is_none() {
+        return Err("--security-opt landlock=on was given, but this kernel doesn't have Landlock".into());
+    }
+    container.landlock = landlock;
+    container.ipc = ipc;
+    container.net = net;
+    container.pid = pid;
+    container.clock_offsets = clock_offsets;
+    container.ulimits = ulimits;
+    container.resources = resources;
+    let container_dir = container.dir()?;
+    let running = container.lock()?;
 
-    // Load config
-    let config = store.load_config(name, tag)?;
+    // gc must not delete layers out from under the extraction
+    let lock = store.lock_shared()?;
+    let created = snapshot::create(&store, &container.manifest, &container_dir, container.rootfs.storage_size, container.selinux.as_ref()).and_then(|overlay| {
+            container.overlay = overlay;
+            container.save()
+        });
+    let created = created.and_then(|_| ContainerState::new(&container).save(&container_dir));
+    if let Err(e) = created {
+        snapshot::release(&container_dir);
+        return Err(format!("Refusing to run {}: {}", image_ref, e).into());
+    }
+    drop(lock);
 
-    // Create temporary rootfs and extract layers
-    let container_id = uuid::Uuid::new_v4();
-    let rootfs = PathBuf::from(format!("/tmp/container-{}", container_id));
-    fs::create_dir_all(&rootfs)?;
+    info!("Container {}", container.id);
+    run_container(&container, running, remove)
+}
 
-    info!("Extracting {} layers...", manifest.layers.len());
-    for (i, layer_digest) in manifest.layers.iter().enumerate() {
-        debug!("  [{}/{}] Extracting layer {}...",
-            i + 1, manifest.layers.len(), &layer_digest[..16]);
+/// start CONTAINER: run a stopped container again on top of the changes it made so far
+pub fn start_container(args: &[String]) -> Result<i32, ForgeError> {
+    let reference = args.get(2).ok_or("Usage: container-runtime start CONTAINER")?;
+    recover::recover_quietly();
+    let container = rwlayer::find(reference)?;
+    let running = container.lock()?;
+    info!("Starting container {} ({})", container.display_name(), container.image);
 
-        let layer_path = store.get_layer_path(layer_digest);
-        std::process::Command::new("tar")
-            .args(&["-xzf", layer_path.to_str().unwrap(), "-C", rootfs.to_str().unwrap()])
-            .status()?;
+    if container.overlay {
+        let store = ImageStore::new(config::images_dir()?)?
+            .with_additional_stores(&config::additional_stores())?;
+        let _lock = store.lock_shared()?;
+        snapshot::remount(&store, &container.manifest, &container.dir()?, container.selinux.as_ref())?;
     }
+    run_container(&container, running, false)
+}
 
+// `running` stays locked until the container and everything it started have exited. Returns
+// the container's exit code, which with a terminal comes back by way of the supervisor's.
+fn run_container(container: &ContainerRecord, running: Flock<fs::File>, remove: bool) -> Result<i32, ForgeError> {
+    let container_dir = container.dir()?;
+    if container.tty {
+        // This process stays in the foreground as the first client of the container's terminal,
+        // and a child in a session of its own supervises it, so detaching leaves that running
+        let detach_keys = attach::parse_detach_keys(&container.detach_keys())?;
+        match unsafe { fork() }? {
+            ForkResult::Parent { child } => {
+                // Unlocking here would unlock the supervisor's copy of the lock as well
+                std::mem::forget(running);
+                return attach::client(container, &detach_keys, Some(child));
+            }
+            ForkResult::Child => {
+                setsid()?;
+                let null = fs::File::open("/dev/null")?;
+                dup2(null.as_raw_fd(), 0)?;
+            }
+        }
+    }
+    let rootfs = snapshot::rootfs(&container_dir);
     debug!("Rootfs ready at {:?}", rootfs);
     debug!("Container config - workdir: {}, env: {:?}, entrypoint: {:?}",
-        config.working_dir, config.env, config.entrypoint);
+        container.config.working_dir, container.config.env, container.config.entrypoint);
+
+    // The address is kept across restarts, until the supervisor exits
+    let setup = hooks::for_container(container).and_then(|hooks| Ok((hooks, Confinement::of(container)?, ipam::acquire(&container_dir)?)));
+    let (hooks, confinement, lease) = match setup {
+        Ok(setup) => setup,
+        Err(e) => {
+            if remove { snapshot::release(&container_dir) } else { snapshot::unmount(&container_dir) }
+            return Err(e);
+        }
+    };
+    let mut options = container.rootfs.clone();
+    options.mount_context = container.selinux.as_ref().map(Label::mount_option);
+    // A rootless container has loopback alone, so its name points there, as does the name of a
+    // container on the host's network. One sharing another container's network has its address.
+    let address = match &container.net {
+        NamespaceMode::Container(id) => shared_address(id),
+        NamespaceMode::Host => Ipv4Addr::LOCALHOST,
+        NamespaceMode::Private if rootless::active() => Ipv4Addr::LOCALHOST,
+        NamespaceMode::Private => lease.subnet.address(),
+    };
+    let prepared = volumes::mount_all(&mut options.mounts)
+        .and_then(|_| selinux::relabel_volumes(&options.mounts, container.selinux.as_ref()))
+        .and_then(|_| netconfig::prepare(&container.network, &container_dir, address, &mut options));
+    if let Err(e) = prepared {
+        lease.release();
+        if remove { snapshot::release(&container_dir) } else { snapshot::unmount(&container_dir) }
+        return Err(e);
+    }
+
+    // The container moves into its own mount namespace and root, so the overlay is taken down
+    // from out here. As subreaper this process also outlives a runtime that exits before PID 1.
+    prctl::set_child_subreaper(true)?;
+    let (container_stdio, mut host_stdio) = stdio::open(container.tty, container.interactive, &container_dir)?;
+    state::record(container, |s| s.starting());
+    let mut backoff = Backoff::default();
+    let mut output = None;
+    // `--timeout` covers every restart as well, from the first start
+    let deadline = container.timeout.map(|seconds| Instant::now() + Duration::from_secs(seconds));
+    let (exit_code, timed_out) = loop {
+        let started = Instant::now();
+        let child = match unsafe { fork() }? {
+            ForkResult::Child => {
+                // Holding on to the supervisor's end of stdin would keep EOF from the container
+                drop(host_stdio);
+                lifecycle::restore_signals();
+                health::monitor(container);
+                oom::monitor(container);
+                let container_name = format!("img-{}", container.id);
+                let start = ProcessOptions { init: container.init, tty: container.tty, stdio: container_stdio, subnet: lease.subnet, userns: container.userns.clone(), ipc: container.ipc.clone(), net: container.net.clone(), pid: container.pid.clone(), clock_offsets: container.clock_offsets, confinement: confinement.clone(), resources: container.resources.clone() };
+                let startup = RuntimeStartup { container, mounts: &options.mounts, hooks: &hooks, address: if container.net.is_default() { lease.subnet.address() } else { address } };
+                // This fork never returns to main, so it exits with main's code for a failure
+                let code = run_container_from_image(rootfs.to_str().unwrap(), &container.config, &container_name, &container.network, &options, start, startup)
+                    .unwrap_or_else(|e| {
+                        error!("{}", e);
+                        crate::exit_code(&e)
+                    });
+                std::process::exit(code);
+            }
+            ForkResult::Parent { child } => child,
+        };
+        if output.is_none() {
+            // Signals meant for the container are passed on rather than leaving it orphaned
+            lifecycle::catch_signals();
+            output = Some(host_stdio.forward());
+        }
+
+        let (exit_code, signalled, timed_out) = wait_for_runtime(container, child, deadline);
+        hooks::run_or_warn(&hooks, Stage::Poststop, container, None);
+        // Read again, as `update --restart` may have changed the policy while it ran
+        let policy = rwlayer::find(&container.id).map_or(container.restart, |record| record.restart);
+        let state = state::read(container).ok();
+        let stopped = signalled || timed_out || state.as_ref().is_some_and(|s| s.stopped);
+        let unhealthy = state.as_ref().and_then(|s| s.health.as_ref()).is_some_and(|h| h.status == HealthStatus::Unhealthy);
+        let oom_killed = state.as_ref().is_some_and(|s| s.oom_kills > 0);
+        if !policy.should_restart(exit_code, state.map_or(0, |s| s.restart_count), stopped, unhealthy, oom_killed) {
+            break (exit_code, timed_out);
+        }
+        let delay = backoff.next_delay(started.elapsed());
+        if deadline.is_some_and(|deadline| Instant::now() + delay >= deadline) {
+            info!("Container {} exited and its timeout runs out before it would restart", container.display_name());
+            break (exit_code, true);
+        }
+        state::record(container, |s| s.restarting(exit_code));
+        info!("Container {} exited with {}, restarting in {:?} ({})",
+            container.display_name(), exit_code.map_or("no exit code".to_string(), |c| c.to_string()), delay, policy);
+        if !wait_to_restart(container, delay) {
+            break (exit_code, false);
+        }
+    };
+
+    // The supervisor's copies were kept for restarts; once they are closed the output ends
+    drop(container_stdio);
+    // What the container wrote last is shown before anything the runtime logs
+    for thread in output.into_iter().flatten() {
+        let _ = thread.join();
+    }
+    volumes::unmount_all(&options.mounts);
+    lease.release();
+    if remove {
+        snapshot::release(&container_dir);
+    } else {
+        snapshot::unmount(&container_dir);
+        state::record(container, |s| if timed_out { s.time_out(exit_code) } else { s.exit(exit_code) });
+        info!("Container {} stopped, `start {}` resumes it with its changes", container.display_name(), container.display_name());
+    }
+    drop(running);
+    if timed_out {
+        return Err(format!("Container {} timed out after {}s", container.display_name(), container.timeout.unwrap_or_default()).into());
+    }
+    // The runtime exits with PID 1's code, 128+N if a signal killed it
+    Ok(exit_code.unwrap_or(1))
+}
+
+// The address of the container whose network namespace is shared, or loopback if it has none
+fn shared_address(id: &str) -> Ipv4Addr {
+    rwlayer::find(id).and_then(|record| state::load(&record))
+        .ok()
+        .and_then(|state| state.network)
+        .and_then(|network| network.ip_address.parse().ok())
+        .unwrap_or(Ipv4Addr::LOCALHOST)
+}
+
+// Records the start and runs the prestart and poststart hooks, from the runtime process
+struct RuntimeStartup<'a> {
+    container: &'a ContainerRecord,
+    mounts: &'a [BindMount],
+    hooks: &'a Hooks,
+    address: Ipv4Addr,
+}
 
-    // Run container using the container runtime
-    let container_name = format!("img-{}", container_id);
-    run_container_from_image(rootfs.to_str().unwrap(), &config, &container_name);
+impl Startup for RuntimeStartup<'_> {
+    fn created(&mut self, pid: i32) -> Result<(), ForgeError> {
+        state::record(self.container, |s| s.start(pid, self.mounts, self.address));
+        hooks::run(self.hooks, Stage::Prestart, self.container, Some(pid))
+    }
+
+    fn started(&mut self, pid: i32) {
+        hooks::run_or_warn(self.hooks, Stage::Poststart, self.container, Some(pid));
+    }
+
+    fn exited(&mut self) {
+        oom::record_exit(self.container);
+    }
+}
+
+/// The runtime exits with PID 1's exit code, and only then do the orphans follow. Also returns
+/// whether a signal was relayed to the container and whether it was stopped at `deadline`,
+/// either of which stops it for good.
+pub fn wait_for_runtime(container: &ContainerRecord, runtime: Pid, deadline: Option<Instant>) -> (Option<i32>, bool, bool) {
+    let mut exit_code = None;
+    let mut signalled = false;
+    let mut timed_out = false;
+    if let Some(deadline) = deadline {
+        lifecycle::set_deadline(deadline.saturating_duration_since(Instant::now()));
+    }
+    loop {
+        match waitpid(None, None) {
+            Ok(WaitStatus::Exited(pid, code)) if pid == runtime => exit_code = Some(code),
+            Ok(WaitStatus::Signaled(pid, signal, _)) if pid == runtime => exit_code = Some(128 + signal as i32),
+            Ok(_) => {}
+            Err(Errno::EINTR) => {
+                signalled |= lifecycle::relay_pending(container);
+                if lifecycle::timed_out() {
+                    info!("Container {} timed out, stopping it", container.display_name());
+                    timed_out = true;
+                    // Until PID 1 is known, try again in a moment
+                    if !lifecycle::stop_container(container) {
+                        lifecycle::set_deadline(Duration::from_secs(1));
+                    }
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    lifecycle::stop_finished();
+    (exit_code, signalled, timed_out)
+}
+
+// Sleep out the restart delay; false if the container was stopped in the meantime
+fn wait_to_restart(container: &ContainerRecord, delay: Duration) -> bool {
+    let deadline = Instant::now() + delay;
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        if lifecycle::relay_pending(container) || state::read(container).is_ok_and(|s| s.stopped) {
+            return false;
+        }
+        thread::sleep(left.min(Duration::from_millis(100)));
+    }
+    true
+}
+
+/// Push a local image to a registry: push IMAGE[:TAG|@DIGEST] [REGISTRY/REPO:TAG]
+pub fn push_image(args: &[String]) -> Result<(), ForgeError> {
+    if args.len() < 3 {
+        return Err("Usage: container-runtime push IMAGE:TAG [REGISTRY/REPO:TAG]".into());
+    }
+    let source = &args[2];
+    let target = args.get(3).unwrap_or(source);
+
+    let store = ImageStore::new(config::images_dir()?)?;
+
+    // A digest pins one stored image; registries need a tag to push it under
+    let index = if source.contains('@') {
+        if target.contains('@') {
+            return Err("Pushing a digest reference needs a REGISTRY/REPO:TAG target".into());
+        }
+        let entry = store.resolve(source)?;
+        ImageIndex {
+            name: entry.manifest.name.clone(),
+            tag: entry.manifest.tag.clone(),
+            manifests: vec![entry],
+        }
+    } else {
+        let parts: Vec<&str> = source.split(':').collect();
+        store.load_index(parts[0], parts.get(1).unwrap_or(&"latest"))?
+    };
+    distribution::push(&store, &index, target)?;
+    Ok(())
+}
+
+/// Download an image into the store: pull [--all-platforms] [--verify KEY.pub] IMAGE[:TAG]
+pub fn pull_image(args: &[String]) -> Result<(), ForgeError> {
+    let mut all_platforms = false;
+    let mut verify_key = None;
+    let mut source = None;
+
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--all-platforms" => all_platforms = true,
+            "--verify" => {
+                verify_key = Some(PathBuf::from(args.get(i + 1).ok_or("--verify requires a public key")?));
+                i += 1;
+            }
+            other if other.starts_with('-') => return Err(format!("Unknown pull option: {}", other).into()),
+            other => source = Some(other),
+        }
+        i += 1;
+    }
+    let source = source.ok_or("Usage: container-runtime pull [--all-platforms] [--verify KEY.pub] IMAGE[:TAG|@DIGEST]")?;
+
+    let store = ImageStore::new(config::images_dir()?)?;
+
+    let keys = signing::Policy::load(&store)?.trusted_keys(verify_key.as_deref());
+    let _lock = store.lock_shared()?;
+    distribution::pull(&store, source, all_platforms, &keys)?;
+    Ok(())
+}
+
+/// Image management subcommands: image export-oci IMAGE:TAG DIR
+pub fn image_command(args: &[String]) -> Result<(), ForgeError> {
+    match args.get(2).map(|s| s.as_str()) {
+        Some("export-oci") => {
+            if args.len() < 5 {
+                return Err("Usage: container-runtime image export-oci IMAGE[:TAG|@DIGEST] DIR".into());
+            }
+
+            let store = ImageStore::new(config::images_dir()?)?;
+
+            let _lock = store.lock_shared()?;
+            let digest = store.export_oci(&args[3], Path::new(&args[4]))?;
+            info!("Exported {} as {} to {}", args[3], digest, args[4]);
+            Ok(())
+        }
+        Some("inspect") => {
+            let reference = args.get(3).ok_or("Usage: container-runtime image inspect IMAGE[:TAG|@DIGEST]")?;
+
+            let store = ImageStore::new(config::images_dir()?)?;
+
+            println!("{}", serde_json::to_string_pretty(&inspect_image(&store, reference)?)?);
+            Ok(())
+        }
+        Some("ls") => images_command(&args[3..]),
+        Some("gc") => {
+            let store = ImageStore::new(config::images_dir()?)?;
+            gc::gc_command(&store, &args[3..])
+        }
+        Some(other) => Err(format!("Unknown image command: {}", other).into()),
+        None => Err("Usage: container-runtime image <ls|inspect|export-oci|gc> ...".into()),
+    }
+}
+
+/// Describe a stored image: its manifest, config and a per-layer breakdown
+pub fn inspect_image(store: &ImageStore, reference: &str) -> Result<serde_json::Value, ForgeError> {
+    let PlatformImage { manifest, config } = store.resolve(reference)?;
+
+    let mut total: u64 = 0;
+    let mut layers = Vec::new();
+    for (digest, history) in manifest.layers.iter().zip(manifest.layer_history()) {
+        let size = fs::metadata(store.get_layer_path(digest)?).map(|m| m.len()).unwrap_or(0);
+        total += size;
+        layers.push(serde_json::json!({
+            "digest": digest,
+            "size": size,
+            "media_type": store.layer_compression(&manifest, digest)?.media_type(),
+            "created_by": history.map(|h| h.created_by.as_str()),
+            "created": history.map(|h| h.created.as_str()).filter(|c| !c.is_empty()),
+            "duration_ms": history.map(|h| h.duration_ms).filter(|d| *d > 0),
+        }));
+    }
+
+    Ok(serde_json::json!({
+        "name": manifest.name,
+        "tag": manifest.tag,
+        "digest": image_digest(&manifest, &config)?,
+        "platform": manifest.platform().to_string(),
+        "created": manifest.created,
+        "author": manifest.author,
+        "builder": manifest.builder,
+        "labels": config.labels,
+        "manifest": manifest,
+        "config": config,
+        "layers": layers,
+        "size": total,
+    }))
+}
+
+/// List stored images with how much of their size is shared with other images
+/// List images: images [--filter KEY=VALUE]...
+pub fn images_command(args: &[String]) -> Result<(), ForgeError> {
+    let store = ImageStore::new(config::images_dir()?)?;
+
+    let mut filters = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--filter" | "-f" => {
+                let spec = args.get(i + 1).ok_or("--filter requires KEY=VALUE")?;
+                filters.push(ImageFilter::parse(&store, spec)?);
+                i += 2;
+            }
+            other => {
+                if let Some(spec) = other.strip_prefix("--filter=") {
+                    filters.push(ImageFilter::parse(&store, spec)?);
+                    i += 1;
+                } else {
+                    return Err(format!("Unknown images option: {}", other).into());
+                }
+            }
+        }
+    }
+
+    // Sharing is counted across the whole store, not just the rows being shown
+    let mut refcounts: HashMap<String, usize> = HashMap::new();
+    for entry in store.list_filtered(&[])? {
+        // Count each layer once per image, even if it repeats within the image
+        let unique: std::collections::HashSet<&String> = entry.manifest.layers.iter().collect();
+        for layer in unique {
+            *refcounts.entry(layer.clone()).or_insert(0) += 1;
+        }
+    }
+    // Each platform of a multi-platform tag gets its own row
+    let manifests: Vec<ImageManifest> = store.list_filtered(&filters)?
+        .into_iter()
+        .map(|entry| entry.manifest)
+        .collect();
+
+    println!("{:<30} {:<15} {:<16} {:<16} {:>7} {:>10} {:>10} {:>10}", "REPOSITORY", "TAG", "PLATFORM", "CREATED", "LAYERS", "SIZE", "SHARED", "UNIQUE");
+    for manifest in &manifests {
+        let mut shared: u64 = 0;
+        let mut unique: u64 = 0;
+        for layer in &manifest.layers {
+            let size = store.layer_size(layer);
+            if refcounts.get(layer).copied().unwrap_or(0) > 1 {
+                shared += size;
+            } else {
+                unique += size;
+            }
+        }
+        println!("{:<30} {:<15} {:<16} {:<16} {:>7} {:>10} {:>10} {:>10}",
+            manifest.name, manifest.tag, manifest.platform().to_string(), format_age(&manifest.created), manifest.layers.len(),
+            format_size(shared + unique), format_size(shared), format_size(unique));
+    }
+    Ok(())
+}
+
+/// Show an image's build steps, newest first: history IMAGE[:TAG|@DIGEST]
+pub fn history_command(args: &[String]) -> Result<(), ForgeError> {
+    let reference = args.get(2).ok_or("Usage: container-runtime history IMAGE[:TAG|@DIGEST]")?;
+
+    let store = ImageStore::new(config::images_dir()?)?;
+    let manifest = store.resolve(reference)?.manifest;
+
+    // Imported images carry no sizes in their history, so fall back to the layer files
+    let mut layers = manifest.layers.iter();
+    let sizes: Vec<u64> = manifest.history.iter()
+        .map(|entry| {
+            if entry.empty_layer {
+                return 0;
+            }
+            let layer = layers.next();
+            if entry.size > 0 {
+                return entry.size;
+            }
+            layer.map(|digest| store.layer_size(digest)).unwrap_or(0)
+        })
+        .collect();
+
+    println!("{:<16} {:<50} {:>10} {:>10}", "CREATED", "CREATED BY", "SIZE", "DURATION");
+    for (entry, size) in manifest.history.iter().zip(sizes).rev() {
+        let created_by: String = if entry.created_by.chars().count() > 50 {
+            entry.created_by.chars().take(47).chain("...".chars()).collect()
+        } else {
+            entry.created_by.clone()
+        };
+        let duration = if entry.duration_ms > 0 {
+            format!("{:.1}s", entry.duration_ms as f64 / 1000.0)
+        } else {
+            "-".to_string()
+        };
+        println!("{:<16} {:<50} {:>10} {:>10}",
+            format_age(&entry.created), created_by, format_size(size), duration);
+    }
+    Ok(())
+}
 
-    // Never reaches here because run_container_from_image never returns
+/// Current time as RFC 3339 in UTC, e.g. "2024-05-01T12:00:00Z"
+pub fn now_rfc3339() -> String {
+    let secs = unix_now();
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+// Seconds since the epoch of an RFC 3339 timestamp; fractions and offsets are ignored
+// Shell-style glob with `*` and `?`; like Docker's reference filter, `*` stops at `/`
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    fn matches(p: &[char], t: &[char]) -> bool {
+        match p.split_first() {
+            None => t.is_empty(),
+            Some(('*', rest)) => {
+                (0..=t.len()).take_while(|&n| n == 0 || t[n - 1] != '/').any(|n| matches(rest, &t[n..]))
+            }
+            Some(('?', rest)) => t.first().is_some_and(|c| *c != '/') && matches(rest, &t[1..]),
+            Some((c, rest)) => t.first() == Some(c) && matches(rest, &t[1..]),
+        }
+    }
+    matches(&pattern, &text)
+}
+
+fn parse_rfc3339(value: &str) -> Option<u64> {
+    let field = |range: std::ops::Range<usize>| value.get(range)?.parse::<u64>().ok();
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+    if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) {
+        return None;
+    }
+
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = (era * 146097 + doe).checked_sub(719468)?;
+
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// "3 hours ago" for an RFC 3339 timestamp, "-" when unknown
+pub fn format_age(created: &str) -> String {
+    let Some(then) = parse_rfc3339(created) else {
+        return "-".to_string();
+    };
+    let age = unix_now().saturating_sub(then);
+    let (count, unit) = match age {
+        0..=59 => (age, "second"),
+        60..=3599 => (age / 60, "minute"),
+        3600..=86399 => (age / 3600, "hour"),
+        86400..=2591999 => (age / 86400, "day"),
+        2592000..=31535999 => (age / 2592000, "month"),
+        _ => (age / 31536000, "year"),
+    };
+    format!("{} {}{} ago", count, unit, if count == 1 { "" } else { "s" })
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGISTRY_DIGEST: &str = "sha256:4bcff63911fcb4448bd4fdacec207030997caf25e9bea4045fa6c8c44de311d1";
+
+    fn pulled_image(tag: &str, registry_digest: &str) -> (ImageManifest, ImageConfig) {
+        let host = host_platform();
+        let oci_config: distribution::OciImageConfig = serde_json::from_value(serde_json::json!({
+            "architecture": host.architecture,
+            "os": host.os,
+            "variant": host.variant,
+            "config": {"Cmd": ["sh"]},
+            "rootfs": {"type": "layers", "diff_ids": []},
+        })).unwrap();
+        let (mut manifest, config) = archive::image_from_oci("alpine", tag, Vec::new(), &oci_config);
+        manifest.registry_digest = registry_digest.to_string();
+        (manifest, config)
+    }
+
+    #[test]
+    fn images_pulled_by_digest_resolve_by_that_digest() {
+        let root = std::env::temp_dir().join(format!("forge-store-{}", uuid::Uuid::new_v4()));
+        let store = ImageStore::new(root.clone()).unwrap();
+        let (manifest, config) = pulled_image(&REGISTRY_DIGEST.replace(':', "-"), REGISTRY_DIGEST);
+        let content_digest = store.save_image(manifest, config).unwrap();
+
+        let image = store.resolve(&format!("alpine@{}", REGISTRY_DIGEST)).unwrap();
+        assert_eq!(image.manifest.registry_digest, REGISTRY_DIGEST);
+        // The content digest recorded when it was stored still works too
+        assert_eq!(store.resolve(&format!("alpine@{}", content_digest)).unwrap().manifest.digest, content_digest);
+        assert!(store.resolve(&format!("busybox@{}", REGISTRY_DIGEST)).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
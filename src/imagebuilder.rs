@@ -1,24 +1,42 @@
-use crate::forgefile::{Forgefile, Instruction};
-use crate::image::{ImageStore, ImageManifest, ImageConfig};
-use std::path::{Path, PathBuf};
+use crate::config;
+use crate::filesystem;
+use crate::error::ForgeError;
+use crate::forgefile::{Forgefile, Instruction, SCRATCH};
+use crate::image::{self, verify_digest, HistoryEntry, ImageStore, ImageManifest, ImageConfig, PlatformImage};
+use crate::baseimage;
+use crate::registry::{self, hash_reader};
+use crate::layer::{self, Compression};
+use std::collections::HashMap;
+use std::path::Path;
 use std::fs;
 use std::process::Command;
+use std::time::Instant;
 use sha2::{Sha256, Digest};
 use log::info;
 
 pub struct ImageBuilder {
     store: ImageStore,
+    compression: Compression,
 }
 
 impl ImageBuilder {
     pub fn new(store: ImageStore) -> Self {
-        Self { store }
+        Self { store, compression: Compression::Gzip }
     }
 
-    pub fn build(&self, forgefile_path: &Path, name: &str, tag: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let forgefile = Forgefile::parse(forgefile_path)?;
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn build(&self, forgefile_path: &Path, name: &str, tag: &str) -> Result<(), ForgeError> {
+        let forgefile = Forgefile::parse(forgefile_path)
+            .map_err(|e| ForgeError::Build(format!("Failed to read {}: {}", forgefile_path.display(), e)))?;
+        // Layers written before the manifest exists are unreferenced, so keep gc away until it is saved
+        let _lock = self.store.lock_shared()?;
 
-        let build_dir = PathBuf::from("/tmp/container-build");
+        // Per-build directory so concurrent builds don't share a rootfs
+        let build_dir = config::tmp_dir()?.join(format!("build-{}", uuid::Uuid::new_v4()));
         if build_dir.exists() {
             fs::remove_dir_all(&build_dir)?;
         }
@@ -31,22 +49,46 @@ impl ImageBuilder {
             entrypoint: Vec::new(),
             env: vec!["PATH=/usr/local/bin:/usr/bin:/bin".to_string()],
             working_dir: "/".to_string(),
+            user: String::new(),
+            labels: HashMap::new(),
+            exposed_ports: Vec::new(),
+            stop_signal: String::new(),
+            healthcheck: None,
         };
+        let mut history: Vec<HistoryEntry> = Vec::new();
+        let mut step_started = Instant::now();
 
         let mut layers: Vec<String> = Vec::new();
         let mut prev_cache_key = String::from("base");
         let mut cache_valid = true;
 
         for instruction in forgefile.instructions.iter() {
+            finish_step(&mut history, step_started);
+            step_started = Instant::now();
+            history.push(HistoryEntry {
+                created_by: instruction.to_string(),
+                created: image::now_rfc3339(),
+                empty_layer: !instruction.creates_layer(),
+                ..Default::default()
+            });
+
             match instruction {
+                Instruction::From { image } if image == SCRATCH => {
+                    // Empty base: the rootfs starts out empty and no layer is recorded
+                    info!("  📦 FROM scratch");
+                    prev_cache_key = self.compute_cache_key(&prev_cache_key, "FROM:scratch");
+                }
+
                 Instruction::From { image } => {
                     let cache_key = self.compute_cache_key(&prev_cache_key, &format!("FROM:{}", image));
 
                     if cache_valid {
                         if let Some(layer_digest) = self.store.get_cached_layer(&cache_key) {
-                            if self.store.layer_exists(&layer_digest) {
+                            // Steps cached before base configs were kept are pulled again to get one
+                            if let (true, Some(base)) = (self.store.layer_exists(&layer_digest), self.store.get_cached_base_config(&cache_key)) {
                                 info!("  📦 FROM {} (cached)", image);
                                 self.extract_layer(&layer_digest, &rootfs)?;
+                                inherit_config(&mut config, base);
                                 layers.push(layer_digest);
                                 prev_cache_key = cache_key;
                                 continue;
@@ -57,10 +99,13 @@ impl ImageBuilder {
                     // Cache miss - execute instruction
                     cache_valid = false;
                     info!("  📥 FROM {} (downloading...)", image);
-                    self.pull_base_image(image, &rootfs)?;
+                    let base = self.pull_base_image(image, &rootfs)
+                        .map_err(|e| ForgeError::Build(format!("FROM {}: {}", image, e)))?;
 
                     let layer_digest = self.create_layer(&rootfs)?;
                     self.store.cache_layer(&cache_key, &layer_digest)?;
+                    self.store.cache_base_config(&cache_key, base.as_ref())?;
+                    inherit_config(&mut config, base);
                     layers.push(layer_digest);
                     prev_cache_key = cache_key;
                 }
@@ -68,7 +113,8 @@ impl ImageBuilder {
                 Instruction::Copy { src, dest } => {
                     // For COPY, cache key includes hash of source file contents
                     let src_path = forgefile.context_dir.join(src);
-                    let content_hash = self.hash_path(&src_path)?;
+                    let content_hash = self.hash_path(&src_path)
+                        .map_err(|e| ForgeError::Build(format!("COPY {}: {}", src, e)))?;
                     let cache_key = self.compute_cache_key(&prev_cache_key, &format!("COPY:{}:{}:{}", src, dest, content_hash));
 
                     if cache_valid {
@@ -91,11 +137,12 @@ impl ImageBuilder {
                     if let Some(parent) = dest_path.parent() {
                         fs::create_dir_all(parent)?;
                     }
-                    if src_path.is_dir() {
-                        copy_dir(&src_path, &dest_path)?;
+                    let copied = if src_path.is_dir() {
+                        copy_dir(&src_path, &dest_path)
                     } else {
-                        fs::copy(&src_path, &dest_path)?;
-                    }
+                        fs::copy(&src_path, &dest_path).map(|_| ()).map_err(ForgeError::from)
+                    };
+                    copied.map_err(|e| ForgeError::Build(format!("COPY {} -> {}: {}", src, dest, e)))?;
 
                     let layer_digest = self.create_layer(&rootfs)?;
                     self.store.cache_layer(&cache_key, &layer_digest)?;
@@ -135,6 +182,12 @@ impl ImageBuilder {
                     prev_cache_key = self.compute_cache_key(&prev_cache_key, &format!("WORKDIR:{}", path));
                 }
 
+                Instruction::User { user } => {
+                    // For the container; RUN still runs as root
+                    config.user = user.clone();
+                    prev_cache_key = self.compute_cache_key(&prev_cache_key, &format!("USER:{}", user));
+                }
+
                 Instruction::Env { key, value } => {
                     config.env.push(format!("{}={}", key, value));
                     prev_cache_key = self.compute_cache_key(&prev_cache_key, &format!("ENV:{}={}", key, value));
@@ -144,26 +197,71 @@ impl ImageBuilder {
                     config.entrypoint = args.clone();
                     prev_cache_key = self.compute_cache_key(&prev_cache_key, &format!("ENTRYPOINT:{:?}", args));
                 }
+
+                Instruction::Label { key, value } => {
+                    config.labels.insert(key.clone(), value.clone());
+                    prev_cache_key = self.compute_cache_key(&prev_cache_key, &format!("LABEL:{}={}", key, value));
+                }
+
+                Instruction::Expose { ports } => {
+                    for port in ports {
+                        if !config.exposed_ports.contains(port) {
+                            config.exposed_ports.push(port.clone());
+                        }
+                    }
+                    // Sorted like the OCI ExposedPorts map, so the digest survives a save/load round trip
+                    config.exposed_ports.sort();
+                    prev_cache_key = self.compute_cache_key(&prev_cache_key, &format!("EXPOSE:{}", ports.join(" ")));
+                }
+
+                Instruction::StopSignal { signal } => {
+                    config.stop_signal = signal.clone();
+                    prev_cache_key = self.compute_cache_key(&prev_cache_key, &format!("STOPSIGNAL:{}", signal));
+                }
+
+                Instruction::Healthcheck { config: health } => {
+                    config.healthcheck = Some(health.clone());
+                    prev_cache_key = self.compute_cache_key(&prev_cache_key, &format!("HEALTHCHECK:{:?}", health));
+                }
             }
         }
 
+        finish_step(&mut history, step_started);
+        for (entry, digest) in history.iter_mut().filter(|h| !h.empty_layer).zip(&layers) {
+            entry.size = self.store.layer_size(digest);
+        }
+
+        // Cached layers may predate the requested compression, so record what each blob really is
+        let mut layer_media_types = HashMap::new();
+        for digest in &layers {
+            let compression = Compression::detect(&self.store.get_layer_path(digest)?)?;
+            if compression != Compression::Gzip {
+                layer_media_types.insert(digest.clone(), compression.media_type().to_string());
+            }
+        }
+
+        let platform = registry::host_platform();
         let manifest = ImageManifest {
             name: name.to_string(),
             tag: tag.to_string(),
             layers,
+            layer_media_types,
+            architecture: platform.architecture,
+            os: platform.os,
+            variant: platform.variant,
+            digest: String::new(),
+            registry_digest: String::new(),
+            history,
+            created: image::now_rfc3339(),
+            author: String::new(),
+            builder: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
         };
-        self.store.save_manifest(&manifest)?;
-
-        let config_json = serde_json::to_string_pretty(&config)?;
-        let config_path = self.store.root.join("manifests")
-            .join(name)
-            .join(format!("{}.config", tag));
-        fs::write(config_path, config_json)?;
+        let digest = self.store.save_image(manifest, config)?;
 
         // Cleanup build directory
         let _ = fs::remove_dir_all(&build_dir);
 
-        info!("  ✅ Build complete: {}:{}", name, tag);
+        info!("  ✅ Build complete: {}:{} ({})", name, tag, digest);
         Ok(())
     }
 
@@ -174,11 +272,11 @@ impl ImageBuilder {
         format!("cache:{}", hex::encode(hasher.finalize()))
     }
 
-    fn hash_path(&self, path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    fn hash_path(&self, path: &Path) -> Result<String, ForgeError> {
         let mut hasher = Sha256::new();
 
         if path.is_file() {
-            hasher.update(&fs::read(path)?);
+            hash_reader(&mut fs::File::open(path)?, &mut hasher)?;
         } else if path.is_dir() {
             self.hash_dir_recursive(path, &mut hasher)?;
         }
@@ -186,7 +284,7 @@ impl ImageBuilder {
         Ok(hex::encode(hasher.finalize()))
     }
 
-    fn hash_dir_recursive(&self, dir: &Path, hasher: &mut Sha256) -> Result<(), Box<dyn std::error::Error>> {
+    fn hash_dir_recursive(&self, dir: &Path, hasher: &mut Sha256) -> Result<(), ForgeError> {
         let mut entries: Vec<_> = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
         entries.sort_by_key(|e| e.path());
 
@@ -195,7 +293,7 @@ impl ImageBuilder {
             hasher.update(path.file_name().unwrap().to_string_lossy().as_bytes());
 
             if path.is_file() {
-                hasher.update(&fs::read(&path)?);
+                hash_reader(&mut fs::File::open(&path)?, hasher)?;
             } else if path.is_dir() {
                 self.hash_dir_recursive(&path, hasher)?;
             }
@@ -203,56 +301,41 @@ impl ImageBuilder {
         Ok(())
     }
 
-    fn extract_layer(&self, digest: &str, rootfs: &Path) -> Result<(), Box<dyn std::error::Error>> {
-        let layer_path = self.store.get_layer_path(digest);
-        Command::new("tar")
-            .args(&["-xzf", layer_path.to_str().unwrap(), "-C", rootfs.to_str().unwrap()])
-            .status()?;
-        Ok(())
+    fn extract_layer(&self, digest: &str, rootfs: &Path) -> Result<(), ForgeError> {
+        let layer_path = self.store.get_layer_path(digest)?;
+        layer::apply_verified_layer(&layer_path, digest, Compression::detect(&layer_path)?, rootfs)
     }
 
-    fn pull_base_image(&self, image: &str, dest: &Path) -> Result<(), Box<dyn std::error::Error>> {
-        if image.starts_with("alpine") {
-            let arch = std::env::consts::ARCH;
-            let alpine_arch = match arch {
-                "x86_64" => "x86_64",
-                "aarch64" => "aarch64",
-                _ => return Err(format!("Unsupported architecture: {}", arch).into()),
-            };
-
-            // Check if we have a cached alpine download
-            let alpine_cache = PathBuf::from(format!(
-                "{}/alpine-{}.tar.gz",
-                self.store.root.to_str().unwrap(),
-                alpine_arch
-            ));
-
-            if !alpine_cache.exists() {
-                let url = format!(
-                    "https://dl-cdn.alpinelinux.org/alpine/v3.19/releases/{}/alpine-minirootfs-3.19.1-{}.tar.gz",
-                    alpine_arch, alpine_arch
-                );
-
-                info!("    Downloading Alpine for {}...", alpine_arch);
-                let output = Command::new("curl")
-                    .args(&["-L", "-o", alpine_cache.to_str().unwrap(), &url])
-                    .output()?;
-
-                if !output.status.success() {
-                    return Err("Failed to download base image".into());
-                }
+    // Unpack the base image into `dest`, returning its config; distribution rootfs tarballs have none
+    fn pull_base_image(&self, image: &str, dest: &Path) -> Result<Option<ImageConfig>, ForgeError> {
+        // Images already in the local store win over the registry, using the entry for this host
+        if let Ok(PlatformImage { manifest, config }) = self.store.resolve(image) {
+            if !manifest.runs_on_host() {
+                return Err(ForgeError::Image(format!("{} is a {} image, this host is {}", image, manifest.platform(), registry::host_platform())));
+            }
+            verify_digest(&manifest, &config)?;
+            info!("    Using local {} image {}", manifest.platform(), image);
+            for digest in &manifest.layers {
+                let compression = self.store.layer_compression(&manifest, digest)?;
+                layer::apply_verified_layer(&self.store.get_layer_path(digest)?, digest, compression, dest)?;
             }
+            return Ok(Some(config));
+        }
 
-            Command::new("tar")
-                .args(&["-xzf", alpine_cache.to_str().unwrap(), "-C", dest.to_str().unwrap()])
-                .status()?;
-        } else {
-            return Err(format!("Unsupported base image: {}. Only 'alpine:*' is supported.", image).into());
+        if let Some((source, tag)) = baseimage::find(image) {
+            info!("    Fetching {}:{} for {}...", source.name, tag, std::env::consts::ARCH);
+            baseimage::fetch(source, &tag, dest)?;
+            return Ok(None);
         }
-        Ok(())
+        // Anything else is resolved against its registry; blobs land in the layer store
+        let blob_dir = self.store.root.join("layers");
+        Ok(Some(registry::pull_image(image, dest, &blob_dir)?))
     }
 
-    fn run_in_chroot(&self, rootfs: &Path, command: &str) -> Result<(), Box<dyn std::error::Error>> {
+    fn run_in_chroot(&self, rootfs: &Path, command: &str) -> Result<(), ForgeError> {
+        if !filesystem::has_shell(rootfs) {
+            return Err(ForgeError::Build(format!("RUN {}: the image has no /bin/sh (FROM scratch images can only COPY)", command)));
+        }
         let resolv_conf = rootfs.join("etc/resolv.conf");
 
         if let Some(parent) = resolv_conf.parent() {
@@ -266,30 +349,39 @@ impl ImageBuilder {
             .arg("/bin/sh")
             .arg("-c")
             .arg(command)
-            .status()?;
+            .status()
+            .map_err(|e| ForgeError::Build(format!("RUN {}: failed to start chroot: {}", command, e)))?;
 
         if !status.success() {
-            return Err(format!("RUN command failed: {}", command).into());
+            return Err(ForgeError::Build(format!("RUN command failed: {}", command)));
         }
         Ok(())
     }
 
-    fn create_layer(&self, rootfs: &Path) -> Result<String, Box<dyn std::error::Error>> {
-        let layer_id = uuid::Uuid::new_v4();
-        let tarball_path = PathBuf::from(format!("/tmp/layer-{}.tar.gz", layer_id));
-
-        Command::new("tar")
-            .args(&["-czf", tarball_path.to_str().unwrap(), "-C", rootfs.to_str().unwrap(), "."])
-            .status()?;
+    fn create_layer(&self, rootfs: &Path) -> Result<String, ForgeError> {
+        self.store.pack_layer(rootfs, self.compression)
+    }
+}
 
-        let digest = self.store.save_layer(&tarball_path)?;
-        fs::remove_file(&tarball_path)?;
+// The built image starts out configured like its base: environment, entrypoint and command, working directory, user, labels and ports
+fn inherit_config(config: &mut ImageConfig, base: Option<ImageConfig>) {
+    let Some(base) = base else { return };
+    let path = config.env.iter().find(|e| e.starts_with("PATH=")).cloned();
+    *config = base;
+    // Keep a PATH for RUN and the container if the base image doesn't set one
+    if let Some(path) = path.filter(|_| !config.env.iter().any(|e| e.starts_with("PATH="))) {
+        config.env.insert(0, path);
+    }
+}
 
-        Ok(digest)
+// Record how long the most recent step took
+fn finish_step(history: &mut [HistoryEntry], started: Instant) {
+    if let Some(last) = history.last_mut() {
+        last.duration_ms = started.elapsed().as_millis() as u64;
     }
 }
 
-fn copy_dir(src: &Path, dest: &Path) -> Result<(), Box<dyn std::error::Error>> {
+fn copy_dir(src: &Path, dest: &Path) -> Result<(), ForgeError> {
     fs::create_dir_all(dest)?;
     for entry in fs::read_dir(src)? {
         let entry = entry?;
@@ -299,7 +391,7 @@ fn copy_dir(src: &Path, dest: &Path) -> Result<(), Box<dyn std::error::Error>> {
         if file_type.is_dir() {
             copy_dir(&entry.path(), &dest_path)?;
         } else {
-            fs::copy(&entry.path(), &dest_path)?;
+            fs::copy(entry.path(), &dest_path)?;
         }
     }
     Ok(())
@@ -0,0 +1,90 @@
+use std::io::IsTerminal;
+use std::process;
+use std::sync::atomic::{AtomicI32, Ordering};
+use nix::libc;
+use nix::sys::signal::{kill, sigaction, sigprocmask, SaFlags, SigAction, SigHandler, SigSet, SigmaskHow, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, setpgid, tcsetpgrp, ForkResult, Pid};
+use log::{debug, error};
+
+// With --init the container's PID 1 is this rather than the entrypoint. A PID 1 has no default
+// signal handling, so an entrypoint that never expected to be one ignores SIGTERM from `stop`
+// and leaves its orphans as zombies. This init runs the entrypoint as its child, in its own
+// process group, passes signals on to that group and reaps whatever gets reparented to it.
+
+// Every other signal is passed on, so an image's STOPSIGNAL and `kill -s` reach the entrypoint
+// whatever they are. These can't be caught, are how init learns of exits, or are raised by
+// init's own faults or terminal access.
+const NOT_FORWARDED: &[Signal] = &[
+    Signal::SIGKILL, Signal::SIGSTOP, Signal::SIGCHLD, Signal::SIGPIPE, Signal::SIGTTIN, Signal::SIGTTOU,
+    Signal::SIGSEGV, Signal::SIGBUS, Signal::SIGFPE, Signal::SIGILL, Signal::SIGTRAP, Signal::SIGSYS, Signal::SIGABRT,
+];
+
+fn forwarded() -> impl Iterator<Item = Signal> {
+    Signal::iterator().filter(|signal| !NOT_FORWARDED.contains(signal))
+}
+
+// The entrypoint's process group, for the signal handler
+static CHILD_GROUP: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward(signal: libc::c_int) {
+    let group = CHILD_GROUP.load(Ordering::SeqCst);
+    if group > 0 {
+        unsafe { libc::kill(-group, signal) };
+    }
+}
+
+/// Fork the entrypoint's process off PID 1, which stays behind as init and exits with the
+/// entrypoint's code; returns only in the entrypoint's process
+pub fn run() {
+    // Held back until the handlers are in place, so none is lost around the fork
+    let mut signals = SigSet::empty();
+    for signal in forwarded() {
+        signals.add(signal);
+    }
+    let _ = sigprocmask(SigmaskHow::SIG_BLOCK, Some(&signals), None);
+
+    match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+            // An interactive entrypoint must be the terminal's foreground group to read from it
+            if std::io::stdin().is_terminal() {
+                let ignore = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
+                let _ = unsafe { sigaction(Signal::SIGTTOU, &ignore) };
+                let _ = tcsetpgrp(std::io::stdin(), nix::unistd::getpgrp());
+                let _ = unsafe { sigaction(Signal::SIGTTOU, &SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty())) };
+            }
+            let _ = sigprocmask(SigmaskHow::SIG_UNBLOCK, Some(&signals), None);
+        }
+        Ok(ForkResult::Parent { child }) => {
+            // Also from this side, so a signal can't reach the group before it exists
+            let _ = setpgid(child, child);
+            CHILD_GROUP.store(child.as_raw(), Ordering::SeqCst);
+            let handler = SigAction::new(SigHandler::Handler(forward), SaFlags::SA_RESTART, SigSet::empty());
+            for signal in forwarded() {
+                let _ = unsafe { sigaction(signal, &handler) };
+            }
+            let _ = sigprocmask(SigmaskHow::SIG_UNBLOCK, Some(&signals), None);
+            debug!("Init started entrypoint as PID {}", child);
+
+            loop {
+                match waitpid(Pid::from_raw(-1), None) {
+                    Ok(WaitStatus::Exited(pid, code)) if pid == child => process::exit(code),
+                    Ok(WaitStatus::Signaled(pid, signal, _)) if pid == child => process::exit(128 + signal as i32),
+                    Ok(status) => debug!("Reaped {:?}", status),
+                    Err(nix::errno::Errno::EINTR) => {}
+                    Err(e) => {
+                        error!("Init lost track of the entrypoint: {}", e);
+                        // Whatever is left would be killed along with PID 1 anyway
+                        let _ = kill(child, Signal::SIGKILL);
+                        process::exit(1);
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            error!("Init failed to fork: {}", e);
+            process::exit(1);
+        }
+    }
+}
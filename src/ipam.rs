@@ -0,0 +1,146 @@
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use nix::fcntl::{Flock, FlockArg};
+use log::debug;
+
+use crate::config;
+use crate::recover;
+use crate::error::ForgeError;
+
+// Every running container leases an address of its own on the forge0 bridge, out of the subnet
+// in $FORGE_SUBNET, 10.0.0.0/16 unless set. The bridge has the first address and is every
+// container's gateway. Leases are files under the state root:
+//   network/leases/<address>   the directory of the container holding the address
+//   network/leases.lock        held while a lease is handed out
+// A lease whose container directory isn't locked any more is stale and handed out again.
+
+/// The subnet containers get their addresses from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pool {
+    base: u32,
+    pub prefix_len: u8,
+}
+
+impl Pool {
+    /// $FORGE_SUBNET, such as 10.88.0.0/16
+    pub fn configured() -> Result<Pool, ForgeError> {
+        let subnet = config::subnet();
+        let invalid = || format!("Invalid subnet {:?}: use an IPv4 network such as 10.0.0.0/16", subnet);
+        let (address, prefix_len) = subnet.split_once('/').ok_or_else(invalid)?;
+        let address: Ipv4Addr = address.parse().map_err(|_| invalid())?;
+        let prefix_len: u8 = prefix_len.parse().map_err(|_| invalid())?;
+        // Room for the bridge and at least one container
+        if !(8..=30).contains(&prefix_len) {
+            return Err(format!("A subnet of /{} is out of range; use /8 to /30", prefix_len).into());
+        }
+        let pool = Pool { base: u32::from(address), prefix_len };
+        if pool.base & pool.host_mask() != 0 {
+            return Err(format!("{} isn't the start of a /{} network", address, prefix_len).into());
+        }
+        Ok(pool)
+    }
+
+    fn host_mask(&self) -> u32 {
+        u32::MAX >> self.prefix_len
+    }
+
+    /// The bridge's address, and the containers' default route
+    pub fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.base + 1)
+    }
+
+    // Every address but the network's own, the bridge's and the broadcast address
+    fn hosts(&self) -> impl Iterator<Item = u32> {
+        self.base + 2..self.base + self.host_mask()
+    }
+
+    fn contains(&self, address: Ipv4Addr) -> bool {
+        let address = u32::from(address);
+        address > self.base + 1 && address < self.base + self.host_mask()
+    }
+}
+
+impl std::fmt::Display for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", Ipv4Addr::from(self.base), self.prefix_len)
+    }
+}
+
+/// One container's address, and the subnet it is on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subnet {
+    address: Ipv4Addr,
+    pub pool: Pool,
+}
+
+impl Subnet {
+    /// The bridge, and the container's default route
+    pub fn gateway(&self) -> Ipv4Addr {
+        self.pool.gateway()
+    }
+
+    /// The container's own address
+    pub fn address(&self) -> Ipv4Addr {
+        self.address
+    }
+}
+
+/// An address held for a container until `release`
+pub struct Lease {
+    pub subnet: Subnet,
+    path: PathBuf,
+}
+
+impl Lease {
+    pub fn release(self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The first free address, for the container in `owner`, which its caller has locked
+pub fn acquire(owner: &Path) -> Result<Lease, ForgeError> {
+    let pool = Pool::configured()?;
+    let dir = leases_dir()?;
+    let _lock = lock(&dir)?;
+    for address in pool.hosts() {
+        if let Some(lease) = claim(&dir, Subnet { address: Ipv4Addr::from(address), pool }, owner)? {
+            return Ok(lease);
+        }
+    }
+    Err(ForgeError::network(format!("lease an address in {}", pool), "every address is taken"))
+}
+
+/// `address` itself, e.g. for a container restored from a checkpoint
+pub fn acquire_address(owner: &Path, address: Ipv4Addr) -> Result<Lease, ForgeError> {
+    let pool = Pool::configured()?;
+    if !pool.contains(address) {
+        return Err(format!("{} is outside {}", address, pool).into());
+    }
+    let dir = leases_dir()?;
+    let _lock = lock(&dir)?;
+    claim(&dir, Subnet { address, pool }, owner)?.ok_or_else(|| format!("{} is in use by another container", address).into())
+}
+
+fn claim(dir: &Path, subnet: Subnet, owner: &Path) -> Result<Option<Lease>, ForgeError> {
+    let path = dir.join(subnet.address.to_string());
+    if let Ok(holder) = fs::read_to_string(&path) {
+        if recover::is_locked(Path::new(&holder)) {
+            return Ok(None);
+        }
+        debug!("Taking over stale lease of {} from {}", subnet.address(), holder);
+    }
+    fs::write(&path, owner.to_string_lossy().as_bytes())?;
+    Ok(Some(Lease { subnet, path }))
+}
+
+fn lock(dir: &Path) -> Result<Flock<fs::File>, ForgeError> {
+    let file = fs::File::create(dir.with_extension("lock"))?;
+    Flock::lock(file, FlockArg::LockExclusive).map_err(|(_, e)| format!("Failed to lock {:?}: {}", dir, e).into())
+}
+
+fn leases_dir() -> Result<PathBuf, ForgeError> {
+    let dir = config::root()?.join("network").join("leases");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
@@ -0,0 +1,429 @@
+use std::path::{Component, Path, PathBuf};
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::process::Command;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use log::{debug, info};
+
+use crate::error::ForgeError;
+use crate::registry::Hashing;
+use crate::rootless;
+use crate::tarball;
+use crate::tools;
+
+pub const MEDIA_TYPE_LAYER_TAR: &str = "application/vnd.oci.image.layer.v1.tar";
+pub const MEDIA_TYPE_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+pub const MEDIA_TYPE_LAYER_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+
+// Upper bound on threads unpacking layers at once
+const MAX_EXTRACT_WORKERS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn parse(s: &str) -> Result<Self, ForgeError> {
+        match s {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            "none" => Ok(Compression::None),
+            other => Err(format!("Unknown compression: {} (expected gzip, zstd or none)", other).into()),
+        }
+    }
+
+    /// Map OCI and Docker layer media types onto a compression
+    pub fn from_media_type(media_type: &str) -> Self {
+        if media_type.ends_with("+gzip") || media_type.ends_with(".gzip") {
+            Compression::Gzip
+        } else if media_type.ends_with("+zstd") {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Sniff the compression from the file's magic bytes
+    pub fn detect(path: &Path) -> Result<Self, ForgeError> {
+        let mut magic = [0u8; 4];
+        let n = fs::File::open(path)?.read(&mut magic)?;
+
+        Ok(match &magic[..n] {
+            [0x1f, 0x8b, ..] => Compression::Gzip,
+            [0x28, 0xb5, 0x2f, 0xfd] => Compression::Zstd,
+            _ => Compression::None,
+        })
+    }
+
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            Compression::None => MEDIA_TYPE_LAYER_TAR,
+            Compression::Gzip => MEDIA_TYPE_LAYER_GZIP,
+            Compression::Zstd => MEDIA_TYPE_LAYER_ZSTD,
+        }
+    }
+}
+
+/// Apply a stored layer on top of `dest`, honouring its whiteouts and failing if it doesn't match `digest`
+pub fn apply_verified_layer(path: &Path, digest: &str, compression: Compression, dest: &Path) -> Result<(), ForgeError> {
+    let staging = sibling(dest, &format!("layer-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&staging)?;
+    let result = extract_verified_layer(path, digest, compression, &staging)
+        .and_then(|_| apply_staged(&staging, dest));
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+// Unpack a stored layer as-is, hashing it on the way through
+fn extract_verified_layer(path: &Path, digest: &str, compression: Compression, dest: &Path) -> Result<(), ForgeError> {
+    let mut file = Hashing::new(io::BufReader::new(fs::File::open(path)?));
+    let result = tarball::decoder(&mut file, compression)
+        .map_err(|e| e.into())
+        .and_then(|reader| tarball::unpack(reader, dest));
+    // Keep hashing if unpacking gives up early, so a corrupt layer is reported as such
+    io::copy(&mut file, &mut io::sink())?;
+
+    let actual = file.digest();
+    if actual != digest {
+        return Err(ForgeError::Image(format!("Layer {} is corrupt or was modified: content hashes to {}", digest, actual)));
+    }
+    result.map_err(|e| ForgeError::Image(format!("Failed to extract layer {}: {}", digest, e)))
+}
+
+// A stored layer to unpack: blob path, digest and compression
+#[derive(Clone)]
+pub struct LayerSource {
+    pub path: PathBuf,
+    pub digest: String,
+    pub compression: Compression,
+}
+
+/// Unpack layers concurrently into per-layer staging directories, then merge them into `dest` in order
+pub fn extract_layers_parallel(layers: &[LayerSource], dest: &Path) -> Result<(), ForgeError> {
+    if layers.len() < 2 {
+        for layer in layers {
+            apply_verified_layer(&layer.path, &layer.digest, layer.compression, dest)?;
+        }
+        return Ok(());
+    }
+
+    let staging = sibling(dest, "staging");
+    fs::create_dir_all(&staging)?;
+    let result = extract_staged(layers, &staging, dest);
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+fn extract_staged(layers: &[LayerSource], staging: &Path, dest: &Path) -> Result<(), ForgeError> {
+    let dirs: Vec<PathBuf> = (0..layers.len()).map(|i| staging.join(i.to_string())).collect();
+    unpack_parallel(layers, &dirs)?;
+
+    // Later layers win, so apply them in order
+    for dir in &dirs {
+        apply_staged(dir, dest)?;
+    }
+    Ok(())
+}
+
+/// Unpack each layer as-is into the matching directory of `dirs`, several at a time
+pub fn unpack_parallel(layers: &[LayerSource], dirs: &[PathBuf]) -> Result<(), ForgeError> {
+    let workers = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(MAX_EXTRACT_WORKERS)
+        .min(layers.len());
+    debug!("Extracting {} layers with {} workers", layers.len(), workers);
+
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<Duration, ForgeError>>>> = Mutex::new((0..layers.len()).map(|_| None).collect());
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let (Some(layer), Some(dir)) = (layers.get(i), dirs.get(i)) else { break };
+
+                let started = Instant::now();
+                let result = fs::create_dir_all(dir)
+                    .map_err(ForgeError::from)
+                    .and_then(|_| extract_verified_layer(&layer.path, &layer.digest, layer.compression, dir))
+                    .map(|_| started.elapsed());
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+
+    let results = results.into_inner().unwrap();
+    for (i, (layer, result)) in layers.iter().zip(results).enumerate() {
+        let elapsed = result.ok_or("Layer extraction did not run")??;
+        info!("  [{}/{}] {} extracted in {:.2}s", i + 1, layers.len(), short(&layer.digest), elapsed.as_secs_f64());
+    }
+    Ok(())
+}
+
+// Scratch directory next to `dest`, so merging can hardlink rather than copy
+fn sibling(dest: &Path, suffix: &str) -> PathBuf {
+    dest.with_file_name(format!("{}-{}", dest.file_name().unwrap_or_default().to_string_lossy(), suffix))
+}
+
+// OCI whiteouts: `.wh.NAME` deletes NAME from lower layers, `.wh..wh..opq` hides a directory's lower contents
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+// Carry out an unpacked layer's deletions on `dest`, then merge in the rest of its contents
+fn apply_staged(layer: &Path, dest: &Path) -> Result<(), ForgeError> {
+    apply_whiteouts(layer, dest, Path::new(""))?;
+    merge_into(layer, dest)
+}
+
+fn apply_whiteouts(layer: &Path, dest: &Path, rel: &Path) -> Result<(), ForgeError> {
+    let dir = layer.join(rel);
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        entries.push(entry?);
+    }
+
+    // The opaque marker clears the lower directory before this layer's own entries land in it
+    if entries.iter().any(|e| e.file_name() == OPAQUE_WHITEOUT) {
+        fs::remove_file(dir.join(OPAQUE_WHITEOUT))?;
+        if let Some(target) = lower_path(dest, rel) {
+            if fs::symlink_metadata(&target).map(|m| m.is_dir()).unwrap_or(false) {
+                for lower in fs::read_dir(&target)? {
+                    remove_path(&lower?.path())?;
+                }
+            }
+        }
+    }
+
+    for entry in entries {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name == OPAQUE_WHITEOUT {
+            continue;
+        }
+        if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            let hidden = whiteout_target(hidden, &dir)?;
+            fs::remove_file(entry.path())?;
+            if let Some(target) = lower_path(dest, &rel.join(hidden)) {
+                debug!("Whiteout removes /{}", rel.join(hidden).display());
+                remove_path(&target)?;
+            }
+            continue;
+        }
+
+        // An entry replaces a lower one of a different kind (e.g. a directory over a symlink)
+        let is_dir = entry.file_type()?.is_dir();
+        let path = rel.join(entry.file_name());
+        if let Some(target) = lower_path(dest, &path) {
+            if fs::symlink_metadata(&target).map(|m| m.is_dir() != is_dir).unwrap_or(false) {
+                remove_path(&target)?;
+            }
+        }
+        if is_dir {
+            apply_whiteouts(layer, dest, &path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Rewrite an unpacked layer's OCI whiteouts into the form overlayfs reads from a lower directory:
+/// a 0/0 character device for a deleted entry and the trusted.overlay.opaque xattr for an opaque one
+pub fn to_overlay_whiteouts(dir: &Path) -> Result<(), ForgeError> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+
+        if name == OPAQUE_WHITEOUT {
+            fs::remove_file(entry.path())?;
+            set_opaque(dir)?;
+        } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            let hidden = whiteout_target(hidden, dir)?;
+            fs::remove_file(entry.path())?;
+            mknod(&dir.join(hidden), SFlag::S_IFCHR, Mode::empty(), makedev(0, 0))
+                .map_err(|e| format!("Failed to create whiteout for {:?}: {}", dir.join(hidden), e))?;
+        } else if entry.file_type()?.is_dir() {
+            to_overlay_whiteouts(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// The reverse of `to_overlay_whiteouts`: for each overlayfs whiteout and opaque directory in
+/// `upper`, put the OCI marker at the same place in `dest`, a copy of `upper` about to be packed
+pub fn to_oci_whiteouts(upper: &Path, dest: &Path) -> Result<(), ForgeError> {
+    for entry in fs::read_dir(upper)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        let copy = dest.join(entry.file_name());
+
+        if is_overlay_whiteout(&meta) {
+            fs::remove_file(&copy)?;
+            let mut marker = std::ffi::OsString::from(WHITEOUT_PREFIX);
+            marker.push(entry.file_name());
+            fs::File::create(dest.join(marker))?;
+        } else if meta.is_dir() {
+            if is_opaque(&entry.path()) {
+                fs::File::create(copy.join(OPAQUE_WHITEOUT))?;
+            }
+            to_oci_whiteouts(&entry.path(), &copy)?;
+        }
+    }
+    Ok(())
+}
+
+/// overlayfs records a deletion as a character device numbered 0/0
+pub fn is_overlay_whiteout(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    meta.file_type().is_char_device() && meta.rdev() == 0
+}
+
+pub fn is_opaque(dir: &Path) -> bool {
+    let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut value = [0u8; 1];
+    let n = unsafe {
+        nix::libc::lgetxattr(path.as_ptr(), opaque_xattr().as_ptr(), value.as_mut_ptr().cast(), 1)
+    };
+    n == 1 && value[0] == b'y'
+}
+
+// overlayfs mounted inside a user namespace reads user.overlay.*, as only the host's root may set trusted.*
+fn opaque_xattr() -> &'static CStr {
+    if rootless::active() { c"user.overlay.opaque" } else { c"trusted.overlay.opaque" }
+}
+
+fn set_opaque(dir: &Path) -> Result<(), ForgeError> {
+    let path = CString::new(dir.as_os_str().as_bytes()).map_err(|_| format!("Failed to mark {:?} opaque: the path has a NUL byte", dir))?;
+    let ret = unsafe {
+        nix::libc::setxattr(path.as_ptr(), opaque_xattr().as_ptr(), b"y".as_ptr().cast(), 1, 0)
+    };
+    if ret != 0 {
+        return Err(format!("Failed to mark {:?} opaque: {}", dir, std::io::Error::last_os_error()).into());
+    }
+    Ok(())
+}
+
+// The entry a `.wh.` marker deletes, which has to be a sibling of the marker: a name like `..`
+// would otherwise take the whiteout out of the layer's directory
+fn whiteout_target<'a>(hidden: &'a str, dir: &Path) -> Result<&'a str, ForgeError> {
+    if hidden.is_empty() || hidden == "." || hidden == ".." || hidden.contains('/') {
+        return Err(ForgeError::Image(format!("Invalid whiteout \"{}{}\" in {:?}", WHITEOUT_PREFIX, hidden, dir)));
+    }
+    Ok(hidden)
+}
+
+// Resolve `rel` inside `dest` without following symlinks, which could point outside the rootfs
+fn lower_path(dest: &Path, rel: &Path) -> Option<PathBuf> {
+    let mut path = dest.to_path_buf();
+    let mut components = rel.components().peekable();
+    while let Some(component) = components.next() {
+        let Component::Normal(name) = component else {
+            return None;
+        };
+        path.push(name);
+        let meta = fs::symlink_metadata(&path).ok()?;
+        if components.peek().is_some() && !meta.is_dir() {
+            return None;
+        }
+    }
+    Some(path)
+}
+
+fn remove_path(path: &Path) -> Result<(), ForgeError> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => return Ok(()),
+    };
+    result.map_err(|e| format!("Failed to remove {:?}: {}", path, e).into())
+}
+
+// Hardlink a staged tree over `dest`, copying when the two are on different filesystems
+fn merge_into(src: &Path, dest: &Path) -> Result<(), ForgeError> {
+    let mut source = src.as_os_str().to_owned();
+    source.push("/.");
+
+    let linked = Command::new("cp")
+        .args(["-a", "--link", "--remove-destination"])
+        .arg(&source).arg(dest)
+        .output()
+        .map_err(|e| tools::spawn_error(&Command::new("cp"), e))?;
+    if linked.status.success() {
+        return Ok(());
+    }
+
+    tools::run(
+        Command::new("cp").args(["-a", "--remove-destination"]).arg(&source).arg(dest),
+        &format!("Failed to apply {:?}", src),
+    )?;
+    Ok(())
+}
+
+fn short(digest: &str) -> &str {
+    &digest[..digest.len().min(19)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("forge-layer-{}-{}", name, uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn whiteouts_remove_lower_entries() {
+        let root = scratch("whiteout");
+        let (layer, dest) = (root.join("layer"), root.join("rootfs"));
+        fs::create_dir_all(layer.join("etc")).unwrap();
+        fs::create_dir_all(dest.join("etc")).unwrap();
+        fs::File::create(layer.join("etc/.wh.motd")).unwrap();
+        fs::write(dest.join("etc/motd"), "hello").unwrap();
+        fs::write(dest.join("etc/hosts"), "").unwrap();
+
+        apply_staged(&layer, &dest).unwrap();
+        assert!(!dest.join("etc/motd").exists());
+        assert!(dest.join("etc/hosts").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn whiteouts_cannot_leave_their_directory() {
+        let root = scratch("traversal");
+        let (layer, dest) = (root.join("layer"), root.join("rootfs"));
+        fs::create_dir_all(&layer).unwrap();
+        fs::create_dir_all(dest.join("bin")).unwrap();
+        fs::write(root.join("sibling"), "").unwrap();
+
+        for name in [".wh...", ".wh..", ".wh."] {
+            fs::File::create(layer.join(name)).unwrap();
+            assert!(matches!(apply_staged(&layer, &dest), Err(ForgeError::Image(_))), "{}", name);
+            assert!(matches!(to_overlay_whiteouts(&layer), Err(ForgeError::Image(_))), "{}", name);
+            fs::remove_file(layer.join(name)).unwrap();
+        }
+        assert!(root.join("sibling").exists());
+        assert!(dest.join("bin").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn lower_path_stays_inside_dest() {
+        let root = scratch("lower");
+        assert_eq!(lower_path(&root, Path::new("..")), None);
+        assert_eq!(lower_path(&root, Path::new("/etc")), None);
+        assert_eq!(lower_path(&root, Path::new("")), Some(root.clone()));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
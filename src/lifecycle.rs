@@ -0,0 +1,507 @@
+use std::ffi::CString;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::fd::OwnedFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use nix::errno::Errno;
+use nix::sched::{setns, CloneFlags};
+use nix::libc;
+use nix::pty::PtyMaster;
+use nix::sys::signal::{kill, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{alarm, chdir, execvp, fork, ForkResult, Pid};
+use log::{debug, error, info, warn};
+
+use crate::cgroups;
+use crate::namespace;
+use crate::error::ForgeError;
+use crate::userns;
+use crate::container::{self, Confinement};
+use crate::rwlayer::{self, ContainerRecord};
+use crate::state::{self, ContainerState, Status};
+use crate::tty;
+use crate::user::{self, User};
+
+// Acting on a container another runtime process is running: everything goes through the PID
+// and status in its state.json, and waits on its lock to know the runtime has cleaned up.
+
+pub const DEFAULT_STOP_TIMEOUT: u64 = 10;
+// The namespaces a container has of its own, in the order exec joins them; the mount
+// namespace goes last, as joining it moves this process into the container's root
+const NAMESPACES: &[(&str, CloneFlags)] = &[
+    ("pid", CloneFlags::CLONE_NEWPID),
+    ("uts", CloneFlags::CLONE_NEWUTS),
+    ("ipc", CloneFlags::CLONE_NEWIPC),
+    ("cgroup", CloneFlags::CLONE_NEWCGROUP),
+    ("time", CloneFlags::from_bits_retain(nix::libc::CLONE_NEWTIME)),
+    ("net", CloneFlags::CLONE_NEWNET),
+    ("mnt", CloneFlags::CLONE_NEWNS),
+];
+
+// Signals the supervisor passes on to the container instead of dying from them. SIGTERM and
+// SIGHUP, as from systemd or a CI timeout, stop the container with its stop signal and
+// SIGKILL it once its stop timeout has run out.
+pub const RELAYED: &[Signal] = &[Signal::SIGINT, Signal::SIGTERM, Signal::SIGQUIT, Signal::SIGHUP];
+
+// The last relayed signal the supervisor caught, until its wait loop passes it on
+static PENDING_SIGNAL: AtomicI32 = AtomicI32::new(0);
+// The process being stopped, which SIGALRM kills at the end of its grace period
+static STOPPING: AtomicI32 = AtomicI32::new(0);
+// Set by SIGALRM once the container's `run --timeout` has run out
+static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+/// "SIGQUIT", "QUIT" or "3"
+pub fn parse_signal(name: &str) -> Result<Signal, ForgeError> {
+    let name = name.trim().to_uppercase();
+    let signal = match name.parse::<i32>() {
+        Ok(number) => Signal::try_from(number).ok(),
+        Err(_) if name.starts_with("SIG") => name.parse().ok(),
+        Err(_) => format!("SIG{}", name).parse().ok(),
+    };
+    signal.ok_or_else(|| format!("Unknown signal {}", name).into())
+}
+
+/// What stops the container: the image's STOPSIGNAL, or SIGTERM
+pub fn stop_signal(record: &ContainerRecord) -> Signal {
+    if record.config.stop_signal.is_empty() {
+        return Signal::SIGTERM;
+    }
+    parse_signal(&record.config.stop_signal).unwrap_or_else(|e| {
+        warn!("{}, stopping with SIGTERM", e);
+        Signal::SIGTERM
+    })
+}
+
+extern "C" fn remember_signal(signal: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // Ctrl-C and friends already reach the container from the terminal; only pass on what was
+    // sent with kill(2), which has a user si_code. A hangup always counts.
+    if unsafe { (*info).si_code } <= 0 || signal == libc::SIGHUP {
+        PENDING_SIGNAL.store(signal, Ordering::SeqCst);
+    }
+}
+
+extern "C" fn time_out(_: libc::c_int) {
+    TIMED_OUT.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn kill_stopping(_: libc::c_int) {
+    let pid = STOPPING.load(Ordering::SeqCst);
+    if pid > 0 {
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+    }
+}
+
+/// Catch SIGINT, SIGTERM, SIGQUIT and SIGHUP in the supervisor so it outlives them and can clean up.
+/// Without SA_RESTART a blocked wait returns EINTR, after which `relay_pending` passes them on.
+pub fn catch_signals() {
+    let action = SigAction::new(SigHandler::SigAction(remember_signal), SaFlags::SA_SIGINFO, SigSet::empty());
+    for signal in RELAYED {
+        if let Err(e) = unsafe { sigaction(*signal, &action) } {
+            warn!("Failed to catch {}: {}", signal, e);
+        }
+    }
+}
+
+/// Put back the default handling of the relayed signals, in a process forked from the supervisor
+pub fn restore_signals() {
+    let action = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+    for signal in RELAYED {
+        let _ = unsafe { sigaction(*signal, &action) };
+    }
+}
+
+/// The relayed signal caught since the last call, if any
+pub fn take_pending() -> Option<Signal> {
+    Signal::try_from(PENDING_SIGNAL.swap(0, Ordering::SeqCst)).ok()
+}
+
+/// Send a caught signal on to the container's PID 1; SIGTERM and SIGHUP stop it gracefully.
+/// Returns whether there was one, which asks the container to stop for good.
+pub fn relay_pending(record: &ContainerRecord) -> bool {
+    let Some(signal) = take_pending() else {
+        return false;
+    };
+    if matches!(signal, Signal::SIGTERM | Signal::SIGHUP) {
+        info!("Got {}, stopping container {}", signal, record.display_name());
+        if !stop_container(record) {
+            debug!("Container {} has no PID 1 yet, dropping {}", record.display_name(), signal);
+        }
+        return true;
+    }
+    match state::read(record).ok().and_then(|s| s.pid) {
+        Some(pid) => {
+            debug!("Relaying {} to PID {}", signal, pid);
+            let _ = kill(Pid::from_raw(pid), signal);
+        }
+        None => debug!("Container {} has no PID 1 yet, dropping {}", record.display_name(), signal),
+    }
+    true
+}
+
+/// From the supervisor: stop the container's PID 1 the way `stop` does; false if it has none yet
+pub fn stop_container(record: &ContainerRecord) -> bool {
+    let Some((pid, status)) = state::read(record).ok().and_then(|s| Some((s.pid?, s.status))) else {
+        return false;
+    };
+    // A frozen process can't act on the stop signal
+    if status == Status::Paused {
+        let _ = cgroups::freeze(&format!("img-{}", record.id), false);
+    }
+    stop_gracefully(Pid::from_raw(pid), stop_signal(record), stop_timeout(record));
+    true
+}
+
+/// Have SIGALRM interrupt the supervisor's wait once `left` has passed, for `timed_out`
+pub fn set_deadline(left: Duration) {
+    let action = SigAction::new(SigHandler::Handler(time_out), SaFlags::empty(), SigSet::empty());
+    if let Err(e) = unsafe { sigaction(Signal::SIGALRM, &action) } {
+        warn!("Failed to set up the timeout: {}", e);
+    }
+    // Whole seconds, rounded up so it never fires early
+    alarm::set(left.as_secs_f64().ceil().clamp(1.0, u32::MAX as f64) as u32);
+}
+
+/// Whether the deadline from `set_deadline` has passed since the last call
+pub fn timed_out() -> bool {
+    TIMED_OUT.swap(false, Ordering::SeqCst)
+}
+
+/// Send `signal` to `pid` now and SIGKILL once `timeout` seconds have passed, unless
+/// `stop_finished` is called first
+pub fn stop_gracefully(pid: Pid, signal: Signal, timeout: u64) {
+    debug!("Sending {} to PID {}, SIGKILL in {}s", signal, pid, timeout);
+    STOPPING.store(pid.as_raw(), Ordering::SeqCst);
+    let action = SigAction::new(SigHandler::Handler(kill_stopping), SaFlags::empty(), SigSet::empty());
+    if let Err(e) = unsafe { sigaction(Signal::SIGALRM, &action) } {
+        warn!("Failed to set up the stop timeout: {}", e);
+    }
+    alarm::set(timeout.clamp(1, u32::MAX as u64) as u32);
+    let _ = kill(pid, signal);
+}
+
+/// The process `stop_gracefully` stopped has been reaped, so its PID mustn't be killed any more
+pub fn stop_finished() {
+    alarm::cancel();
+    STOPPING.store(0, Ordering::SeqCst);
+}
+
+/// Seconds between the stop signal and SIGKILL: `run --stop-timeout`, or 10
+pub fn stop_timeout(record: &ContainerRecord) -> u64 {
+    record.stop_timeout.unwrap_or(DEFAULT_STOP_TIMEOUT)
+}
+
+// A running container and its PID 1
+fn running(reference: &str) -> Result<(ContainerRecord, ContainerState, Pid), ForgeError> {
+    let record = rwlayer::find(reference)?;
+    let state = state::load(&record)?;
+    match state.pid {
+        Some(pid) if state.is_active() => Ok((record, state, Pid::from_raw(pid))),
+        _ => Err(format!("Container {} is not running", record.display_name()).into()),
+    }
+}
+
+/// The host PID of a running container's PID 1
+pub fn running_pid(reference: &str) -> Result<Pid, ForgeError> {
+    running(reference).map(|(_, _, pid)| pid)
+}
+
+fn wait_until_stopped(record: &ContainerRecord, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while record.is_running() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    true
+}
+
+/// stop [-t SECONDS] CONTAINER...: the stop signal, then SIGKILL once the timeout runs out
+pub fn stop_command(args: &[String]) -> Result<(), ForgeError> {
+    let mut timeout = None;
+    let mut references = Vec::new();
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-t" | "--time" => {
+                let value = args.get(i + 1).ok_or("-t requires a number of seconds")?;
+                timeout = Some(value.parse().map_err(|_| format!("Invalid timeout {:?}", value))?);
+                i += 2;
+            }
+            reference => {
+                references.push(reference);
+                i += 1;
+            }
+        }
+    }
+    if references.is_empty() {
+        return Err("Usage: container-runtime stop [-t SECONDS] CONTAINER...".into());
+    }
+
+    for reference in references {
+        let record = rwlayer::find(reference)?;
+        let timeout = timeout.unwrap_or_else(|| stop_timeout(&record));
+        // Keeps the restart policy from starting it again, also while it waits to
+        if record.is_running() {
+            state::update(&record, |s| s.stopped = true)?;
+        }
+        if state::load(&record)?.status == Status::Restarting {
+            wait_until_stopped(&record, Duration::from_secs(timeout));
+            info!("Stopped container {}", record.display_name());
+            continue;
+        }
+        let (record, state, pid) = match running(reference) {
+            Ok(running) => running,
+            Err(e) => {
+                info!("{}", e);
+                continue;
+            }
+        };
+        // A frozen process can't act on the stop signal
+        if state.status == Status::Paused {
+            cgroups::freeze(&format!("img-{}", record.id), false)?;
+        }
+
+        let signal = stop_signal(&record);
+        debug!("Sending {} to PID {}", signal, pid);
+        let _ = kill(pid, signal);
+        if !wait_until_stopped(&record, Duration::from_secs(timeout)) {
+            warn!("Container {} didn't stop within {}s, killing it", record.display_name(), timeout);
+            let _ = kill(pid, Signal::SIGKILL);
+            wait_until_stopped(&record, Duration::from_secs(DEFAULT_STOP_TIMEOUT));
+        }
+        info!("Stopped container {}", record.display_name());
+    }
+    Ok(())
+}
+
+/// pause CONTAINER / unpause CONTAINER: freeze or thaw every process in the container
+pub fn pause_command(args: &[String]) -> Result<(), ForgeError> {
+    let pause = args[1] == "pause";
+    let reference = args.get(2).ok_or(format!("Usage: container-runtime {} CONTAINER", args[1]))?;
+    let (record, state, _) = running(reference)?;
+
+    let (from, to) = if pause { (Status::Running, Status::Paused) } else { (Status::Paused, Status::Running) };
+    if state.status != from {
+        return Err(format!("Container {} is already {}", record.display_name(), if pause { "paused" } else { "running" }).into());
+    }
+    cgroups::freeze(&format!("img-{}", record.id), pause)?;
+    state::update(&record, |s| s.status = to)?;
+    info!("{} container {}", if pause { "Paused" } else { "Unpaused" }, record.display_name());
+    Ok(())
+}
+
+/// exec [-t] [-u USER[:GROUP]] [-e KEY[=VALUE]]... [-w DIR] CONTAINER COMMAND [ARG...]: run
+/// another process inside a running container, in its cgroup
+pub fn exec_command(args: &[String]) -> Result<i32, ForgeError> {
+    let usage = "Usage: container-runtime exec [-t] [-u USER[:GROUP]] [-e KEY[=VALUE]]... [-w DIR] CONTAINER COMMAND [ARG...]";
+    let mut options = ExecOptions::default();
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-t" | "--tty" => {
+                options.tty = true;
+                i += 1;
+            }
+            "-u" | "--user" => {
+                options.user = Some(args.get(i + 1).ok_or("--user requires USER[:GROUP]")?.clone());
+                i += 2;
+            }
+            "-e" | "--env" => {
+                let variable = args.get(i + 1).ok_or("--env requires KEY=VALUE")?;
+                // A bare KEY passes on this process's value
+                match variable.split_once('=') {
+                    Some(_) => options.env.push(variable.clone()),
+                    None => options.env.extend(std::env::var(variable).ok().map(|value| format!("{}={}", variable, value))),
+                }
+                i += 2;
+            }
+            "-w" | "--workdir" => {
+                options.workdir = Some(args.get(i + 1).ok_or("--workdir requires a directory")?.clone());
+                i += 2;
+            }
+            _ => break,
+        }
+    }
+    let (Some(reference), Some(command)) = (args.get(i), args.get(i + 1..).filter(|c| !c.is_empty())) else {
+        return Err(usage.into());
+    };
+    let (record, state, pid) = running(reference)?;
+    if state.status == Status::Paused {
+        return Err(format!("Container {} is paused", record.display_name()).into());
+    }
+
+    // Open them all first: once in the container's mount namespace, /proc is the container's
+    // One shared with the host, as by --ipc host, is already ours and may be out of reach to join
+    let mut namespaces = Vec::new();
+    for (name, flag) in NAMESPACES {
+        // Kernels before 5.6 have no time namespaces
+        let Ok(own) = fs::File::open(format!("/proc/self/ns/{}", name)) else { continue };
+        let ns = fs::File::open(format!("/proc/{}/ns/{}", pid, name))?;
+        if !userns::same(&ns, &own)? {
+            namespaces.push((ns, *flag));
+        }
+    }
+    // The user namespace those belong to, a rootless runtime's, comes first for the privileges to
+    // join them. One of the container's own comes last, as it has no privileges over them.
+    let owner = userns::owner(&namespaces[0].0)?;
+    let user = fs::File::open(format!("/proc/{}/ns/user", pid))?;
+    if !userns::same(&user, &owner)? {
+        namespaces.push((user, CloneFlags::CLONE_NEWUSER));
+    }
+    if !userns::same(&owner, &fs::File::open("/proc/self/ns/user")?)? {
+        namespaces.insert(0, (owner, CloneFlags::CLONE_NEWUSER));
+    }
+    let confinement = Confinement::of(&record)?;
+    let pty = if options.tty { Some(tty::open_pty()?) } else { None };
+
+    // Joining the namespaces happens in a child, as a process with threads can't join a mount
+    // namespace and one that has joined a PID namespace can't start threads
+    let child = match unsafe { fork() }? {
+        ForkResult::Child => {
+            let code = match exec_in_container(&record, &namespaces, pty.as_ref().map(|p| &p.slave), &options, &confinement, command) {
+                Ok(code) => code,
+                Err(e) => {
+                    error!("Failed to exec {}: {}", command[0], e);
+                    126
+                }
+            };
+            std::process::exit(code);
+        }
+        ForkResult::Parent { child } => child,
+    };
+
+    if let Some(pty) = pty {
+        drop(pty.slave);
+        forward_terminal(pty.master);
+    }
+    Ok(match waitpid(child, None)? {
+        WaitStatus::Exited(_, code) => code,
+        WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
+        _ => 1,
+    })
+}
+
+#[derive(Default)]
+struct ExecOptions {
+    tty: bool,
+    user: Option<String>,
+    env: Vec<String>,           // after the image's, so they win
+    workdir: Option<String>,
+}
+
+// In the forked child: join the cgroup and namespaces, then run the command in a child of our
+// own, which is the first to be in the PID namespace, and return its exit code
+fn exec_in_container(record: &ContainerRecord, namespaces: &[(fs::File, CloneFlags)], tty: Option<&OwnedFd>, options: &ExecOptions, confinement: &Confinement, command: &[String]) -> Result<i32, ForgeError> {
+    // Through the host's /sys/fs/cgroup, before the mount namespace hides it
+    if let Err(e) = cgroups::add_process_to_cgroup(&format!("img-{}", record.id), Pid::this()) {
+        warn!("{}", e);
+    }
+    confinement.set_ulimits()?;
+    // PID 1's session keyring is found by name in the user namespace it was created in, the
+    // one outside the container's own, which comes last
+    let outside = match namespaces.last() {
+        Some((_, flag)) if *flag == CloneFlags::CLONE_NEWUSER => namespaces.len() - 1,
+        _ => namespaces.len(),
+    };
+    for (i, (file, flag)) in namespaces.iter().enumerate() {
+        if i == outside {
+            namespace::join_session_keyring(&format!("img-{}", record.id))?;
+        }
+        setns(file, *flag).map_err(|e| ForgeError::namespace("join the container's namespaces", e))?;
+    }
+    if outside == namespaces.len() {
+        namespace::join_session_keyring(&format!("img-{}", record.id))?;
+    }
+    // Names are looked up in the container's /etc. In a user namespace of the container's own
+    // this process is nobody until it becomes someone mapped there.
+    let user = match &options.user {
+        Some(spec) => User::resolve(spec)?,
+        None => user::default_user(&record.config.user, record.userns.as_ref())?,
+    };
+
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            if let Some(slave) = tty {
+                if let Err(e) = tty::make_controlling(slave) {
+                    error!("Failed to set up the terminal: {}", e);
+                    std::process::exit(126);
+                }
+            }
+            // What PID 1 got, so prompts and scripts see the same name, and then `-e`
+            let env: Vec<String> = record.config.env.iter().chain(&options.env).cloned().collect();
+            container::set_environment(&record.network.hostname, options.tty, &env);
+            match &options.workdir {
+                Some(dir) => {
+                    if let Err(e) = chdir(dir.as_str()) {
+                        error!("Failed to change to {}: {}", dir, e);
+                        std::process::exit(126);
+                    }
+                }
+                None => {
+                    if chdir(record.config.working_dir.as_str()).is_err() {
+                        let _ = chdir("/");
+                    }
+                }
+            }
+            // Confined as the container's own processes are
+            if let Err(e) = confinement.attach_labels().and_then(|_| confinement.limit()) {
+                error!("{}", e);
+                std::process::exit(126);
+            }
+            if let Err(e) = user.switch() {
+                error!("Failed to become {}: {}", options.user.clone().unwrap_or_else(|| format!("{}:{}", user.uid, user.gid)), e);
+                std::process::exit(126);
+            }
+            if let Err(e) = confinement.finish() {
+                error!("{}", e);
+                std::process::exit(126);
+            }
+            let argv: Vec<CString> = command.iter().map(|a| CString::new(a.as_str())).collect::<Result<_, _>>()
+                .map_err(|_| ForgeError::Exec { program: command[0].clone(), cause: Errno::EINVAL })?;
+            let e = execvp(&argv[0], &argv).unwrap_err();
+            error!("Failed to exec {}: {}", command[0], e);
+            std::process::exit(127);
+        }
+        ForkResult::Parent { child } => Ok(match waitpid(child, None)? {
+            WaitStatus::Exited(_, code) => code,
+            WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
+            _ => 1,
+        }),
+    }
+}
+
+// Copy between the host terminal, in raw mode, and the exec'd process's until it has exited
+fn forward_terminal(master: PtyMaster) {
+    let master = Arc::new(master);
+    let raw_mode = tty::enter_raw_mode();
+    tty::watch_resize();
+    let input = master.clone();
+    thread::spawn(move || {
+        tty::block_signals();
+        let _ = std::io::copy(&mut std::io::stdin().lock(), &mut &*input);
+    });
+    let mut stdout = std::io::stdout();
+    let mut buffer = [0u8; 8192];
+    loop {
+        match (&*master).read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                if stdout.write_all(&buffer[..n]).and_then(|_| stdout.flush()).is_err() {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                if let (true, Some((rows, columns))) = (tty::resized(), tty::window_size()) {
+                    tty::set_window_size(&*master, rows, columns);
+                }
+            }
+            // EIO once everything in the container has closed the terminal
+            Err(_) => break,
+        }
+    }
+    drop(raw_mode);
+}
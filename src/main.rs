@@ -7,16 +7,64 @@ mod network;
 mod image;
 mod forgefile;
 mod imagebuilder;
+mod registry;
+mod distribution;
+mod auth;
+mod http;
+mod archive;
+mod gc;
+mod layer;
+mod tarball;
+mod signing;
+mod store;
+mod tools;
+mod baseimage;
+mod config;
+mod snapshot;
+mod rwlayer;
+mod volumes;
+mod netconfig;
+mod quota;
+mod recover;
+mod init;
+mod state;
+mod lifecycle;
+mod tty;
+mod stdio;
+mod restart;
+mod checkpoint;
+mod hooks;
+mod ipam;
+mod attach;
+mod health;
+mod error;
+mod rootless;
+mod userns;
+mod capabilities;
+mod seccomp;
+mod apparmor;
+mod syscalls;
+mod selinux;
+mod spawn;
+mod rlimits;
+mod user;
+mod landlock;
+mod oom;
+mod devices;
 
 use nix::unistd::{fork, ForkResult};
-use nix::sys::wait::waitpid;
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::fcntl::{Flock, FlockArg};
 use std::process;
 use std::env;
-use log::{debug, info, error};
+use log::{debug, info, warn, error};
 
 use container::run_container;
+use error::ForgeError;
 use cgroups::cleanup_cgroup;
-use image::{build_image, run_image};
+use image::{build_image, run_image, push_image, pull_image, image_command, images_command, history_command};
 
 fn main() {
     // Initialize logger - defaults to "info", use RUST_LOG=debug for verbose
@@ -26,43 +74,339 @@ fn main() {
 
     debug!("Starting container runtime (PID: {})...", process::id());
 
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    if let Err(e) = config::take_global_flags(&mut args) {
+        error!("{}", e);
+        process::exit(exit_code(&e));
+    }
+    if let Err(e) = rootless::enter(args.get(1).map(String::as_str)) {
+        error!("{}", e);
+        process::exit(exit_code(&e));
+    }
 
     if args.len() > 1 && args[1] == "build" {
         if let Err(e) = build_image(&args) {
             error!("Build failed: {}", e);
-            process::exit(1);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "push" {
+        if let Err(e) = push_image(&args) {
+            error!("Push failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "pull" {
+        if let Err(e) = pull_image(&args) {
+            error!("Pull failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "images" {
+        if let Err(e) = images_command(&args[2..]) {
+            error!("Listing images failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "history" {
+        if let Err(e) = history_command(&args) {
+            error!("History failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "image" {
+        if let Err(e) = image_command(&args) {
+            error!("Image command failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "load" {
+        if let Err(e) = archive::load_image(&args) {
+            error!("Load failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "save" {
+        if let Err(e) = archive::save_image(&args) {
+            error!("Save failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "login" {
+        if let Err(e) = auth::login(&args) {
+            error!("Login failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "store" {
+        if let Err(e) = store::store_command(&args) {
+            error!("Store command failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "builder" {
+        if let Err(e) = gc::builder_command(&args) {
+            error!("Builder command failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "sign" {
+        if let Err(e) = signing::sign_command(&args) {
+            error!("Sign failed: {}", e);
+            process::exit(exit_code(&e));
         }
         return;
     }
 
     if args.len() > 1 && args[1] == "run" {
-        if args.len() < 3 {
-            error!("Usage: container-runtime run IMAGE:TAG");
-            process::exit(1);
+        match run_image(&args) {
+            Ok(code) => process::exit(code),
+            Err(e) => {
+                error!("Run failed: {}", e);
+                process::exit(exit_code(&e));
+            }
+        }
+    }
+
+    if args.len() > 1 && args[1] == "start" {
+        match image::start_container(&args) {
+            Ok(code) => process::exit(code),
+            Err(e) => {
+                error!("Start failed: {}", e);
+                process::exit(exit_code(&e));
+            }
+        }
+    }
+
+    if args.len() > 1 && args[1] == "rm" {
+        if let Err(e) = rwlayer::rm_command(&args) {
+            error!("Remove failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "diff" {
+        if let Err(e) = rwlayer::diff_command(&args) {
+            error!("Diff failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "commit" {
+        if let Err(e) = rwlayer::commit_command(&args) {
+            error!("Commit failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "volume" {
+        if let Err(e) = volumes::volume_command(&args) {
+            error!("Volume command failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "ps" {
+        if let Err(e) = state::ps_command(&args) {
+            error!("Ps failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "inspect" {
+        if let Err(e) = state::inspect_command(&args) {
+            error!("Inspect failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "stats" {
+        if let Err(e) = state::stats_command(&args) {
+            error!("Stats failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "stop" {
+        if let Err(e) = lifecycle::stop_command(&args) {
+            error!("Stop failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "update" {
+        if let Err(e) = restart::update_command(&args) {
+            error!("Update failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "attach" {
+        match attach::attach_command(&args) {
+            Ok(code) => process::exit(code),
+            Err(e) => {
+                error!("Attach failed: {}", e);
+                process::exit(exit_code(&e));
+            }
+        }
+    }
+
+    if args.len() > 1 && args[1] == "checkpoint" {
+        if let Err(e) = checkpoint::checkpoint_command(&args) {
+            error!("Checkpoint failed: {}", e);
+            process::exit(exit_code(&e));
         }
-        if let Err(e) = run_image(&args[2]) {
-            error!("Run failed: {}", e);
-            process::exit(1);
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "restore" {
+        if let Err(e) = checkpoint::restore_command(&args) {
+            error!("Restore failed: {}", e);
+            process::exit(exit_code(&e));
         }
         return;
     }
 
-    // Default: run interactive container
+    if args.len() > 1 && (args[1] == "pause" || args[1] == "unpause") {
+        if let Err(e) = lifecycle::pause_command(&args) {
+            error!("{} failed: {}", args[1], e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "exec" {
+        match lifecycle::exec_command(&args) {
+            Ok(code) => process::exit(code),
+            Err(e) => {
+                error!("Exec failed: {}", e);
+                process::exit(exit_code(&e));
+            }
+        }
+    }
+
+    if args.len() > 1 && args[1] == "system" {
+        if let Err(e) = recover::system_command(&args) {
+            error!("System command failed: {}", e);
+            process::exit(exit_code(&e));
+        }
+        return;
+    }
+
+    // Default: run interactive container, in a directory under the state root that goes when it exits
+    let container_dir = match config::containers_dir() {
+        Ok(dir) => dir.join(format!("shell-{}", uuid::Uuid::new_v4())),
+        Err(e) => {
+            error!("Failed to create container directory: {}", e);
+            process::exit(exit_code(&e));
+        }
+    };
+    // Held for as long as the shell runs, so `system recover` leaves the directory alone
+    let _running = match lock_shell_dir(&container_dir) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("Failed to lock container directory: {}", e);
+            process::exit(exit_code(&e));
+        }
+    };
+    let lease = match ipam::acquire(&container_dir) {
+        Ok(lease) => lease,
+        Err(e) => {
+            error!("Failed to get an address for the container: {}", e);
+            process::exit(exit_code(&e));
+        }
+    };
+    // Named like its directory, so shells running side by side each have their own
+    let cgroup = container_dir.file_name().unwrap_or_default().to_string_lossy().into_owned();
     match unsafe { fork() } {
         Ok(ForkResult::Parent { child }) => {
             debug!("Waiting for container process: {}", child);
-            let _ = waitpid(child, None);
-            info!("Container exited");
-            cleanup_cgroup("my_container");
-            process::exit(0);
+            // Signals are passed on to the runtime below, which stops the shell and returns
+            lifecycle::catch_signals();
+            let status = loop {
+                match waitpid(child, None) {
+                    Err(Errno::EINTR) => {
+                        if let Some(signal) = lifecycle::take_pending() {
+                            let _ = kill(child, signal);
+                        }
+                    }
+                    status => break status,
+                }
+            };
+            // The child exits with the shell's code, so this process exits with it too
+            let code = match status {
+                Ok(WaitStatus::Exited(_, code)) => code,
+                Ok(WaitStatus::Signaled(_, signal, _)) => 128 + signal as i32,
+                _ => 1,
+            };
+            info!("Container exited with {}", code);
+            if let Err(e) = cleanup_cgroup(&cgroup) {
+                warn!("{}", e);
+            }
+            lease.release();
+            let _ = std::fs::remove_dir_all(&container_dir);
+            process::exit(code);
         }
         Ok(ForkResult::Child) => {
-            run_container();
+            let code = run_container(&container_dir.join("rootfs").to_string_lossy(), &cgroup, lease.subnet).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit_code(&e)
+            });
+            process::exit(code);
         }
         Err(e) => {
             error!("Fork failed: {}", e);
-            process::exit(1);
+            process::exit(exit_code(&ForgeError::Sys(e)));
         }
     }
 }
+
+/// What the process exits with after failing with `error`, like other container runtimes do:
+/// 125 when the runtime itself failed, 126 when the container's command couldn't be run and
+/// 127 when it doesn't exist. Everything else, a failed build included, is 1.
+pub fn exit_code(error: &ForgeError) -> i32 {
+    match error {
+        ForgeError::Exec { cause: Errno::ENOENT, .. } => 127,
+        ForgeError::Exec { .. } => 126,
+        ForgeError::Child { code, .. } => *code,
+        ForgeError::Build(_) | ForgeError::Io(_) | ForgeError::Json(_) | ForgeError::Sys(_) | ForgeError::Other(_) => 1,
+        _ => 125,
+    }
+}
+
+fn lock_shell_dir(dir: &std::path::Path) -> Result<Flock<std::fs::File>, ForgeError> {
+    std::fs::create_dir_all(dir)?;
+    let file = std::fs::File::create(dir.join(rwlayer::LOCK_FILE))?;
+    Flock::lock(file, FlockArg::LockExclusiveNonblock).map_err(|(_, e)| e.into())
+}
@@ -1,31 +1,190 @@
-use nix::sched::{unshare, CloneFlags};
-use std::process;
-use log::{debug, error};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sched::{setns, unshare, CloneFlags};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use log::debug;
 
-pub fn create_namespaces_without_network() {
-    debug!("Creating namespaces (PID, Mount, UTS)...");
+use crate::error::ForgeError;
+use crate::lifecycle;
+use crate::rwlayer;
 
-    let flags = CloneFlags::CLONE_NEWPID |
-                CloneFlags::CLONE_NEWNS |
-                CloneFlags::CLONE_NEWUTS;
+/// Whether a container has a namespace of its own or shares the host's or another container's,
+/// from `run --ipc`, `--net` and `--pid`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NamespaceMode {
+    #[default]
+    Private,
+    Host,
+    Container(String),          // the id of the container whose namespace is shared
+}
+
+impl NamespaceMode {
+    pub fn is_default(&self) -> bool {
+        *self == NamespaceMode::Private
+    }
+
+    /// private, host or container:NAME|ID, for `flag`
+    pub fn parse(flag: &str, value: &str) -> Result<Self, ForgeError> {
+        match value {
+            "private" => Ok(NamespaceMode::Private),
+            "host" => Ok(NamespaceMode::Host),
+            _ => match value.strip_prefix("container:").filter(|r| !r.is_empty()) {
+                Some(reference) => Ok(NamespaceMode::Container(rwlayer::find(reference)?.id)),
+                None => Err(format!("Invalid {} {:?}: use host, private or container:NAME", flag, value).into()),
+            },
+        }
+    }
+}
+
+/// How far the container's CLOCK_MONOTONIC and CLOCK_BOOTTIME are set ahead of the host's,
+/// in seconds, from `run --clock-offset`. Either one makes the container a time namespace.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClockOffsets {
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub monotonic: i64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub boottime: i64,
+}
+
+fn is_zero(seconds: &i64) -> bool {
+    *seconds == 0
+}
+
+impl ClockOffsets {
+    pub fn is_default(&self) -> bool {
+        *self == ClockOffsets::default()
+    }
+
+    /// One `--clock-offset CLOCK=OFFSET`: monotonic or boottime, and seconds or a duration like
+    /// 90s, 30m, 12h or 7d, negative to go back
+    pub fn set(&mut self, spec: &str) -> Result<(), ForgeError> {
+        let invalid = || format!("Invalid --clock-offset {:?}: use monotonic=OFFSET or boottime=OFFSET, as 3600, 12h or -1d", spec);
+        let (clock, offset) = spec.split_once('=').ok_or_else(invalid)?;
+        let (sign, magnitude) = match offset.strip_prefix('-') {
+            Some(magnitude) => (-1, magnitude),
+            None => (1, offset),
+        };
+        let split = magnitude.find(|c: char| !c.is_ascii_digit()).unwrap_or(magnitude.len());
+        let (number, unit) = magnitude.split_at(split);
+        let scale = match unit {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            _ => return Err(invalid().into()),
+        };
+        let seconds = number.parse::<i64>().ok().and_then(|n| n.checked_mul(scale)).ok_or_else(invalid)? * sign;
+        match clock {
+            "monotonic" => self.monotonic = seconds,
+            "boottime" => self.boottime = seconds,
+            _ => return Err(invalid().into()),
+        }
+        Ok(())
+    }
+}
+
+/// With clock offsets, a time namespace that the processes forked from here on are in. The
+/// offsets can only be set before the first of them starts.
+pub fn create_time_namespace(offsets: &ClockOffsets) -> Result<(), ForgeError> {
+    if offsets.is_default() {
+        return Ok(());
+    }
+    unshare(CloneFlags::from_bits_retain(libc::CLONE_NEWTIME)).map_err(|e| ForgeError::namespace("create the time namespace", e))?;
+    fs::write("/proc/self/timens_offsets", format!("monotonic {} 0\nboottime {} 0\n", offsets.monotonic, offsets.boottime))
+        .map_err(|e| ForgeError::namespace("set the clock offsets", errno(&e)))?;
+    debug!("Time namespace created, monotonic {:+}s, boottime {:+}s", offsets.monotonic, offsets.boottime);
+    Ok(())
+}
+
+/// The namespaces PID 1 is cloned into. The runtime itself stays in the host's: it still needs
+/// the host's `ip` and `iptables`, and pivoting would move the root of every process in the
+/// mount namespace. The user namespace waits until the root is set up, the cgroup namespace
+/// until PID 1 is in its cgroup, and the time namespace is the runtime's, set up beforehand.
+/// Shared namespaces are left out and joined instead.
+pub fn clone_flags(ipc: &NamespaceMode, net: &NamespaceMode, pid: &NamespaceMode) -> CloneFlags {
+    let mut flags = CloneFlags::CLONE_NEWUTS | CloneFlags::CLONE_NEWNS;
+    for (mode, flag) in [(ipc, CloneFlags::CLONE_NEWIPC), (net, CloneFlags::CLONE_NEWNET), (pid, CloneFlags::CLONE_NEWPID)] {
+        if mode.is_default() {
+            flags |= flag;
+        }
+    }
+    flags
+}
 
-    if let Err(e) = unshare(flags) {
-        error!("Failed to create namespaces: {}", e);
-        process::exit(1);
+/// For `container:`, join that container's `kind` namespace ("ipc", "net" or "pid"). The other
+/// container has to be running. A PID namespace is only the one this process's children go into.
+pub fn join_namespace(mode: &NamespaceMode, kind: &str, flag: CloneFlags) -> Result<(), ForgeError> {
+    if let NamespaceMode::Container(id) = mode {
+        let pid = lifecycle::running_pid(id)?;
+        // Out of reach from another rootless runtime's user namespace
+        let ns = fs::File::open(format!("/proc/{}/ns/{}", pid, kind))
+            .map_err(|e| ForgeError::namespace(format!("open the {} namespace of container {}", kind, id), errno(&e)))?;
+        setns(ns, flag).map_err(|e| ForgeError::namespace(format!("join the {} namespace of container {}", kind, id), e))?;
     }
+    Ok(())
+}
+
+/// Have this process's children go into its own PID namespace again, after `join_namespace`
+pub fn leave_pid_namespace() -> Result<(), ForgeError> {
+    let ns = fs::File::open("/proc/self/ns/pid")
+        .map_err(|e| ForgeError::namespace("open the runtime's PID namespace", errno(&e)))?;
+    setns(ns, CloneFlags::CLONE_NEWPID).map_err(|e| ForgeError::namespace("return to the runtime's PID namespace", e))?;
+    Ok(())
+}
 
-    debug!("Namespaces created");
+/// A cgroup namespace rooted at the cgroup this process is in now, so the container's processes
+/// see their own cgroup as / in /proc/self/cgroup and /sys/fs/cgroup
+pub fn create_cgroup_namespace() -> Result<(), ForgeError> {
+    unshare(CloneFlags::CLONE_NEWCGROUP).map_err(|e| ForgeError::namespace("create the cgroup namespace", e))
 }
 
-pub fn create_network_namespace() {
-    debug!("Creating network namespace...");
+// The errno behind a failed open or write of a /proc file
+fn errno(error: &std::io::Error) -> Errno {
+    Errno::from_raw(error.raw_os_error().unwrap_or(libc::EIO))
+}
 
-    let flags = CloneFlags::CLONE_NEWNET;
+// From <linux/keyctl.h>
+const KEYCTL_JOIN_SESSION_KEYRING: libc::c_long = 1;
+const KEYCTL_SETPERM: libc::c_long = 5;
+// Everything for possessors; view, read, search and link for its user, so an exec can find it
+const SESSION_KEYRING_PERM: libc::c_ulong = 0x3f1b0000;
 
-    if let Err(e) = unshare(flags) {
-        error!("Failed to create network namespace: {}", e);
-        process::exit(1);
+/// Join a session keyring named `name`, creating it unless one by that name is there to join,
+/// so keys in the host user's session are out of the container's reach. Keyrings aren't
+/// namespaced, and everything the container runs inherits the session keyring.
+pub fn join_session_keyring(name: &str) -> Result<(), ForgeError> {
+    let name = std::ffi::CString::new(name).map_err(|_| ForgeError::namespace("name the session keyring", Errno::EINVAL))?;
+    match Errno::result(unsafe { libc::syscall(libc::SYS_keyctl, KEYCTL_JOIN_SESSION_KEYRING, name.as_ptr()) }) {
+        Ok(id) => {
+            Errno::result(unsafe { libc::syscall(libc::SYS_keyctl, KEYCTL_SETPERM, id, SESSION_KEYRING_PERM) })
+                .map_err(|e| ForgeError::namespace("open up the session keyring to exec", e))?;
+            Ok(())
+        }
+        // Kernels built without keys have nothing to isolate
+        Err(Errno::ENOSYS) => {
+            debug!("No kernel keyrings, so no session keyring for the container");
+            Ok(())
+        }
+        Err(e) => Err(ForgeError::namespace("join the container's session keyring", e)),
     }
+}
+
+/// Name the container inside its own UTS namespace
+pub fn set_hostname(hostname: &str) -> Result<(), ForgeError> {
+    nix::errno::Errno::result(unsafe {
+        nix::libc::sethostname(hostname.as_ptr() as *const nix::libc::c_char, hostname.len())
+    }).map_err(|e| ForgeError::namespace(format!("set hostname {}", hostname), e))?;
+    Ok(())
+}
 
-    debug!("Network namespace created");
+/// Set the container's NIS domain name, as `domainname` shows it. Without one it is "(none)"
+/// rather than the host's, which a new UTS namespace starts out with.
+pub fn set_domainname(domainname: &str) -> Result<(), ForgeError> {
+    let name = if domainname.is_empty() { "(none)" } else { domainname };
+    nix::errno::Errno::result(unsafe {
+        nix::libc::setdomainname(name.as_ptr() as *const nix::libc::c_char, name.len())
+    }).map_err(|e| ForgeError::namespace(format!("set domain name {}", name), e))?;
+    Ok(())
 }
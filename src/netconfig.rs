@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use log::debug;
+
+use crate::filesystem::{BindMount, RootfsOptions};
+use crate::rwlayer;
+use crate::error::ForgeError;
+
+// Name resolution files are generated per container rather than taken from the image:
+//   containers/<id>/hostname    bound onto /etc/hostname
+//   containers/<id>/hosts       bound onto /etc/hosts
+//   containers/<id>/resolv.conf bound onto /etc/resolv.conf
+// They are rewritten on every start and never end up in the writable layer.
+
+/// How the container names itself and resolves names
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NetworkConfig {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hostname: String,           // the short id unless --hostname gave one
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub domainname: String,         // the NIS domain name from --domainname
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_hosts: Vec<String>,   // "db:10.0.0.5" from --add-host
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns: Vec<String>,           // nameservers from --dns, replacing the host's
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_search: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_options: Vec<String>,   // "ndots:2", "timeout:1"
+}
+
+// systemd-resolved points /etc/resolv.conf at its stub on 127.0.0.53; this file lists the real servers
+const RESOLVED_UPSTREAMS: &str = "/run/systemd/resolve/resolv.conf";
+const HOST_RESOLV_CONF: &str = "/etc/resolv.conf";
+// When the host only has resolvers on its own loopback, which the container can't reach
+const FALLBACK_NAMESERVERS: &[&str] = &["8.8.8.8", "8.8.4.4"];
+// HOST_NAME_MAX, for domain names as well
+const UTS_NAME_MAX: usize = 64;
+
+impl NetworkConfig {
+    /// Check a `--hostname` or `--domainname`, which the kernel keeps to 64 bytes
+    pub fn parse_uts_name(kind: &str, name: &str) -> Result<String, ForgeError> {
+        rwlayer::check_name(kind, name)?;
+        if name.len() > UTS_NAME_MAX {
+            return Err(format!("Invalid {} name {:?}: at most {} characters", kind, name, UTS_NAME_MAX).into());
+        }
+        Ok(name.to_string())
+    }
+
+    /// Check an `--add-host HOST:IP` entry
+    pub fn parse_host(spec: &str) -> Result<String, ForgeError> {
+        let (host, ip) = spec.split_once(':')
+            .ok_or_else(|| format!("Invalid --add-host {:?}: expected HOST:IP", spec))?;
+        if host.is_empty() || host.contains(char::is_whitespace) {
+            return Err(format!("Invalid --add-host {:?}: bad host name", spec).into());
+        }
+        ip.parse::<IpAddr>().map_err(|_| format!("Invalid --add-host {:?}: {} is not an IP address", spec, ip))?;
+        Ok(spec.to_string())
+    }
+
+    /// Check a `--dns` nameserver
+    pub fn parse_nameserver(ip: &str) -> Result<String, ForgeError> {
+        ip.parse::<IpAddr>().map_err(|_| format!("Invalid --dns {:?}: not an IP address", ip))?;
+        Ok(ip.to_string())
+    }
+
+    // /etc/resolv.conf: the host's resolvers, search domains and options, each replaced by the
+    // corresponding flags when given
+    fn resolv_conf(&self) -> String {
+        let host = fs::read_to_string(RESOLVED_UPSTREAMS)
+            .or_else(|_| fs::read_to_string(HOST_RESOLV_CONF))
+            .unwrap_or_default();
+        let host_entries = |key: &str| -> Vec<String> {
+            host.lines()
+                .filter_map(|line| line.trim().strip_prefix(key))
+                .filter(|rest| rest.starts_with(char::is_whitespace))
+                .flat_map(|rest| rest.split_whitespace().map(str::to_string).collect::<Vec<_>>())
+                .collect()
+        };
+
+        let mut nameservers = self.dns.clone();
+        if nameservers.is_empty() {
+            // The container has its own loopback, so the host's local resolvers aren't there
+            nameservers = host_entries("nameserver").into_iter()
+                .filter(|ns| ns.parse::<IpAddr>().is_ok_and(|ip| !ip.is_loopback()))
+                .collect();
+            if nameservers.is_empty() {
+                debug!("No usable host nameservers, using {}", FALLBACK_NAMESERVERS.join(", "));
+                nameservers = FALLBACK_NAMESERVERS.iter().map(|ns| ns.to_string()).collect();
+            }
+        }
+        let search = if self.dns_search.is_empty() { host_entries("search") } else { self.dns_search.clone() };
+        let options = if self.dns_options.is_empty() { host_entries("options") } else { self.dns_options.clone() };
+
+        let mut conf = String::new();
+        for ns in nameservers {
+            conf.push_str(&format!("nameserver {}\n", ns));
+        }
+        if !search.is_empty() {
+            conf.push_str(&format!("search {}\n", search.join(" ")));
+        }
+        if !options.is_empty() {
+            conf.push_str(&format!("options {}\n", options.join(" ")));
+        }
+        conf
+    }
+
+    // /etc/hosts: loopback names, the container's own name and the --add-host entries
+    fn hosts(&self, address: Ipv4Addr) -> String {
+        let mut hosts = String::from("127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n");
+        if self.domainname.is_empty() {
+            hosts.push_str(&format!("{}\t{}\n", address, self.hostname));
+        } else {
+            hosts.push_str(&format!("{}\t{}.{}\t{}\n", address, self.hostname, self.domainname, self.hostname));
+        }
+        for entry in &self.extra_hosts {
+            if let Some((host, ip)) = entry.split_once(':') {
+                hosts.push_str(&format!("{}\t{}\n", ip, host));
+            }
+        }
+        hosts
+    }
+}
+
+/// Write the container's name resolution files into `container_dir` and add the mounts that
+/// put them in place, unless the user mounted something of their own there
+pub fn prepare(network: &NetworkConfig, container_dir: &Path, address: Ipv4Addr, rootfs: &mut RootfsOptions) -> Result<(), ForgeError> {
+    let files = [
+        ("hostname", "/etc/hostname", format!("{}\n", network.hostname)),
+        ("hosts", "/etc/hosts", network.hosts(address)),
+        ("resolv.conf", "/etc/resolv.conf", network.resolv_conf()),
+    ];
+
+    let mut generated = Vec::new();
+    for (name, target, contents) in files {
+        if rootfs.covers(target) {
+            debug!("{} is mounted by the user, not generating it", target);
+            continue;
+        }
+        let source = container_dir.join(name);
+        fs::write(&source, contents)?;
+        generated.push(BindMount { source, target: target.to_string(), volume: None, options: Vec::new() });
+    }
+    // After the user's mounts, so a volume on /etc doesn't hide these files
+    rootfs.mounts.extend(generated);
+    Ok(())
+}
@@ -1,37 +1,56 @@
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 use std::process::Command;
-use log::{debug, warn, error};
+use nix::libc;
 
-pub fn get_default_interface_public() -> String {
+use crate::error::ForgeError;
+use crate::ipam::{Pool, Subnet};
+use crate::rootless;
+use crate::tools;
+use log::{debug, warn};
+
+/// The bridge every container's veth is attached to; it has the subnet's gateway address
+pub const BRIDGE: &str = "forge0";
+
+pub fn get_default_interface_public() -> Result<String, ForgeError> {
     get_default_interface()
 }
 
-pub fn setup_veth_pair_with_iface(container_pid: u32, default_iface: &str) {
+pub fn setup_veth_pair_with_iface(container_pid: u32, default_iface: &str, subnet: Subnet) -> Result<(), ForgeError> {
     debug!("Setting up network...");
     let veth_host = format!("veth-{}", container_pid);
     let veth_container = format!("veth-c-{}", container_pid);
 
-    create_veth_pair(&veth_host, &veth_container);
-    move_to_netns(&veth_container, container_pid);
-    configure_host_veth(&veth_host);
-    configure_container_veth(&veth_container, container_pid);
-    enable_nat(&veth_host, default_iface);
+    create_veth_pair(&veth_host, &veth_container)?;
+    move_to_netns(&veth_container, container_pid)?;
+    attach_to_bridge(&veth_host, subnet.pool)?;
+    configure_container_veth(&veth_container, container_pid, subnet)?;
+    enable_nat(subnet.pool, default_iface)?;
 
-    debug!("Network setup complete");
+    debug!("Network setup complete, container at {}", subnet.address());
+    Ok(())
 }
 
-fn create_veth_pair(veth_host: &str, veth_container: &str) {
-    run_ip(&["link", "add", veth_host, "type", "veth", "peer", "name", veth_container]);
+/// After a restore: the host side of a container's veth was recreated as `veth_host`, the name it
+/// had at checkpoint; rename it after the new PID 1 and attach it to the bridge again
+pub fn attach_restored_veth(veth_host: &str, container_pid: u32, default_iface: &str, subnet: Subnet) -> Result<(), ForgeError> {
+    let renamed = format!("veth-{}", container_pid);
+    run_ip(&["link", "set", veth_host, "name", &renamed])?;
+    attach_to_bridge(&renamed, subnet.pool)?;
+    enable_nat(subnet.pool, default_iface)
 }
 
-fn move_to_netns(veth_container: &str, container_pid: u32) {
+fn create_veth_pair(veth_host: &str, veth_container: &str) -> Result<(), ForgeError> {
+    run_ip(&["link", "add", veth_host, "type", "veth", "peer", "name", veth_container])
+}
+
+fn move_to_netns(veth_container: &str, container_pid: u32) -> Result<(), ForgeError> {
     let netns_path = format!("/proc/{}/ns/net", container_pid);
     let netns_name = format!("cnt-{}", container_pid);
 
     debug!("Moving {} to namespace PID {}", veth_container, container_pid);
 
     if !std::path::Path::new(&netns_path).exists() {
-        error!("Netns path doesn't exist: {}", netns_path);
-        return;
+        return Err(ForgeError::network(format!("move {} into PID {}'s namespace", veth_container, container_pid), "the process is gone"));
     }
 
     std::fs::create_dir_all("/var/run/netns").ok();
@@ -39,18 +58,16 @@ fn move_to_netns(veth_container: &str, container_pid: u32) {
 
     let _ = std::fs::remove_file(&netns_link);
 
-    if let Err(e) = std::os::unix::fs::symlink(&netns_path, &netns_link) {
-        error!("Failed to create symlink: {}", e);
-        return;
-    }
+    std::os::unix::fs::symlink(&netns_path, &netns_link)
+        .map_err(|e| ForgeError::network(format!("link {} to {}", netns_link, netns_path), e))?;
 
     debug!("Created symlink: {} -> {}", netns_link, netns_path);
 
-    run_ip(&["link", "set", veth_container, "netns", &netns_name]);
+    let moved = run_ip(&["link", "set", veth_container, "netns", &netns_name]);
 
     // Verify it worked
     let check = Command::new("ip")
-        .args(&["link", "show", veth_container])
+        .args(["link", "show", veth_container])
         .output();
 
     if let Ok(output) = check {
@@ -62,14 +79,53 @@ fn move_to_netns(veth_container: &str, container_pid: u32) {
     }
 
     std::fs::remove_file(&netns_link).ok();
+    moved
 }
 
-fn configure_host_veth(veth_host: &str) {
-    run_ip(&["addr", "add", "10.0.0.1/24", "dev", veth_host]);
-    run_ip(&["link", "set", veth_host, "up"]);
+fn attach_to_bridge(veth_host: &str, pool: Pool) -> Result<(), ForgeError> {
+    ensure_bridge(pool)?;
+    run_ip(&["link", "set", veth_host, "master", BRIDGE, "up"])
 }
 
-fn configure_container_veth(veth_container: &str, container_pid: u32) {
+// Create forge0 with the gateway address the first time a container needs it
+fn ensure_bridge(pool: Pool) -> Result<(), ForgeError> {
+    let gateway = format!("{}/{}", pool.gateway(), pool.prefix_len);
+    let exists = |bridge: &str| Command::new("ip").args(["link", "show", bridge]).output().is_ok_and(|output| output.status.success());
+    if !exists(BRIDGE) {
+        debug!("Creating bridge {} at {}", BRIDGE, gateway);
+        // Another container may be creating it at the same time
+        if let Err(e) = run_ip(&["link", "add", BRIDGE, "type", "bridge"]) {
+            if !exists(BRIDGE) {
+                return Err(e);
+            }
+        }
+    }
+
+    let output = tools::run(Command::new("ip").args(["-o", "-4", "addr", "show", "dev", BRIDGE]), &format!("ip addr show dev {}", BRIDGE))
+        .map_err(|e| ForgeError::network(format!("read {}'s address", BRIDGE), e))?;
+    // "5: forge0    inet 10.0.0.1/16 brd 10.0.255.255 scope global forge0"
+    let output = String::from_utf8_lossy(&output.stdout);
+    let addresses: Vec<&str> = output.lines().filter_map(|line| line.split_whitespace().skip_while(|w| *w != "inet").nth(1)).collect();
+    match addresses.as_slice() {
+        [] => {
+            if let Err(e) = run_ip(&["addr", "add", &gateway, "dev", BRIDGE]) {
+                // Lost the race for the address to another container
+                if !Command::new("ip").args(["-o", "-4", "addr", "show", "dev", BRIDGE]).output()
+                    .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains(&format!("inet {} ", gateway))) {
+                    return Err(e);
+                }
+            }
+        }
+        [address] if *address == gateway => {}
+        _ => return Err(ForgeError::network(
+            format!("use {} for {}", BRIDGE, pool),
+            format!("it has {}; stop its containers and delete it with `ip link del {}` to change the subnet", addresses.join(", "), BRIDGE),
+        )),
+    }
+    run_ip(&["link", "set", BRIDGE, "up"])
+}
+
+fn configure_container_veth(veth_container: &str, container_pid: u32, subnet: Subnet) -> Result<(), ForgeError> {
     let netns_path = format!("/proc/{}/ns/net", container_pid);
     let netns_name = format!("cnt-{}", container_pid);
 
@@ -79,27 +135,48 @@ fn configure_container_veth(veth_container: &str, container_pid: u32) {
     let _ = std::fs::remove_file(&netns_link);
     std::os::unix::fs::symlink(&netns_path, &netns_link).ok();
 
-    run_ip(&["netns", "exec", &netns_name, "ip", "addr", "add", "10.0.0.2/24", "dev", veth_container]);
-    run_ip(&["netns", "exec", &netns_name, "ip", "link", "set", veth_container, "up"]);
-    run_ip(&["netns", "exec", &netns_name, "ip", "link", "set", "lo", "up"]);
-    run_ip(&["netns", "exec", &netns_name, "ip", "route", "add", "default", "via", "10.0.0.1"]);
+    let configured = run_ip(&["netns", "exec", &netns_name, "ip", "addr", "add", &format!("{}/{}", subnet.address(), subnet.pool.prefix_len), "dev", veth_container])
+        .and_then(|_| run_ip(&["netns", "exec", &netns_name, "ip", "link", "set", veth_container, "up"]))
+        .and_then(|_| run_ip(&["netns", "exec", &netns_name, "ip", "link", "set", "lo", "up"]))
+        .and_then(|_| run_ip(&["netns", "exec", &netns_name, "ip", "route", "add", "default", "via", &subnet.gateway().to_string()]));
 
     std::fs::remove_file(&netns_link).ok();
+    configured
 }
 
-fn enable_nat(veth_host: &str, default_iface: &str) {
+// Rules for the whole bridge, added by the first container that needs each
+fn enable_nat(pool: Pool, default_iface: &str) -> Result<(), ForgeError> {
     debug!("Enabling NAT via {}", default_iface);
 
-    run_iptables(&["-t", "nat", "-A", "POSTROUTING", "-s", "10.0.0.0/24", "-o", default_iface, "-j", "MASQUERADE"]);
-    run_iptables(&["-A", "FORWARD", "-i", veth_host, "-o", default_iface, "-j", "ACCEPT"]);
-    run_iptables(&["-A", "FORWARD", "-i", default_iface, "-o", veth_host, "-j", "ACCEPT"]);
+    let subnet = pool.to_string();
+    let rules: [&[&str]; 4] = [
+        &["-t", "nat", "POSTROUTING", "-s", &subnet, "!", "-o", BRIDGE, "-j", "MASQUERADE"],
+        &["FORWARD", "-i", BRIDGE, "-o", default_iface, "-j", "ACCEPT"],
+        // Only replies to connections containers opened, so the outside can't reach into the bridge
+        &["FORWARD", "-i", default_iface, "-o", BRIDGE, "-m", "conntrack", "--ctstate", "RELATED,ESTABLISHED", "-j", "ACCEPT"],
+        // Between containers, should br_netfilter pass bridged traffic through iptables
+        &["FORWARD", "-i", BRIDGE, "-o", BRIDGE, "-j", "ACCEPT"],
+    ];
+    for rule in rules {
+        let (table, rule) = match rule {
+            ["-t", table, rule @ ..] => (*table, rule),
+            rule => ("filter", rule),
+        };
+        let present = Command::new("iptables").args(["-t", table, "-C"]).args(rule).output()
+            .is_ok_and(|output| output.status.success());
+        if !present {
+            run_iptables(&[&["-t", table, "-A"][..], rule].concat())?;
+        }
+    }
+
+    // Earlier versions accepted everything inbound
+    let _ = Command::new("iptables").args(["-D", "FORWARD", "-i", default_iface, "-o", BRIDGE, "-j", "ACCEPT"]).output();
+    Ok(())
 }
 
-fn get_default_interface() -> String {
-    let output = Command::new("ip")
-        .args(&["route", "show", "default"])
-        .output()
-        .expect("Failed to get route");
+fn get_default_interface() -> Result<String, ForgeError> {
+    let output = tools::run(Command::new("ip").args(["route", "show", "default"]), "ip route show default")
+        .map_err(|e| ForgeError::network("find the default route", e))?;
 
     let route = String::from_utf8_lossy(&output.stdout);
     debug!("Default route output: {}", route);
@@ -109,30 +186,105 @@ fn get_default_interface() -> String {
         if dev_pos + 1 < parts.len() {
             let iface = parts[dev_pos + 1].to_string();
             debug!("Detected interface: {}", iface);
-            return iface;
+            return Ok(iface);
         }
     }
 
     warn!("Falling back to enp0s1");
-    "enp0s1".to_string()
+    Ok("enp0s1".to_string())
 }
 
-fn run_ip(args: &[&str]) {
-    let output = Command::new("ip").args(args).output().expect("ip failed");
-    if !output.status.success() {
-        error!("ip {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr));
+/// Bring up the loopback interface of this process's network namespace, which rootless
+/// containers have on their own: with ioctls, as `ip` may not be in the new root
+pub fn bring_up_loopback() -> Result<(), ForgeError> {
+    let fail = |e: std::io::Error| ForgeError::network("bring up lo", e);
+    let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if socket < 0 {
+        return Err(fail(std::io::Error::last_os_error()));
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in request.ifr_name.iter_mut().zip(b"lo") {
+        *dst = *src as libc::c_char;
     }
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS, &mut request) } < 0 {
+        return Err(fail(std::io::Error::last_os_error()));
+    }
+    unsafe { request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS, &request) } < 0 {
+        return Err(fail(std::io::Error::last_os_error()));
+    }
+    debug!("Brought up lo");
+    Ok(())
 }
 
-fn run_iptables(args: &[&str]) {
-    debug!("Running: iptables {}", args.join(" "));
+/// Network leftovers of containers whose PID 1 is gone, as removed by `remove_stale`
+#[derive(Default)]
+pub struct StaleNetwork {
+    pub netns_links: usize,
+    pub veths: usize,
+    pub forward_rules: usize,
+}
 
-    let output = Command::new("iptables")
-        .args(args)
-        .output()
-        .expect("iptables command failed to execute");
+/// Remove netns links, host-side veths and FORWARD rules of containers whose PID 1 has exited.
+/// Everything is named after that PID: cnt-<pid>, veth-<pid> and rules on veth-<pid>.
+pub fn remove_stale() -> Result<StaleNetwork, ForgeError> {
+    // Rootless containers have no veths or rules, and their namespaces go with their processes
+    if rootless::active() {
+        return Ok(StaleNetwork::default());
+    }
+    let dead = |pid: &str| pid.parse::<u32>().is_ok_and(|pid| !std::path::Path::new(&format!("/proc/{}", pid)).exists());
+    let mut removed = StaleNetwork::default();
+
+    if let Ok(entries) = std::fs::read_dir("/var/run/netns") {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.strip_prefix("cnt-").is_some_and(dead) && std::fs::remove_file(entry.path()).is_ok() {
+                removed.netns_links += 1;
+            }
+        }
+    }
 
-    if !output.status.success() {
-        error!("iptables {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr));
+    // Deleting one end of a veth pair deletes both
+    let links = tools::run(Command::new("ip").args(["-o", "link", "show"]), "ip -o link show")
+        .map_err(|e| ForgeError::network("list the host's links", e))?;
+    for line in String::from_utf8_lossy(&links.stdout).lines() {
+        // "12: veth-4242@if11: <BROADCAST,...>"
+        let Some(name) = line.split(": ").nth(1).map(|n| n.split('@').next().unwrap_or(n)) else {
+            continue;
+        };
+        if name.strip_prefix("veth-").is_some_and(dead) {
+            tools::run(Command::new("ip").args(["link", "del", name]), &format!("ip link del {}", name))
+                .map_err(|e| ForgeError::network(format!("delete stale veth {}", name), e))?;
+            removed.veths += 1;
+        }
     }
+
+    let rules = tools::run(Command::new("iptables").args(["-S", "FORWARD"]), "iptables -S FORWARD")
+        .map_err(|e| ForgeError::network("list the FORWARD rules", e))?;
+    for rule in String::from_utf8_lossy(&rules.stdout).lines() {
+        let words: Vec<&str> = rule.split_whitespace().collect();
+        let stale = words.windows(2).any(|w| (w[0] == "-i" || w[0] == "-o") && w[1].strip_prefix("veth-").is_some_and(dead));
+        if stale && words.first() == Some(&"-A") {
+            tools::run(Command::new("iptables").arg("-D").args(&words[1..]), &format!("iptables -D {}", words[1..].join(" ")))
+                .map_err(|e| ForgeError::network("delete a stale FORWARD rule", e))?;
+            removed.forward_rules += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn run_ip(args: &[&str]) -> Result<(), ForgeError> {
+    tools::run(Command::new("ip").args(args), &format!("ip {}", args.join(" ")))
+        .map_err(|e| ForgeError::network("set up the container's network", e))?;
+    Ok(())
+}
+
+fn run_iptables(args: &[&str]) -> Result<(), ForgeError> {
+    debug!("Running: iptables {}", args.join(" "));
+
+    tools::run(Command::new("iptables").args(args), &format!("iptables {}", args.join(" ")))
+        .map_err(|e| ForgeError::network("set up the container's network", e))?;
+    Ok(())
 }
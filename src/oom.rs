@@ -0,0 +1,102 @@
+use std::thread;
+use std::time::Duration;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::sys::prctl;
+use nix::sys::signal::Signal;
+use nix::unistd::{fork, ForkResult, Pid};
+use log::{debug, info, warn};
+
+use crate::cgroups::{self, MemoryEvents};
+use crate::lifecycle;
+use crate::restart::RestartPolicy;
+use crate::rootless;
+use crate::rwlayer::{self, ContainerRecord};
+use crate::state::{self, Status};
+
+// The memory events of a container's cgroup, watched by a child of its runtime like the health
+// checks, so an OOM kill is told apart from any other exit. Every run counts afresh in
+// state.json, as its cgroup is made anew:
+//   oom_kills   processes the OOM killer took; with --restart on-oom the container is stopped,
+//               and started again by its supervisor
+// The runtime reads the count once more when PID 1 has exited, before the cgroup goes.
+
+// Where inotify can't tell, as on cgroup v1
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// In the runtime, before it creates the container's namespaces: fork a process that watches
+/// the container's memory events for as long as the runtime lives
+pub fn monitor(record: &ContainerRecord) {
+    // Rootless containers have no cgroup to watch
+    if rootless::active() {
+        return;
+    }
+    match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            let _ = prctl::set_pdeathsig(Signal::SIGKILL);
+            watch_until_stopped(record);
+        }
+        Ok(ForkResult::Parent { child }) => debug!("Memory events are watched by process {}", child),
+        Err(e) => warn!("Failed to watch for OOM kills: {}", e),
+    }
+}
+
+fn watch_until_stopped(record: &ContainerRecord) -> ! {
+    // The cgroup is there once PID 1 is recorded
+    let pid = loop {
+        match state::read(record) {
+            Ok(state) if state.status == Status::Running && state.pid.is_some() => break state.pid.unwrap_or_default(),
+            _ => thread::sleep(Duration::from_millis(100)),
+        }
+    };
+    let cgroup = format!("img-{}", record.id);
+    let inotify = cgroups::memory_events_file(&cgroup).and_then(|file| {
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC).ok()?;
+        inotify.add_watch(file.as_str(), AddWatchFlags::IN_MODIFY).ok()?;
+        Some(inotify)
+    });
+    let mut last = MemoryEvents::default();
+    loop {
+        let Some(events) = cgroups::memory_events(&cgroup) else {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        };
+        if events.max > last.max && last.max == 0 {
+            info!("Container {} reached its memory limit and is reclaiming memory", record.display_name());
+        }
+        if events.oom_kill > last.oom_kill {
+            warn!("Container {}: the OOM killer killed {} process{}", record.display_name(),
+                events.oom_kill - last.oom_kill, if events.oom_kill - last.oom_kill == 1 { "" } else { "es" });
+            record_kills(record, &events);
+            // Read again, as `update --restart` may have changed the policy
+            let policy = rwlayer::find(&record.id).map_or(record.restart, |r| r.restart);
+            if policy == RestartPolicy::OnOom {
+                info!("Container {} had a process killed for lack of memory, stopping it to restart", record.display_name());
+                lifecycle::stop_gracefully(Pid::from_raw(pid), lifecycle::stop_signal(record), lifecycle::stop_timeout(record));
+                // Kept alive for the SIGKILL at the end of the stop timeout
+                loop {
+                    thread::sleep(Duration::from_secs(3600));
+                }
+            }
+        }
+        last = events;
+        match &inotify {
+            Some(inotify) => {
+                let _ = inotify.read_events();
+            }
+            None => thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+/// From the runtime once PID 1 has exited, so a kill of PID 1 itself is counted before the
+/// watching process dies with the runtime
+pub fn record_exit(record: &ContainerRecord) {
+    if let Some(events) = cgroups::memory_events(&format!("img-{}", record.id)).filter(|e| e.oom_kill > 0) {
+        record_kills(record, &events);
+    }
+}
+
+// The counts are the cgroup's own, so the watcher and the runtime can both write them
+fn record_kills(record: &ContainerRecord, events: &MemoryEvents) {
+    state::record(record, |s| s.oom_kills = events.oom_kill as u32);
+}
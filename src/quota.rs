@@ -0,0 +1,143 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use nix::mount::{umount2, MntFlags};
+use nix::sys::statfs::{statfs, XFS_SUPER_MAGIC};
+use sha2::{Digest, Sha256};
+use log::{debug, info, warn};
+
+use crate::filesystem::is_mounted;
+use crate::image;
+use crate::tools;
+use crate::error::ForgeError;
+
+// A size limit on a container's writable layer, so one container can't fill the host's disk.
+// On XFS mounted with prjquota the container's directories get a project quota:
+//   containers/<id>/xfs-project    the project id, so rm can lift the limit again
+// Anywhere else the writable layer lives on a loopback ext4 filesystem of that size:
+//   containers/<id>/storage.img    the sparse backing file
+//   containers/<id>/storage/       where it is mounted, holding upper/ and work/
+
+const STORAGE_IMAGE: &str = "storage.img";
+const STORAGE_DIR: &str = "storage";
+const PROJECT_FILE: &str = "xfs-project";
+
+/// The directory holding a container's upper/ and work/
+pub fn writable_root(container_dir: &Path) -> PathBuf {
+    if container_dir.join(STORAGE_IMAGE).exists() {
+        container_dir.join(STORAGE_DIR)
+    } else {
+        container_dir.to_path_buf()
+    }
+}
+
+/// Limit the writable layer of a new container to `size` bytes, before its overlay is mounted
+pub fn apply(container_dir: &Path, size: u64) -> Result<(), ForgeError> {
+    match xfs_quota_mount(container_dir) {
+        Some(mountpoint) => apply_project_quota(container_dir, &mountpoint, size),
+        None => create_storage(container_dir, size),
+    }
+}
+
+/// Mount a loopback-backed writable layer again, e.g. after a reboot; nothing to do otherwise
+pub fn mount_storage(container_dir: &Path) -> Result<(), ForgeError> {
+    let image = container_dir.join(STORAGE_IMAGE);
+    let storage = container_dir.join(STORAGE_DIR);
+    if !image.exists() || is_mounted(&storage) {
+        return Ok(());
+    }
+    tools::run(
+        Command::new("mount").args(["-o", "loop"]).arg(&image).arg(&storage),
+        "Failed to mount the container's writable layer",
+    )?;
+    Ok(())
+}
+
+/// Undo what `apply` set up, ahead of removing the container's directory
+pub fn release(container_dir: &Path) {
+    let storage = container_dir.join(STORAGE_DIR);
+    if is_mounted(&storage) {
+        // The loop device is freed with the last unmount
+        if let Err(e) = umount2(&storage, MntFlags::MNT_DETACH) {
+            warn!("Failed to unmount {:?}: {}", storage, e);
+        }
+    }
+
+    if let Ok(project) = fs::read_to_string(container_dir.join(PROJECT_FILE)) {
+        if let Some(mountpoint) = xfs_quota_mount(container_dir) {
+            let limit = format!("limit -p bhard=0 {}", project.trim());
+            if let Err(e) = tools::run(Command::new("xfs_quota").args(["-x", "-c", &limit]).arg(&mountpoint), "xfs_quota failed") {
+                warn!("Failed to lift quota of project {}: {}", project.trim(), e);
+            }
+        }
+    }
+}
+
+fn apply_project_quota(container_dir: &Path, mountpoint: &Path, size: u64) -> Result<(), ForgeError> {
+    // Project ids are global to the filesystem; derive one from the container's directory
+    let name = container_dir.file_name().unwrap_or_default().to_string_lossy();
+    let hash = Sha256::digest(name.as_bytes());
+    let project = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) | 1;
+
+    for dir in ["upper", "work"] {
+        fs::create_dir_all(container_dir.join(dir))?;
+    }
+    fs::write(container_dir.join(PROJECT_FILE), project.to_string())?;
+
+    for command in [
+        format!("project -s -p {} {}", container_dir.display(), project),
+        format!("limit -p bhard={} {}", size, project),
+    ] {
+        tools::run(
+            Command::new("xfs_quota").args(["-x", "-c", &command]).arg(mountpoint),
+            "Failed to set the writable layer's XFS quota",
+        )?;
+    }
+    info!("Writable layer limited to {} by XFS project quota {}", image::format_size(size), project);
+    Ok(())
+}
+
+fn create_storage(container_dir: &Path, size: u64) -> Result<(), ForgeError> {
+    let image = container_dir.join(STORAGE_IMAGE);
+    let storage = container_dir.join(STORAGE_DIR);
+    fs::create_dir_all(&storage)?;
+
+    // Sparse, so only what the container writes takes up space on the host
+    fs::File::create(&image)?.set_len(size)?;
+    let created = tools::run(
+        Command::new("mkfs.ext4").args(["-q", "-F", "-m", "0"]).arg(&image),
+        "Failed to create the writable layer's filesystem",
+    ).and_then(|_| mount_storage(container_dir));
+    if let Err(e) = created {
+        // Without the image the container falls back to an unlimited writable layer, so remove it
+        let _ = fs::remove_file(&image);
+        return Err(e);
+    }
+
+    for dir in ["upper", "work"] {
+        fs::create_dir_all(storage.join(dir))?;
+    }
+    info!("Writable layer limited to {} on a loopback filesystem", image::format_size(size));
+    Ok(())
+}
+
+// The mount point of the XFS filesystem holding `dir`, if it has project quotas turned on
+fn xfs_quota_mount(dir: &Path) -> Option<PathBuf> {
+    if statfs(dir).ok()?.filesystem_type() != XFS_SUPER_MAGIC {
+        return None;
+    }
+    let dir = fs::canonicalize(dir).ok()?;
+    let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+
+    // The longest mount point containing the directory is the one it lives on
+    let (mountpoint, options) = mounts.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            Some((PathBuf::from(fields.get(1)?), fields.get(3)?.to_string()))
+        })
+        .filter(|(mountpoint, _)| dir.starts_with(mountpoint))
+        .max_by_key(|(mountpoint, _)| mountpoint.as_os_str().len())?;
+    let enabled = options.split(',').any(|o| o == "prjquota" || o == "pquota");
+    debug!("{:?} is on XFS at {:?}, project quotas {}", dir, mountpoint, if enabled { "on" } else { "off" });
+    enabled.then_some(mountpoint)
+}
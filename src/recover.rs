@@ -0,0 +1,134 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use nix::fcntl::{Flock, FlockArg};
+use log::{debug, info, warn};
+
+use crate::cgroups::{self, Layout};
+use crate::config;
+use crate::filesystem::is_mounted;
+use crate::network;
+use crate::rwlayer;
+use crate::snapshot;
+use crate::state;
+use crate::volumes;
+use crate::error::ForgeError;
+
+// A runtime that is killed mid-run never gets to tear its container down. Whatever it left
+// is found by cross-checking the state store against what is actually alive:
+//   containers/<id>/rootfs      still mounted while nothing holds the container's lock
+//   containers/<id>/state.json  still saying running, likewise
+//   containers/shell-<uuid>/    the default shell's scratch rootfs, likewise unlocked
+//   img-<id> cgroups            of containers that aren't running or no longer exist
+//   shell-<uuid> cgroups        of default shells whose directory isn't locked
+//   cnt-<pid>, veth-<pid>       netns links, veths and FORWARD rules of PIDs that are gone
+
+/// What `recover` tore down
+#[derive(Default)]
+pub struct RecoverReport {
+    pub rootfs_unmounted: usize,
+    pub shell_dirs_removed: usize,
+    pub cgroups_removed: usize,
+    pub network: network::StaleNetwork,
+}
+
+/// Tear down everything belonging to containers whose runtime is gone
+pub fn recover() -> Result<RecoverReport, ForgeError> {
+    let mut report = RecoverReport::default();
+
+    for container in rwlayer::list()? {
+        // Holding the lock also keeps the container from starting while it is cleaned up
+        let Ok(_lock) = container.lock() else {
+            continue;
+        };
+        let dir = container.dir()?;
+        if state::read(&container)?.is_active() {
+            state::record(&container, |s| s.exit(None));
+        }
+        if is_mounted(&snapshot::rootfs(&dir)) {
+            info!("Unmounting leftover rootfs of container {}", container.display_name());
+            volumes::unmount_all(&container.rootfs.mounts);
+            snapshot::unmount(&dir);
+            report.rootfs_unmounted += 1;
+        }
+    }
+
+    // Including cgroups of containers that have since been removed
+    let running: HashSet<String> = rwlayer::list()?.into_iter()
+        .filter(|c| c.is_running())
+        .map(|c| format!("img-{}", c.id))
+        .collect();
+    let containers_dir = config::containers_dir()?;
+    for cgroup in cgroups::list_cgroups() {
+        // A default shell's cgroup is named like its directory
+        let leftover = (cgroup.starts_with("img-") && !running.contains(&cgroup))
+            || (cgroup.starts_with("shell-") && !is_locked(&containers_dir.join(&cgroup)));
+        if leftover {
+            debug!("Removing leftover cgroup {}", cgroup);
+            match cgroups::cleanup_cgroup(&cgroup) {
+                Ok(()) => report.cgroups_removed += 1,
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+
+    for entry in fs::read_dir(&containers_dir)? {
+        let path = entry?.path();
+        let is_shell = path.file_name().is_some_and(|n| n.to_string_lossy().starts_with("shell-"));
+        if is_shell && !is_locked(&path) {
+            debug!("Removing leftover shell rootfs {:?}", path);
+            fs::remove_dir_all(&path)?;
+            report.shell_dirs_removed += 1;
+        }
+    }
+
+    report.network = network::remove_stale()?;
+    Ok(report)
+}
+
+/// `recover` before starting a container, so leftovers don't pile up; failures only warn
+pub fn recover_quietly() {
+    if let Err(e) = recover() {
+        warn!("Failed to clean up after dead containers: {}", e);
+    }
+}
+
+/// Whether a live runtime holds the lock in a container directory
+pub fn is_locked(dir: &Path) -> bool {
+    let Ok(file) = fs::File::open(dir.join(rwlayer::LOCK_FILE)) else {
+        return false;
+    };
+    Flock::lock(file, FlockArg::LockExclusiveNonblock).is_err()
+}
+
+/// system recover
+pub fn system_command(args: &[String]) -> Result<(), ForgeError> {
+    match args.get(2).map(String::as_str) {
+        Some("recover") => {
+            let report = recover()?;
+            let net = &report.network;
+            info!(
+                "Recovered: {} rootfs unmounted, {} shell rootfs removed, {} cgroups, {} netns links, {} veths, {} iptables rules removed",
+                report.rootfs_unmounted, report.shell_dirs_removed, report.cgroups_removed,
+                net.netns_links, net.veths, net.forward_rules,
+            );
+            Ok(())
+        }
+        Some("cgroups") => {
+            let layout = match cgroups::layout() {
+                Layout::Unified => "cgroup v2",
+                Layout::Hybrid => "cgroup v1, with v2 at /sys/fs/cgroup/unified",
+                Layout::Legacy => "cgroup v1",
+            };
+            println!("Layout: {}", layout);
+            for feature in cgroups::features()? {
+                match feature.missing {
+                    None => println!("  {:<32} available", feature.name),
+                    Some(why) => println!("  {:<32} unavailable: {}", feature.name, why),
+                }
+            }
+            Ok(())
+        }
+        _ => Err("Usage: system recover|cgroups".into()),
+    }
+}
@@ -0,0 +1,712 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::sync::OnceLock;
+use sha2::{Sha256, Digest};
+use log::{debug, info, warn};
+
+use crate::archive;
+use crate::config;
+use crate::auth;
+use crate::distribution::OciImageConfig;
+use crate::error::ForgeError;
+use crate::http;
+use crate::image::{temp_path, ImageConfig};
+use crate::layer::{self, Compression};
+
+pub const DEFAULT_REGISTRY: &str = "registry-1.docker.io";
+const DEFAULT_TAG: &str = "latest";
+
+pub const MEDIA_TYPE_OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const MEDIA_TYPE_OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const MEDIA_TYPE_DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+pub const MEDIA_TYPE_DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+// A parsed image reference like "ghcr.io/org/app:v1" or "python:3.12-alpine"
+#[derive(Debug, Clone)]
+pub struct ImageReference {
+    pub registry: String,    // "registry-1.docker.io"
+    pub repository: String,  // "library/python"
+    pub reference: String,   // "3.12-alpine" or "sha256:..."
+}
+
+impl ImageReference {
+    pub fn parse(image: &str) -> Result<Self, ForgeError> {
+        let image = image.trim();
+        if image.is_empty() {
+            return Err("Empty image reference".into());
+        }
+
+        // Split off "@sha256:..." first, then the tag after the last path component.
+        // A digest pins the content, so a tag given with it ("name:tag@sha256:...") is dropped.
+        let (name, reference) = if let Some((name, digest)) = image.split_once('@') {
+            if !archive::is_digest(digest) {
+                return Err(format!("Invalid digest in image reference {}: expected sha256:<64 hex digits>", image).into());
+            }
+            let last_slash = name.rfind('/').map(|p| p + 1).unwrap_or(0);
+            let name = name[last_slash..].rfind(':').map_or(name, |pos| &name[..last_slash + pos]);
+            (name, digest.to_string())
+        } else {
+            let last_slash = image.rfind('/').map(|p| p + 1).unwrap_or(0);
+            match image[last_slash..].rfind(':') {
+                Some(pos) => (&image[..last_slash + pos], image[last_slash + pos + 1..].to_string()),
+                None => (image, DEFAULT_TAG.to_string()),
+            }
+        };
+
+        // The first component is a registry host if it looks like one
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, rest)) if host.contains('.') || host.contains(':') || host == "localhost" => {
+                (host.to_string(), rest.to_string())
+            }
+            _ => (DEFAULT_REGISTRY.to_string(), name.to_string()),
+        };
+
+        let registry = normalize_registry(&registry);
+
+        // Official Docker Hub images live under "library/"
+        let repository = if registry == DEFAULT_REGISTRY && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository
+        };
+
+        if repository.is_empty() || reference.is_empty() {
+            return Err(format!("Invalid image reference: {}", image).into());
+        }
+
+        Ok(Self { registry, repository, reference })
+    }
+
+    /// Whether this names content by digest ("name@sha256:...") rather than by tag
+    pub fn is_digest(&self) -> bool {
+        self.reference.starts_with("sha256:")
+    }
+}
+
+impl std::fmt::Display for ImageReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let separator = if self.is_digest() { '@' } else { ':' };
+        write!(f, "{}/{}{}{}", self.registry, self.repository, separator, self.reference)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistryManifest {
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ManifestList {
+    manifests: Vec<Descriptor>,
+}
+
+impl RegistryManifest {
+    /// Digests name the files blobs are stored in, so refuse any that isn't `sha256:<hex>`
+    /// before it is used to build a path
+    pub fn check_digests(&self) -> Result<(), ForgeError> {
+        for descriptor in std::iter::once(&self.config).chain(&self.layers) {
+            if !archive::is_digest(&descriptor.digest) {
+                return Err(ForgeError::Image(format!("Invalid digest {:?} in image manifest", descriptor.digest)));
+            }
+        }
+        Ok(())
+    }
+}
+
+// What a tag or digest points at on the registry
+enum Resolved {
+    List(Vec<Descriptor>),
+    Image(RegistryManifest),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+// Manifests and image configs are read into memory, so a registry can't send an endless one
+const MAX_DOCUMENT_SIZE: u64 = 8 * 1024 * 1024;
+
+pub struct HttpResponse {
+    pub status: u16,
+    response: ureq::http::Response<ureq::Body>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.response.headers().get(name).and_then(|v| v.to_str().ok())
+    }
+
+    /// The body, read as it arrives
+    pub fn into_reader(self) -> impl Read {
+        self.response.into_body().into_reader()
+    }
+
+    /// The whole body, refusing one larger than `limit` bytes
+    pub fn read_body(mut self, limit: u64) -> Result<Vec<u8>, ForgeError> {
+        self.response.body_mut().with_config().limit(limit).read_to_vec()
+            .map_err(|e| ForgeError::Image(format!("Failed to read the registry's response: {}", e)))
+    }
+}
+
+pub enum RequestBody<'a> {
+    Empty,
+    File(&'a Path),
+    Bytes(&'a [u8]),
+}
+
+// Registry settings for air-gapped and internal setups, from registries.json in the state root:
+//   {
+//     "mirrors": { "docker.io": ["mirror.internal:5000"] },
+//     "insecure_registries": ["mirror.internal:5000"],
+//     "ca_bundle": "/etc/ssl/certs/internal-ca.pem",
+//     "ca_bundles": { "registry.internal": "/etc/forge/registry-ca.pem" }
+//   }
+// plus --registry-mirror (for Docker Hub), --insecure-registry and --registry-ca on the command line.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct RegistrySettings {
+    // Hosts tried in order before the registry itself when pulling
+    mirrors: HashMap<String, Vec<String>>,
+    // Hosts spoken to over plain HTTP
+    insecure_registries: Vec<String>,
+    // Replaces the system trust store for every registry without its own bundle
+    ca_bundle: Option<PathBuf>,
+    ca_bundles: HashMap<String, PathBuf>,
+}
+
+static SETTINGS: OnceLock<RegistrySettings> = OnceLock::new();
+
+impl RegistrySettings {
+    /// The settings for this process, loaded on first use
+    pub fn get() -> Result<&'static Self, ForgeError> {
+        if let Some(settings) = SETTINGS.get() {
+            return Ok(settings);
+        }
+        let settings = Self::load()?;
+        Ok(SETTINGS.get_or_init(|| settings))
+    }
+
+    fn load() -> Result<Self, ForgeError> {
+        let path = config::registries_file()?;
+        let file: Self = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| format!("Invalid {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e).into()),
+        };
+
+        // Flags come first so a mirror given on the command line is tried before configured ones
+        let flags = config::flags();
+        let mut settings = Self {
+            mirrors: HashMap::new(),
+            insecure_registries: flags.insecure_registries.clone(),
+            ca_bundle: flags.registry_ca.clone().or(file.ca_bundle),
+            ca_bundles: file.ca_bundles,
+        };
+        settings.insecure_registries.extend(file.insecure_registries);
+        if !flags.registry_mirrors.is_empty() {
+            settings.mirrors.insert(DEFAULT_REGISTRY.to_string(), flags.registry_mirrors.clone());
+        }
+        for (registry, mirrors) in file.mirrors {
+            settings.mirrors.entry(normalize_registry(&registry)).or_default().extend(mirrors);
+        }
+
+        // Mirrors may be written as URLs; an http:// one is allowed plain HTTP like docker's daemon.json
+        for mirror in settings.mirrors.values_mut().flatten() {
+            let host = mirror.trim_end_matches('/');
+            let (insecure, host) = match host.split_once("://") {
+                Some((scheme, host)) => (scheme == "http", host.to_string()),
+                None => (false, host.to_string()),
+            };
+            if insecure {
+                settings.insecure_registries.push(host.clone());
+            }
+            *mirror = host;
+        }
+        for host in settings.insecure_registries.iter_mut() {
+            *host = normalize_registry(host.trim_end_matches('/'));
+        }
+
+        debug!("Registry settings: {:?}", settings);
+        Ok(settings)
+    }
+
+    pub fn mirrors(&self, registry: &str) -> &[String] {
+        self.mirrors.get(registry).map(|m| m.as_slice()).unwrap_or(&[])
+    }
+
+    /// `https://host`, or `http://host` for registries allowed plain HTTP
+    pub fn base_url(&self, host: &str) -> String {
+        if self.insecure_registries.iter().any(|h| h == host) {
+            format!("http://{}", host)
+        } else {
+            format!("https://{}", host)
+        }
+    }
+
+    /// The HTTP agent for a request to `url`, trusting the host's CA bundle if one is configured
+    pub fn agent(&self, url: &str) -> Result<ureq::Agent, ForgeError> {
+        let host = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+        let host = host.split('/').next().unwrap_or(host);
+        http::agent(self.ca_bundles.get(host).or(self.ca_bundle.as_ref()).map(|p| p.as_path()))
+    }
+}
+
+fn normalize_registry(registry: &str) -> String {
+    if registry == "docker.io" || registry == "index.docker.io" {
+        DEFAULT_REGISTRY.to_string()
+    } else {
+        registry.to_string()
+    }
+}
+
+pub struct RegistryClient {
+    reference: ImageReference,
+    // The host requests go to: the registry, or a mirror that answered for it
+    endpoint: String,
+    settings: &'static RegistrySettings,
+    authorization: Option<String>,  // "Bearer ..." or "Basic ..."
+}
+
+impl RegistryClient {
+    pub fn new(reference: ImageReference) -> Result<Self, ForgeError> {
+        let endpoint = reference.registry.clone();
+        Ok(Self { reference, endpoint, settings: RegistrySettings::get()?, authorization: None })
+    }
+
+    pub fn reference(&self) -> &ImageReference {
+        &self.reference
+    }
+
+    /// Resolve the reference to a single-platform manifest for this host
+    pub fn fetch_manifest(&mut self) -> Result<RegistryManifest, ForgeError> {
+        match self.resolve()? {
+            Resolved::List(manifests) => {
+                let digest = select_platform_manifest(&manifests)?;
+                debug!("Resolved manifest list to {}", digest);
+                self.fetch_manifest_by_digest(&digest)
+            }
+            Resolved::Image(manifest) => Ok(manifest),
+        }
+    }
+
+    /// Resolve the reference to one manifest per linux platform it was published for
+    pub fn fetch_all_manifests(&mut self) -> Result<Vec<RegistryManifest>, ForgeError> {
+        match self.resolve()? {
+            Resolved::List(manifests) => {
+                let mut resolved = Vec::new();
+                // Attestation manifests are listed with an "unknown" platform
+                for entry in manifests.iter().filter(|m| m.platform.as_ref().map(|p| p.os == "linux").unwrap_or(false)) {
+                    resolved.push(self.fetch_manifest_by_digest(&entry.digest)?);
+                }
+                if resolved.is_empty() {
+                    return Err(ForgeError::Image("Manifest list has no linux images".to_string()));
+                }
+                Ok(resolved)
+            }
+            Resolved::Image(manifest) => Ok(vec![manifest]),
+        }
+    }
+
+    // Pulls go to the first mirror that has the manifest, then its blobs are fetched from there too.
+    // Pushes never resolve, so they always go to the registry itself.
+    fn resolve(&mut self) -> Result<Resolved, ForgeError> {
+        for mirror in self.settings.mirrors(&self.reference.registry) {
+            self.use_endpoint(mirror);
+            match self.resolve_from_endpoint() {
+                Ok(resolved) => {
+                    info!("    Using mirror {}", mirror);
+                    return Ok(resolved);
+                }
+                Err(e) => warn!("    Mirror {} failed: {}", mirror, e),
+            }
+        }
+        let registry = self.reference.registry.clone();
+        self.use_endpoint(&registry);
+        self.resolve_from_endpoint()
+    }
+
+    fn use_endpoint(&mut self, host: &str) {
+        if self.endpoint != host {
+            self.endpoint = host.to_string();
+            self.authorization = None;
+        }
+    }
+
+    fn resolve_from_endpoint(&mut self) -> Result<Resolved, ForgeError> {
+        let reference = self.reference.reference.clone();
+        let (media_type, body) = self.get_manifest(&reference)?;
+
+        if self.reference.is_digest() {
+            verify_bytes(&body, &reference)?;
+        }
+        if media_type == MEDIA_TYPE_OCI_INDEX || media_type == MEDIA_TYPE_DOCKER_MANIFEST_LIST {
+            let list: ManifestList = serde_json::from_slice(&body)?;
+            return Ok(Resolved::List(list.manifests));
+        }
+        Ok(Resolved::Image(serde_json::from_slice(&body)?))
+    }
+
+    fn fetch_manifest_by_digest(&mut self, digest: &str) -> Result<RegistryManifest, ForgeError> {
+        let (media_type, body) = self.get_manifest(digest)?;
+        if media_type == MEDIA_TYPE_OCI_INDEX || media_type == MEDIA_TYPE_DOCKER_MANIFEST_LIST {
+            return Err(ForgeError::Image("Nested manifest lists are not supported".to_string()));
+        }
+        verify_bytes(&body, digest)?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Download a blob to `dest`, verifying its digest before moving it into place
+    pub fn fetch_blob(&mut self, descriptor: &Descriptor, dest: &Path) -> Result<(), ForgeError> {
+        let response = self.get_blob(descriptor)?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Hash the blob as it is written beside the target, so readers never see a partial or unverified one
+        let tmp = temp_path(dest);
+        let result = write_verified(response.into_reader(), &tmp, &descriptor.digest)
+            .and_then(|_| Ok(fs::rename(&tmp, dest)?));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    /// Download a small blob such as an image config into memory, verifying its digest
+    pub fn fetch_blob_bytes(&mut self, descriptor: &Descriptor) -> Result<Vec<u8>, ForgeError> {
+        let data = self.get_blob(descriptor)?.read_body(MAX_DOCUMENT_SIZE)?;
+        verify_bytes(&data, &descriptor.digest)?;
+        Ok(data)
+    }
+
+    fn get_blob(&mut self, descriptor: &Descriptor) -> Result<HttpResponse, ForgeError> {
+        let path = format!("/v2/{}/blobs/{}", self.reference.repository, descriptor.digest);
+        let response = self.request("GET", &path, &[], RequestBody::Empty)?;
+        if response.status != 200 {
+            return Err(ForgeError::Image(format!("Failed to download blob {}: HTTP {}", descriptor.digest, response.status)));
+        }
+        Ok(response)
+    }
+
+    fn get_manifest(&mut self, reference: &str) -> Result<(String, Vec<u8>), ForgeError> {
+        let accept = [
+            MEDIA_TYPE_OCI_INDEX,
+            MEDIA_TYPE_DOCKER_MANIFEST_LIST,
+            MEDIA_TYPE_OCI_MANIFEST,
+            MEDIA_TYPE_DOCKER_MANIFEST,
+        ].join(", ");
+
+        let path = format!("/v2/{}/manifests/{}", self.reference.repository, reference);
+        let accept = format!("Accept: {}", accept);
+        let response = self.request("GET", &path, &[accept], RequestBody::Empty)?;
+
+        if response.status != 200 {
+            return Err(ForgeError::Image(format!(
+                "Failed to fetch manifest {}/{}:{}: HTTP {}",
+                self.endpoint, self.reference.repository, reference, response.status
+            )));
+        }
+
+        let content_type = response.header("content-type").map(|ct| ct.to_string());
+        let body = response.read_body(MAX_DOCUMENT_SIZE)?;
+
+        // Fall back to the mediaType field when the registry sends a generic content type
+        let media_type = match content_type {
+            Some(ct) if ct.starts_with("application/vnd.") => ct.split(';').next().unwrap_or(&ct).trim().to_string(),
+            _ => {
+                let value: serde_json::Value = serde_json::from_slice(&body)?;
+                value["mediaType"].as_str().unwrap_or(MEDIA_TYPE_OCI_MANIFEST).to_string()
+            }
+        };
+
+        Ok((media_type, body))
+    }
+
+    /// Send a request to a registry path (or absolute URL), answering a 401 challenge once
+    pub fn request(
+        &mut self,
+        method: &str,
+        path: &str,
+        headers: &[String],
+        body: RequestBody,
+    ) -> Result<HttpResponse, ForgeError> {
+        let url = self.url_for(path);
+
+        let response = send_request(method, &url, &self.headers_with_auth(headers), &body)?;
+        if response.status != 401 {
+            return Ok(response);
+        }
+
+        // A cached token may lack the scope this request needs, so always re-run the challenge
+        let challenge = response.header("www-authenticate")
+            .ok_or("Registry returned 401 without an authentication challenge")?
+            .to_string();
+        self.authorization = Some(auth::authorize(&self.endpoint, &challenge)?);
+
+        send_request(method, &url, &self.headers_with_auth(headers), &body)
+    }
+
+    /// Upload locations may be absolute or relative to the registry
+    pub fn url_for(&self, path: &str) -> String {
+        if path.starts_with("http://") || path.starts_with("https://") {
+            path.to_string()
+        } else {
+            format!("{}{}", self.settings.base_url(&self.endpoint), path)
+        }
+    }
+
+    fn headers_with_auth(&self, headers: &[String]) -> Vec<String> {
+        let mut all = headers.to_vec();
+        if let Some(authorization) = &self.authorization {
+            all.push(format!("Authorization: {}", authorization));
+        }
+        all
+    }
+}
+
+/// Pull an image from its registry and extract each layer into `dest`, caching blobs in `blob_dir`.
+/// Returns how the image is configured to run.
+pub fn pull_image(image: &str, dest: &Path, blob_dir: &Path) -> Result<ImageConfig, ForgeError> {
+    let reference = ImageReference::parse(image)?;
+    info!("    Resolving {}", reference);
+
+    let mut client = RegistryClient::new(reference)?;
+    let manifest = client.fetch_manifest()?;
+    manifest.check_digests()?;
+    debug!("Image config {}, {} layers", manifest.config.digest, manifest.layers.len());
+    let config: OciImageConfig = serde_json::from_slice(&client.fetch_blob_bytes(&manifest.config)?)?;
+
+    for (i, layer) in manifest.layers.iter().enumerate() {
+        let blob_path = blob_dir.join(&layer.digest);
+
+        if blob_path.exists() {
+            debug!("  [{}/{}] Layer {} already present", i + 1, manifest.layers.len(), layer.digest);
+        } else {
+            info!("    [{}/{}] Downloading {} ({} bytes)...",
+                i + 1, manifest.layers.len(), short_digest(&layer.digest), layer.size);
+            client.fetch_blob(layer, &blob_path)?;
+        }
+
+        layer::apply_verified_layer(&blob_path, &layer.digest, Compression::from_media_type(&layer.media_type), dest)?;
+    }
+
+    Ok(config.image_config())
+}
+
+/// The OCI platform of this machine, e.g. linux/arm64/v8
+pub fn host_platform() -> Platform {
+    let (architecture, variant) = match std::env::consts::ARCH {
+        "x86_64" => ("amd64", None),
+        "aarch64" => ("arm64", Some("v8")),
+        "arm" => ("arm", Some("v7")),
+        other => (other, None),
+    };
+    Platform {
+        architecture: architecture.to_string(),
+        os: "linux".to_string(),
+        variant: variant.map(|v| v.to_string()),
+    }
+}
+
+/// Pick the linux entry for this host's architecture out of an index / manifest list
+pub fn select_platform_manifest(manifests: &[Descriptor]) -> Result<String, ForgeError> {
+    let host = host_platform();
+
+    let candidates: Vec<&Descriptor> = manifests.iter()
+        .filter(|m| m.platform.as_ref().map(|p| p.os == host.os && p.architecture == host.architecture).unwrap_or(false))
+        .collect();
+
+    // Prefer an exact variant match, otherwise take the first entry for the architecture
+    let chosen = candidates.iter()
+        .find(|m| m.platform.as_ref().and_then(|p| p.variant.as_ref()) == host.variant.as_ref())
+        .or_else(|| candidates.first())
+        .ok_or_else(|| format!("No manifest for linux/{} in manifest list", host.architecture))?;
+
+    Ok(chosen.digest.clone())
+}
+
+fn send_request(method: &str, url: &str, headers: &[String], body: &RequestBody) -> Result<HttpResponse, ForgeError> {
+    let agent = RegistrySettings::get()?.agent(url)?;
+    let request = http::request(method, url, headers)?;
+
+    // Only follow redirects for reads; blob GETs are commonly redirected to a CDN
+    let follow = method == "GET" || method == "HEAD";
+    let response = match body {
+        RequestBody::Empty if follow => http::send(&agent, request, (), follow)?,
+        // Registries expect a length on every write, even an empty one
+        RequestBody::Empty => http::send(&agent, request, &[][..], follow)?,
+        RequestBody::File(path) => http::send(&agent, request, fs::File::open(path)?, follow)?,
+        RequestBody::Bytes(data) => http::send(&agent, request, *data, follow)?,
+    };
+    Ok(HttpResponse { status: response.status().as_u16(), response })
+}
+
+// Copy `reader` to a new file at `path`, failing unless what was written matches `digest`
+fn write_verified(reader: impl Read, path: &Path, digest: &str) -> Result<(), ForgeError> {
+    let mut reader = Hashing::new(reader);
+    let mut file = fs::File::create(path)?;
+    std::io::copy(&mut reader, &mut file)?;
+    file.sync_all()?;
+
+    let actual = reader.digest();
+    if actual != digest {
+        return Err(ForgeError::Image(format!("Digest mismatch: expected {}, got {}", digest, actual)));
+    }
+    Ok(())
+}
+
+fn verify_bytes(data: &[u8], digest: &str) -> Result<(), ForgeError> {
+    let actual = format!("sha256:{}", hex::encode(Sha256::digest(data)));
+    if actual != digest {
+        return Err(ForgeError::Image(format!("Digest mismatch: expected {}, got {}", digest, actual)));
+    }
+    Ok(())
+}
+
+pub fn verify_file(path: &Path, digest: &str) -> Result<(), ForgeError> {
+    let actual = file_digest(path)?;
+    if actual != digest {
+        return Err(ForgeError::Image(format!("Digest mismatch: expected {}, got {}", digest, actual)));
+    }
+    Ok(())
+}
+
+/// The `sha256:...` digest of a file, read in fixed-size chunks so layers of any size hash in constant memory
+pub fn file_digest(path: &Path) -> Result<String, ForgeError> {
+    let mut hasher = Sha256::new();
+    hash_reader(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("sha256:{}", hex::encode(hasher.finalize())))
+}
+
+/// Feed everything `reader` yields into `hasher`
+pub fn hash_reader(reader: &mut impl Read, hasher: &mut Sha256) -> std::io::Result<()> {
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// A reader or writer that hashes everything passing through it
+pub struct Hashing<T> {
+    pub inner: T,
+    hasher: Sha256,
+}
+
+impl<T> Hashing<T> {
+    pub fn new(inner: T) -> Self {
+        Hashing { inner, hasher: Sha256::new() }
+    }
+
+    /// The `sha256:...` digest of what went through so far
+    pub fn digest(&self) -> String {
+        format!("sha256:{}", hex::encode(self.hasher.clone().finalize()))
+    }
+}
+
+impl<T: Read> Read for Hashing<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<T: std::io::Write> std::io::Write for Hashing<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn short_digest(digest: &str) -> &str {
+    let end = digest.len().min(19);
+    &digest[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:4bcff63911fcb4448bd4fdacec207030997caf25e9bea4045fa6c8c44de311d1";
+
+    #[test]
+    fn parse_reads_a_tag() {
+        let reference = ImageReference::parse("python:3.12-alpine").unwrap();
+        assert_eq!(reference.registry, DEFAULT_REGISTRY);
+        assert_eq!(reference.repository, "library/python");
+        assert_eq!(reference.reference, "3.12-alpine");
+        assert!(!reference.is_digest());
+    }
+
+    #[test]
+    fn parse_reads_a_digest() {
+        let reference = ImageReference::parse(&format!("ghcr.io/org/app@{}", DIGEST)).unwrap();
+        assert_eq!(reference.registry, "ghcr.io");
+        assert_eq!(reference.repository, "org/app");
+        assert_eq!(reference.reference, DIGEST);
+        assert!(reference.is_digest());
+        assert_eq!(reference.to_string(), format!("ghcr.io/org/app@{}", DIGEST));
+
+        // The digest wins over a tag given alongside it
+        let reference = ImageReference::parse(&format!("localhost:5000/app:v1@{}", DIGEST)).unwrap();
+        assert_eq!(reference.registry, "localhost:5000");
+        assert_eq!(reference.repository, "app");
+        assert_eq!(reference.reference, DIGEST);
+    }
+
+    #[test]
+    fn parse_rejects_malformed_digests() {
+        for image in ["alpine@sha256:abc", "alpine@sha512:4bcff63911fcb4448bd4fdacec207030", "alpine@../../etc", "alpine@"] {
+            assert!(ImageReference::parse(image).is_err(), "{}", image);
+        }
+        assert!(ImageReference::parse(&format!("alpine@{}", DIGEST.to_uppercase())).is_err());
+    }
+
+    #[test]
+    fn verify_bytes_checks_the_digest() {
+        let body = br#"{"schemaVersion":2}"#;
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(body)));
+        assert!(verify_bytes(body, &digest).is_ok());
+        assert!(matches!(verify_bytes(br#"{"schemaVersion":3}"#, &digest), Err(ForgeError::Image(_))));
+    }
+}
@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use log::info;
+
+use crate::rwlayer;
+use crate::error::ForgeError;
+
+// The supervisor of a container is also its restart monitor: when the runtime exits it reads
+// the policy from container.json again, so `update --restart` applies to a running container,
+// and starts a new runtime, which sets up namespaces, cgroup and network from scratch.
+
+// Doubled after every restart, up to a minute, and back to the start once a run lasted a while
+const FIRST_DELAY: Duration = Duration::from_millis(100);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+const STABLE_RUN: Duration = Duration::from_secs(10);
+
+/// --restart no|always|on-failure[:MAX_RETRIES]|unless-stopped|on-unhealthy|on-oom
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub enum RestartPolicy {
+    #[default]
+    No,
+    Always,
+    OnFailure(u32),             // at most this many restarts in a row, 0 for no limit
+    UnlessStopped,
+    OnUnhealthy,                // only once the health check found it unhealthy
+    OnOom,                      // only once the OOM killer took one of its processes
+}
+
+impl RestartPolicy {
+    pub fn is_no(&self) -> bool {
+        *self == RestartPolicy::No
+    }
+
+    /// Whether a container that exited with `exit_code` after `restarts` restarts is started again.
+    /// A container that `stop` or a signal to its supervisor ended stays down under every policy.
+    pub fn should_restart(&self, exit_code: Option<i32>, restarts: u32, stopped: bool, unhealthy: bool, oom_killed: bool) -> bool {
+        match self {
+            _ if stopped => false,
+            RestartPolicy::No => false,
+            RestartPolicy::Always | RestartPolicy::UnlessStopped => true,
+            RestartPolicy::OnFailure(max) => exit_code != Some(0) && (*max == 0 || restarts < *max),
+            RestartPolicy::OnUnhealthy => unhealthy,
+            RestartPolicy::OnOom => oom_killed,
+        }
+    }
+}
+
+impl FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "no" => Ok(RestartPolicy::No),
+            None if s == "always" => Ok(RestartPolicy::Always),
+            None if s == "unless-stopped" => Ok(RestartPolicy::UnlessStopped),
+            None if s == "on-failure" => Ok(RestartPolicy::OnFailure(0)),
+        None if s == "on-unhealthy" => Ok(RestartPolicy::OnUnhealthy),
+            None if s == "on-oom" => Ok(RestartPolicy::OnOom),
+            Some(("on-failure", max)) => max.parse().map(RestartPolicy::OnFailure)
+                .map_err(|_| format!("Invalid maximum retry count {:?}", max)),
+            _ => Err(format!("Invalid restart policy {:?}, expected no, always, on-failure[:MAX_RETRIES], unless-stopped, on-unhealthy or on-oom", s)),
+        }
+    }
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestartPolicy::No => write!(f, "no"),
+            RestartPolicy::Always => write!(f, "always"),
+            RestartPolicy::OnFailure(0) => write!(f, "on-failure"),
+            RestartPolicy::OnFailure(max) => write!(f, "on-failure:{}", max),
+            RestartPolicy::UnlessStopped => write!(f, "unless-stopped"),
+            RestartPolicy::OnUnhealthy => write!(f, "on-unhealthy"),
+            RestartPolicy::OnOom => write!(f, "on-oom"),
+        }
+    }
+}
+
+impl TryFrom<String> for RestartPolicy {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<RestartPolicy> for String {
+    fn from(policy: RestartPolicy) -> Self {
+        policy.to_string()
+    }
+}
+
+/// How long to wait before the next restart
+pub struct Backoff {
+    delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff { delay: FIRST_DELAY }
+    }
+}
+
+impl Backoff {
+    /// The delay before the next run, given how long the one that just ended lasted
+    pub fn next_delay(&mut self, ran_for: Duration) -> Duration {
+        if ran_for >= STABLE_RUN {
+            self.delay = FIRST_DELAY;
+        }
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(MAX_DELAY);
+        delay
+    }
+}
+
+/// update --restart POLICY CONTAINER...: change the restart policy, also of a running container
+pub fn update_command(args: &[String]) -> Result<(), ForgeError> {
+    let mut policy = None;
+    let mut references = Vec::new();
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--restart" => {
+                let value = args.get(i + 1).ok_or("--restart requires a policy")?;
+                policy = Some(value.parse::<RestartPolicy>()?);
+                i += 2;
+            }
+            reference => {
+                references.push(reference);
+                i += 1;
+            }
+        }
+    }
+    let (Some(policy), false) = (policy, references.is_empty()) else {
+        return Err("Usage: container-runtime update --restart POLICY CONTAINER...".into());
+    };
+
+    for reference in references {
+        let mut record = rwlayer::find(reference)?;
+        record.restart = policy;
+        record.save()?;
+        info!("Container {} restarts {}", record.display_name(), describe(policy));
+    }
+    Ok(())
+}
+
+fn describe(policy: RestartPolicy) -> String {
+    match policy {
+        RestartPolicy::No => "never".to_string(),
+        RestartPolicy::Always => "always".to_string(),
+        RestartPolicy::OnFailure(0) => "on failure".to_string(),
+        RestartPolicy::OnFailure(max) => format!("on failure, at most {} times", max),
+        RestartPolicy::UnlessStopped => "unless stopped".to_string(),
+        RestartPolicy::OnUnhealthy => "when unhealthy".to_string(),
+        RestartPolicy::OnOom => "when a process is OOM killed".to_string(),
+    }
+}
@@ -0,0 +1,77 @@
+use nix::sys::resource::{setrlimit, Resource, RLIM_INFINITY};
+use serde::{Deserialize, Serialize};
+
+use crate::error::ForgeError;
+
+// Resource limits cgroups don't cover, such as open files or core dumps, from `run --ulimit`.
+// They are set on PID 1 and every exec before either joins the container's user namespace,
+// where raising a hard limit above the host's is out of reach even for root.
+
+// By the names ulimit(1) and other runtimes use
+const RESOURCES: &[(&str, Resource)] = &[
+    ("as", Resource::RLIMIT_AS),
+    ("core", Resource::RLIMIT_CORE),
+    ("cpu", Resource::RLIMIT_CPU),
+    ("data", Resource::RLIMIT_DATA),
+    ("fsize", Resource::RLIMIT_FSIZE),
+    ("locks", Resource::RLIMIT_LOCKS),
+    ("memlock", Resource::RLIMIT_MEMLOCK),
+    ("msgqueue", Resource::RLIMIT_MSGQUEUE),
+    ("nice", Resource::RLIMIT_NICE),
+    ("nofile", Resource::RLIMIT_NOFILE),
+    ("nproc", Resource::RLIMIT_NPROC),
+    ("rss", Resource::RLIMIT_RSS),
+    ("rtprio", Resource::RLIMIT_RTPRIO),
+    ("rttime", Resource::RLIMIT_RTTIME),
+    ("sigpending", Resource::RLIMIT_SIGPENDING),
+    ("stack", Resource::RLIMIT_STACK),
+];
+
+/// One limit from `run --ulimit NAME=SOFT[:HARD]`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Ulimit {
+    pub name: String,
+    pub soft: u64,              // RLIM_INFINITY for unlimited
+    pub hard: u64,
+}
+
+impl Ulimit {
+    /// "nofile=1024:65536", or "core=0" for the same soft and hard limit
+    pub fn parse(spec: &str) -> Result<Self, ForgeError> {
+        let invalid = || format!("Invalid --ulimit {:?}: use NAME=SOFT[:HARD]", spec);
+        let (name, values) = spec.split_once('=').ok_or_else(invalid)?;
+        resource(name).ok_or_else(|| format!("Unknown ulimit {:?}: use one of {}", name, RESOURCES.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(", ")))?;
+        let (soft, hard) = values.split_once(':').unwrap_or((values, values));
+        let (soft, hard) = (value(soft).ok_or_else(invalid)?, value(hard).ok_or_else(invalid)?);
+        if soft > hard {
+            return Err(format!("Invalid --ulimit {:?}: the soft limit is above the hard limit", spec).into());
+        }
+        Ok(Ulimit { name: name.to_string(), soft, hard })
+    }
+}
+
+fn resource(name: &str) -> Option<Resource> {
+    RESOURCES.iter().find(|(n, _)| *n == name).map(|(_, r)| *r)
+}
+
+// A number, or "unlimited" or -1 as other runtimes take it
+fn value(text: &str) -> Option<u64> {
+    match text {
+        "unlimited" | "-1" => Some(RLIM_INFINITY),
+        _ => text.parse().ok(),
+    }
+}
+
+/// Set the limits on this process, for whatever it execs to inherit
+pub fn apply(ulimits: &[Ulimit]) -> Result<(), ForgeError> {
+    for ulimit in ulimits {
+        let Some(resource) = resource(&ulimit.name) else { continue };
+        setrlimit(resource, ulimit.soft, ulimit.hard)
+            .map_err(|e| ForgeError::limit(format!("set the {} limit to {}:{}", ulimit.name, show(ulimit.soft), show(ulimit.hard)), e))?;
+    }
+    Ok(())
+}
+
+fn show(value: u64) -> String {
+    if value == RLIM_INFINITY { "unlimited".to_string() } else { value.to_string() }
+}
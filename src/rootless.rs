@@ -0,0 +1,144 @@
+use nix::errno::Errno;
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::libc;
+use nix::unistd::{fork, getpid, pipe, ForkResult};
+use std::fs;
+use std::io::{Read, Write};
+use std::process::Command;
+use log::{debug, error};
+
+use crate::error::ForgeError;
+use crate::tools;
+
+// Without root, every command first moves into a user namespace of its own in which the invoking
+// user is root: mounts, namespaces and layer extraction then work as they do for root, on files
+// the user owns. What needs the host's root stays out: cgroups, veths and iptables, mknod.
+//
+// With newuidmap, newgidmap and a range in /etc/subuid and /etc/subgid, the namespace's ids 1 and
+// up come from that range, so images with users of their own unpack and run as they should.
+// Otherwise the user is the only id there is, and files keep no owner but root.
+
+/// Move into a fresh user and mount namespace with the invoking user as root, unless this
+/// process already is root. exec stays out: it joins the container's user namespace instead
+pub fn enter(command: Option<&str>) -> Result<(), ForgeError> {
+    if unsafe { libc::geteuid() } == 0 || command == Some("exec") {
+        return Ok(());
+    }
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let name = user_name(uid);
+    let ranges = subordinate_range("/etc/subuid", name.as_deref(), uid)
+        .zip(subordinate_range("/etc/subgid", name.as_deref(), uid))
+        .filter(|_| in_path("newuidmap") && in_path("newgidmap"));
+
+    match ranges {
+        Some((uids, gids)) => map_ranges(uid, gid, uids, gids)?,
+        None => {
+            unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)
+                .map_err(|e| ForgeError::namespace("create a user namespace", e))?;
+            // Only an unprivileged process that can't drop groups with setgroups may map its gid
+            fs::write("/proc/self/setgroups", "deny")?;
+            fs::write("/proc/self/uid_map", format!("0 {} 1", uid))?;
+            fs::write("/proc/self/gid_map", format!("0 {} 1", gid))?;
+            debug!("Rootless: uid {} is root, with no subordinate ids", uid);
+        }
+    }
+    Ok(())
+}
+
+// newuidmap has to run from outside the namespace, so a child started beforehand maps this
+// process once it has moved in
+fn map_ranges(uid: u32, gid: u32, uids: (u32, u32), gids: (u32, u32)) -> Result<(), ForgeError> {
+    let pid = getpid().to_string();
+    let (ready_read, ready_write) = pipe()?;
+    let child = match unsafe { fork() }? {
+        ForkResult::Child => {
+            drop(ready_write);
+            // Closed without a byte when the parent couldn't unshare
+            if fs::File::from(ready_read).read(&mut [0]).unwrap_or(0) == 0 {
+                std::process::exit(1);
+            }
+            let mapped = tools::run(
+                Command::new("newuidmap").args([&pid, "0", &uid.to_string(), "1", "1", &uids.0.to_string(), &uids.1.to_string()]),
+                "newuidmap failed",
+            ).and_then(|_| tools::run(
+                Command::new("newgidmap").args([&pid, "0", &gid.to_string(), "1", "1", &gids.0.to_string(), &gids.1.to_string()]),
+                "newgidmap failed",
+            ));
+            if let Err(e) = mapped {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+            std::process::exit(0);
+        }
+        ForkResult::Parent { child } => child,
+    };
+    drop(ready_read);
+
+    unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)
+        .map_err(|e| ForgeError::namespace("create a user namespace", e))?;
+    fs::File::from(ready_write).write_all(&[1])?;
+    match waitpid(child, None)? {
+        WaitStatus::Exited(_, 0) => {
+            debug!("Rootless: uid {} is root, ids 1-{} are {}-{}", uid, uids.1, uids.0, uids.0 + uids.1 - 1);
+            Ok(())
+        }
+        _ => Err(ForgeError::namespace(format!("map uid {} and its subordinate ids into the user namespace", uid), Errno::EPERM)),
+    }
+}
+
+/// Whether this process lacks the host's root: it was started by another user, or it is in a
+/// user namespace, as everything a rootless command starts is
+pub fn active() -> bool {
+    (unsafe { libc::geteuid() }) != 0 || !is_initial_namespace()
+}
+
+/// The namespace has only the invoking user's id, with no subordinate ranges
+pub fn single_id() -> bool {
+    active() && mapped_ids() == 1
+}
+
+/// Extracting as root restores each file's owner, which fails for ids the namespace doesn't have
+pub fn keep_owners() -> bool {
+    !single_id()
+}
+
+// The initial user namespace maps every id to itself
+fn is_initial_namespace() -> bool {
+    fs::read_to_string("/proc/self/uid_map")
+        .is_ok_and(|map| map.split_whitespace().eq(["0", "0", "4294967295"]))
+}
+
+fn mapped_ids() -> u64 {
+    fs::read_to_string("/proc/self/uid_map").unwrap_or_default().lines()
+        .filter_map(|line| line.split_whitespace().nth(2)?.parse::<u64>().ok())
+        .sum()
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    let passwd = fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.get(2) == Some(&uid.to_string().as_str()))
+        .map(|fields| fields[0].to_string())
+}
+
+// The first range the file gives the user, by name or number: "dev:100000:65536"
+fn subordinate_range(file: &str, name: Option<&str>, id: u32) -> Option<(u32, u32)> {
+    let id = id.to_string();
+    fs::read_to_string(file).ok()?.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        let [owner, start, count] = fields[..] else {
+            return None;
+        };
+        if owner != id && Some(owner) != name {
+            return None;
+        }
+        Some((start.parse().ok()?, count.parse().ok()?)).filter(|(_, count)| *count > 0)
+    })
+}
+
+pub fn in_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
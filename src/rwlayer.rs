@@ -0,0 +1,372 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use log::{info, warn};
+
+use crate::attach;
+use crate::cgroups::CgroupConfig;
+use crate::config;
+use crate::error::ForgeError;
+use crate::filesystem::RootfsOptions;
+use crate::hooks::Hooks;
+use crate::image::{self, write_atomic, HistoryEntry, ImageConfig, ImageManifest, ImageStore};
+use crate::layer::{self, Compression};
+use crate::netconfig::NetworkConfig;
+use crate::quota;
+use crate::restart::RestartPolicy;
+use crate::snapshot;
+use crate::tools;
+use crate::userns::UserNamespace;
+use crate::capabilities::Capabilities;
+use crate::seccomp::Seccomp;
+use crate::selinux::Label;
+use crate::rlimits::Ulimit;
+use crate::namespace::{ClockOffsets, NamespaceMode};
+
+// A container outlives its process. containers/<id>/ keeps its writable layer, which is upper/
+// over the image's snapshots, or the whole extracted rootfs/ where overlayfs wasn't available,
+// and container.json, which records the image so the same rootfs can be mounted again.
+
+const RECORD_FILE: &str = "container.json";
+// Held while the container runs, so it is never mounted twice or committed mid-write
+pub const LOCK_FILE: &str = ".lock";
+
+#[derive(Serialize, Deserialize)]
+pub struct ContainerRecord {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    pub image: String,          // the reference it was run from, "myapp:v1.0"
+    pub created: String,        // RFC 3339
+    pub overlay: bool,          // false when the image was extracted into rootfs/ and there is no upper/
+    pub manifest: ImageManifest,
+    pub config: ImageConfig,
+    #[serde(flatten)]
+    pub rootfs: RootfsOptions,
+    #[serde(flatten)]
+    pub network: NetworkConfig,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub init: bool,             // PID 1 is the built-in init rather than the entrypoint
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tty: bool,              // PID 1 gets a pseudo-terminal of its own
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub interactive: bool,      // the supervisor's stdin is passed on to the container
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub detach_keys: String,    // from `run --detach-keys`, empty for the default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_timeout: Option<u64>,  // seconds from the stop signal to SIGKILL, from `run --stop-timeout`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,   // seconds a `run` or `start` may take before it is stopped, from `run --timeout`
+    #[serde(default, skip_serializing_if = "RestartPolicy::is_no")]
+    pub restart: RestartPolicy,
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    pub hooks: Hooks,           // from `run --hook`, after those in hooks.json
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub userns: Option<UserNamespace>,  // from `run --uidmap`, `--gidmap` or `--userns keep-id`
+    #[serde(default, skip_serializing_if = "Capabilities::is_default")]
+    pub capabilities: Capabilities,  // from `run --cap-add`, `--cap-drop` and `--privileged`
+    #[serde(default, skip_serializing_if = "Seccomp::is_default")]
+    pub seccomp: Seccomp,       // from `run --security-opt seccomp=` and `--privileged`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub new_privileges: bool,   // setuid binaries work, from `run --security-opt no-new-privileges=false`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub apparmor: Option<String>,   // a profile or "unconfined" from `run --security-opt apparmor=`, else the default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selinux: Option<Label>,     // the container's SELinux labels, on SELinux hosts
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub landlock: bool,         // sandboxed beneath its root, from `run --security-opt landlock=on`
+    #[serde(default, skip_serializing_if = "NamespaceMode::is_default")]
+    pub ipc: NamespaceMode,
+    #[serde(default, skip_serializing_if = "NamespaceMode::is_default")]
+    pub net: NamespaceMode,     // a network namespace of its own, with a veth, unless shared by `run --net`
+    #[serde(default, skip_serializing_if = "NamespaceMode::is_default")]
+    pub pid: NamespaceMode,     // from `run --pid`
+    #[serde(default, skip_serializing_if = "ClockOffsets::is_default")]
+    pub clock_offsets: ClockOffsets,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ulimits: Vec<Ulimit>,   // from `run --ulimit`, for PID 1 and every exec
+    #[serde(default, skip_serializing_if = "CgroupConfig::is_empty")]
+    pub resources: CgroupConfig,    // the cgroup limits from `run --memory` and the rest
+}
+
+impl ContainerRecord {
+    /// A record for a new container; nothing is written until `save`
+    pub fn new(name: Option<&str>, image: &str, manifest: ImageManifest, config: ImageConfig, rootfs: RootfsOptions, mut network: NetworkConfig) -> Result<Self, ForgeError> {
+        let name = name.unwrap_or_default();
+        if !name.is_empty() {
+            check_name("container", name)?;
+            if list()?.iter().any(|c| c.name == name) {
+                return Err(format!("A container named {} already exists", name).into());
+            }
+        }
+        let id = new_id()?;
+        if network.hostname.is_empty() {
+            network.hostname = short_id(&id).to_string();
+        }
+        Ok(Self {
+            id,
+            name: name.to_string(),
+            image: image.to_string(),
+            created: image::now_rfc3339(),
+            overlay: false,
+            manifest,
+            config,
+            rootfs,
+            network,
+            init: false,
+            tty: false,
+            interactive: false,
+            detach_keys: String::new(),
+            stop_timeout: None,
+            timeout: None,
+            restart: RestartPolicy::No,
+            hooks: Hooks::default(),
+            userns: None,
+            capabilities: Capabilities::default(),
+            seccomp: Seccomp::default(),
+            new_privileges: false,
+            apparmor: None,
+            selinux: None,
+            landlock: false,
+            ipc: NamespaceMode::default(),
+            net: NamespaceMode::default(),
+            pid: NamespaceMode::default(),
+            clock_offsets: ClockOffsets::default(),
+            ulimits: Vec::new(),
+            resources: CgroupConfig::default(),
+        })
+    }
+
+    /// The keys that leave an attached terminal, as given to `--detach-keys`
+    pub fn detach_keys(&self) -> String {
+        if self.detach_keys.is_empty() { attach::DEFAULT_DETACH_KEYS.to_string() } else { self.detach_keys.clone() }
+    }
+
+    pub fn dir(&self) -> Result<PathBuf, ForgeError> {
+        Ok(config::containers_dir()?.join(&self.id))
+    }
+
+    pub fn save(&self) -> Result<(), ForgeError> {
+        write_atomic(&self.dir()?.join(RECORD_FILE), serde_json::to_string_pretty(self)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// Claim the container for running, committing or removing; fails if it is running
+    pub fn lock(&self) -> Result<Flock<fs::File>, ForgeError> {
+        let dir = self.dir()?;
+        fs::create_dir_all(&dir)?;
+        let file = fs::File::options().create(true).truncate(false).write(true).open(dir.join(LOCK_FILE))?;
+        Flock::lock(file, FlockArg::LockExclusiveNonblock).map_err(|(_, e)| match e {
+            Errno::EWOULDBLOCK => format!("Container {} is running", self.display_name()).into(),
+            e => format!("Failed to lock container {}: {}", self.display_name(), e).into(),
+        })
+    }
+
+    /// Whether something holds the container's lock, i.e. it is running or being committed
+    pub fn is_running(&self) -> bool {
+        self.lock().is_err()
+    }
+
+    pub fn display_name(&self) -> &str {
+        if self.name.is_empty() { short_id(&self.id) } else { &self.name }
+    }
+}
+
+/// Names of containers and volumes become directory names and CLI arguments
+pub fn check_name(kind: &str, name: &str) -> Result<(), ForgeError> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+    if !valid {
+        return Err(format!("Invalid {} name {:?}: use letters, digits, '_', '.' and '-'", kind, name).into());
+    }
+    Ok(())
+}
+
+/// 64 random hex digits; containers from before these kept their UUIDs
+fn new_id() -> Result<String, ForgeError> {
+    let mut bytes = [0u8; 32];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(hex::encode(bytes))
+}
+
+/// The first 12 digits of the id, as `ps` shows it
+pub fn short_id(id: &str) -> &str {
+    &id[..id.len().min(12)]
+}
+
+/// Every container under the state root
+pub fn list() -> Result<Vec<ContainerRecord>, ForgeError> {
+    let Ok(entries) = fs::read_dir(config::containers_dir()?) else {
+        return Ok(Vec::new());
+    };
+    let mut records = Vec::new();
+    for entry in entries {
+        let path = entry?.path().join(RECORD_FILE);
+        let Ok(json) = fs::read_to_string(&path) else {
+            continue;
+        };
+        match serde_json::from_str(&json) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("Ignoring unreadable {:?}: {}", path, e),
+        }
+    }
+    Ok(records)
+}
+
+/// Look a container up by name, or by its id or an unambiguous prefix of it
+pub fn find(reference: &str) -> Result<ContainerRecord, ForgeError> {
+    if reference.is_empty() {
+        return Err("No container given".into());
+    }
+    let mut matches: Vec<ContainerRecord> = list()?.into_iter()
+        .filter(|c| c.name == reference || c.id.starts_with(reference))
+        .collect();
+    if let Some(pos) = matches.iter().position(|c| c.name == reference) {
+        return Ok(matches.swap_remove(pos));
+    }
+    match matches.len() {
+        0 => Err(format!("No such container: {}", reference).into()),
+        1 => Ok(matches.remove(0)),
+        n => {
+            let ids: Vec<&str> = matches.iter().map(|c| short_id(&c.id)).collect();
+            Err(format!("Ambiguous reference {}: it matches {} containers ({}), give more of the id", reference, n, ids.join(", ")).into())
+        }
+    }
+}
+
+/// Layers that containers are still built on, which gc has to keep
+pub fn referenced_layers() -> Result<HashSet<String>, ForgeError> {
+    Ok(list()?.into_iter().flat_map(|c| c.manifest.layers).collect())
+}
+
+/// rm CONTAINER...
+pub fn rm_command(args: &[String]) -> Result<(), ForgeError> {
+    if args.len() < 3 {
+        return Err("Usage: container-runtime rm CONTAINER...".into());
+    }
+    for reference in &args[2..] {
+        let record = find(reference)?;
+        let _lock = record.lock()?;
+        snapshot::release(&record.dir()?);
+        info!("Removed container {}", record.display_name());
+    }
+    Ok(())
+}
+
+/// diff CONTAINER: what the container added (A), changed (C) or deleted (D) relative to its image
+pub fn diff_command(args: &[String]) -> Result<(), ForgeError> {
+    let reference = args.get(2).ok_or("Usage: container-runtime diff CONTAINER")?;
+    let record = find(reference)?;
+    let upper = upper_dir(&record)?;
+
+    let store = ImageStore::new(config::images_dir()?)?
+        .with_additional_stores(&config::additional_stores())?;
+    let _lock = store.lock_shared()?;
+    let lowers = snapshot::lower_dirs(&store, &record.manifest)?;
+
+    let mut changes = Vec::new();
+    collect_changes(&upper, &lowers, Path::new(""), &mut changes)?;
+    for (kind, path) in changes {
+        println!("{} /{}", kind, path.display());
+    }
+    Ok(())
+}
+
+// Walk the writable layer; everything in it is new unless a lower layer has the same path
+fn collect_changes(upper: &Path, lowers: &[PathBuf], rel: &Path, changes: &mut Vec<(char, PathBuf)>) -> Result<(), ForgeError> {
+    let mut entries = fs::read_dir(upper.join(rel))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let path = rel.join(entry.file_name());
+        let meta = entry.metadata()?;
+        if layer::is_overlay_whiteout(&meta) {
+            changes.push(('D', path));
+            continue;
+        }
+
+        let in_lower = lower_entry(lowers, &path).is_some();
+        changes.push((if in_lower { 'C' } else { 'A' }, path.clone()));
+        if meta.is_dir() {
+            // Nothing below a new or opaque directory comes from the image
+            let below = if in_lower && !layer::is_opaque(&entry.path()) { lowers } else { &[] };
+            collect_changes(upper, below, &path, changes)?;
+        }
+    }
+    Ok(())
+}
+
+// The topmost lower layer's entry at `rel`, unless that layer deleted it
+fn lower_entry(lowers: &[PathBuf], rel: &Path) -> Option<fs::Metadata> {
+    let meta = lowers.iter().find_map(|lower| fs::symlink_metadata(lower.join(rel)).ok())?;
+    (!layer::is_overlay_whiteout(&meta)).then_some(meta)
+}
+
+/// commit CONTAINER NAME[:TAG]: store the container's writable layer as a new image on top of its own
+pub fn commit_command(args: &[String]) -> Result<(), ForgeError> {
+    if args.len() < 4 {
+        return Err("Usage: container-runtime commit CONTAINER NAME[:TAG]".into());
+    }
+    let (name, tag) = args[3].split_once(':').unwrap_or((&args[3], "latest"));
+    let mut record = find(&args[2])?;
+    let _container_lock = record.lock()?;
+    let upper = upper_dir(&record)?;
+
+    let store = ImageStore::new(config::images_dir()?)?;
+    // gc must not delete the new layer before the image referencing it is saved
+    let _lock = store.lock_shared()?;
+    info!("Committing {} as {}:{}", record.display_name(), name, tag);
+
+    let staging = config::tmp_dir()?.join(format!("commit-{}", uuid::Uuid::new_v4()));
+    let digest = pack_upper(&store, &upper, &staging);
+    let _ = fs::remove_dir_all(&staging);
+    let digest = digest?;
+
+    let created_by = format!("commit {} ({})", record.display_name(), record.image);
+    let manifest = &mut record.manifest;
+    manifest.name = name.to_string();
+    manifest.tag = tag.to_string();
+    manifest.layers.push(digest.clone());
+    manifest.history.push(HistoryEntry {
+        created_by,
+        created: image::now_rfc3339(),
+        size: store.layer_size(&digest),
+        ..Default::default()
+    });
+    manifest.created = image::now_rfc3339();
+    manifest.builder = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    let image_digest = store.save_image(record.manifest, record.config)?;
+
+    info!("  ✅ Committed {}:{} ({})", name, tag, image_digest);
+    Ok(())
+}
+
+// Copy the writable layer, turn overlayfs whiteouts back into OCI ones and store it as a layer
+fn pack_upper(store: &ImageStore, upper: &Path, staging: &Path) -> Result<String, ForgeError> {
+    fs::create_dir_all(staging)?;
+    let mut source = upper.as_os_str().to_owned();
+    source.push("/.");
+    tools::run(
+        Command::new("cp").arg("-a").arg(&source).arg(staging),
+        "Failed to copy the container's writable layer",
+    )?;
+    layer::to_oci_whiteouts(upper, staging)?;
+    store.pack_layer(staging, Compression::Gzip)
+}
+
+fn upper_dir(record: &ContainerRecord) -> Result<PathBuf, ForgeError> {
+    if !record.overlay {
+        return Err(format!(
+            "{} has no separate writable layer, overlayfs was unavailable when it was created",
+            record.display_name()
+        ).into());
+    }
+    // A size-limited layer may not be mounted while the container is stopped
+    quota::mount_storage(&record.dir()?)?;
+    Ok(snapshot::upper_dir(&record.dir()?))
+}
@@ -0,0 +1,557 @@
+use nix::errno::Errno;
+use nix::libc;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use log::debug;
+
+use crate::capabilities;
+use crate::error::ForgeError;
+use crate::syscalls;
+
+// Seccomp profiles in Docker's JSON format, compiled to a BPF filter by hand: rules are tried in
+// order and the first whose syscall and arguments match decides, else the default action. Only
+// this architecture's system calls are let through; anything else, such as 32-bit syscalls on
+// x86_64, is killed.
+
+const DEFAULT_PROFILE: &str = include_str!("seccomp.json");
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000003e;
+#[cfg(target_arch = "x86_64")]
+const ARCH_NAME: &str = "amd64";
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc00000b7;
+#[cfg(target_arch = "aarch64")]
+const ARCH_NAME: &str = "arm64";
+
+// x32 system calls share x86_64's arch, with this bit set in their number
+const X32_SYSCALL_BIT: u32 = 0x40000000;
+// The kernel's limit on a filter's length
+const MAX_INSTRUCTIONS: usize = 4096;
+
+// Offsets in struct seccomp_data
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+const ARGS_OFFSET: u32 = 16;
+
+const LD_ABS: u16 = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+const AND: u16 = (libc::BPF_ALU | libc::BPF_AND | libc::BPF_K) as u16;
+const JEQ: u16 = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+const JGT: u16 = (libc::BPF_JMP | libc::BPF_JGT | libc::BPF_K) as u16;
+const JGE: u16 = (libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K) as u16;
+const RET: u16 = (libc::BPF_RET | libc::BPF_K) as u16;
+
+/// Which filter a container's processes run under, from `--security-opt seccomp=`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Seccomp {
+    #[default]
+    Default,
+    Unconfined,
+    Profile(Profile),
+}
+
+impl Seccomp {
+    pub fn is_default(&self) -> bool {
+        *self == Seccomp::Default
+    }
+
+    /// `unconfined` or the path of a JSON profile, which is read now and kept in the container's record
+    pub fn parse(value: &str) -> Result<Self, ForgeError> {
+        if value == "unconfined" {
+            return Ok(Seccomp::Unconfined);
+        }
+        let json = fs::read_to_string(value).map_err(|e| format!("Failed to read seccomp profile {}: {}", value, e))?;
+        let profile = Profile::from_json(&json).map_err(|e| format!("Invalid seccomp profile {}: {}", value, e))?;
+        Ok(Seccomp::Profile(profile))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    default_action: Action,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_errno_ret: Option<u32>,
+    #[serde(default)]
+    syscalls: Vec<Rule>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Rule {
+    #[serde(default)]
+    names: Vec<String>,
+    // Older profiles name one syscall per rule
+    #[serde(default, skip_serializing)]
+    name: Option<String>,
+    action: Action,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    errno_ret: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    args: Vec<Arg>,
+    #[serde(default, skip_serializing_if = "Condition::is_empty")]
+    includes: Condition,
+    #[serde(default, skip_serializing_if = "Condition::is_empty")]
+    excludes: Condition,
+}
+
+// A rule applies only when the container has all the `includes` and none of the `excludes`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Condition {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    caps: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    arches: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_kernel: Option<String>,
+}
+
+impl Condition {
+    fn is_empty(&self) -> bool {
+        *self == Condition::default()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Arg {
+    index: u32,
+    value: u64,
+    #[serde(default)]
+    value_two: u64,
+    op: Op,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+enum Action {
+    #[serde(rename = "SCMP_ACT_ALLOW")]
+    Allow,
+    #[serde(rename = "SCMP_ACT_ERRNO")]
+    Errno,
+    #[serde(rename = "SCMP_ACT_KILL", alias = "SCMP_ACT_KILL_THREAD")]
+    Kill,
+    #[serde(rename = "SCMP_ACT_KILL_PROCESS")]
+    KillProcess,
+    #[serde(rename = "SCMP_ACT_TRAP")]
+    Trap,
+    #[serde(rename = "SCMP_ACT_TRACE")]
+    Trace,
+    #[serde(rename = "SCMP_ACT_LOG")]
+    Log,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+enum Op {
+    #[serde(rename = "SCMP_CMP_EQ")]
+    Eq,
+    #[serde(rename = "SCMP_CMP_NE")]
+    Ne,
+    #[serde(rename = "SCMP_CMP_LT")]
+    Lt,
+    #[serde(rename = "SCMP_CMP_LE")]
+    Le,
+    #[serde(rename = "SCMP_CMP_GT")]
+    Gt,
+    #[serde(rename = "SCMP_CMP_GE")]
+    Ge,
+    #[serde(rename = "SCMP_CMP_MASKED_EQ")]
+    MaskedEq,
+}
+
+impl Profile {
+    fn from_json(json: &str) -> Result<Self, ForgeError> {
+        let mut profile: Profile = serde_json::from_str(json)?;
+        for rule in &mut profile.syscalls {
+            rule.names.extend(rule.name.take());
+            if let Some(arg) = rule.args.iter().find(|arg| arg.index > 5) {
+                return Err(format!("argument index {} is out of range: system calls have 6", arg.index).into());
+            }
+        }
+        Ok(profile)
+    }
+
+    // The filter for a container keeping the capabilities in `capabilities`
+    fn compile(&self, capabilities: u64) -> Result<Vec<libc::sock_filter>, ForgeError> {
+        let errno = self.default_errno_ret.unwrap_or(libc::EPERM as u32);
+        let default = self.default_action.code(errno);
+        let mut program = vec![
+            statement(LD_ABS, ARCH_OFFSET),
+            jump(JEQ, AUDIT_ARCH, 1, 0),
+            statement(RET, libc::SECCOMP_RET_KILL_PROCESS),
+            statement(LD_ABS, NR_OFFSET),
+            jump(JGE, X32_SYSCALL_BIT, 0, 1),
+            statement(RET, libc::SECCOMP_RET_KILL_PROCESS),
+        ];
+        for rule in self.syscalls.iter().filter(|rule| rule.applies(capabilities)) {
+            let action = rule.action.code(rule.errno_ret.unwrap_or(errno));
+            for name in &rule.names {
+                // Other ABIs' system calls can't be made here, but a name no ABI has is a typo that
+                // would leave the call it meant unfiltered
+                let Some(number) = syscalls::number(name) else {
+                    if syscalls::other_abi(name) {
+                        continue;
+                    }
+                    return Err(ForgeError::seccomp("load the seccomp profile", format!("unknown system call {:?}", name)));
+                };
+                let checks = rule.args.iter().flat_map(|arg| arg.checks()).collect::<Vec<_>>();
+                program.push(statement(LD_ABS, NR_OFFSET));
+                program.push(jump(JEQ, number, 0, checks.len() as u8 + 1));
+                // A failed check skips what is left of the rule, its return included
+                let remaining = checks.len();
+                for (i, (code, k, jt, jf)) in checks.into_iter().enumerate() {
+                    let target = |t: Target| match t {
+                        Target::Next => 0,
+                        Target::Pass(n) => n,
+                        Target::Fail => (remaining - i) as u8,
+                    };
+                    program.push(libc::sock_filter { code, jt: target(jt), jf: target(jf), k });
+                }
+                program.push(statement(RET, action));
+            }
+        }
+        program.push(statement(RET, default));
+        if program.len() > MAX_INSTRUCTIONS {
+            return Err(ForgeError::seccomp("load the seccomp profile", format!("its filter has {} instructions, more than the kernel's {}", program.len(), MAX_INSTRUCTIONS)));
+        }
+        Ok(program)
+    }
+}
+
+impl Rule {
+    fn applies(&self, capabilities: u64) -> bool {
+        let Condition { caps, arches, min_kernel } = &self.includes;
+        let included = caps.iter().all(|cap| capabilities::has(capabilities, cap))
+            && (arches.is_empty() || arches.iter().any(|arch| arch == ARCH_NAME))
+            && min_kernel.as_deref().is_none_or(kernel_at_least);
+        let Condition { caps, arches, min_kernel } = &self.excludes;
+        let excluded = caps.iter().any(|cap| capabilities::has(capabilities, cap))
+            || arches.iter().any(|arch| arch == ARCH_NAME)
+            || min_kernel.as_deref().is_some_and(kernel_at_least);
+        included && !excluded
+    }
+}
+
+impl Action {
+    fn code(self, errno: u32) -> u32 {
+        match self {
+            Action::Allow => libc::SECCOMP_RET_ALLOW,
+            Action::Errno => libc::SECCOMP_RET_ERRNO | (errno & libc::SECCOMP_RET_DATA),
+            Action::Kill => libc::SECCOMP_RET_KILL_THREAD,
+            Action::KillProcess => libc::SECCOMP_RET_KILL_PROCESS,
+            Action::Trap => libc::SECCOMP_RET_TRAP,
+            Action::Trace => libc::SECCOMP_RET_TRACE | (errno & libc::SECCOMP_RET_DATA),
+            Action::Log => libc::SECCOMP_RET_LOG,
+        }
+    }
+}
+
+// Where a check's jump goes: the next instruction, past the rest of this argument's check
+// (`Pass(n)` skips n), or past the rule
+#[derive(Clone, Copy)]
+enum Target {
+    Next,
+    Pass(u8),
+    Fail,
+}
+
+impl Arg {
+    // BPF compares 32 bits at a time: the high half of the argument, then the low one
+    fn checks(&self) -> Vec<(u16, u32, Target, Target)> {
+        use Target::{Fail, Next, Pass};
+        let (high, low) = (ARGS_OFFSET + 8 * self.index + 4, ARGS_OFFSET + 8 * self.index);
+        let (value_high, value_low) = ((self.value >> 32) as u32, self.value as u32);
+        let load = |offset| (LD_ABS, offset, Next, Next);
+        match self.op {
+            Op::Eq => vec![load(high), (JEQ, value_high, Next, Fail), load(low), (JEQ, value_low, Next, Fail)],
+            Op::Ne => vec![load(high), (JEQ, value_high, Next, Pass(2)), load(low), (JEQ, value_low, Fail, Next)],
+            Op::MaskedEq => {
+                let (expect_high, expect_low) = ((self.value_two >> 32) as u32, self.value_two as u32);
+                vec![
+                    load(high), (AND, value_high, Next, Next), (JEQ, expect_high, Next, Fail),
+                    load(low), (AND, value_low, Next, Next), (JEQ, expect_low, Next, Fail),
+                ]
+            }
+            Op::Gt | Op::Ge => {
+                let last = if self.op == Op::Gt { JGT } else { JGE };
+                vec![load(high), (JGT, value_high, Pass(3), Next), (JEQ, value_high, Next, Fail), load(low), (last, value_low, Next, Fail)]
+            }
+            Op::Lt | Op::Le => {
+                let last = if self.op == Op::Lt { JGE } else { JGT };
+                vec![load(high), (JGT, value_high, Fail, Next), (JEQ, value_high, Next, Pass(2)), load(low), (last, value_low, Fail, Next)]
+            }
+        }
+    }
+}
+
+/// Put this process and all it starts under the filter. Needs CAP_SYS_ADMIN, so it comes before
+/// the process changes to a user other than root or gives up its capabilities.
+pub fn install(seccomp: &Seccomp, capabilities: u64) -> Result<(), ForgeError> {
+    let default;
+    let profile = match seccomp {
+        Seccomp::Unconfined => return Ok(()),
+        Seccomp::Profile(profile) => profile,
+        Seccomp::Default => {
+            default = Profile::from_json(DEFAULT_PROFILE).map_err(|e| ForgeError::seccomp("load the default seccomp profile", e))?;
+            &default
+        }
+    };
+    let mut filter = profile.compile(capabilities)?;
+    let program = libc::sock_fprog { len: filter.len() as u16, filter: filter.as_mut_ptr() };
+    Errno::result(unsafe { libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program as *const libc::sock_fprog) })
+        .map_err(|e| ForgeError::seccomp("install the seccomp filter", e))?;
+    debug!("Seccomp filter of {} instructions installed", filter.len());
+    Ok(())
+}
+
+fn statement(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+// Whether the running kernel is at least `version`, e.g. "4.8"
+fn kernel_at_least(version: &str) -> bool {
+    let parse = |s: &str| -> Vec<u32> {
+        s.split(['.', '-']).take(2).map_while(|part| part.parse().ok()).collect()
+    };
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+    parse(release.trim()) >= parse(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::Capabilities;
+
+    // The instructions before the first rule: the arch check and the x32 check
+    const PRELUDE: usize = 6;
+    const EPERM: u32 = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+    fn profile(json: &str) -> Profile {
+        Profile::from_json(json).unwrap()
+    }
+
+    fn nr(name: &str) -> u32 {
+        syscalls::number(name).unwrap()
+    }
+
+    // Run `program` as the kernel would for a system call `nr` with `args`, from `arch`
+    fn run(program: &[libc::sock_filter], arch: u32, nr: u32, args: [u64; 6]) -> u32 {
+        let word = |offset: u32| match offset {
+            NR_OFFSET => nr,
+            ARCH_OFFSET => arch,
+            _ => {
+                // The low half is at the argument's offset and the high half 4 bytes on
+                let (index, half) = ((offset - ARGS_OFFSET) / 8, (offset - ARGS_OFFSET) % 8);
+                (args[index as usize] >> (half * 8)) as u32
+            }
+        };
+        let (mut pc, mut acc) = (0, 0);
+        loop {
+            let instruction = program[pc];
+            pc += 1;
+            let taken = match instruction.code {
+                LD_ABS => {
+                    acc = word(instruction.k);
+                    continue;
+                }
+                AND => {
+                    acc &= instruction.k;
+                    continue;
+                }
+                RET => return instruction.k,
+                JEQ => acc == instruction.k,
+                JGT => acc > instruction.k,
+                JGE => acc >= instruction.k,
+                code => panic!("unexpected instruction {:#x}", code),
+            };
+            pc += if taken { instruction.jt } else { instruction.jf } as usize;
+        }
+    }
+
+    fn call(program: &[libc::sock_filter], name: &str, args: [u64; 6]) -> u32 {
+        run(program, AUDIT_ARCH, nr(name), args)
+    }
+
+    fn layout(program: &[libc::sock_filter]) -> Vec<(u16, u8, u8, u32)> {
+        program.iter().map(|i| (i.code, i.jt, i.jf, i.k)).collect()
+    }
+
+    #[test]
+    fn unlisted_syscalls_get_the_default_action() {
+        let program = profile(r#"{"defaultAction": "SCMP_ACT_ERRNO", "syscalls": [{"names": ["getpid"], "action": "SCMP_ACT_ALLOW"}]}"#)
+            .compile(0).unwrap();
+        assert_eq!(call(&program, "getpid", [0; 6]), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(&program, "mount", [0; 6]), EPERM);
+
+        let program = profile(r#"{"defaultAction": "SCMP_ACT_KILL_PROCESS", "syscalls": []}"#).compile(0).unwrap();
+        assert_eq!(program.len(), PRELUDE + 1);
+        assert_eq!(call(&program, "getpid", [0; 6]), libc::SECCOMP_RET_KILL_PROCESS);
+    }
+
+    #[test]
+    fn errno_ret_sets_the_returned_errno() {
+        let program = profile(r#"{
+            "defaultAction": "SCMP_ACT_ERRNO",
+            "defaultErrnoRet": 38,
+            "syscalls": [
+                {"names": ["mount"], "action": "SCMP_ACT_ERRNO", "errnoRet": 13},
+                {"names": ["umount2"], "action": "SCMP_ACT_ERRNO"}
+            ]
+        }"#).compile(0).unwrap();
+        assert_eq!(call(&program, "mount", [0; 6]), libc::SECCOMP_RET_ERRNO | 13);
+        // A rule without its own errnoRet takes the profile's
+        assert_eq!(call(&program, "umount2", [0; 6]), libc::SECCOMP_RET_ERRNO | 38);
+        assert_eq!(call(&program, "getpid", [0; 6]), libc::SECCOMP_RET_ERRNO | 38);
+    }
+
+    #[test]
+    fn argument_checks_skip_the_rest_of_the_rule_when_they_fail() {
+        let program = profile(r#"{
+            "defaultAction": "SCMP_ACT_ERRNO",
+            "syscalls": [{"names": ["personality"], "action": "SCMP_ACT_ALLOW", "args": [{"index": 0, "value": 8, "op": "SCMP_CMP_EQ"}]}]
+        }"#).compile(0).unwrap();
+        let personality = nr("personality");
+        let low = ARGS_OFFSET;
+        let high = ARGS_OFFSET + 4;
+        assert_eq!(layout(&program[PRELUDE..]), vec![
+            (LD_ABS, 0, 0, NR_OFFSET),
+            (JEQ, 0, 5, personality),   // another syscall skips the four checks and the return
+            (LD_ABS, 0, 0, high),
+            (JEQ, 0, 3, 0),             // past the low half's load and check and the return
+            (LD_ABS, 0, 0, low),
+            (JEQ, 0, 1, 8),
+            (RET, 0, 0, libc::SECCOMP_RET_ALLOW),
+            (RET, 0, 0, EPERM),
+        ]);
+        assert_eq!(call(&program, "personality", [8, 0, 0, 0, 0, 0]), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(&program, "personality", [9, 0, 0, 0, 0, 0]), EPERM);
+        assert_eq!(call(&program, "personality", [8 | 1 << 32, 0, 0, 0, 0, 0]), EPERM);
+    }
+
+    #[test]
+    fn every_comparison_matches_as_the_kernel_compares_64_bits() {
+        let values = [0, 1, 7, 8, 9, 0xffff_ffff, 1 << 32, (1 << 32) | 8, (8 << 32) | 1, u64::MAX];
+        // Each op against 0x1_0000_0008, as a plain 64-bit comparison
+        type Reference = fn(u64) -> bool;
+        let ops: [(&str, Reference); 7] = [
+            ("SCMP_CMP_EQ", |a| a == (1 << 32) | 8),
+            ("SCMP_CMP_NE", |a| a != (1 << 32) | 8),
+            ("SCMP_CMP_LT", |a| a < (1 << 32) | 8),
+            ("SCMP_CMP_LE", |a| a <= (1 << 32) | 8),
+            ("SCMP_CMP_GT", |a| a > (1 << 32) | 8),
+            ("SCMP_CMP_GE", |a| a >= (1 << 32) | 8),
+            ("SCMP_CMP_MASKED_EQ", |a| a & ((1 << 32) | 8) == 8),
+        ];
+        for (op, expected) in ops {
+            let json = format!(
+                r#"{{"defaultAction": "SCMP_ACT_ERRNO", "syscalls": [
+                    {{"names": ["personality"], "action": "SCMP_ACT_ALLOW", "args": [{{"index": 1, "value": 4294967304, "valueTwo": 8, "op": "{}"}}]}},
+                    {{"names": ["personality"], "action": "SCMP_ACT_LOG"}}
+                ]}}"#,
+                op,
+            );
+            let program = profile(&json).compile(0).unwrap();
+            for value in values {
+                let action = call(&program, "personality", [0, value, 0, 0, 0, 0]);
+                // A failed check falls through to the next rule for the same syscall
+                let want = if expected(value) { libc::SECCOMP_RET_ALLOW } else { libc::SECCOMP_RET_LOG };
+                assert_eq!(action, want, "{} with {:#x}", op, value);
+            }
+        }
+    }
+
+    #[test]
+    fn every_argument_of_a_rule_has_to_match() {
+        let program = profile(r#"{
+            "defaultAction": "SCMP_ACT_ERRNO",
+            "syscalls": [{"names": ["socket"], "action": "SCMP_ACT_ALLOW", "args": [
+                {"index": 0, "value": 16, "op": "SCMP_CMP_NE"},
+                {"index": 2, "value": 100, "op": "SCMP_CMP_LE"}
+            ]}]
+        }"#).compile(0).unwrap();
+        assert_eq!(call(&program, "socket", [2, 1, 0, 0, 0, 0]), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(&program, "socket", [16, 1, 0, 0, 0, 0]), EPERM);
+        assert_eq!(call(&program, "socket", [2, 1, 101, 0, 0, 0]), EPERM);
+        assert_eq!(call(&program, "socket", [2, 1, 1 << 32, 0, 0, 0]), EPERM);
+    }
+
+    #[test]
+    fn capability_conditions_select_rules() {
+        let json = r#"{
+            "defaultAction": "SCMP_ACT_ERRNO",
+            "syscalls": [
+                {"names": ["mount"], "action": "SCMP_ACT_ALLOW", "includes": {"caps": ["CAP_SYS_ADMIN"]}},
+                {"names": ["getpid"], "action": "SCMP_ACT_ALLOW", "excludes": {"caps": ["CAP_SYS_ADMIN"]}}
+            ]
+        }"#;
+        let default = Capabilities::default().resolve();
+        let admin = Capabilities { add: vec!["SYS_ADMIN".to_string()], ..Capabilities::default() }.resolve();
+
+        let program = profile(json).compile(default).unwrap();
+        assert_eq!(call(&program, "mount", [0; 6]), EPERM);
+        assert_eq!(call(&program, "getpid", [0; 6]), libc::SECCOMP_RET_ALLOW);
+
+        let program = profile(json).compile(admin).unwrap();
+        assert_eq!(call(&program, "mount", [0; 6]), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(&program, "getpid", [0; 6]), EPERM);
+    }
+
+    #[test]
+    fn other_architectures_and_x32_are_killed() {
+        let program = profile(r#"{"defaultAction": "SCMP_ACT_ALLOW", "syscalls": []}"#).compile(0).unwrap();
+        assert_eq!(call(&program, "getpid", [0; 6]), libc::SECCOMP_RET_ALLOW);
+        // i386's AUDIT_ARCH
+        assert_eq!(run(&program, 0x4000_0003, nr("getpid"), [0; 6]), libc::SECCOMP_RET_KILL_PROCESS);
+        assert_eq!(run(&program, AUDIT_ARCH, X32_SYSCALL_BIT | nr("getpid"), [0; 6]), libc::SECCOMP_RET_KILL_PROCESS);
+    }
+
+    #[test]
+    fn default_profile_allows_the_usual_and_blocks_the_dangerous() {
+        let program = profile(DEFAULT_PROFILE).compile(Capabilities::default().resolve()).unwrap();
+        assert!(program.len() <= MAX_INSTRUCTIONS);
+        let newer = ["io_pgetevents", "cachestat", "map_shadow_stack", "futex_wake", "futex_wait", "futex_requeue"];
+        for name in ["read", "write", "getpid", "execve"].into_iter().chain(newer) {
+            assert_eq!(call(&program, name, [0; 6]), libc::SECCOMP_RET_ALLOW, "{}", name);
+        }
+        for name in ["mount", "kexec_load", "bpf"] {
+            assert_ne!(call(&program, name, [0; 6]), libc::SECCOMP_RET_ALLOW, "{}", name);
+        }
+    }
+
+    #[test]
+    fn unknown_syscalls_are_rejected() {
+        let program = profile(r#"{"defaultAction": "SCMP_ACT_ALLOW", "syscalls": [{"names": ["mount", "unshar"], "action": "SCMP_ACT_ERRNO"}]}"#)
+            .compile(0);
+        let message = program.unwrap_err().to_string();
+        assert!(message.contains("\"unshar\""), "{}", message);
+
+        // Only rules that apply here have to name this architecture's or another ABI's system calls
+        let program = profile(r#"{"defaultAction": "SCMP_ACT_ALLOW", "syscalls": [
+            {"names": ["chown32", "mount"], "action": "SCMP_ACT_ERRNO"},
+            {"names": ["s390_runtime_instr"], "action": "SCMP_ACT_ERRNO", "includes": {"arches": ["s390x"]}}
+        ]}"#).compile(0).unwrap();
+        assert_eq!(call(&program, "mount", [0; 6]), EPERM);
+    }
+
+    #[test]
+    fn default_profile_names_resolve() {
+        for name in profile(DEFAULT_PROFILE).syscalls.iter().flat_map(|rule| &rule.names) {
+            assert!(syscalls::number(name).is_some() || syscalls::other_abi(name), "{}", name);
+        }
+        // A name on the other-ABI lists must not hide a gap in this architecture's table
+        for name in syscalls::OTHER_ABIS.iter().flat_map(|names| names.iter()) {
+            assert_eq!(syscalls::number(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn argument_indexes_past_the_sixth_are_rejected() {
+        let json = r#"{"defaultAction": "SCMP_ACT_ERRNO", "syscalls": [{"names": ["read"], "action": "SCMP_ACT_ALLOW", "args": [{"index": 6, "value": 0, "op": "SCMP_CMP_EQ"}]}]}"#;
+        assert!(Profile::from_json(json).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use serde::{Deserialize, Serialize};
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use log::debug;
+
+use crate::error::ForgeError;
+use crate::filesystem::BindMount;
+use crate::rootless;
+
+// On SELinux hosts each container's processes and files get the container types with an MCS
+// level of two categories no other container has. Its processes can then touch only its own
+// files, those labeled for every container, and the volumes relabeled for it with :z or :Z.
+
+const DEFAULT_PROCESS_LABEL: &str = "system_u:system_r:container_t:s0";
+const DEFAULT_FILE_LABEL: &str = "system_u:object_r:container_file_t:s0";
+const XATTR: &CStr = c"security.selinux";
+// The categories c0 to c1023 that levels are picked from
+const CATEGORIES: u32 = 1024;
+
+// Relabeling these for a container would take them away from the host
+const PROTECTED_PATHS: &[&str] = &[
+    "/", "/bin", "/boot", "/dev", "/etc", "/home", "/lib", "/lib64", "/proc", "/root", "/run",
+    "/sbin", "/sys", "/usr", "/var",
+];
+
+/// A container's process and file labels, with the same level
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Label {
+    pub process: String,
+    pub file: String,
+}
+
+/// What `run --security-opt label=` asked for
+#[derive(Default)]
+pub struct LabelOptions {
+    pub disable: bool,
+    user: Option<String>,
+    role: Option<String>,
+    kind: Option<String>,
+    level: Option<String>,
+}
+
+impl LabelOptions {
+    /// One `label=` value: disable, or user:, role:, type: or level: with a value
+    pub fn set(&mut self, option: &str) -> Result<(), ForgeError> {
+        let invalid = || format!("Invalid label option {:?}: use disable, user:USER, role:ROLE, type:TYPE or level:LEVEL", option);
+        if option == "disable" {
+            self.disable = true;
+            return Ok(());
+        }
+        let (key, value) = option.split_once(':').filter(|(_, value)| !value.is_empty()).ok_or_else(invalid)?;
+        let field = match key {
+            "user" => &mut self.user,
+            "role" => &mut self.role,
+            "type" => &mut self.kind,
+            "level" => &mut self.level,
+            _ => return Err(invalid().into()),
+        };
+        *field = Some(value.to_string());
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.user.is_none() && self.role.is_none() && self.kind.is_none() && self.level.is_none()
+    }
+}
+
+/// Whether the host kernel has SELinux turned on
+pub fn enabled() -> bool {
+    Path::new("/sys/fs/selinux/enforce").exists()
+}
+
+impl Label {
+    /// A new container's labels, at a level none of `taken` has. None without SELinux, with
+    /// label=disable, and for rootless containers, which can't label their mounts.
+    pub fn new(options: &LabelOptions, taken: &[Label]) -> Result<Option<Self>, ForgeError> {
+        if options.disable {
+            return Ok(None);
+        }
+        if !enabled() {
+            if !options.is_empty() {
+                return Err("A label was asked for, but SELinux isn't enabled on this host".into());
+            }
+            return Ok(None);
+        }
+        if rootless::active() {
+            debug!("Rootless containers keep the runtime's SELinux label");
+            return Ok(None);
+        }
+        let (process, file) = base_labels();
+        let level = match &options.level {
+            Some(level) => level.clone(),
+            None => unique_level(taken)?,
+        };
+        Ok(Some(Label {
+            process: relabeled(&process, [options.user.as_deref(), options.role.as_deref(), options.kind.as_deref(), Some(&level)]),
+            file: relabeled(&file, [None, None, None, Some(&level)]),
+        }))
+    }
+
+    /// The mount option that labels every file of a filesystem mounted for the container
+    pub fn mount_option(&self) -> String {
+        format!("context=\"{}\"", self.file)
+    }
+}
+
+/// Run with `label` from the next exec on
+pub fn apply(label: &str) -> Result<(), ForgeError> {
+    fs::write("/proc/self/attr/exec", label)
+        .map_err(|e| ForgeError::label(format!("switch to SELinux label {}", label), e))
+}
+
+/// Relabel the sources of the mounts given :z or :Z for a container with `label`
+pub fn relabel_volumes(mounts: &[BindMount], label: Option<&Label>) -> Result<(), ForgeError> {
+    let Some(label) = label else { return Ok(()) };
+    for mount in mounts {
+        if let Some(shared) = mount.relabel() {
+            relabel_volume(&mount.source, label, shared)?;
+        }
+    }
+    Ok(())
+}
+
+// Relabel a volume for `-v SOURCE:TARGET:z`, which every container may use, or `:Z`, which is
+/// the container's alone
+fn relabel_volume(source: &Path, label: &Label, shared: bool) -> Result<(), ForgeError> {
+    if PROTECTED_PATHS.iter().any(|p| source == Path::new(p)) {
+        return Err(format!("Refusing to relabel {:?} for a container; mount a directory below it", source).into());
+    }
+    let file = if shared { base_labels().1 } else { label.file.clone() };
+    debug!("Relabeling {:?} as {}", source, file);
+    relabel(source, &file)
+}
+
+/// Give `path` and everything under it `label`, without following symlinks
+pub fn relabel(path: &Path, label: &str) -> Result<(), ForgeError> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|e| ForgeError::label(format!("relabel {:?}", path), e))?;
+    let ret = unsafe { nix::libc::lsetxattr(c_path.as_ptr(), XATTR.as_ptr(), label.as_ptr().cast(), label.len(), 0) };
+    if ret != 0 {
+        return Err(ForgeError::label(format!("relabel {:?}", path), std::io::Error::last_os_error()));
+    }
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            relabel(&entry?.path(), label)?;
+        }
+    }
+    Ok(())
+}
+
+// The policy's container types, from its lxc_contexts: `process = "..."` and `file = "..."`
+fn base_labels() -> (String, String) {
+    let policy = fs::read_to_string("/etc/selinux/config").unwrap_or_default().lines()
+        .find_map(|line| line.trim().strip_prefix("SELINUXTYPE=").map(str::to_string))
+        .unwrap_or_else(|| "targeted".to_string());
+    let contexts = fs::read_to_string(format!("/etc/selinux/{}/contexts/lxc_contexts", policy)).unwrap_or_default();
+    let find = |key: &str| contexts.lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(k, _)| k.trim() == key)
+        .map(|(_, value)| value.trim().trim_matches('"').to_string());
+    (
+        find("process").unwrap_or_else(|| DEFAULT_PROCESS_LABEL.to_string()),
+        find("file").unwrap_or_else(|| DEFAULT_FILE_LABEL.to_string()),
+    )
+}
+
+// USER:ROLE:TYPE:LEVEL with the given fields replaced; the level may have colons of its own
+fn relabeled(label: &str, fields: [Option<&str>; 4]) -> String {
+    let mut parts: Vec<&str> = label.splitn(4, ':').collect();
+    parts.resize(4, "s0");
+    for (part, field) in parts.iter_mut().zip(fields) {
+        if let Some(field) = field {
+            *part = field;
+        }
+    }
+    parts.join(":")
+}
+
+// s0 with two random categories, a pair no other container has
+fn unique_level(taken: &[Label]) -> Result<String, ForgeError> {
+    let levels: Vec<&str> = taken.iter().filter_map(|label| label.file.splitn(4, ':').nth(3)).collect();
+    let mut urandom = fs::File::open("/dev/urandom")?;
+    loop {
+        let mut bytes = [0u8; 4];
+        urandom.read_exact(&mut bytes)?;
+        let (a, b) = (u16::from_le_bytes([bytes[0], bytes[1]]) as u32 % CATEGORIES, u16::from_le_bytes([bytes[2], bytes[3]]) as u32 % CATEGORIES);
+        if a == b {
+            continue;
+        }
+        let level = format!("s0:c{},c{}", a.min(b), a.max(b));
+        if !levels.contains(&level.as_str()) {
+            return Ok(level);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::process::Command;
+use log::{debug, info};
+
+use crate::config;
+use crate::image::{image_digest, ImageStore, ImageManifest, ImageConfig};
+use crate::registry::verify_file;
+use crate::error::ForgeError;
+
+const SIGNATURES: &str = "signatures";
+const POLICY_FILE: &str = "policy.json";
+
+// Trust policy at <store>/policy.json, e.g. {"require_signatures": true, "keys": ["/etc/forge/signing.pub"]}
+#[derive(Deserialize, Debug, Default)]
+pub struct Policy {
+    #[serde(default)]
+    pub require_signatures: bool,
+    #[serde(default)]
+    pub keys: Vec<PathBuf>,
+}
+
+impl Policy {
+    pub fn load(store: &ImageStore) -> Result<Self, ForgeError> {
+        match fs::read_to_string(store.root.join(POLICY_FILE)) {
+            Ok(json) => Ok(serde_json::from_str(&json)?),
+            Err(_) => Ok(Policy::default()),
+        }
+    }
+
+    /// Keys an image must be signed with; `--verify KEY` overrides the policy, empty means no check
+    pub fn trusted_keys(&self, verify_key: Option<&Path>) -> Vec<PathBuf> {
+        match verify_key {
+            Some(key) => vec![key.to_path_buf()],
+            None if self.require_signatures => self.keys.clone(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Write a detached signature over the image digest into the store
+pub fn sign(store: &ImageStore, manifest: &ImageManifest, config: &ImageConfig, key: &Path) -> Result<String, ForgeError> {
+    let digest = image_digest(manifest, config)?;
+    let dir = store.root.join(SIGNATURES);
+    fs::create_dir_all(&dir)?;
+
+    with_payload(&digest, |payload| {
+        let output = Command::new("openssl")
+            .args(["dgst", "-sha256", "-sign"])
+            .arg(key)
+            .arg("-out").arg(dir.join(&digest))
+            .arg(payload)
+            .output()?;
+        if !output.status.success() {
+            return Err(format!("Signing failed: {}", String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        Ok(())
+    })?;
+
+    debug!("Signed {} with {:?}", digest, key);
+    Ok(digest)
+}
+
+/// Refuse images whose layers were modified or whose digest is not signed by one of `keys`
+pub fn verify(store: &ImageStore, manifest: &ImageManifest, config: &ImageConfig, keys: &[PathBuf]) -> Result<(), ForgeError> {
+    let image = format!("{}:{}", manifest.name, manifest.tag);
+    for digest in &manifest.layers {
+        store.get_layer_path(digest).and_then(|path| verify_file(&path, digest))
+            .map_err(|e| format!("{} has a tampered layer {}: {}", image, digest, e))?;
+    }
+
+    // Images from a read-only store carry their signatures with them
+    let digest = image_digest(manifest, config)?;
+    let signature = store.chain()
+        .map(|s| s.root.join(SIGNATURES).join(&digest))
+        .find(|path| path.exists())
+        .ok_or_else(|| format!("{} ({}) is not signed", image, digest))?;
+
+    let trusted = with_payload(&digest, |payload| {
+        for key in keys {
+            let status = Command::new("openssl")
+                .args(["dgst", "-sha256", "-verify"])
+                .arg(key)
+                .arg("-signature").arg(&signature)
+                .arg(payload)
+                .output()?
+                .status;
+            if status.success() {
+                debug!("{} verified with {:?}", digest, key);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    })?;
+
+    if !trusted {
+        return Err(format!("{} ({}) is not signed by a trusted key", image, digest).into());
+    }
+    info!("Verified signature of {}", image);
+    Ok(())
+}
+
+// openssl signs files, so the digest is written to a scratch payload file first
+fn with_payload<T>(
+    digest: &str,
+    f: impl FnOnce(&Path) -> Result<T, ForgeError>,
+) -> Result<T, ForgeError> {
+    let payload = config::tmp_dir()?.join(format!("signature-payload-{}", uuid::Uuid::new_v4()));
+    fs::write(&payload, digest)?;
+    let result = f(&payload);
+    let _ = fs::remove_file(&payload);
+    result
+}
+
+/// sign IMAGE[:TAG|@DIGEST] --key KEY.pem
+pub fn sign_command(args: &[String]) -> Result<(), ForgeError> {
+    let mut key = None;
+    let mut image = None;
+
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--key" | "-k" => {
+                key = Some(PathBuf::from(args.get(i + 1).ok_or("--key requires a path")?));
+                i += 2;
+            }
+            other => {
+                image = Some(other);
+                i += 1;
+            }
+        }
+    }
+    let usage = "Usage: container-runtime sign IMAGE[:TAG|@DIGEST] --key KEY.pem";
+    let image = image.ok_or(usage)?;
+    let key = key.ok_or(usage)?;
+
+    let store = ImageStore::new(config::images_dir()?)?;
+
+    // A digest reference signs exactly that image, a tag signs all of its platforms
+    let entries = if image.contains('@') {
+        vec![store.resolve(image)?]
+    } else {
+        let parts: Vec<&str> = image.split(':').collect();
+        store.load_index(parts[0], parts.get(1).unwrap_or(&"latest"))?.manifests
+    };
+
+    for entry in entries {
+        let digest = sign(&store, &entry.manifest, &entry.config, &key)?;
+        info!("Signed {}:{} ({}) as {}", entry.manifest.name, entry.manifest.tag, entry.manifest.platform(), digest);
+    }
+    Ok(())
+}
@@ -0,0 +1,354 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use log::{debug, info, warn};
+
+use crate::config;
+use crate::image::{temp_path, ImageManifest, ImageStore};
+use crate::filesystem::is_mounted;
+use crate::layer::{self, LayerSource};
+use crate::quota;
+use crate::rootless;
+use crate::selinux::{self, Label};
+use crate::tools;
+use crate::error::ForgeError;
+
+// Each layer is unpacked once, the first time an image using it runs:
+//   images/snapshots/<hex>     the layer's files, whiteouts in overlayfs form
+// or, with FORGE_SNAPSHOT_FORMAT=squashfs or erofs, packed into a read-only filesystem image
+// that is loop-mounted on images/snapshots/<hex> whenever it isn't already:
+//   images/snapshots/<hex>.squashfs, images/snapshots/<hex>.erofs
+// and every container gets its own writable directories on top:
+//   containers/<id>/upper      files the container creates or changes
+//   containers/<id>/work       overlayfs scratch space
+// (both inside containers/<id>/storage/ when the writable layer has a size limit, see quota.rs)
+//   containers/<id>/rootfs     the overlay mount, or a full extraction without overlayfs
+
+const SNAPSHOTS: &str = "snapshots";
+
+// Filesystem image formats a snapshot can be packed into, with the tool that builds each
+const IMAGE_FORMATS: &[(&str, &str)] = &[("squashfs", "mksquashfs"), ("erofs", "mkfs.erofs")];
+
+// Mount options must fit in one page
+const MAX_MOUNT_DATA: usize = 4000;
+
+/// Assemble a new container's rootfs under `container_dir` from the image's layers, returning
+/// whether it is an overlay (true) or a full extraction (false). A `size_limit` caps the writable
+/// layer, which needs an overlay. With an SELinux `label` every file gets the container's own
+/// file label. The caller holds the store's shared lock so gc can't remove
+/// layers while they are unpacked.
+pub fn create(store: &ImageStore, manifest: &ImageManifest, container_dir: &Path, size_limit: Option<u64>, label: Option<&Label>) -> Result<bool, ForgeError> {
+    let rootfs = rootfs(container_dir);
+    fs::create_dir_all(&rootfs)?;
+    let layers = layer_sources(store, manifest)?;
+
+    if let Some(size) = size_limit {
+        if layers.is_empty() || !overlay_available() {
+            return Err("A writable layer size limit needs an overlay rootfs, which is unavailable here".into());
+        }
+        quota::apply(container_dir, size)?;
+        mount_overlay(store, &layers, container_dir, label)?;
+        return Ok(true);
+    }
+
+    if !layers.is_empty() && overlay_available() {
+        match mount_overlay(store, &layers, container_dir, label) {
+            Ok(()) => return Ok(true),
+            Err(e) => warn!("Overlay rootfs unavailable, extracting layers instead: {}", e),
+        }
+    }
+
+    info!("Extracting {} layers...", layers.len());
+    layer::extract_layers_parallel(&layers, &rootfs)?;
+    if let Some(label) = label {
+        selinux::relabel(&rootfs, &label.file)?;
+    }
+    Ok(false)
+}
+
+/// Mount an existing container's overlay again, on top of the writable layer it already has
+pub fn remount(store: &ImageStore, manifest: &ImageManifest, container_dir: &Path, label: Option<&Label>) -> Result<(), ForgeError> {
+    quota::mount_storage(container_dir)?;
+    let layers = layer_sources(store, manifest)?;
+    mount_overlay(store, &layers, container_dir, label)
+}
+
+/// The unpacked layers of an image, topmost first, unpacking any that are missing
+pub fn lower_dirs(store: &ImageStore, manifest: &ImageManifest) -> Result<Vec<PathBuf>, ForgeError> {
+    let mut dirs = ensure_snapshots(store, &layer_sources(store, manifest)?)?;
+    dirs.reverse();
+    Ok(dirs)
+}
+
+pub fn rootfs(container_dir: &Path) -> PathBuf {
+    container_dir.join("rootfs")
+}
+
+/// A container's own changes when its rootfs is an overlay
+pub fn upper_dir(container_dir: &Path) -> PathBuf {
+    quota::writable_root(container_dir).join("upper")
+}
+
+/// Take down a container's overlay, keeping its writable layer
+pub fn unmount(container_dir: &Path) {
+    let rootfs = rootfs(container_dir);
+    if let Err(e) = umount2(&rootfs, MntFlags::MNT_DETACH) {
+        debug!("{:?} was not mounted: {}", rootfs, e);
+    }
+}
+
+/// Unmount and delete everything a container left under `container_dir`
+pub fn release(container_dir: &Path) {
+    unmount(container_dir);
+    quota::release(container_dir);
+    if let Err(e) = fs::remove_dir_all(container_dir) {
+        warn!("Failed to remove {:?}: {}", container_dir, e);
+    }
+}
+
+fn layer_sources(store: &ImageStore, manifest: &ImageManifest) -> Result<Vec<LayerSource>, ForgeError> {
+    manifest.layers.iter()
+        .map(|digest| Ok(LayerSource {
+            path: store.get_layer_path(digest)?,
+            digest: digest.clone(),
+            compression: store.layer_compression(manifest, digest)?,
+        }))
+        .collect()
+}
+
+fn mount_overlay(store: &ImageStore, layers: &[LayerSource], container_dir: &Path, label: Option<&Label>) -> Result<(), ForgeError> {
+    let snapshots = ensure_snapshots(store, layers)?;
+
+    let rootfs = rootfs(container_dir);
+    let upper = upper_dir(container_dir);
+    let work = quota::writable_root(container_dir).join("work");
+    fs::create_dir_all(&upper)?;
+    fs::create_dir_all(&work)?;
+
+    // overlayfs lists the topmost lower directory first
+    let lower: Vec<String> = snapshots.iter().rev().map(|p| p.display().to_string()).collect();
+    let mut data = format!("lowerdir={},upperdir={},workdir={}", lower.join(":"), upper.display(), work.display());
+    if let Some(label) = label {
+        data = format!("{},{}", data, label.mount_option());
+    }
+    if data.len() > MAX_MOUNT_DATA {
+        return Err(format!("{} layers are too many for one overlay mount", layers.len()).into());
+    }
+
+    let mounted = if !overlay_supported() {
+        Err("the kernel has no overlayfs".to_string())
+    } else if rootless::active() {
+        // A user namespace's overlay keeps its whiteout markers in user.overlay.* xattrs
+        mount(Some("overlay"), &rootfs, Some("overlay"), MsFlags::empty(), Some(format!("{},userxattr", data).as_str()))
+            .map_err(|e| e.to_string())
+    } else {
+        mount(Some("overlay"), &rootfs, Some("overlay"), MsFlags::empty(), Some(data.as_str()))
+            .map_err(|e| e.to_string())
+    };
+    match mounted {
+        Ok(()) => debug!("Mounted overlay of {} layers on {:?}", layers.len(), rootfs),
+        // Kernels before 5.11 can't mount overlayfs from a user namespace, FUSE can
+        Err(e) if fuse_overlay_available() => {
+            debug!("Kernel overlay unavailable ({}), using fuse-overlayfs", e);
+            tools::run(
+                Command::new("fuse-overlayfs").arg("-o").arg(&data).arg(&rootfs),
+                &format!("Failed to mount fuse-overlayfs on {:?}", rootfs),
+            )?;
+            debug!("Mounted fuse-overlayfs of {} layers on {:?}", layers.len(), rootfs);
+        }
+        Err(e) => return Err(ForgeError::mount(format!("mount overlay on {:?}", rootfs), e)),
+    }
+    Ok(())
+}
+
+/// Where a store keeps its snapshots
+pub fn dir(store: &ImageStore) -> PathBuf {
+    store.root.join(SNAPSHOTS)
+}
+
+// Unpack the layers that have no snapshot yet, returning every layer's snapshot in order
+fn ensure_snapshots(store: &ImageStore, layers: &[LayerSource]) -> Result<Vec<PathBuf>, ForgeError> {
+    let dir = dir(store);
+    fs::create_dir_all(&dir)?;
+
+    let paths: Vec<PathBuf> = layers.iter().map(|l| dir.join(snapshot_name(&l.digest))).collect();
+    let mut missing = Vec::new();
+    for (i, path) in paths.iter().enumerate() {
+        match packed_image(path) {
+            Some((image, format)) => mount_image(&image, format, path)?,
+            None if path.is_dir() => {}
+            None => missing.push(i),
+        }
+    }
+    if missing.is_empty() {
+        debug!("All {} layers already unpacked", layers.len());
+        return Ok(paths);
+    }
+
+    // Unpack beside the final name and rename, so a snapshot is either complete or absent
+    let sources: Vec<LayerSource> = missing.iter().map(|&i| layers[i].clone()).collect();
+    let staging: Vec<PathBuf> = missing.iter().map(|&i| temp_path(&paths[i])).collect();
+
+    let format = image_format()?;
+    info!("Unpacking {} of {} layers...", missing.len(), layers.len());
+    let result = layer::unpack_parallel(&sources, &staging).and_then(|_| {
+        for (&i, tmp) in missing.iter().zip(&staging) {
+            layer::to_overlay_whiteouts(tmp)?;
+            if let Some((format, tool)) = format {
+                pack_image(tmp, &paths[i], format, tool)?;
+                continue;
+            }
+            // Another run may have unpacked the same layer meanwhile; its copy is just as good
+            if fs::rename(tmp, &paths[i]).is_err() && !paths[i].is_dir() {
+                return Err(format!("Failed to store snapshot {:?}", paths[i]).into());
+            }
+        }
+        Ok(())
+    });
+    for tmp in &staging {
+        let _ = fs::remove_dir_all(tmp);
+    }
+    result.map(|_| paths)
+}
+
+// The image format new snapshots are packed into, None for plain directories
+fn image_format() -> Result<Option<(&'static str, &'static str)>, ForgeError> {
+    let format = config::snapshot_format();
+    if format == "dir" {
+        return Ok(None);
+    }
+    IMAGE_FORMATS.iter().copied().find(|(name, _)| *name == format)
+        .map(Some)
+        .ok_or_else(|| format!("Unknown snapshot format {}, use dir, squashfs or erofs", format).into())
+}
+
+// The packed image behind the snapshot at `path`, if it was stored as one
+fn packed_image(path: &Path) -> Option<(PathBuf, &'static str)> {
+    IMAGE_FORMATS.iter()
+        .map(|(format, _)| (path.with_extension(format), *format))
+        .find(|(image, _)| image.is_file())
+}
+
+// Pack an unpacked layer into a filesystem image beside `path` and mount it there
+fn pack_image(unpacked: &Path, path: &Path, format: &'static str, tool: &str) -> Result<(), ForgeError> {
+    let image = path.with_extension(format);
+    let staging = temp_path(&image);
+    let mut cmd = Command::new(tool);
+    match format {
+        // Whiteouts are device nodes and opaque directories xattrs, both of which have to survive
+        "squashfs" => cmd.arg(unpacked).arg(&staging).args(["-noappend", "-quiet", "-xattrs", "-comp", "zstd"]),
+        _ => cmd.arg(&staging).arg(unpacked),
+    };
+    let packed = tools::run(&mut cmd, &format!("Failed to pack snapshot as {}", format))
+        .and_then(|_| Ok(fs::rename(&staging, &image)?));
+    if let Err(e) = packed {
+        let _ = fs::remove_file(&staging);
+        return Err(e);
+    }
+    debug!("Packed {:?} as {}", image, format);
+    mount_image(&image, format, path)
+}
+
+fn mount_image(image: &Path, format: &str, path: &Path) -> Result<(), ForgeError> {
+    if is_mounted(path) {
+        return Ok(());
+    }
+    fs::create_dir_all(path)?;
+    tools::run(
+        Command::new("mount").args(["-t", format, "-o", "loop,ro"]).arg(image).arg(path),
+        &format!("Failed to mount snapshot {:?}", image),
+    )?;
+    Ok(())
+}
+
+// overlayfs splits lowerdir on ':', so name snapshots by the digest's hex alone
+fn snapshot_name(digest: &str) -> &str {
+    digest.strip_prefix("sha256:").unwrap_or(digest)
+}
+
+// Either overlay will do for a container's rootfs
+fn overlay_available() -> bool {
+    overlay_supported() || fuse_overlay_available()
+}
+
+fn fuse_overlay_available() -> bool {
+    rootless::active() && rootless::in_path("fuse-overlayfs")
+}
+
+fn overlay_supported() -> bool {
+    fs::read_to_string("/proc/filesystems")
+        .map(|list| list.lines().any(|line| line.split_whitespace().last() == Some("overlay")))
+        .unwrap_or(false)
+}
+
+/// Delete snapshots whose layer is gone from this store and the read-only stores, and
+/// half-written ones, unless a running container has them mounted. Returns how many went.
+pub fn remove_unused(store: &ImageStore) -> Result<usize, ForgeError> {
+    let dir = dir(store);
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return Ok(0);
+    };
+    let mounted = mounted_lower_dirs();
+    let stores: Vec<PathBuf> = std::iter::once(store.root.clone()).chain(config::additional_stores()).collect();
+
+    let mut removed = 0;
+    for entry in entries {
+        let mut path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        // A packed image and its mount point are one snapshot; go by the mount point
+        let is_image = IMAGE_FORMATS.iter().any(|(format, _)| name.ends_with(&format!(".{}", format)));
+        if is_image && !name.starts_with('.') {
+            path.set_extension("");
+            if path.exists() {
+                continue;
+            }
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let layer = format!("sha256:{}", name);
+        if !name.starts_with('.') && stores.iter().any(|s| s.join("layers").join(&layer).exists()) {
+            continue;
+        }
+        if mounted.contains(&path) {
+            debug!("Snapshot {} is in use, keeping it", name);
+            continue;
+        }
+        debug!("Removing snapshot {}", name);
+        remove_snapshot(&path)?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+// Delete a snapshot directory, or a packed image along with its mount point
+fn remove_snapshot(path: &Path) -> Result<(), ForgeError> {
+    if is_mounted(path) {
+        umount2(path, MntFlags::MNT_DETACH)?;
+    }
+    for (format, _) in IMAGE_FORMATS {
+        let image = path.with_extension(format);
+        if image.is_file() {
+            fs::remove_file(image)?;
+        }
+    }
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+// Lower directories of every overlay mounted on this host
+fn mounted_lower_dirs() -> HashSet<PathBuf> {
+    let mounts = fs::read_to_string("/proc/self/mounts").unwrap_or_default();
+    mounts.lines()
+        .filter(|line| line.split_whitespace().nth(2) == Some("overlay"))
+        .filter_map(|line| line.split_whitespace().nth(3))
+        .flat_map(|options| options.split(','))
+        .filter_map(|option| option.strip_prefix("lowerdir="))
+        .flat_map(|lower| lower.split(':'))
+        .map(PathBuf::from)
+        .collect()
+}
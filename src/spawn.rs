@@ -0,0 +1,111 @@
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::libc;
+use nix::sched::CloneFlags;
+use nix::unistd::{pipe2, Pid};
+use std::fs;
+use std::io::{Read, Write};
+use std::os::fd::OwnedFd;
+use std::process;
+
+use crate::error::ForgeError;
+
+// PID 1 is cloned with clone3 straight into its namespaces, so there is no moment in which the
+// runtime could set up the network of a process that isn't in its own network namespace yet.
+// The two then talk over a pair of pipes:
+//   go      runtime -> PID 1: one byte once the network is up and the prestart hooks have run;
+//           closed without it, PID 1 gives up
+//   status  PID 1 -> runtime, close-on-exec: the exit code and message of a failure before the
+//           entrypoint runs, or nothing at all once the exec closed it
+
+const SYS_CLONE3: libc::c_long = 435;
+
+// struct clone_args from <linux/sched.h>, up to the cgroup field of Linux 5.7
+#[repr(C)]
+#[derive(Default)]
+struct CloneArgs {
+    flags: u64,
+    pidfd: u64,
+    child_tid: u64,
+    parent_tid: u64,
+    exit_signal: u64,
+    stack: u64,
+    stack_size: u64,
+    tls: u64,
+    set_tid: u64,
+    set_tid_size: u64,
+    cgroup: u64,
+}
+
+/// Which side of `clone` this process is on
+pub enum Spawn {
+    Parent(Child),
+    Child(Runtime),
+}
+
+/// The runtime's hold on a PID 1 that waits to go on
+pub struct Child {
+    pub pid: Pid,
+    go: OwnedFd,
+    status: OwnedFd,
+}
+
+/// PID 1's line back to the runtime that cloned it
+pub struct Runtime {
+    go: Option<OwnedFd>,
+    status: OwnedFd,
+}
+
+/// Clone a child into new namespaces `flags`, as fork does otherwise. The caller must have
+/// no other threads, as the child gets none of fork's help with their locks.
+pub fn clone(flags: CloneFlags) -> Result<Spawn, ForgeError> {
+    let (go_read, go_write) = pipe2(OFlag::O_CLOEXEC).map_err(|e| ForgeError::namespace("create a pipe", e))?;
+    let (status_read, status_write) = pipe2(OFlag::O_CLOEXEC).map_err(|e| ForgeError::namespace("create a pipe", e))?;
+    let args = CloneArgs { flags: flags.bits() as u64, exit_signal: libc::SIGCHLD as u64, ..CloneArgs::default() };
+    let pid = Errno::result(unsafe { libc::syscall(SYS_CLONE3, &args as *const CloneArgs, std::mem::size_of::<CloneArgs>()) })
+        .map_err(|e| ForgeError::namespace("clone PID 1 into its namespaces", e))?;
+    if pid == 0 {
+        Ok(Spawn::Child(Runtime { go: Some(go_read), status: status_write }))
+    } else {
+        Ok(Spawn::Parent(Child { pid: Pid::from_raw(pid as libc::pid_t), go: go_write, status: status_read }))
+    }
+}
+
+impl Child {
+    /// Let PID 1 go on, then wait until it has exec'd, returning the failure it reported if it
+    /// didn't get that far
+    pub fn go(self) -> Result<(), ForgeError> {
+        let Child { go, status, .. } = self;
+        // PID 1 having died already shows in the status it reported
+        let _ = fs::File::from(go).write_all(&[1]);
+        let mut report = Vec::new();
+        let _ = fs::File::from(status).read_to_end(&mut report);
+        match report.split_first_chunk::<4>() {
+            None => Ok(()),
+            Some((code, message)) => Err(ForgeError::Child {
+                code: i32::from_le_bytes(*code),
+                message: String::from_utf8_lossy(message).into_owned(),
+            }),
+        }
+    }
+}
+
+impl Runtime {
+    /// Wait for the runtime to let PID 1 go on; exits if it never will
+    pub fn wait_for_go(&mut self) {
+        let mut byte = [0];
+        let told = self.go.take().is_some_and(|go| fs::File::from(go).read(&mut byte).unwrap_or(0) == 1);
+        if !told {
+            process::exit(1);
+        }
+    }
+
+    /// Report a failure to the runtime and exit with the code main gives it
+    pub fn fail(self, error: &ForgeError) -> ! {
+        let code = crate::exit_code(error);
+        let mut report = code.to_le_bytes().to_vec();
+        report.extend_from_slice(error.to_string().as_bytes());
+        let _ = fs::File::from(self.status).write_all(&report);
+        process::exit(code)
+    }
+}
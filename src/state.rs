@@ -0,0 +1,325 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use log::warn;
+
+use crate::cgroups::{self, CgroupStats};
+use crate::filesystem::BindMount;
+use crate::health::HealthState;
+use crate::image::{self, write_atomic};
+use crate::rwlayer::{self, ContainerRecord};
+use crate::error::ForgeError;
+
+// What a container is doing, as opposed to container.json, which records how it was created:
+//   containers/<id>/state.json   rewritten atomically on every transition
+// Only the runtime that holds the container's lock moves it to running and exited; a runtime
+// that is killed leaves "running" behind, which `load` corrects from the lock.
+
+const STATE_FILE: &str = "state.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Created,
+    Running,
+    Paused,
+    Restarting,                 // between the runtime exiting and the restart policy starting the next
+    Exited,
+    #[serde(rename = "timed-out")]
+    TimedOut,                   // exited after being stopped by its `run --timeout`
+}
+
+/// Where a running container can be reached
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetworkState {
+    pub ip_address: String,
+    pub host_veth: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContainerState {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    pub image: String,
+    pub status: Status,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<i32>,       // the container's PID 1 as the host sees it, while it runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>, // 128 + the signal when PID 1 was killed; unknown if the runtime was
+    pub created: String,        // RFC 3339, like started and finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkState>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mounts: Vec<String>,    // "SOURCE:TARGET" as mounted on the last start
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub restart_count: u32,     // restarts by the restart policy since `run` or `start`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stopped: bool,          // `stop` was asked for, so the restart policy leaves it down
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<HealthState>,  // from the HEALTHCHECK probes since the last start
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub oom_kills: u32,         // processes the OOM killer took since the last start
+}
+
+impl ContainerState {
+    /// A container that has never been started
+    pub fn new(record: &ContainerRecord) -> Self {
+        ContainerState {
+            id: record.id.clone(),
+            name: record.name.clone(),
+            image: record.image.clone(),
+            status: Status::Created,
+            pid: None,
+            exit_code: None,
+            created: record.created.clone(),
+            started: None,
+            finished: None,
+            network: None,
+            mounts: Vec::new(),
+            restart_count: 0,
+            stopped: false,
+            health: None,
+            oom_kills: 0,
+        }
+    }
+
+    /// PID 1 is up with its network and mounts in place
+    pub fn start(&mut self, pid: i32, mounts: &[BindMount], address: Ipv4Addr) {
+        self.status = Status::Running;
+        self.pid = Some(pid);
+        self.exit_code = None;
+        self.started = Some(image::now_rfc3339());
+        self.finished = None;
+        self.network = Some(NetworkState {
+            ip_address: address.to_string(),
+            host_veth: format!("veth-{}", pid),
+        });
+        self.mounts = mounts.iter().map(|m| format!("{}:{}", m.source.display(), m.target)).collect();
+        self.health = None;
+        self.oom_kills = 0;
+    }
+
+    /// `run` or `start` is about to start the container, rather than its restart policy
+    pub fn starting(&mut self) {
+        self.restart_count = 0;
+        self.stopped = false;
+    }
+
+    /// The runtime has exited and the restart policy starts another after a delay
+    pub fn restarting(&mut self, exit_code: Option<i32>) {
+        self.exit(exit_code);
+        self.status = Status::Restarting;
+        self.restart_count += 1;
+    }
+
+    /// Everything in the container has exited and its runtime has cleaned up
+    pub fn exit(&mut self, exit_code: Option<i32>) {
+        self.status = Status::Exited;
+        self.pid = None;
+        self.exit_code = exit_code;
+        self.finished = Some(image::now_rfc3339());
+        self.network = None;
+    }
+
+    /// Stopped when its timeout ran out, then cleaned up like any exit
+    pub fn time_out(&mut self, exit_code: Option<i32>) {
+        self.exit(exit_code);
+        self.status = Status::TimedOut;
+    }
+
+    pub fn is_active(&self) -> bool {
+        matches!(self.status, Status::Running | Status::Paused | Status::Restarting)
+    }
+
+    /// "Up 5 minutes (healthy)", "Up 5 minutes (Paused)", "Exited (0) 3 hours ago", "Exited (137, OOM) 1 hour ago"
+    pub fn describe(&self) -> String {
+        let since = |time: &Option<String>| image::format_age(time.as_deref().unwrap_or_default());
+        match self.status {
+            Status::Created => "Created".to_string(),
+            Status::Running => match &self.health {
+                Some(health) => format!("Up {} ({})", since(&self.started).trim_end_matches(" ago"), health.status),
+                None => format!("Up {}", since(&self.started).trim_end_matches(" ago")),
+            },
+            Status::Paused => format!("Up {} (Paused)", since(&self.started).trim_end_matches(" ago")),
+            Status::Restarting => format!("Restarting ({}) {}", self.exit_code.unwrap_or_default(), since(&self.finished)),
+            Status::Exited => match self.exit_code {
+                Some(code) if self.oom_kills > 0 => format!("Exited ({}, OOM) {}", code, since(&self.finished)),
+                Some(code) => format!("Exited ({}) {}", code, since(&self.finished)),
+                None => format!("Exited {}", since(&self.finished)),
+            },
+            Status::TimedOut => format!("Timed out ({}) {}", self.exit_code.unwrap_or_default(), since(&self.finished)),
+        }
+    }
+
+    pub fn save(&self, container_dir: &Path) -> Result<(), ForgeError> {
+        write_atomic(&container_dir.join(STATE_FILE), serde_json::to_string_pretty(self)?.as_bytes())
+    }
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+/// The container's state, corrected when whatever ran it is gone without saying so
+pub fn load(record: &ContainerRecord) -> Result<ContainerState, ForgeError> {
+    let mut state = read(record)?;
+    if state.is_active() && !record.is_running() {
+        state.exit(None);
+    }
+    Ok(state)
+}
+
+/// Load, change and save a container's state
+pub fn update(record: &ContainerRecord, change: impl FnOnce(&mut ContainerState)) -> Result<(), ForgeError> {
+    let mut state = read(record)?;
+    change(&mut state);
+    state.save(&record.dir()?)
+}
+
+/// The state as last saved, without checking it against the lock
+pub fn read(record: &ContainerRecord) -> Result<ContainerState, ForgeError> {
+    let path = record.dir()?.join(STATE_FILE);
+    match fs::read_to_string(&path) {
+        Ok(json) => Ok(serde_json::from_str(&json).map_err(|e| format!("Unreadable {:?}: {}", path, e))?),
+        // Containers from before state was kept
+        Err(_) => Ok(ContainerState::new(record)),
+    }
+}
+
+/// Save a state change from the runtime; the container keeps going if it can't be recorded
+pub fn record(record: &ContainerRecord, change: impl FnOnce(&mut ContainerState)) {
+    if let Err(e) = update(record, change) {
+        warn!("Failed to save the state of container {}: {}", record.display_name(), e);
+    }
+}
+
+/// ps [-a]: running containers, or all of them
+pub fn ps_command(args: &[String]) -> Result<(), ForgeError> {
+    let all = args[2..].iter().any(|a| a == "-a" || a == "--all");
+    if let Some(other) = args[2..].iter().find(|a| *a != "-a" && *a != "--all") {
+        return Err(format!("Unknown option {}, usage: container-runtime ps [-a]", other).into());
+    }
+
+    let mut containers = Vec::new();
+    for record in rwlayer::list()? {
+        let state = load(&record)?;
+        if all || state.is_active() {
+            containers.push((record, state));
+        }
+    }
+    containers.sort_by(|a, b| b.0.created.cmp(&a.0.created));
+
+    println!("{:<14} {:<30} {:<28} {:<18} NAMES", "CONTAINER ID", "IMAGE", "STATUS", "CREATED");
+    for (record, state) in containers {
+        println!("{:<14} {:<30} {:<28} {:<18} {}",
+            rwlayer::short_id(&record.id), record.image, state.describe(),
+            image::format_age(&record.created), record.name);
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Inspect<'a> {
+    #[serde(flatten)]
+    record: &'a ContainerRecord,
+    state: ContainerState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<CgroupStats>,     // while it runs
+}
+
+/// inspect CONTAINER: how the container was created and what it is doing, as JSON
+pub fn inspect_command(args: &[String]) -> Result<(), ForgeError> {
+    let reference = args.get(2).ok_or("Usage: container-runtime inspect CONTAINER")?;
+    let record = rwlayer::find(reference)?;
+    let state = load(&record)?;
+    let stats = state.is_active().then(|| cgroups::stats(&format!("img-{}", record.id)).ok()).flatten();
+    println!("{}", serde_json::to_string_pretty(&Inspect { record: &record, state, stats })?);
+    Ok(())
+}
+
+/// stats [--no-stream] [--pressure] [CONTAINER...]: what running containers use, or with
+/// --pressure how long they stalled, every container's unless named, refreshed every second
+pub fn stats_command(args: &[String]) -> Result<(), ForgeError> {
+    let no_stream = args[2..].iter().any(|a| a == "--no-stream");
+    let pressure = args[2..].iter().any(|a| a == "--pressure");
+    if let Some(other) = args[2..].iter().find(|a| a.starts_with('-') && *a != "--no-stream" && *a != "--pressure") {
+        return Err(format!("Unknown option {}, usage: container-runtime stats [--no-stream] [--pressure] [CONTAINER...]", other).into());
+    }
+    let named: Vec<&String> = args[2..].iter().filter(|a| !a.starts_with('-')).collect();
+    let records = if named.is_empty() {
+        rwlayer::list()?.into_iter().filter(|r| load(r).is_ok_and(|s| s.is_active())).collect()
+    } else {
+        named.iter().map(|name| rwlayer::find(name)).collect::<Result<Vec<_>, _>>()?
+    };
+    let host_memory = host_memory();
+
+    // CPU use is a rate, so the first table waits for a second sample
+    let sample = |records: &[ContainerRecord]| -> Vec<Option<CgroupStats>> {
+        records.iter().map(|r| cgroups::stats(&format!("img-{}", r.id)).ok()).collect()
+    };
+    let mut previous = (Instant::now(), sample(&records));
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+        let current = (Instant::now(), sample(&records));
+        let elapsed = current.0 - previous.0;
+        if !no_stream {
+            // Clear the terminal for the next table
+            print!("\x1b[2J\x1b[H");
+        }
+        if pressure {
+            print_pressure(&records, &current.1);
+        } else {
+            print_usage(&records, &current.1, &previous.1, elapsed, host_memory);
+        }
+        if no_stream {
+            return Ok(());
+        }
+        previous = current;
+    }
+}
+
+fn print_usage(records: &[ContainerRecord], stats: &[Option<CgroupStats>], previous: &[Option<CgroupStats>], elapsed: Duration, host_memory: u64) {
+    println!("{:<14} {:<20} {:>7} {:>21} {:>7} {:>19} {:>5}", "CONTAINER ID", "NAME", "CPU %", "MEM USAGE / LIMIT", "MEM %", "BLOCK I/O", "PIDS");
+    for (record, (now, before)) in records.iter().zip(stats.iter().zip(previous)) {
+        let id = rwlayer::short_id(&record.id);
+        let (Some(now), Some(before)) = (now, before) else {
+            println!("{:<14} {:<20} {:>7} {:>21} {:>7} {:>19} {:>5}", id, record.name, "--", "--", "--", "--", "--");
+            continue;
+        };
+        let limit = now.memory.limit.unwrap_or(host_memory);
+        println!("{:<14} {:<20} {:>6.2}% {:>21} {:>6.2}% {:>19} {:>5}",
+            id, record.name, now.cpu_percent(before, elapsed),
+            format!("{} / {}", image::format_size(now.memory.usage), image::format_size(limit)),
+            if limit > 0 { now.memory.usage as f64 / limit as f64 * 100.0 } else { 0.0 },
+            format!("{} / {}", image::format_size(now.io.read_bytes), image::format_size(now.io.write_bytes)),
+            now.pids.current);
+    }
+}
+
+// The share of the last 10 seconds that some or all of each container's tasks were stalled
+fn print_pressure(records: &[ContainerRecord], stats: &[Option<CgroupStats>]) {
+    println!("{:<14} {:<20} {:>9} {:>9} {:>9} {:>9} {:>9}", "CONTAINER ID", "NAME", "CPU SOME", "MEM SOME", "MEM FULL", "IO SOME", "IO FULL");
+    for (record, stats) in records.iter().zip(stats) {
+        let id = rwlayer::short_id(&record.id);
+        match stats.as_ref().and_then(|s| s.pressure.as_ref()) {
+            Some(p) => println!("{:<14} {:<20} {:>8.2}% {:>8.2}% {:>8.2}% {:>8.2}% {:>8.2}%", id, record.name,
+                p.cpu.some.avg10, p.memory.some.avg10, p.memory.full.avg10, p.io.some.avg10, p.io.full.avg10),
+            None => println!("{:<14} {:<20} {:>9} {:>9} {:>9} {:>9} {:>9}", id, record.name, "--", "--", "--", "--", "--"),
+        }
+    }
+}
+
+// MemTotal from /proc/meminfo, the limit of a container without one
+fn host_memory() -> u64 {
+    fs::read_to_string("/proc/meminfo").unwrap_or_default().lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|kb| kb.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map_or(0, |kb| kb * 1024)
+}
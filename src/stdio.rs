@@ -0,0 +1,108 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use nix::fcntl::OFlag;
+use nix::pty::PtyMaster;
+use nix::unistd::{dup2, pipe2, setsid};
+use log::debug;
+
+use crate::attach;
+use crate::tty;
+use crate::error::ForgeError;
+
+// The container never gets the supervisor's own stdin, stdout and stderr. Without --tty it
+// writes into pipes the supervisor copies out to its stdout and stderr, so `run image > out.txt`
+// and `run image | grep` see everything the container wrote, and with -i it reads from a pipe
+// the supervisor fills from its stdin and closes on EOF. Without -i its stdin is a pipe that is
+// already closed, which reads like /dev/null; being pipes, all three can be checkpointed.
+
+/// What the container's PID 1 gets as stdin, stdout and stderr
+pub enum ContainerStdio {
+    Terminal(OwnedFd),
+    Pipes { stdin: OwnedFd, stdout: OwnedFd, stderr: OwnedFd },
+}
+
+/// The supervisor's ends of the container's stdio
+pub enum HostStdio {
+    Terminal { master: Arc<PtyMaster>, container_dir: PathBuf },
+    Pipes { stdin: Option<OwnedFd>, stdout: OwnedFd, stderr: OwnedFd },
+}
+
+/// A pseudo-terminal served for attaching with --tty, pipes otherwise; stdin is only passed on when `interactive`
+pub fn open(tty: bool, interactive: bool, container_dir: &Path) -> Result<(ContainerStdio, HostStdio), ForgeError> {
+    if tty {
+        let pty = tty::open_pty()?;
+        let host = HostStdio::Terminal { master: Arc::new(pty.master), container_dir: container_dir.to_path_buf() };
+        return Ok((ContainerStdio::Terminal(pty.slave), host));
+    }
+    // Close-on-exec until PID 1 moves them onto 0, 1 and 2
+    let (stdin_read, stdin_write) = pipe2(OFlag::O_CLOEXEC)?;
+    let stdin_write = interactive.then_some(stdin_write);
+    let (stdout_read, stdout_write) = pipe2(OFlag::O_CLOEXEC)?;
+    let (stderr_read, stderr_write) = pipe2(OFlag::O_CLOEXEC)?;
+    Ok((
+        ContainerStdio::Pipes { stdin: stdin_read, stdout: stdout_write, stderr: stderr_write },
+        HostStdio::Pipes { stdin: stdin_write, stdout: stdout_read, stderr: stderr_read },
+    ))
+}
+
+impl ContainerStdio {
+    /// In the container's PID 1, before it leaves the host's mount namespace
+    pub fn attach(&self) -> Result<(), ForgeError> {
+        match self {
+            ContainerStdio::Terminal(slave) => tty::make_controlling(slave),
+            ContainerStdio::Pipes { stdin, stdout, stderr } => {
+                // A session of its own all the same, rather than the supervisor's with its terminal
+                setsid()?;
+                dup2(stdin.as_raw_fd(), 0)?;
+                dup2(stdout.as_raw_fd(), 1)?;
+                dup2(stderr.as_raw_fd(), 2)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl HostStdio {
+    /// Start copying; the returned threads end once everything in the container has closed its
+    /// stdout and stderr. Stdin is copied on a thread of its own that nothing waits for.
+    pub fn forward(&mut self) -> Vec<JoinHandle<()>> {
+        match self {
+            HostStdio::Terminal { master, container_dir } => vec![attach::serve(container_dir, master)],
+            HostStdio::Pipes { stdin, stdout, stderr } => {
+                if let Some(stdin) = stdin.take() {
+                    thread::spawn(move || {
+                        tty::block_signals();
+                        let mut pipe = fs::File::from(stdin);
+                        let _ = io::copy(&mut io::stdin().lock(), &mut pipe);
+                        // Dropping the pipe's last write end is the container's EOF
+                        debug!("Stdin closed, passing EOF on to the container");
+                    });
+                }
+                let mut threads = Vec::new();
+                if let Ok(stdout) = stdout.try_clone() {
+                    threads.push(thread::spawn(move || copy_out(stdout, io::stdout())));
+                }
+                if let Ok(stderr) = stderr.try_clone() {
+                    threads.push(thread::spawn(move || copy_out(stderr, io::stderr())));
+                }
+                threads
+            }
+        }
+    }
+}
+
+// Written through as it arrives, so prompts without a newline show up too
+fn copy_out(from: OwnedFd, mut to: impl Write) {
+    tty::block_signals();
+    let mut from = fs::File::from(from);
+    let mut buffer = [0u8; 8192];
+    while let Ok(n @ 1..) = from.read(&mut buffer) {
+        if to.write_all(&buffer[..n]).and_then(|_| to.flush()).is_err() {
+            break;
+        }
+    }
+}
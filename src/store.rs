@@ -0,0 +1,227 @@
+use std::collections::HashSet;
+use std::fs;
+use std::time::SystemTime;
+use log::{debug, info, warn};
+
+use crate::config;
+use crate::image::{image_digest, write_atomic, ImageStore};
+use crate::registry::verify_file;
+use crate::snapshot;
+use crate::error::ForgeError;
+
+const VERSION_FILE: &str = "version";
+
+// Layout history:
+//   1 - manifests carry only name/tag/layers (no version file)
+//   2 - manifests record their platform and content digest
+//   3 - cache_usage.json records when each cache key was last used, for builder prune
+//   4 - base image downloads move out of base-images/ to the download cache (config::download_cache_dir)
+//   5 - layers are unpacked once into snapshots/ and containers mount them with overlayfs
+// Containers' writable layers live under the state root, not here, and carry their own image reference.
+pub const STORE_VERSION: u32 = 5;
+
+/// Bring an older store up to STORE_VERSION, refusing stores written by a newer release
+pub fn migrate(store: &ImageStore) -> Result<(), ForgeError> {
+    let mut version = read_version(store)?;
+    if version == STORE_VERSION {
+        return Ok(());
+    }
+    if version > STORE_VERSION {
+        return Err(format!(
+            "Image store {:?} is version {}, this build only understands up to {}",
+            store.root, version, STORE_VERSION
+        ).into());
+    }
+
+    let _lock = store.lock_exclusive()?;
+    // Another process may have migrated while we waited for the lock
+    version = read_version(store)?;
+
+    // A store without images has nothing to migrate, it just needs stamping
+    if version < STORE_VERSION && store.list_images()?.is_empty() {
+        return write_atomic(&store.root.join(VERSION_FILE), STORE_VERSION.to_string().as_bytes());
+    }
+
+    while version < STORE_VERSION {
+        info!("Migrating image store from version {} to {}", version, version + 1);
+        match version {
+            1 => record_platforms_and_digests(store)?,
+            2 => record_cache_usage(store)?,
+            3 => remove_base_image_downloads(store)?,
+            4 => fs::create_dir_all(snapshot::dir(store))?,
+            other => return Err(format!("No migration from store version {}", other).into()),
+        }
+        version += 1;
+        write_atomic(&store.root.join(VERSION_FILE), version.to_string().as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Refuse stores written by a newer release without changing anything, for stores that are only read
+pub fn check_version(store: &ImageStore) -> Result<(), ForgeError> {
+    let version = read_version(store)?;
+    if version > STORE_VERSION {
+        return Err(format!(
+            "Image store {:?} is version {}, this build only understands up to {}",
+            store.root, version, STORE_VERSION
+        ).into());
+    }
+    Ok(())
+}
+
+fn read_version(store: &ImageStore) -> Result<u32, ForgeError> {
+    match fs::read_to_string(store.root.join(VERSION_FILE)) {
+        Ok(contents) => contents.trim().parse()
+            .map_err(|_| format!("Corrupt store version file: {:?}", contents.trim()).into()),
+        Err(_) => Ok(1),
+    }
+}
+
+// v1 -> v2: loading fills in the host platform, saving records it along with the digest
+fn record_platforms_and_digests(store: &ImageStore) -> Result<(), ForgeError> {
+    for (name, tag) in store.list_images()? {
+        for entry in store.load_index(&name, &tag)?.manifests {
+            if entry.manifest.digest.is_empty() {
+                debug!("Recording platform and digest for {}:{}", name, tag);
+                store.save_image(entry.manifest, entry.config)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// v2 -> v3: cache keys that predate usage tracking were last used when their layer was written
+fn record_cache_usage(store: &ImageStore) -> Result<(), ForgeError> {
+    let mut usage = store.load_cache_usage();
+    for (key, digest) in store.load_cache_index() {
+        let written = store.get_layer_path(&digest).ok()
+            .and_then(|path| fs::metadata(path).ok())
+            .and_then(|m| m.modified().ok())
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        usage.entry(key).or_insert(written);
+    }
+    store.save_cache_usage(&usage)
+}
+
+// v3 -> v4: downloads are cached by URL now, so the old name-tag-arch tarballs would never be used
+// again; they are fetched once more on the next build that needs them
+fn remove_base_image_downloads(store: &ImageStore) -> Result<(), ForgeError> {
+    let dir = store.root.join("base-images");
+    if dir.is_dir() {
+        debug!("Removing old base image downloads in {:?}", dir);
+        fs::remove_dir_all(&dir)?;
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct CheckReport {
+    pub corrupt_layers: Vec<String>,
+    pub broken_images: Vec<String>,
+    pub cache_entries_removed: usize,
+    pub temp_files_removed: usize,
+    pub snapshots_removed: usize,
+    pub unexpected_entries: Vec<String>,
+}
+
+/// Verify every layer against its digest and every image against its layers and recorded digest.
+/// Corrupt layers and leftover temp files are deleted and the cache index is pruned to intact layers.
+pub fn check(store: &ImageStore) -> Result<CheckReport, ForgeError> {
+    let _lock = store.lock_exclusive()?;
+    let _metadata_lock = store.lock_metadata()?;
+    let mut report = CheckReport::default();
+
+    let mut intact: HashSet<String> = HashSet::new();
+    for entry in fs::read_dir(store.root.join("layers"))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        // Layers are plain files; anything else isn't ours to verify or delete
+        if !entry.file_type()?.is_file() {
+            warn!("Skipping {:?}, which is not a layer file", entry.path());
+            report.unexpected_entries.push(name);
+            continue;
+        }
+
+        // Interrupted writes leave hidden temp files behind
+        if name.starts_with('.') {
+            fs::remove_file(entry.path())?;
+            report.temp_files_removed += 1;
+            continue;
+        }
+
+        match verify_file(&entry.path(), &name) {
+            Ok(()) => {
+                intact.insert(name);
+            }
+            Err(e) => {
+                warn!("Layer {} is corrupt: {}", name, e);
+                fs::remove_file(entry.path())?;
+                report.corrupt_layers.push(name);
+            }
+        }
+    }
+
+    for (name, tag) in store.list_images()? {
+        let index = match store.load_index(&name, &tag) {
+            Ok(index) => index,
+            Err(e) => {
+                warn!("{}:{} has an unreadable manifest: {}", name, tag, e);
+                report.broken_images.push(format!("{}:{}", name, tag));
+                continue;
+            }
+        };
+
+        for entry in index.manifests {
+            let image = format!("{}:{} ({})", name, tag, entry.manifest.platform());
+            if let Some(missing) = entry.manifest.layers.iter().find(|l| !intact.contains(*l)) {
+                warn!("{} is missing layer {}", image, missing);
+                report.broken_images.push(image);
+            } else if !entry.manifest.digest.is_empty() && image_digest(&entry.manifest, &entry.config)? != entry.manifest.digest {
+                warn!("{} does not match its recorded digest {}", image, entry.manifest.digest);
+                report.broken_images.push(image);
+            }
+        }
+    }
+
+    let mut cache_index = store.load_cache_index();
+    let before = cache_index.len();
+    cache_index.retain(|_, digest| intact.contains(digest));
+    report.cache_entries_removed = before - cache_index.len();
+    if report.cache_entries_removed > 0 {
+        store.save_cache_index(&cache_index)?;
+    }
+
+    // Unpacked copies of the corrupt layers go with them
+    report.snapshots_removed = snapshot::remove_unused(store)?;
+
+    Ok(report)
+}
+
+/// Store maintenance subcommands: store check
+pub fn store_command(args: &[String]) -> Result<(), ForgeError> {
+    match args.get(2).map(|s| s.as_str()) {
+        Some("check") => {
+            let store = ImageStore::new(config::images_dir()?)?;
+
+            let report = check(&store)?;
+            info!("Removed {} corrupt layers, {} stale cache entries, {} temp files and {} stale snapshots",
+                report.corrupt_layers.len(), report.cache_entries_removed, report.temp_files_removed, report.snapshots_removed);
+            if !report.unexpected_entries.is_empty() {
+                info!("Left {} entries in layers/ that are not layer files: {}",
+                    report.unexpected_entries.len(), report.unexpected_entries.join(", "));
+            }
+
+            if !report.broken_images.is_empty() {
+                return Err(format!("{} images are damaged and need to be rebuilt or pulled again: {}",
+                    report.broken_images.len(), report.broken_images.join(", ")).into());
+            }
+            info!("Image store is consistent");
+            Ok(())
+        }
+        Some(other) => Err(format!("Unknown store command: {}", other).into()),
+        None => Err("Usage: container-runtime store check".into()),
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{lchown, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use nix::sys::stat::{major, makedev, minor, mknod, Mode, SFlag};
+use tar::{Archive, Builder, Entry, EntryType, Header, HeaderMode};
+use log::debug;
+
+use crate::layer::Compression;
+use crate::rootless;
+use crate::error::ForgeError;
+
+// Tar archives are read and written in process: layers, base image rootfs tarballs, image
+// archives and volume backups. Compressed streams are decoded on the way through, never staged.
+
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// The uncompressed contents of `reader`
+pub fn decoder<'a>(reader: impl Read + 'a, compression: Compression) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        // Like gzip -d, read every member of a concatenated stream
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+    })
+}
+
+/// Open a tarball in any of the compressions publishers use, told apart by their magic bytes
+pub fn open(path: &Path) -> Result<Box<dyn Read>, ForgeError> {
+    let mut magic = [0u8; 6];
+    let n = fs::File::open(path)?.read(&mut magic)?;
+    let file = io::BufReader::new(fs::File::open(path)?);
+    if magic[..n] == *XZ_MAGIC {
+        return Ok(Box::new(xz2::read::XzDecoder::new_multi_decoder(file)));
+    }
+    Ok(decoder(file, Compression::detect(path)?)?)
+}
+
+/// Unpack a tar stream into `dest`, keeping modes, times and, where the user namespace can
+/// hold them, owners. Entries that would land outside `dest` are skipped.
+pub fn unpack(reader: impl Read, dest: &Path) -> Result<(), ForgeError> {
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_preserve_ownerships(rootless::keep_owners());
+    archive.set_overwrite(true);
+
+    // Directories go last, deepest first, so a read-only one can't block its own contents
+    let mut directories = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        match entry.header().entry_type() {
+            EntryType::Directory => directories.push(entry),
+            EntryType::Char | EntryType::Block | EntryType::Fifo => make_node(&entry, dest)?,
+            _ => {
+                entry.unpack_in(dest).map_err(|e| format!("Failed to unpack {}: {}", String::from_utf8_lossy(&entry.path_bytes()), e))?;
+            }
+        }
+    }
+    directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+    for mut dir in directories {
+        dir.unpack_in(dest).map_err(|e| format!("Failed to unpack {}: {}", String::from_utf8_lossy(&dir.path_bytes()), e))?;
+    }
+    Ok(())
+}
+
+// tar::Entry::unpack_in writes device nodes and fifos out as empty regular files
+fn make_node<R: Read>(entry: &Entry<R>, dest: &Path) -> Result<(), ForgeError> {
+    let header = entry.header();
+    let name = entry.path()?;
+    if !name.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        debug!("Skipping {:?}, which points outside the archive", name);
+        return Ok(());
+    }
+    let path = dest.join(&name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+        if !parent.canonicalize()?.starts_with(dest.canonicalize()?) {
+            debug!("Skipping {:?}, whose directory links outside the archive", name);
+            return Ok(());
+        }
+    }
+
+    let kind = match header.entry_type() {
+        EntryType::Char => SFlag::S_IFCHR,
+        EntryType::Block => SFlag::S_IFBLK,
+        _ => SFlag::S_IFIFO,
+    };
+    let device = makedev(header.device_major()?.unwrap_or(0).into(), header.device_minor()?.unwrap_or(0).into());
+    let mode = header.mode()? & 0o7777;
+    let _ = fs::remove_file(&path);
+    mknod(&path, kind, Mode::from_bits_truncate(mode), device)
+        .map_err(|e| format!("Failed to create {:?}: {}", path, e))?;
+    // mknod applies the umask
+    fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+    if rootless::keep_owners() {
+        lchown(&path, Some(header.uid()? as u32), Some(header.gid()? as u32))
+            .map_err(|e| format!("Failed to chown {:?}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+/// Write the contents of `dir` to `out` as a tar stream: numeric owners, hard links kept as links,
+/// and entries in name order so the same tree always packs the same way
+pub fn pack<W: Write>(dir: &Path, out: W) -> Result<W, ForgeError> {
+    let mut builder = Builder::new(out);
+    let mut header = header_for(&fs::symlink_metadata(dir)?);
+    builder.append_data(&mut header, ".", io::empty())?;
+
+    let mut links = HashMap::new();
+    append_tree(&mut builder, dir, Path::new("."), &mut links)?;
+    Ok(builder.into_inner()?)
+}
+
+/// `pack`, compressed
+pub fn pack_compressed<W: Write>(dir: &Path, compression: Compression, out: W) -> Result<W, ForgeError> {
+    Ok(match compression {
+        Compression::None => pack(dir, out)?,
+        Compression::Gzip => pack(dir, GzEncoder::new(out, flate2::Compression::default()))?.finish()?,
+        Compression::Zstd => pack(dir, zstd::Encoder::new(out, 0)?)?.finish()?,
+    })
+}
+
+// Files seen so far that have other hard links, by device and inode
+type Links = HashMap<(u64, u64), PathBuf>;
+
+fn append_tree<W: Write>(builder: &mut Builder<W>, root: &Path, rel: &Path, links: &mut Links) -> Result<(), ForgeError> {
+    let mut entries = fs::read_dir(root.join(rel))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let path = rel.join(entry.file_name());
+        let meta = entry.metadata()?;
+        let file_type = meta.file_type();
+        let mut header = header_for(&meta);
+
+        if file_type.is_dir() {
+            builder.append_data(&mut header, &path, io::empty())?;
+            append_tree(builder, root, &path, links)?;
+        } else if file_type.is_symlink() {
+            builder.append_link(&mut header, &path, fs::read_link(entry.path())?)?;
+        } else if file_type.is_file() {
+            if meta.nlink() > 1 {
+                if let Some(first) = links.get(&(meta.dev(), meta.ino())) {
+                    header.set_entry_type(EntryType::Link);
+                    header.set_size(0);
+                    builder.append_link(&mut header, &path, first)?;
+                    continue;
+                }
+                links.insert((meta.dev(), meta.ino()), path.clone());
+            }
+            builder.append_data(&mut header, &path, fs::File::open(entry.path())?)?;
+        } else if file_type.is_char_device() || file_type.is_block_device() || file_type.is_fifo() {
+            header.set_device_major(major(meta.rdev()) as u32)?;
+            header.set_device_minor(minor(meta.rdev()) as u32)?;
+            builder.append_data(&mut header, &path, io::empty())?;
+        } else {
+            // Like tar, leave sockets out
+            debug!("Skipping socket {:?}", path);
+        }
+    }
+    Ok(())
+}
+
+fn header_for(meta: &fs::Metadata) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_metadata_in_mode(meta, HeaderMode::Complete);
+    if !meta.is_file() {
+        header.set_size(0);
+    }
+    header
+}
@@ -0,0 +1,37 @@
+use std::io;
+use std::process::{Command, Output, Stdio};
+
+use crate::error::ForgeError;
+
+// Host tools such as ip, iptables, cp and mksquashfs do part of the work; these helpers make
+// their failures readable instead of a bare exit status.
+
+/// Run a tool to completion, returning its output or an error carrying what it printed to stderr
+pub fn run(cmd: &mut Command, what: &str) -> Result<Output, ForgeError> {
+    let output = cmd.stdin(Stdio::null()).output().map_err(|e| spawn_error(cmd, e))?;
+    check(&output, what)?;
+    Ok(output)
+}
+
+/// Fail with the tool's stderr if it exited unsuccessfully
+pub fn check(output: &Output, what: &str) -> Result<(), ForgeError> {
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let detail = match stderr.trim() {
+        "" => output.status.to_string(),
+        text => text.to_string(),
+    };
+    Err(format!("{}: {}", what, detail).into())
+}
+
+/// A missing binary gets a clear message instead of "No such file or directory"
+pub fn spawn_error(cmd: &Command, e: io::Error) -> ForgeError {
+    let program = cmd.get_program().to_string_lossy();
+    if e.kind() == io::ErrorKind::NotFound {
+        format!("`{}` is required but was not found in PATH", program).into()
+    } else {
+        format!("Failed to run {}: {}", program, e).into()
+    }
+}
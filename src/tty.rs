@@ -0,0 +1,118 @@
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use nix::fcntl::OFlag;
+use nix::libc;
+use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt, PtyMaster};
+use nix::sys::signal::{pthread_sigmask, sigaction, SaFlags, SigAction, SigHandler, SigSet, SigmaskHow, Signal};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios};
+use nix::unistd::{dup2, setsid};
+use log::warn;
+
+use crate::error::ForgeError;
+
+// With --tty the container gets a pseudo-terminal of its own rather than the host's terminal.
+// The supervisor keeps the master side and serves it to clients attached through attach.rs,
+// which put the host terminal in raw mode so every key reaches the container as typed and pass
+// window size changes on. The container's PID 1 gets the other side as stdio and controlling terminal.
+
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+/// A fresh pseudo-terminal, sized like the host's
+pub struct Pty {
+    pub master: PtyMaster,
+    pub slave: OwnedFd,
+}
+
+pub fn open_pty() -> Result<Pty, ForgeError> {
+    let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY)?;
+    grantpt(&master)?;
+    unlockpt(&master)?;
+    // Opened here rather than by the container, which can't see the host's /dev/pts after the
+    // pivot; std opens it close-on-exec, so the `ip` runs of the network setup don't hold it
+    let slave = fs::OpenOptions::new().read(true).write(true).custom_flags(libc::O_NOCTTY).open(ptsname_r(&master)?)?;
+    copy_window_size(&master);
+    Ok(Pty { master, slave: OwnedFd::from(slave) })
+}
+
+/// In the container's PID 1: start a session with the terminal as its controlling terminal and use it for stdio
+pub fn make_controlling(slave: &OwnedFd) -> Result<(), ForgeError> {
+    setsid()?;
+    if unsafe { libc::ioctl(slave.as_raw_fd(), libc::TIOCSCTTY, 0) } < 0 {
+        return Err(format!("TIOCSCTTY: {}", io::Error::last_os_error()).into());
+    }
+    for fd in 0..=2 {
+        dup2(slave.as_raw_fd(), fd)?;
+    }
+    Ok(())
+}
+
+/// The host terminal's settings from before raw mode, put back when dropped
+pub struct RawMode(Option<Termios>);
+
+/// Put the host terminal in raw mode, if stdin is one
+pub fn enter_raw_mode() -> RawMode {
+    if !io::stdin().is_terminal() {
+        return RawMode(None);
+    }
+    let Ok(saved) = tcgetattr(io::stdin()) else {
+        return RawMode(None);
+    };
+    let mut raw = saved.clone();
+    cfmakeraw(&mut raw);
+    if let Err(e) = tcsetattr(io::stdin(), SetArg::TCSANOW, &raw) {
+        warn!("Failed to put the terminal in raw mode: {}", e);
+        return RawMode(None);
+    }
+    RawMode(Some(saved))
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.0 {
+            let _ = tcsetattr(io::stdin(), SetArg::TCSANOW, saved);
+        }
+    }
+}
+
+extern "C" fn on_resize(_: libc::c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+}
+
+/// Note when the host terminal's window size changes, for `resized`. Without SA_RESTART a
+/// blocked read returns EINTR, so a client waiting for output notices at once.
+pub fn watch_resize() {
+    let action = SigAction::new(SigHandler::Handler(on_resize), SaFlags::empty(), SigSet::empty());
+    if let Err(e) = unsafe { sigaction(Signal::SIGWINCH, &action) } {
+        warn!("Failed to watch for terminal resizes: {}", e);
+    }
+}
+
+/// Whether the window size changed since the last call
+pub fn resized() -> bool {
+    RESIZED.swap(false, Ordering::SeqCst)
+}
+
+/// The host terminal's rows and columns
+pub fn window_size() -> Option<(u16, u16)> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    (unsafe { libc::ioctl(io::stdin().as_raw_fd(), libc::TIOCGWINSZ, &mut size) } == 0).then_some((size.ws_row, size.ws_col))
+}
+
+/// Resize the container's terminal
+pub fn set_window_size(master: &impl AsFd, rows: u16, columns: u16) {
+    let size = libc::winsize { ws_row: rows, ws_col: columns, ws_xpixel: 0, ws_ypixel: 0 };
+    unsafe { libc::ioctl(master.as_fd().as_raw_fd(), libc::TIOCSWINSZ, &size) };
+}
+
+fn copy_window_size(master: &impl AsFd) {
+    if let Some((rows, columns)) = window_size() {
+        set_window_size(master, rows, columns);
+    }
+}
+
+pub fn block_signals() {
+    let _ = pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&SigSet::all()), None);
+}
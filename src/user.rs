@@ -0,0 +1,95 @@
+use nix::errno::Errno;
+use nix::libc;
+use std::fs;
+
+use crate::userns::{self, UserNamespace};
+use crate::error::ForgeError;
+
+/// Who a container's process runs as: the image's USER, `run --user` or `exec --user`
+pub struct User {
+    pub uid: u32,
+    pub gid: u32,
+    pub groups: Vec<u32>,       // supplementary, the primary group first
+    pub name: Option<String>,   // with an entry in the container's /etc/passwd
+    pub home: Option<String>,
+}
+
+impl User {
+    /// USER[:GROUP], as names from the container's /etc/passwd and /etc/group or as numbers
+    pub fn resolve(spec: &str) -> Result<Self, ForgeError> {
+        let (user, group) = match spec.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (spec, None),
+        };
+        let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+        let entry = passwd.lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .find(|fields| fields.len() >= 6 && (fields[0] == user || fields[2] == user));
+        let (name, uid, mut gid, home) = match entry {
+            Some(fields) => {
+                let invalid = || format!("Invalid entry for {} in the container's /etc/passwd", fields[0]);
+                (Some(fields[0]), fields[2].parse().map_err(|_| invalid())?, fields[3].parse().map_err(|_| invalid())?, Some(fields[5].to_string()))
+            }
+            None => match user.parse::<u32>() {
+                // Numeric ids need no entry; the group is the same number, as in Docker
+                Ok(uid) => (None, uid, uid, None),
+                Err(_) => return Err(format!("No user {} in the container's /etc/passwd", user).into()),
+            },
+        };
+
+        let groups_file = fs::read_to_string("/etc/group").unwrap_or_default();
+        let groups: Vec<Vec<&str>> = groups_file.lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .filter(|fields| fields.len() >= 4)
+            .collect();
+        if let Some(group) = group {
+            gid = match groups.iter().find(|fields| fields[0] == group || fields[2] == group) {
+                Some(fields) => fields[2].parse().map_err(|_| format!("Invalid entry for {} in the container's /etc/group", fields[0]))?,
+                None => group.parse().map_err(|_| format!("No group {} in the container's /etc/group", group))?,
+            };
+        }
+        let mut supplementary = vec![gid];
+        if let Some(name) = name {
+            for fields in &groups {
+                if fields[3].split(',').any(|member| member == name) {
+                    match fields[2].parse() {
+                        Ok(id) if !supplementary.contains(&id) => supplementary.push(id),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(User { uid, gid, groups: supplementary, name: name.map(str::to_string), home })
+    }
+
+    /// Set HOME, USER and LOGNAME where the image and `-e` haven't, then take on the user's
+    /// groups and ids, real, effective and saved alike
+    pub fn switch(&self) -> Result<(), Errno> {
+        if std::env::var_os("HOME").is_none() {
+            std::env::set_var("HOME", self.home.as_deref().filter(|h| !h.is_empty()).unwrap_or("/"));
+        }
+        if let Some(name) = &self.name {
+            for key in ["USER", "LOGNAME"] {
+                if std::env::var_os(key).is_none() {
+                    std::env::set_var(key, name);
+                }
+            }
+        }
+        // Groups first, while still allowed to change them
+        if userns::setgroups_allowed() {
+            Errno::result(unsafe { libc::setgroups(self.groups.len(), self.groups.as_ptr()) })?;
+        }
+        Errno::result(unsafe { libc::setresgid(self.gid, self.gid, self.gid) })?;
+        Errno::result(unsafe { libc::setresuid(self.uid, self.uid, self.uid) }).map(drop)
+    }
+}
+
+/// Who the container's processes run as unless told otherwise: the image's USER or `run --user`,
+/// else the user a user namespace was set up for, else root
+pub fn default_user(spec: &str, userns: Option<&UserNamespace>) -> Result<User, ForgeError> {
+    match (spec, userns) {
+        ("", Some(userns)) => Ok(User { uid: userns.uid, gid: userns.gid, groups: Vec::new(), name: None, home: None }),
+        ("", None) => User::resolve("0"),
+        (spec, _) => User::resolve(spec),
+    }
+}
@@ -0,0 +1,231 @@
+use nix::errno::Errno;
+use nix::libc;
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, getpid, pipe, ForkResult};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::MetadataExt;
+
+use crate::error::ForgeError;
+use crate::rootless;
+
+// A container run with --uidmap, --gidmap or --userns keep-id gets a user namespace of its own.
+// PID 1 sets up its root with the runtime's privileges and only then moves into it, just before
+// the entrypoint, so the container's other namespaces and mounts stay the runtime's. Host ids
+// are the runtime's own: for a rootless runtime, 0 is the user and 1 and up their /etc/subuid range.
+
+// The kernel refuses more lines in a uid_map or gid_map
+const MAX_MAPPINGS: usize = 340;
+// ioctl on a namespace file returning the user namespace it belongs to
+const NS_GET_USERNS: libc::c_ulong = 0xb701;
+
+/// `count` ids from `container` up inside the container are `host` and up outside it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct IdMapping {
+    pub container: u32,
+    pub host: u32,
+    pub count: u32,
+}
+
+impl IdMapping {
+    /// CONTAINER_ID:HOST_ID:COUNT, as in `--uidmap 0:100000:65536`
+    pub fn parse(spec: &str) -> Result<Self, ForgeError> {
+        let invalid = || format!("Invalid id mapping {:?}: expected CONTAINER_ID:HOST_ID:COUNT", spec);
+        let fields = spec.split(':').map(|f| f.parse::<u32>().map_err(|_| invalid())).collect::<Result<Vec<_>, _>>()?;
+        let [container, host, count] = fields[..] else {
+            return Err(invalid().into());
+        };
+        if count == 0 || container.checked_add(count - 1).is_none() || host.checked_add(count - 1).is_none() {
+            return Err(format!("Invalid id mapping {:?}: the range is empty or runs past 4294967295", spec).into());
+        }
+        Ok(IdMapping { container, host, count })
+    }
+
+    fn overlaps(&self, other: &IdMapping, side: fn(&IdMapping) -> u32) -> bool {
+        let (a, b) = (side(self) as u64, side(other) as u64);
+        a < b + other.count as u64 && b < a + self.count as u64
+    }
+}
+
+impl std::fmt::Display for IdMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.container, self.host, self.count)
+    }
+}
+
+/// A container's own user namespace: the ids it maps and who its processes run as inside
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserNamespace {
+    pub uid_map: Vec<IdMapping>,
+    pub gid_map: Vec<IdMapping>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl UserNamespace {
+    /// From --uidmap and --gidmap, running as root inside; the uid mappings serve for gids too
+    /// when there is no --gidmap
+    pub fn from_maps(uid_map: Vec<IdMapping>, gid_map: Vec<IdMapping>) -> Result<Self, ForgeError> {
+        if uid_map.is_empty() {
+            return Err("--gidmap needs --uidmap as well".into());
+        }
+        let gid_map = if gid_map.is_empty() { uid_map.clone() } else { gid_map };
+        let userns = UserNamespace { uid_map, gid_map, uid: 0, gid: 0 };
+        userns.validate()?;
+        Ok(userns)
+    }
+
+    /// --userns keep-id: the invoking user has their own uid and gid inside as well, and the
+    /// container runs as them, so what it writes to a bind mount is theirs on the host
+    pub fn keep_id() -> Result<Self, ForgeError> {
+        let (Some(uid), Some(gid)) = (invoking_id("uid"), invoking_id("gid")) else {
+            return Err("--userns keep-id is for rootless containers; as root, use --uidmap and --gidmap".into());
+        };
+        let userns = UserNamespace {
+            uid_map: keep_id_map(uid, available("uid")),
+            gid_map: keep_id_map(gid, available("gid")),
+            uid,
+            gid,
+        };
+        userns.validate()?;
+        Ok(userns)
+    }
+
+    fn validate(&self) -> Result<(), ForgeError> {
+        for (kind, map, id) in [("uid", &self.uid_map, self.uid), ("gid", &self.gid_map, self.gid)] {
+            if map.len() > MAX_MAPPINGS {
+                return Err(format!("At most {} --{}map ranges are allowed, not {}", MAX_MAPPINGS, kind, map.len()).into());
+            }
+            for (i, mapping) in map.iter().enumerate() {
+                for other in &map[..i] {
+                    if mapping.overlaps(other, |m| m.container) {
+                        return Err(format!("--{}map {} and {} map the same container {}s", kind, other, mapping, kind).into());
+                    }
+                    if mapping.overlaps(other, |m| m.host) {
+                        return Err(format!("--{}map {} and {} map the same host {}s", kind, other, mapping, kind).into());
+                    }
+                }
+                let ranges = available(kind);
+                let (first, last) = (mapping.host as u64, mapping.host as u64 + mapping.count as u64 - 1);
+                if !ranges.iter().any(|&(start, count)| start <= first && last < start + count) {
+                    return Err(not_available(kind, mapping, &ranges).into());
+                }
+            }
+            if !map.iter().any(|m| m.container <= id && (id - m.container) < m.count) {
+                return Err(format!("--{}map doesn't map {} {}, which the container runs as", kind, kind, id).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// In the container's PID 1, once its root is set up: move into the namespace, as root of
+    /// it with a full bounding set until it becomes the container's user
+    pub fn enter(&self) -> Result<(), ForgeError> {
+        // Only a process still outside may write the maps, so a child forked beforehand does
+        let pid = getpid();
+        let (ready_read, ready_write) = pipe().map_err(|e| ForgeError::namespace("create a pipe", e))?;
+        let helper = match unsafe { fork() }.map_err(|e| ForgeError::namespace("fork", e))? {
+            ForkResult::Child => {
+                drop(ready_write);
+                if fs::File::from(ready_read).read(&mut [0]).unwrap_or(0) == 0 {
+                    std::process::exit(0);
+                }
+                let written = fs::write(format!("/proc/{}/uid_map", pid), map_lines(&self.uid_map))
+                    .and_then(|_| fs::write(format!("/proc/{}/gid_map", pid), map_lines(&self.gid_map)));
+                std::process::exit(match written {
+                    Ok(()) => 0,
+                    Err(e) => e.raw_os_error().unwrap_or(libc::EIO),
+                });
+            }
+            ForkResult::Parent { child } => child,
+        };
+        drop(ready_read);
+
+        unshare(CloneFlags::CLONE_NEWUSER).map_err(|e| ForgeError::namespace("create the container's user namespace", e))?;
+        let _ = fs::File::from(ready_write).write_all(&[1]);
+        match waitpid(helper, None) {
+            Ok(WaitStatus::Exited(_, 0)) => {}
+            Ok(WaitStatus::Exited(_, errno)) => return Err(ForgeError::namespace("map the container's uids and gids", Errno::from_raw(errno))),
+            Ok(_) => return Err(ForgeError::namespace("map the container's uids and gids", Errno::EINTR)),
+            Err(e) => return Err(ForgeError::namespace("map the container's uids and gids", e)),
+        }
+        Ok(())
+    }
+}
+
+/// Whether this process may change its supplementary groups: a rootless runtime without
+/// subordinate ids had to give that up, for the namespaces below it too
+pub fn setgroups_allowed() -> bool {
+    fs::read_to_string("/proc/self/setgroups").is_ok_and(|s| s.trim() == "allow")
+}
+
+/// The user namespace the namespace `ns` belongs to
+pub fn owner(ns: &fs::File) -> std::io::Result<fs::File> {
+    let fd = unsafe { libc::ioctl(ns.as_raw_fd(), NS_GET_USERNS) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
+
+/// Whether two namespace files are the same namespace
+pub fn same(a: &fs::File, b: &fs::File) -> std::io::Result<bool> {
+    let (a, b) = (a.metadata()?, b.metadata()?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+fn map_lines(map: &[IdMapping]) -> String {
+    map.iter().map(|m| format!("{} {} {}\n", m.container, m.host, m.count)).collect()
+}
+
+// The runtime's own ids as (first, count) ranges, from /proc/self/uid_map or gid_map
+fn available(kind: &str) -> Vec<(u64, u64)> {
+    fs::read_to_string(format!("/proc/self/{}_map", kind)).unwrap_or_default().lines()
+        .filter_map(|line| {
+            let fields: Vec<u64> = line.split_whitespace().filter_map(|f| f.parse().ok()).collect();
+            Some((*fields.first()?, *fields.get(2)?))
+        })
+        .collect()
+}
+
+// The host id a rootless runtime's root is, which is the user who ran it
+fn invoking_id(kind: &str) -> Option<u32> {
+    if !rootless::active() {
+        return None;
+    }
+    fs::read_to_string(format!("/proc/self/{}_map", kind)).ok()?.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.first() == Some(&"0"))
+        .and_then(|fields| fields.get(1)?.parse().ok())
+}
+
+// `id` inside is the user, root of the runtime's namespace; 0 and up take the subordinate ids
+// that follow, and the ids above `id` keep their numbers where the namespace has them
+fn keep_id_map(id: u32, ranges: Vec<(u64, u64)>) -> Vec<IdMapping> {
+    let total = ranges.iter().map(|(_, count)| count).sum::<u64>().min(u32::MAX as u64) as u32;
+    let mut map = vec![IdMapping { container: id, host: 0, count: 1 }];
+    let below = id.min(total.saturating_sub(1));
+    if below > 0 {
+        map.push(IdMapping { container: 0, host: 1, count: below });
+    }
+    if total > id + 1 {
+        map.push(IdMapping { container: id + 1, host: id + 1, count: total - id - 1 });
+    }
+    map
+}
+
+fn not_available(kind: &str, mapping: &IdMapping, ranges: &[(u64, u64)]) -> String {
+    let have = ranges.iter()
+        .map(|(start, count)| if *count == 1 { start.to_string() } else { format!("{}-{}", start, start + count - 1) })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let hint = if ranges.iter().map(|(_, count)| count).sum::<u64>() <= 1 {
+        format!("; add a range for this user to /etc/sub{} and install new{}map for more", kind, kind)
+    } else {
+        format!(", 0 being this user and the rest from /etc/sub{}", kind)
+    };
+    format!("--{}map {}: host {}s {}-{} aren't available, only {}{}", kind, mapping, kind, mapping.host, mapping.host as u64 + mapping.count as u64 - 1, have, hint)
+}
@@ -0,0 +1,407 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::io;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use log::{debug, info, warn};
+
+use crate::config;
+use crate::error::ForgeError;
+use crate::filesystem::{is_mounted, BindMount};
+use crate::image::{self, write_atomic};
+use crate::rwlayer;
+use crate::tarball;
+
+// Named volumes live under the state root, apart from any container, so their data
+// survives `rm` and can be shared between containers:
+//   volumes/<name>/_data          what containers see at the mount target
+//   volumes/<name>/volume.json    driver, options, labels and creation time
+// A driver decides what backs _data; adding a backend means implementing VolumeDriver and
+// adding it to DRIVERS.
+
+const DATA_DIR: &str = "_data";
+const METADATA_FILE: &str = "volume.json";
+const DEFAULT_DRIVER: &str = "local";
+
+/// A storage backend for volumes
+pub trait VolumeDriver: Sync {
+    fn name(&self) -> &'static str;
+    /// Set up a new volume whose _data directory already exists, rejecting options it doesn't know
+    fn create(&self, volume: &Volume) -> Result<(), ForgeError>;
+    /// Make the volume's data available before a container starts, returning the host path to bind
+    fn mount(&self, volume: &Volume) -> Result<PathBuf, ForgeError>;
+    /// Called once a container using the volume has stopped
+    fn unmount(&self, volume: &Volume) -> Result<(), ForgeError>;
+    /// Tear the backend down; the volume's directory is deleted afterwards
+    fn remove(&self, volume: &Volume) -> Result<(), ForgeError>;
+}
+
+const DRIVERS: &[&dyn VolumeDriver] = &[&LocalDriver, &TmpfsDriver];
+
+fn driver(name: &str) -> Result<&'static dyn VolumeDriver, ForgeError> {
+    DRIVERS.iter().copied().find(|d| d.name() == name).ok_or_else(|| {
+        let known: Vec<&str> = DRIVERS.iter().map(|d| d.name()).collect();
+        format!("Unknown volume driver {}, available: {}", name, known.join(", ")).into()
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Volume {
+    pub name: String,
+    pub driver: String,         // a name from DRIVERS
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub options: HashMap<String, String>,   // driver options from --opt
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+    pub created: String,        // RFC 3339
+}
+
+impl Volume {
+    pub fn mountpoint(&self) -> Result<PathBuf, ForgeError> {
+        Ok(volume_dir(&self.name)?.join(DATA_DIR))
+    }
+}
+
+/// A plain directory on the host's disk
+struct LocalDriver;
+
+impl VolumeDriver for LocalDriver {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn create(&self, volume: &Volume) -> Result<(), ForgeError> {
+        reject_options(volume, &[])
+    }
+
+    fn mount(&self, volume: &Volume) -> Result<PathBuf, ForgeError> {
+        volume.mountpoint()
+    }
+
+    fn unmount(&self, _volume: &Volume) -> Result<(), ForgeError> {
+        Ok(())
+    }
+
+    fn remove(&self, _volume: &Volume) -> Result<(), ForgeError> {
+        Ok(())
+    }
+}
+
+/// Memory-backed scratch shared between containers; the contents last until the volume is
+/// removed or the host reboots, not across either
+struct TmpfsDriver;
+
+impl VolumeDriver for TmpfsDriver {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn create(&self, volume: &Volume) -> Result<(), ForgeError> {
+        reject_options(volume, &["size", "mode"])
+    }
+
+    fn mount(&self, volume: &Volume) -> Result<PathBuf, ForgeError> {
+        let data = volume.mountpoint()?;
+        if !is_mounted(&data) {
+            let options: Vec<String> = volume.options.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            mount(Some("tmpfs"), &data, Some("tmpfs"), MsFlags::MS_NOSUID | MsFlags::MS_NODEV, Some(options.join(",").as_str()))
+                .map_err(|e| ForgeError::mount(format!("mount tmpfs for volume {}", volume.name), e))?;
+        }
+        Ok(data)
+    }
+
+    // Other containers may still have it mounted, so it stays until the volume goes
+    fn unmount(&self, _volume: &Volume) -> Result<(), ForgeError> {
+        Ok(())
+    }
+
+    fn remove(&self, volume: &Volume) -> Result<(), ForgeError> {
+        let data = volume.mountpoint()?;
+        if is_mounted(&data) {
+            umount2(&data, MntFlags::MNT_DETACH)?;
+        }
+        Ok(())
+    }
+}
+
+fn reject_options(volume: &Volume, known: &[&str]) -> Result<(), ForgeError> {
+    match volume.options.keys().find(|k| !known.contains(&k.as_str())) {
+        Some(key) => Err(format!("The {} volume driver has no option {}", volume.driver, key).into()),
+        None => Ok(()),
+    }
+}
+
+fn volume_dir(name: &str) -> Result<PathBuf, ForgeError> {
+    Ok(config::volumes_dir()?.join(name))
+}
+
+pub fn create(name: &str, driver_name: &str, options: HashMap<String, String>, labels: HashMap<String, String>) -> Result<Volume, ForgeError> {
+    rwlayer::check_name("volume", name)?;
+    let driver = driver(driver_name)?;
+    let dir = volume_dir(name)?;
+    fs::create_dir_all(dir.parent().ok_or("Volume directory has no parent")?)?;
+    // create_dir fails if the volume exists, so two creates can't both win
+    fs::create_dir(&dir).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => format!("Volume {} already exists", name),
+        _ => format!("Failed to create volume {}: {}", name, e),
+    })?;
+
+    let volume = Volume {
+        name: name.to_string(),
+        driver: driver.name().to_string(),
+        options,
+        labels,
+        created: image::now_rfc3339(),
+    };
+    fs::create_dir(dir.join(DATA_DIR))?;
+    if let Err(e) = driver.create(&volume) {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(e);
+    }
+    write_atomic(&dir.join(METADATA_FILE), serde_json::to_string_pretty(&volume)?.as_bytes())?;
+    debug!("Created volume {} at {:?}", name, dir);
+    Ok(volume)
+}
+
+pub fn get(name: &str) -> Result<Volume, ForgeError> {
+    let path = volume_dir(name)?.join(METADATA_FILE);
+    let json = fs::read_to_string(&path).map_err(|_| format!("No such volume: {}", name))?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+pub fn list() -> Result<Vec<Volume>, ForgeError> {
+    let Ok(entries) = fs::read_dir(config::volumes_dir()?) else {
+        return Ok(Vec::new());
+    };
+    let mut volumes = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if let Ok(volume) = get(&name) {
+            volumes.push(volume);
+        }
+    }
+    volumes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(volumes)
+}
+
+/// Containers, running or stopped, with the volume mounted
+pub fn users(name: &str) -> Result<Vec<String>, ForgeError> {
+    Ok(rwlayer::list()?.into_iter()
+        .filter(|c| c.rootfs.mounts.iter().any(|m| m.volume.as_deref() == Some(name)))
+        .map(|c| c.display_name().to_string())
+        .collect())
+}
+
+pub fn remove(name: &str) -> Result<(), ForgeError> {
+    let volume = get(name)?;
+    let users = users(name)?;
+    if !users.is_empty() {
+        return Err(format!("Volume {} is in use by {}", name, users.join(", ")).into());
+    }
+    driver(&volume.driver)?.remove(&volume)?;
+    fs::remove_dir_all(volume_dir(name)?)?;
+    Ok(())
+}
+
+/// Containers running right now with the volume mounted writable, whose writes an export could tear
+fn writers(name: &str) -> Result<Vec<String>, ForgeError> {
+    Ok(rwlayer::list()?.into_iter()
+        .filter(|c| c.rootfs.mounts.iter().any(|m| m.volume.as_deref() == Some(name) && !m.options.iter().any(|o| o == "ro")))
+        .filter(|c| c.is_running())
+        .map(|c| c.display_name().to_string())
+        .collect())
+}
+
+/// Write the volume's contents to a tar archive, owners and modes included
+pub fn export(name: &str, output: &Path) -> Result<(), ForgeError> {
+    let volume = get(name)?;
+    let writers = writers(name)?;
+    if !writers.is_empty() {
+        return Err(format!("Volume {} is mounted read-write by running {}, stop them first", name, writers.join(", ")).into());
+    }
+    let data = driver(&volume.driver)?.mount(&volume)?;
+    let written = fs::File::create(output)
+        .map_err(|e| e.into())
+        .and_then(|file| tarball::pack(&data, io::BufWriter::new(file)))
+        .and_then(|file| Ok(file.into_inner().map_err(|e| e.into_error())?.sync_all()?));
+    written.map_err(|e| format!("Failed to write {:?}: {}", output, e).into())
+}
+
+/// Fill a volume from a tar archive made by `export`, creating the volume if needed
+pub fn import(name: &str, input: &Path) -> Result<(), ForgeError> {
+    if !input.is_file() {
+        return Err(format!("No such archive: {:?}", input).into());
+    }
+    let volume = match get(name) {
+        Ok(volume) => volume,
+        Err(_) => create(name, DEFAULT_DRIVER, HashMap::new(), HashMap::new())?,
+    };
+    let users = users(name)?;
+    if !users.is_empty() {
+        return Err(format!("Volume {} is in use by {}", name, users.join(", ")).into());
+    }
+    let data = driver(&volume.driver)?.mount(&volume)?;
+    // Merging two sets of files is rarely what a restore wants
+    if fs::read_dir(&data)?.next().is_some() {
+        return Err(format!("Volume {} is not empty, remove it or import into a new one", name).into());
+    }
+    tarball::open(input)
+        .and_then(|reader| tarball::unpack(reader, &data))
+        .map_err(|e| format!("Failed to unpack {:?}: {}", input, e).into())
+}
+
+/// Have the drivers of the volumes among `mounts` make them available, pointing each mount at
+/// the path its driver returns
+pub fn mount_all(mounts: &mut [BindMount]) -> Result<(), ForgeError> {
+    for bind in mounts.iter_mut() {
+        if let Some(name) = &bind.volume {
+            let volume = get(name)?;
+            bind.source = driver(&volume.driver)?.mount(&volume)?;
+        }
+    }
+    Ok(())
+}
+
+/// Tell the drivers a container using these mounts has stopped
+pub fn unmount_all(mounts: &[BindMount]) {
+    for name in mounts.iter().filter_map(|m| m.volume.as_ref()) {
+        let unmounted = get(name).and_then(|volume| driver(&volume.driver)?.unmount(&volume));
+        if let Err(e) = unmounted {
+            warn!("Failed to unmount volume {}: {}", name, e);
+        }
+    }
+}
+
+/// Turn a `-v SOURCE:TARGET[:OPTIONS]` spec into a mount, creating named volumes on first use
+pub fn resolve_mount(spec: &str) -> Result<BindMount, ForgeError> {
+    let (source, target, options) = BindMount::parse(spec)?;
+    if source.starts_with('/') {
+        let source = PathBuf::from(source);
+        if !source.exists() {
+            return Err(format!("Bind mount source {:?} does not exist", source).into());
+        }
+        return Ok(BindMount { source, target, volume: None, options });
+    }
+
+    let volume = match get(&source) {
+        Ok(volume) => volume,
+        Err(_) => {
+            info!("Creating volume {}", source);
+            create(&source, DEFAULT_DRIVER, HashMap::new(), HashMap::new())?
+        }
+    };
+    Ok(BindMount { source: volume.mountpoint()?, target, volume: Some(volume.name), options })
+}
+
+/// volume create [--driver NAME] [--opt KEY=VALUE]... [--label KEY=VALUE]... [NAME] | ls | inspect NAME... | rm NAME...
+///        | export NAME -o FILE | import NAME FILE
+pub fn volume_command(args: &[String]) -> Result<(), ForgeError> {
+    match args.get(2).map(|s| s.as_str()) {
+        Some("create") => {
+            let mut driver_name = DEFAULT_DRIVER;
+            let mut options = HashMap::new();
+            let mut labels = HashMap::new();
+            let mut name = None;
+            let mut i = 3;
+            while i < args.len() {
+                match args[i].as_str() {
+                    "--driver" | "-d" => {
+                        driver_name = args.get(i + 1).ok_or("--driver requires a name")?;
+                        i += 2;
+                    }
+                    "--opt" | "-o" => {
+                        let opt = args.get(i + 1).ok_or("--opt requires KEY=VALUE")?;
+                        let (key, value) = opt.split_once('=').ok_or_else(|| format!("Invalid --opt {:?}: expected KEY=VALUE", opt))?;
+                        options.insert(key.to_string(), value.to_string());
+                        i += 2;
+                    }
+                    "--label" | "-l" => {
+                        let label = args.get(i + 1).ok_or("--label requires KEY=VALUE")?;
+                        let (key, value) = label.split_once('=').unwrap_or((label, ""));
+                        labels.insert(key.to_string(), value.to_string());
+                        i += 2;
+                    }
+                    other => {
+                        name = Some(other.to_string());
+                        i += 1;
+                    }
+                }
+            }
+            // Anonymous volumes get a random name, like containers get an id
+            let name = name.unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+            create(&name, driver_name, options, labels)?;
+            println!("{}", name);
+            Ok(())
+        }
+        Some("ls") => {
+            println!("{:<10} {:<40} {:>10}", "DRIVER", "VOLUME NAME", "CONTAINERS");
+            for volume in list()? {
+                println!("{:<10} {:<40} {:>10}", volume.driver, volume.name, users(&volume.name)?.len());
+            }
+            Ok(())
+        }
+        Some("inspect") => {
+            if args.len() < 4 {
+                return Err("Usage: container-runtime volume inspect NAME...".into());
+            }
+            let mut details = Vec::new();
+            for name in &args[3..] {
+                let volume = get(name)?;
+                details.push(serde_json::json!({
+                    "Name": volume.name,
+                    "Driver": volume.driver,
+                    "Options": volume.options,
+                    "Mountpoint": volume.mountpoint()?,
+                    "Labels": volume.labels,
+                    "CreatedAt": volume.created,
+                    "UsedBy": users(name)?,
+                }));
+            }
+            println!("{}", serde_json::to_string_pretty(&details)?);
+            Ok(())
+        }
+        Some("rm") => {
+            if args.len() < 4 {
+                return Err("Usage: container-runtime volume rm NAME...".into());
+            }
+            for name in &args[3..] {
+                remove(name)?;
+                println!("{}", name);
+            }
+            Ok(())
+        }
+        Some("export") => {
+            let usage = "Usage: container-runtime volume export NAME -o FILE";
+            let mut output = None;
+            let mut name = None;
+            let mut i = 3;
+            while i < args.len() {
+                match args[i].as_str() {
+                    "-o" | "--output" => {
+                        output = Some(PathBuf::from(args.get(i + 1).ok_or(usage)?));
+                        i += 2;
+                    }
+                    other => {
+                        name = Some(other);
+                        i += 1;
+                    }
+                }
+            }
+            let (name, output) = (name.ok_or(usage)?, output.ok_or(usage)?);
+            export(name, &output)?;
+            info!("Exported volume {} to {:?}", name, output);
+            Ok(())
+        }
+        Some("import") => {
+            if args.len() < 5 {
+                return Err("Usage: container-runtime volume import NAME FILE".into());
+            }
+            import(&args[3], Path::new(&args[4]))?;
+            info!("Imported {:?} into volume {}", args[4], args[3]);
+            Ok(())
+        }
+        Some(other) => Err(format!("Unknown volume command: {}", other).into()),
+        None => Err("Usage: container-runtime volume create|ls|inspect|rm|export|import".into()),
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+
+// Shell containers started side by side must each get a cgroup, rootfs, veth pair and address
+// of their own, and give the address back when they exit. Needs root, ip and iptables:
+//   sudo -E cargo test --test concurrent_containers -- --ignored
+
+const CONTAINERS: usize = 4;
+
+struct Running {
+    child: Child,
+    stdout: BufReader<std::process::ChildStdout>,
+}
+
+fn start_shell(root: &Path) -> Running {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_container-runtime"))
+        .args(["--cgroup-manager", "cgroupfs"])
+        .env("FORGE_HOME", root)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to start the runtime");
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    Running { child, stdout }
+}
+
+// The container's side of its veth pair and its address, once its network is up
+fn container_interface(running: &mut Running) -> (String, String) {
+    let stdin = running.child.stdin.as_mut().unwrap();
+    stdin.write_all(b"ip -o -4 addr show\n").unwrap();
+    stdin.flush().unwrap();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        assert!(running.stdout.read_line(&mut line).unwrap() > 0, "the container exited before showing its address");
+        // "612: veth-c-15571    inet 10.0.0.2/16 scope global veth-c-15571"
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let [_, name, "inet", address, ..] = fields[..] {
+            if name.starts_with("veth-c-") {
+                return (name.to_string(), address.split('/').next().unwrap().to_string());
+            }
+        }
+    }
+}
+
+fn cgroup_exists(name: &str) -> bool {
+    let v2 = Path::new("/sys/fs/cgroup/cgroup.controllers").exists();
+    let dir = if v2 { format!("/sys/fs/cgroup/forge.slice/{}", name) } else { format!("/sys/fs/cgroup/pids/forge.slice/{}", name) };
+    Path::new(&dir).is_dir()
+}
+
+fn read_leases(root: &Path) -> Vec<(String, PathBuf)> {
+    let Ok(entries) = fs::read_dir(root.join("network/leases")) else {
+        return Vec::new();
+    };
+    entries
+        .map(|entry| {
+            let entry = entry.unwrap();
+            let owner = fs::read_to_string(entry.path()).unwrap();
+            (entry.file_name().to_string_lossy().into_owned(), PathBuf::from(owner))
+        })
+        .collect()
+}
+
+#[test]
+#[ignore = "needs root, ip and iptables"]
+fn containers_started_together_share_nothing() {
+    let root = std::env::temp_dir().join(format!("forge-concurrent-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+
+    let mut shells: Vec<Running> = (0..CONTAINERS).map(|_| start_shell(&root)).collect();
+    let interfaces: Vec<(String, String)> = shells.iter_mut().map(container_interface).collect();
+
+    // Every shell is up at this point, so each holds its lease, cgroup and rootfs
+    let leases = read_leases(&root);
+    assert_eq!(leases.len(), CONTAINERS, "leases: {:?}", leases);
+    let owners: HashSet<&PathBuf> = leases.iter().map(|(_, owner)| owner).collect();
+    assert_eq!(owners.len(), CONTAINERS, "containers sharing a lease: {:?}", leases);
+    let mut cgroups = HashSet::new();
+    let mut rootfs = HashSet::new();
+    for owner in &owners {
+        let name = owner.file_name().unwrap().to_string_lossy().into_owned();
+        assert!(cgroup_exists(&name), "no cgroup {}", name);
+        assert!(owner.join("rootfs").is_dir(), "no rootfs in {:?}", owner);
+        cgroups.insert(name);
+        rootfs.insert(owner.join("rootfs"));
+    }
+    assert_eq!(cgroups.len(), CONTAINERS);
+    assert_eq!(rootfs.len(), CONTAINERS);
+
+    let veths: HashSet<&String> = interfaces.iter().map(|(veth, _)| veth).collect();
+    assert_eq!(veths.len(), CONTAINERS, "containers sharing a veth: {:?}", interfaces);
+    let addresses: HashSet<&String> = interfaces.iter().map(|(_, address)| address).collect();
+    let leased: HashSet<&String> = leases.iter().map(|(address, _)| address).collect();
+    assert_eq!(addresses, leased, "addresses in use aren't the ones leased");
+
+    // The shells exit at the end of their input
+    for mut shell in shells.drain(..) {
+        drop(shell.child.stdin.take());
+        assert!(shell.child.wait().unwrap().success());
+    }
+    let left = read_leases(&root);
+    assert!(left.is_empty(), "leases left behind: {:?}", left);
+    for name in &cgroups {
+        assert!(!cgroup_exists(name), "cgroup {} left behind", name);
+    }
+
+    let _ = fs::remove_dir_all(&root);
+}
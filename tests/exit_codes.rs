@@ -0,0 +1,36 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// `run` exits with the container's own exit code. Needs root and the network to fetch alpine:
+//   sudo -E cargo test --test exit_codes -- --ignored
+
+fn runtime(root: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_container-runtime"));
+    command.args(["--cgroup-manager", "cgroupfs"]).args(args).env("FORGE_HOME", root);
+    command
+}
+
+#[test]
+#[ignore = "needs root and the network"]
+fn run_exits_with_the_containers_code() {
+    let root = std::env::temp_dir().join(format!("forge-exit-codes-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let forgefile = root.join("Forgefile");
+    fs::write(&forgefile, "FROM alpine:3.19\nENTRYPOINT [\"true\"]\n").unwrap();
+    let built = runtime(&root, &["build", "-f", forgefile.to_str().unwrap(), "-t", "exit-codes:v1"]).status().unwrap();
+    assert!(built.success(), "build failed: {}", built);
+
+    let status = |command: &[&str]| {
+        let mut args = vec!["run", "--rm", "exit-codes:v1"];
+        args.extend(command);
+        runtime(&root, &args).status().unwrap().code()
+    };
+    assert_eq!(status(&[]), Some(0));
+    assert_eq!(status(&["false"]), Some(1));
+    assert_eq!(status(&["sh", "-c", "exit 3"]), Some(3));
+    // The runtime's own codes for a command that can't be run
+    assert_eq!(status(&["/does-not-exist"]), Some(127));
+
+    let _ = fs::remove_dir_all(&root);
+}